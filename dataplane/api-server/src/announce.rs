@@ -0,0 +1,247 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Error};
+use aya::maps::{HashMap, MapData};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::netutils::words_to_ip;
+
+/// How often the addresses of the Gateways are checked for new ones to
+/// announce.
+const VIP_SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The EtherType of ARP, and the lengths of the Ethernet header and of the
+/// ARP packets of IPv4 over Ethernet.
+const ETH_P_ARP: u16 = 0x0806;
+const ETH_HDR_LEN: usize = 14;
+const ARP_LEN: usize = 28;
+
+/// The ARP operations.
+const ARPOP_REQUEST: u16 = 1;
+const ARPOP_REPLY: u16 = 2;
+
+const BROADCAST_MAC: [u8; 6] = [0xff; 6];
+
+/// Answers the ARP requests for the IPv4 addresses of the Gateways on the
+/// interface, and announces each address with a gratuitous ARP when this node
+/// starts listening on it (e.g. when the control plane fails a VIP over from
+/// another node), so that the hosts of the segment send the traffic of the
+/// VIPs to this node without BGP. The control plane is expected to program a
+/// VIP on a single node of the segment. The IPv6 addresses aren't announced.
+/// Runs until the interface can't be listened on anymore.
+pub async fn announce_vips(
+    iface: String,
+    vip_addresses_map: Arc<Mutex<HashMap<MapData, [u32; 4], u32>>>,
+) -> Result<(), Error> {
+    let mac = iface_mac(&iface)?;
+    let socket = AsyncFd::new(arp_socket(&iface).context("failed to open an ARP socket")?)?;
+    info!(interface = %iface, "announcing the addresses of the gateways");
+
+    let mut announced = HashSet::new();
+    let mut interval = tokio::time::interval(VIP_SCAN_INTERVAL);
+    let mut buf = [0; ETH_HDR_LEN + ARP_LEN];
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let addresses = match vip_addresses(&vip_addresses_map).await {
+                    Ok(addresses) => addresses,
+                    Err(err) => {
+                        warn!(error = %err, "failed to read the addresses of the gateways");
+                        continue;
+                    }
+                };
+                for addr in addresses.difference(&announced) {
+                    debug!(interface = %iface, vip = %addr, "sending a gratuitous ARP");
+                    let frame = arp_frame(ARPOP_REQUEST, mac, *addr, BROADCAST_MAC, *addr);
+                    if let Err(err) = send(socket.get_ref(), &frame) {
+                        warn!(interface = %iface, vip = %addr, error = %err, "failed to announce");
+                    }
+                }
+                announced = addresses;
+            }
+            guard = socket.readable() => {
+                let mut guard = guard?;
+                let len = match guard.try_io(|socket| recv(socket.get_ref(), &mut buf)) {
+                    Ok(result) => result?,
+                    Err(_would_block) => continue,
+                };
+                // The socket receives the gratuitous ARPs this node sends too, which are requests
+                // for the addresses of the Gateways it must not answer.
+                if buf[..len].get(6..12) == Some(&mac[..]) {
+                    continue;
+                }
+                let (sender_mac, sender_addr, target_addr) = match parse_arp_request(&buf[..len]) {
+                    Some(request) => request,
+                    None => continue,
+                };
+                if !announced.contains(&target_addr) {
+                    continue;
+                }
+                let frame = arp_frame(ARPOP_REPLY, mac, target_addr, sender_mac, sender_addr);
+                if let Err(err) = send(socket.get_ref(), &frame) {
+                    warn!(
+                        interface = %iface,
+                        vip = %target_addr,
+                        sender = %sender_addr,
+                        error = %err,
+                        "failed to answer an ARP request"
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Returns the IPv4 addresses which Gateways listen on.
+async fn vip_addresses(
+    vip_addresses_map: &Mutex<HashMap<MapData, [u32; 4], u32>>,
+) -> Result<HashSet<Ipv4Addr>, Error> {
+    let mut addresses = HashSet::new();
+    for key in vip_addresses_map.lock().await.keys() {
+        if let IpAddr::V4(addr) = words_to_ip(key?) {
+            addresses.insert(addr);
+        }
+    }
+    Ok(addresses)
+}
+
+/// Returns the MAC address of the interface.
+fn iface_mac(iface: &str) -> Result<[u8; 6], Error> {
+    let path = Path::new("/sys/class/net").join(iface).join("address");
+    let address = std::fs::read_to_string(&path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let mut mac = [0; 6];
+    let mut octets = address.trim().split(':');
+    for byte in mac.iter_mut() {
+        *byte = octets
+            .next()
+            .and_then(|octet| u8::from_str_radix(octet, 16).ok())
+            .with_context(|| format!("invalid MAC address {} for {}", address.trim(), iface))?;
+    }
+    Ok(mac)
+}
+
+/// Returns the sender's MAC and IPv4 addresses and the target IPv4 address of
+/// the ARP request in the frame, if it is one.
+/// Ref: https://www.rfc-editor.org/rfc/rfc826
+fn parse_arp_request(frame: &[u8]) -> Option<([u8; 6], Ipv4Addr, Ipv4Addr)> {
+    let arp = frame.get(ETH_HDR_LEN..ETH_HDR_LEN + ARP_LEN)?;
+    // Ethernet hardware, IPv4 protocol, and their address lengths.
+    if arp[..6] != [0, 1, 0x08, 0x00, 6, 4] || arp[6..8] != ARPOP_REQUEST.to_be_bytes() {
+        return None;
+    }
+    let sender_mac = arp[8..14].try_into().ok()?;
+    let sender_addr = <[u8; 4]>::try_from(&arp[14..18]).ok()?.into();
+    let target_addr = <[u8; 4]>::try_from(&arp[24..28]).ok()?.into();
+    Some((sender_mac, sender_addr, target_addr))
+}
+
+/// Returns an Ethernet frame carrying an ARP packet of the operation from
+/// `mac` and `addr` to `target_mac` and `target_addr`. Gratuitous ARPs are
+/// requests for the sender's own address, broadcast to the segment.
+fn arp_frame(
+    op: u16,
+    mac: [u8; 6],
+    addr: Ipv4Addr,
+    target_mac: [u8; 6],
+    target_addr: Ipv4Addr,
+) -> [u8; ETH_HDR_LEN + ARP_LEN] {
+    let mut frame = [0; ETH_HDR_LEN + ARP_LEN];
+    frame[..6].copy_from_slice(&target_mac);
+    frame[6..12].copy_from_slice(&mac);
+    frame[12..14].copy_from_slice(&ETH_P_ARP.to_be_bytes());
+    let arp = &mut frame[ETH_HDR_LEN..];
+    arp[..6].copy_from_slice(&[0, 1, 0x08, 0x00, 6, 4]);
+    arp[6..8].copy_from_slice(&op.to_be_bytes());
+    arp[8..14].copy_from_slice(&mac);
+    arp[14..18].copy_from_slice(&addr.octets());
+    // The target hardware address of a request is unknown.
+    if op == ARPOP_REPLY {
+        arp[18..24].copy_from_slice(&target_mac);
+    }
+    arp[24..28].copy_from_slice(&target_addr.octets());
+    frame
+}
+
+/// Returns a non-blocking packet socket which receives the ARP packets of the
+/// interface, and sends Ethernet frames out of it.
+fn arp_socket(iface: &str) -> Result<OwnedFd, io::Error> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_PACKET,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+            ETH_P_ARP.to_be() as i32,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let name = CString::new(iface)?;
+    let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if ifindex == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as u16;
+    addr.sll_protocol = ETH_P_ARP.to_be();
+    addr.sll_ifindex = ifindex as i32;
+    let ret = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_ll>() as u32,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+fn recv(socket: &OwnedFd, buf: &mut [u8]) -> Result<usize, io::Error> {
+    let len = unsafe {
+        libc::recv(
+            socket.as_raw_fd(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            0,
+        )
+    };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(len as usize)
+}
+
+fn send(socket: &OwnedFd, frame: &[u8]) -> Result<(), io::Error> {
+    let len = unsafe {
+        libc::send(
+            socket.as_raw_fd(),
+            frame.as_ptr() as *const libc::c_void,
+            frame.len(),
+            0,
+        )
+    };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
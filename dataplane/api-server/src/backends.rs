@@ -3,8 +3,18 @@
 pub struct Vip {
     #[prost(uint32, tag = "1")]
     pub ip: u32,
+    /// Port 0 forwards all the ports of the VIP which no other VIP listens on to the same port on the
+    /// targets, whose ports are ignored, e.g. for protocols negotiating dynamic ports.
     #[prost(uint32, tag = "2")]
     pub port: u32,
+    /// IPv6 address of the VIP in network byte order, takes precedence over ip when set.
+    #[prost(bytes = "vec", optional, tag = "3")]
+    pub ipv6: ::core::option::Option<::prost::alloc::vec::Vec<u8>>,
+    /// Last port of the range of ports the VIP listens on, starting at port. The ports of the range
+    /// are forwarded to the same offset from the targets' ports. Unset for VIPs listening on a
+    /// single port, which take precedence over the ranges covering their port.
+    #[prost(uint32, optional, tag = "4")]
+    pub port_end: ::core::option::Option<u32>,
 }
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -15,6 +25,59 @@ pub struct Target {
     pub dport: u32,
     #[prost(uint32, optional, tag = "3")]
     pub ifindex: ::core::option::Option<u32>,
+    /// IPv6 address of the target in network byte order, takes precedence over daddr when set,
+    /// unless the target is dual-stack (see Targets.aliases) and the VIP is IPv4.
+    /// IPv6 VIPs may have IPv4 targets, their TCP connections are then translated to connections
+    /// from the node's IPv4 source NAT address (NAT64), and their UDP flows are dropped.
+    #[prost(bytes = "vec", optional, tag = "4")]
+    pub daddr_ipv6: ::core::option::Option<::prost::alloc::vec::Vec<u8>>,
+    /// Relative share of new connections sent to the target, defaults to 1. Targets with a weight of
+    /// 0 receive no new connections. The weights of the targets of a VIP are scaled down together
+    /// when one exceeds 65535, e.g. with the Gateway API's weights of up to 1000000.
+    #[prost(uint32, optional, tag = "5")]
+    pub weight: ::core::option::Option<u32>,
+    /// MAC address of the target, required when the targets are reached with direct server return.
+    #[prost(bytes = "vec", optional, tag = "6")]
+    pub mac: ::core::option::Option<::prost::alloc::vec::Vec<u8>>,
+    /// Whether the target is a pod on this node, whose host side veth is at ifindex. Packets are then
+    /// redirected straight into the pod. Detected from the interface when unset.
+    #[prost(bool, optional, tag = "7")]
+    pub local: ::core::option::Option<bool>,
+    /// Drain the target: it receives no new connections, but keeps those it has until they
+    /// terminate, e.g. while its pod is shutting down.
+    #[prost(bool, tag = "8")]
+    pub drain: bool,
+    /// Group of the target when the VIP splits its traffic between groups of targets, see
+    /// Targets.split_weights.
+    #[prost(uint32, tag = "9")]
+    pub split_group: u32,
+    /// Most live connections assigned to the target, the new connections spilling over to the other
+    /// targets while it has them. Unlimited when unset or 0.
+    #[prost(uint32, optional, tag = "10")]
+    pub max_conns: ::core::option::Option<u32>,
+}
+/// Checks of the targets performed by the dataplane, which ejects the targets failing them from new
+/// connection selection until they pass them again.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct HealthCheck {
+    #[prost(enumeration = "HealthCheckProtocol", tag = "1")]
+    pub protocol: i32,
+    /// Port the targets are checked on instead of their own, required for VIPs listening on any port.
+    #[prost(uint32, optional, tag = "2")]
+    pub port: ::core::option::Option<u32>,
+    /// Seconds between two checks of a target, defaults to 5.
+    #[prost(uint32, optional, tag = "3")]
+    pub interval: ::core::option::Option<u32>,
+    /// Seconds after which a check without an answer fails, defaults to 1.
+    #[prost(uint32, optional, tag = "4")]
+    pub timeout: ::core::option::Option<u32>,
+    /// Consecutive failed checks after which a target is ejected, defaults to 3.
+    #[prost(uint32, optional, tag = "5")]
+    pub unhealthy_threshold: ::core::option::Option<u32>,
+    /// Consecutive passed checks after which an ejected target is reinstated, defaults to 2.
+    #[prost(uint32, optional, tag = "6")]
+    pub healthy_threshold: ::core::option::Option<u32>,
 }
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -23,6 +86,220 @@ pub struct Targets {
     pub vip: ::core::option::Option<Vip>,
     #[prost(message, repeated, tag = "2")]
     pub targets: ::prost::alloc::vec::Vec<Target>,
+    /// Algorithm used to assign new connections to the targets.
+    #[prost(enumeration = "Algorithm", tag = "3")]
+    pub algorithm: i32,
+    /// Seconds during which new connections from a client IP go to the same target as its previous
+    /// ones, regardless of the algorithm. Session affinity is disabled when unset or 0.
+    #[prost(uint32, optional, tag = "4")]
+    pub affinity_timeout: ::core::option::Option<u32>,
+    /// Direct server return: packets are forwarded to the targets with the VIP as their destination,
+    /// and the targets (which must hold the VIP and be on the same L2 segment) reply directly to the
+    /// clients.
+    #[prost(bool, tag = "5")]
+    pub dsr: bool,
+    /// Inject a PROXY protocol v2 header carrying the client's address and port in front of the data
+    /// of the TCP connections forwarded to the targets. Not supported with direct server return.
+    #[prost(bool, tag = "6")]
+    pub proxy_protocol: bool,
+    /// Add the client's address and port as a TOA (TCP Option Address) option to the SYNs forwarded
+    /// to the targets, for targets running a TOA kernel module. Not supported with direct server
+    /// return.
+    #[prost(bool, tag = "7")]
+    pub toa: bool,
+    /// Source NAT: the source of the TCP connections forwarded to the targets is also rewritten, to
+    /// the node's address and a port allocated to the connection, for targets which have no route
+    /// back to the clients. Mutually exclusive with direct server return.
+    #[prost(bool, tag = "8")]
+    pub snat: bool,
+    /// Other VIPs sharing the targets and the balancing state of vip, e.g. the other addresses of a
+    /// Gateway. Clients get their replies from the VIP they connected to. VIPs of both IP families
+    /// make a dual-stack Gateway, whose targets have both daddr and daddr_ipv6 set: clients are
+    /// forwarded to the address of their own family, and a target's connections are counted under
+    /// its IPv4 address. Targets with a single IPv4 address are reached by the IPv6 clients through
+    /// NAT64.
+    #[prost(message, repeated, tag = "9")]
+    pub aliases: ::prost::alloc::vec::Vec<Vip>,
+    /// Health checks of the targets, which are not checked when unset.
+    #[prost(message, optional, tag = "10")]
+    pub health_check: ::core::option::Option<HealthCheck>,
+    /// Length of the connection IDs which the targets choose for their QUIC connections, whose UDP
+    /// flows are then pinned to a target by connection ID: clients whose address changes keep their
+    /// connection's target. Between 1 and 20, QUIC affinity is disabled when unset or 0. The connection
+    /// IDs are learnt from the targets' replies, which direct server return bypasses.
+    #[prost(uint32, optional, tag = "11")]
+    pub quic_cid_len: ::core::option::Option<u32>,
+    /// Percentages of the new connections which go to each group of targets, indexed by
+    /// Target.split_group, e.g. \[90, 10\] for a canary version of the targets in group 1. Up to 4
+    /// groups, whose weights add up to 100. The groups are balanced with the algorithm on their own,
+    /// a group without a target accepting new connections leaves its share to the others. Existing
+    /// connections keep their target when the weights change. The traffic isn't split when unset.
+    #[prost(uint32, repeated, tag = "12")]
+    pub split_weights: ::prost::alloc::vec::Vec<u32>,
+    /// Flush the tracked connections of the targets which the update removes, whose packets are then
+    /// handled like those of untracked connections instead of being sent to a target which is gone.
+    /// Deleting the VIP flushes all of its connections.
+    #[prost(bool, tag = "13")]
+    pub flush_removed: bool,
+    /// Seconds after which an idle UDP flow to the VIP is no longer pinned to its target, e.g. a few
+    /// seconds for DNS or minutes for gaming and VoIP. The node's default is used when unset or 0.
+    #[prost(uint32, optional, tag = "14")]
+    pub udp_idle_timeout: ::core::option::Option<u32>,
+    /// Don't track the connections to the VIP: each packet goes to the target its 5-tuple hashes to
+    /// with MAGLEV, and the replies of the targets are translated back from their address and port,
+    /// for VIPs with more new connections than can be tracked. Connections move to other targets
+    /// when the targets change, and a target can only be in one stateless VIP.
+    #[prost(bool, tag = "15")]
+    pub stateless: bool,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AclRule {
+    /// Prefix of the addresses of the clients, either IPv4 or IPv6.
+    #[prost(uint32, tag = "1")]
+    pub ip: u32,
+    /// IPv6 address of the prefix in network byte order, takes precedence over ip when set.
+    #[prost(bytes = "vec", optional, tag = "2")]
+    pub ipv6: ::core::option::Option<::prost::alloc::vec::Vec<u8>>,
+    #[prost(uint32, tag = "3")]
+    pub prefix_len: u32,
+    #[prost(enumeration = "AclAction", tag = "4")]
+    pub action: i32,
+}
+/// Source prefixes allowed or denied to connect to the VIPs of an address, e.g. a Gateway's
+/// loadBalancerSourceRanges. The longest prefix matching a client decides, and clients matching none
+/// are allowed: deny 0.0.0.0/0 to only allow the other prefixes.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Acl {
+    /// Address of the VIPs the ACL applies to, whose port is ignored.
+    #[prost(message, optional, tag = "1")]
+    pub vip: ::core::option::Option<Vip>,
+    /// The rules replacing those of the address, which has no ACL left when empty.
+    #[prost(message, repeated, tag = "2")]
+    pub rules: ::prost::alloc::vec::Vec<AclRule>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ConnectionLimit {
+    #[prost(message, optional, tag = "1")]
+    pub vip: ::core::option::Option<Vip>,
+    /// Live connections of the VIP's targets past which its new connections are rejected. 0 removes
+    /// the limit.
+    #[prost(uint32, tag = "2")]
+    pub max_connections: u32,
+    #[prost(enumeration = "LimitAction", tag = "3")]
+    pub action: i32,
+}
+/// Whether a VIP answers the SYNs of new connections with SYN cookies, so that a flood of spoofed
+/// SYNs takes no room in the connection tracking. The connections opened with a cookie go without
+/// window scaling, SACK and timestamps, and are reset if the VIP's targets are in DSR mode.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SynCookies {
+    #[prost(message, optional, tag = "1")]
+    pub vip: ::core::option::Option<Vip>,
+    #[prost(bool, tag = "2")]
+    pub enabled: bool,
+}
+/// The DSCP a VIP marks the packets it forwards to its targets with, so that the underlay can
+/// prioritize them. The packets forwarded with direct server return, and the fragments past the
+/// first one, keep their own.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DscpMarking {
+    #[prost(message, optional, tag = "1")]
+    pub vip: ::core::option::Option<Vip>,
+    /// DSCP of the packets, from 0 to 63. Unset stops the marking.
+    #[prost(uint32, optional, tag = "2")]
+    pub dscp: ::core::option::Option<u32>,
+}
+/// Where copies of the packets to a VIP are sent, e.g. for a new version of a target to be tested
+/// with the production traffic. The mirror gets the packets as the VIP received them, like the
+/// targets reached with direct server return, and discards its replies itself.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Mirror {
+    #[prost(message, optional, tag = "1")]
+    pub vip: ::core::option::Option<Vip>,
+    /// MAC address of the mirror, on the same L2 segment as the interface at ifindex. Unset stops
+    /// the mirroring.
+    #[prost(bytes = "vec", optional, tag = "2")]
+    pub mac: ::core::option::Option<::prost::alloc::vec::Vec<u8>>,
+    #[prost(uint32, tag = "3")]
+    pub ifindex: u32,
+}
+/// The local transparent proxy which the new connections to a VIP are handed to instead of its
+/// targets, e.g. for the routes which need L7 handling. The proxy listens on the port of this node
+/// with IP_TRANSPARENT set, and the host routes the packets marked with the dataplane's TPROXY mark
+/// locally. The connections to the VIP which were already forwarded to a target stay with it.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Tproxy {
+    #[prost(message, optional, tag = "1")]
+    pub vip: ::core::option::Option<Vip>,
+    /// Port the proxy listens on, for TCP and UDP alike. Unset stops handing the connections to the
+    /// proxy.
+    #[prost(uint32, optional, tag = "2")]
+    pub port: ::core::option::Option<u32>,
+}
+/// Whether the pings to the address of a VIP are forwarded to its targets, balanced like its
+/// connections, instead of being answered by the dataplane, so that they exercise the path to the
+/// targets. The replies of the targets are translated back to come from the VIP. A single VIP of an
+/// address forwards its pings, the VIP enabled last.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PingForwarding {
+    #[prost(message, optional, tag = "1")]
+    pub vip: ::core::option::Option<Vip>,
+    #[prost(bool, tag = "2")]
+    pub enabled: bool,
+}
+/// The node a target runs on, which the packets to the target are encapsulated to, for targets which
+/// aren't routable from this node. The node decapsulates the packets and delivers them to the target,
+/// whose replies go back the way they would without the tunnel.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TargetNode {
+    /// Address of the target, as in Target.
+    #[prost(uint32, tag = "1")]
+    pub daddr: u32,
+    #[prost(bytes = "vec", optional, tag = "2")]
+    pub daddr_ipv6: ::core::option::Option<::prost::alloc::vec::Vec<u8>>,
+    /// Address of the node, of the IP family of the target's address. Unset removes the target's
+    /// node.
+    #[prost(uint32, tag = "3")]
+    pub node_ip: u32,
+    /// IPv6 address of the node in network byte order, takes precedence over node_ip when set.
+    #[prost(bytes = "vec", optional, tag = "4")]
+    pub node_ipv6: ::core::option::Option<::prost::alloc::vec::Vec<u8>>,
+    #[prost(enumeration = "Encapsulation", tag = "5")]
+    pub encapsulation: i32,
+    /// UDP port the node receives the encapsulated packets on, defaults to 6081 for Geneve and
+    /// 6080 for GUE.
+    #[prost(uint32, optional, tag = "6")]
+    pub port: ::core::option::Option<u32>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DatapathLogging {
+    #[prost(enumeration = "LogLevel", tag = "1")]
+    pub level: i32,
+}
+/// Seconds the TCP connections may stay idle in each state before they're expired. Unset timeouts
+/// are left as they are, 0 never expires the connections of a state.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TcpTimeouts {
+    #[prost(uint32, optional, tag = "1")]
+    pub syn_sent: ::core::option::Option<u32>,
+    #[prost(uint32, optional, tag = "2")]
+    pub established: ::core::option::Option<u32>,
+    /// Timeout of the connections being closed, until the last FIN is acknowledged.
+    #[prost(uint32, optional, tag = "3")]
+    pub fin_wait: ::core::option::Option<u32>,
+    #[prost(uint32, optional, tag = "4")]
+    pub time_wait: ::core::option::Option<u32>,
 }
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -35,6 +312,9 @@ pub struct Confirmation {
 pub struct PodIp {
     #[prost(uint32, tag = "1")]
     pub ip: u32,
+    /// IPv6 address of the pod in network byte order, takes precedence over ip when set.
+    #[prost(bytes = "vec", optional, tag = "2")]
+    pub ipv6: ::core::option::Option<::prost::alloc::vec::Vec<u8>>,
 }
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -42,6 +322,631 @@ pub struct InterfaceIndexConfirmation {
     #[prost(uint32, tag = "1")]
     pub ifindex: u32,
 }
+/// A state of the Gateways the control plane wants the dataplane to be in.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DesiredState {
+    /// Generation of the state, which the dataplane acknowledges once it is applied.
+    #[prost(uint64, tag = "1")]
+    pub generation: u64,
+    /// Whether the state holds every VIP, in which case the VIPs which aren't updated are deleted.
+    /// Otherwise the state is a set of changes to the state of base_generation.
+    #[prost(bool, tag = "2")]
+    pub full: bool,
+    /// Generation of the state the changes apply to, ignored for full states.
+    #[prost(uint64, tag = "3")]
+    pub base_generation: u64,
+    #[prost(message, repeated, tag = "4")]
+    pub updates: ::prost::alloc::vec::Vec<Targets>,
+    #[prost(message, repeated, tag = "5")]
+    pub deletes: ::prost::alloc::vec::Vec<Vip>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct StateAck {
+    /// Generation of the state this acknowledges.
+    #[prost(uint64, tag = "1")]
+    pub generation: u64,
+    /// Set if the state couldn't be applied entirely.
+    #[prost(string, optional, tag = "2")]
+    pub error: ::core::option::Option<::prost::alloc::string::String>,
+    /// Set when the dataplane needs a full state to carry on, e.g. after a restart or when the
+    /// changes don't apply to the state it's in. The state acknowledged was not applied.
+    #[prost(bool, tag = "3")]
+    pub resync: bool,
+}
+/// Statistics of a target, counted by its address and port. Targets shared by several VIPs have
+/// the same statistics for all of them.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct BackendStats {
+    #[prost(message, optional, tag = "1")]
+    pub target: ::core::option::Option<Target>,
+    /// Connections assigned to the target which are still open.
+    #[prost(uint64, tag = "2")]
+    pub active_connections: u64,
+    /// Connections assigned to the target since the dataplane started.
+    #[prost(uint64, tag = "3")]
+    pub total_connections: u64,
+    /// Bytes forwarded to the target.
+    #[prost(uint64, tag = "4")]
+    pub bytes_in: u64,
+    /// Bytes of the replies of the target, which don't go through the dataplane with direct server
+    /// return.
+    #[prost(uint64, tag = "5")]
+    pub bytes_out: u64,
+    /// When the target was last assigned a connection, in milliseconds since the Unix epoch. Unset
+    /// if it never was.
+    #[prost(uint64, optional, tag = "6")]
+    pub last_selected: ::core::option::Option<u64>,
+    /// Whether the target failed its health checks or too many new connections in a row, and is
+    /// receiving no new connections.
+    #[prost(bool, tag = "7")]
+    pub ejected: bool,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct BackendStatsList {
+    #[prost(message, repeated, tag = "1")]
+    pub backends: ::prost::alloc::vec::Vec<BackendStats>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DataplaneInfoRequest {}
+/// The health of the map tracking the TCP connections, whose least recently used entries are
+/// evicted once it is full. The counts start when the dataplane does.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct FlowTableInfo {
+    #[prost(uint64, tag = "1")]
+    pub entries: u64,
+    #[prost(uint64, tag = "2")]
+    pub capacity: u64,
+    /// Entries inserted by the datapath, and imported from other dataplanes.
+    #[prost(uint64, tag = "3")]
+    pub inserts: u64,
+    /// New connections the datapath failed to insert, which weren't forwarded.
+    #[prost(uint64, tag = "4")]
+    pub insert_failures: u64,
+    /// Entries removed once closed, idle or flushed.
+    #[prost(uint64, tag = "5")]
+    pub removals: u64,
+    /// Entries evicted to make room for new ones, estimated from the other counts. Connections
+    /// whose entry is evicted may be sent to another target, the map is too small when they grow.
+    #[prost(uint64, tag = "6")]
+    pub evictions: u64,
+    /// Entries inserted and removed per second, over the last few seconds.
+    #[prost(double, tag = "7")]
+    pub insert_rate: f64,
+    #[prost(double, tag = "8")]
+    pub removal_rate: f64,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DataplaneInfo {
+    #[prost(message, optional, tag = "1")]
+    pub flow_table: ::core::option::Option<FlowTableInfo>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CapabilitiesRequest {}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Capabilities {
+    /// Version of the API, which is incremented by the changes the control planes written for an
+    /// earlier version can't ignore. Features added along the way are reported in features instead.
+    #[prost(uint32, tag = "1")]
+    pub api_version: u32,
+    /// Version of the dataplane itself.
+    #[prost(string, tag = "2")]
+    pub version: ::prost::alloc::string::String,
+    #[prost(enumeration = "Feature", repeated, tag = "3")]
+    pub features: ::prost::alloc::vec::Vec<i32>,
+    /// The algorithms the targets can be balanced with.
+    #[prost(enumeration = "Algorithm", repeated, tag = "4")]
+    pub algorithms: ::prost::alloc::vec::Vec<i32>,
+    /// Whether the calls which change the state of the dataplane require a bearer token.
+    #[prost(bool, tag = "5")]
+    pub token_required: bool,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListGatewaysRequest {}
+/// A VIP programmed in the datapath, with its targets. VIPs with a range of ports are listed by the
+/// first port of the range.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Gateway {
+    #[prost(message, optional, tag = "1")]
+    pub vip: ::core::option::Option<Vip>,
+    #[prost(message, repeated, tag = "2")]
+    pub targets: ::prost::alloc::vec::Vec<Target>,
+    #[prost(enumeration = "Algorithm", tag = "3")]
+    pub algorithm: i32,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GatewayList {
+    #[prost(message, repeated, tag = "1")]
+    pub gateways: ::prost::alloc::vec::Vec<Gateway>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CaptureRequest {
+    #[prost(message, optional, tag = "1")]
+    pub vip: ::core::option::Option<Vip>,
+    /// The number of packets after which the capture stops, at most 10000, which is also the
+    /// default.
+    #[prost(uint32, tag = "2")]
+    pub max_packets: u32,
+    /// The seconds after which the capture stops, at most 300, which is also the default.
+    #[prost(uint32, tag = "3")]
+    pub duration_secs: u32,
+    /// Name of a pcap file in the capture directory of the dataplane which the packets are written
+    /// to as well, replacing any file of that name. Only dataplanes with a capture directory write
+    /// pcap files.
+    #[prost(string, optional, tag = "4")]
+    pub pcap_file: ::core::option::Option<::prost::alloc::string::String>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CapturedFrame {
+    /// When the packet came in, in nanoseconds since the Unix epoch.
+    #[prost(uint64, tag = "1")]
+    pub timestamp: u64,
+    /// The length of the packet, whose first 256 bytes at most are in data.
+    #[prost(uint32, tag = "2")]
+    pub len: u32,
+    /// The packet from its Ethernet header on.
+    #[prost(bytes = "vec", tag = "3")]
+    pub data: ::prost::alloc::vec::Vec<u8>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ConnectionsFilter {
+    /// Only select the connections to this VIP when set.
+    #[prost(message, optional, tag = "1")]
+    pub vip: ::core::option::Option<Vip>,
+    /// Only select the connections pinned to this target when set, by its address and port.
+    #[prost(message, optional, tag = "2")]
+    pub target: ::core::option::Option<Target>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Connection {
+    #[prost(uint32, tag = "1")]
+    pub client_ip: u32,
+    #[prost(uint32, tag = "2")]
+    pub client_port: u32,
+    /// IPv6 address of the client in network byte order, set instead of client_ip for IPv6 clients.
+    #[prost(bytes = "vec", optional, tag = "3")]
+    pub client_ipv6: ::core::option::Option<::prost::alloc::vec::Vec<u8>>,
+    /// The VIP the client connected to.
+    #[prost(message, optional, tag = "4")]
+    pub vip: ::core::option::Option<Vip>,
+    /// The target the connection is pinned to.
+    #[prost(message, optional, tag = "5")]
+    pub target: ::core::option::Option<Target>,
+    /// Unset for the entries which aren't TCP connections.
+    #[prost(enumeration = "TcpState", optional, tag = "6")]
+    pub tcp_state: ::core::option::Option<i32>,
+    /// Source port allocated to the connection, if it is source NATed.
+    #[prost(uint32, optional, tag = "7")]
+    pub snat_port: ::core::option::Option<u32>,
+}
+/// A connection, UDP flow or SCTP association which the datapath started or stopped tracking.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ConnectionChange {
+    /// The client, VIP and target of the connection. The targets only have their address and port.
+    #[prost(message, optional, tag = "1")]
+    pub connection: ::core::option::Option<Connection>,
+    /// The IP protocol of the connection: tcp, udp or sctp.
+    #[prost(string, tag = "2")]
+    pub protocol: ::prost::alloc::string::String,
+    /// Whether the connection was closed, rather than opened.
+    #[prost(bool, tag = "3")]
+    pub closed: bool,
+    /// Why the connection was closed: fin, rst, replaced or timeout.
+    #[prost(string, tag = "4")]
+    pub reason: ::prost::alloc::string::String,
+    /// When the connection was opened or closed, in milliseconds since the Unix epoch.
+    #[prost(uint64, tag = "5")]
+    pub timestamp: u64,
+}
+/// An entry of the connection tracking maps of the datapath, as laid out in the maps.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ConntrackEntry {
+    #[prost(bytes = "vec", tag = "1")]
+    pub key: ::prost::alloc::vec::Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub value: ::prost::alloc::vec::Vec<u8>,
+    /// How long ago the last packet of the connection was seen, in nanoseconds, as the clocks of
+    /// the nodes differ.
+    #[prost(uint64, tag = "3")]
+    pub idle_ns: u64,
+}
+/// The connections and UDP flows tracked by a dataplane, which can only be imported by dataplanes
+/// of the same version.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ConntrackSnapshot {
+    #[prost(message, repeated, tag = "1")]
+    pub tcp: ::prost::alloc::vec::Vec<ConntrackEntry>,
+    #[prost(message, repeated, tag = "2")]
+    pub udp: ::prost::alloc::vec::Vec<ConntrackEntry>,
+}
+/// The connections and UDP flows a dataplane started and stopped tracking since its previous update.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ConntrackUpdate {
+    #[prost(message, repeated, tag = "1")]
+    pub tcp_opened: ::prost::alloc::vec::Vec<ConntrackEntry>,
+    #[prost(message, repeated, tag = "2")]
+    pub udp_opened: ::prost::alloc::vec::Vec<ConntrackEntry>,
+    /// The keys of the connections and UDP flows which were closed, as laid out in the maps.
+    #[prost(bytes = "vec", repeated, tag = "3")]
+    pub tcp_closed: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+    #[prost(bytes = "vec", repeated, tag = "4")]
+    pub udp_closed: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum Algorithm {
+    /// Weighted round robin over the targets.
+    RoundRobin = 0,
+    /// Maglev consistent hashing of the client address and port, which keeps most connections on the
+    /// same target when targets are added or removed.
+    Maglev = 1,
+    /// The target with the fewest live connections.
+    LeastConn = 2,
+    /// The target with the fewer live connections of two targets picked by Maglev consistent hashing
+    /// of the client address and port, which keeps most connections on the same targets while
+    /// steering them away from the busier one.
+    PowerOfTwo = 3,
+    /// A target drawn at random, without the shared rotation state of the round robin, for the VIPs
+    /// with so many new connections that the rotation becomes contended. The weights other than 0
+    /// aren't taken into account.
+    Random = 4,
+}
+impl Algorithm {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Algorithm::RoundRobin => "ROUND_ROBIN",
+            Algorithm::Maglev => "MAGLEV",
+            Algorithm::LeastConn => "LEAST_CONN",
+            Algorithm::PowerOfTwo => "POWER_OF_TWO",
+            Algorithm::Random => "RANDOM",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "ROUND_ROBIN" => Some(Self::RoundRobin),
+            "MAGLEV" => Some(Self::Maglev),
+            "LEAST_CONN" => Some(Self::LeastConn),
+            "POWER_OF_TWO" => Some(Self::PowerOfTwo),
+            "RANDOM" => Some(Self::Random),
+            _ => None,
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum HealthCheckProtocol {
+    /// A TCP connection is opened to the target.
+    Tcp = 0,
+    /// A UDP datagram is sent to the target, which fails if it is answered with an ICMP port
+    /// unreachable message.
+    Udp = 1,
+}
+impl HealthCheckProtocol {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            HealthCheckProtocol::Tcp => "TCP",
+            HealthCheckProtocol::Udp => "UDP",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "TCP" => Some(Self::Tcp),
+            "UDP" => Some(Self::Udp),
+            _ => None,
+        }
+    }
+}
+/// What is done with the packets of the clients matching a prefix of an ACL.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum AclAction {
+    Allow = 0,
+    Deny = 1,
+}
+impl AclAction {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            AclAction::Allow => "ALLOW",
+            AclAction::Deny => "DENY",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "ALLOW" => Some(Self::Allow),
+            "DENY" => Some(Self::Deny),
+            _ => None,
+        }
+    }
+}
+/// What is done with the new connections to a VIP over its connection limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum LimitAction {
+    /// The SYN is dropped, so that the client retries later.
+    Drop = 0,
+    /// The connection is reset, so that the client fails fast.
+    Reset = 1,
+}
+impl LimitAction {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            LimitAction::Drop => "DROP",
+            LimitAction::Reset => "RESET",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "DROP" => Some(Self::Drop),
+            "RESET" => Some(Self::Reset),
+            _ => None,
+        }
+    }
+}
+/// What the packets to a target on another node are encapsulated with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum Encapsulation {
+    /// Geneve, whose payload is the IP packet, e.g. for a Linux geneve device in external mode
+    /// with innerprotoinherit.
+    Geneve = 0,
+    /// Generic UDP Encapsulation, e.g. for a Linux fou port in gue mode.
+    Gue = 1,
+}
+impl Encapsulation {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Encapsulation::Geneve => "GENEVE",
+            Encapsulation::Gue => "GUE",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "GENEVE" => Some(Self::Geneve),
+            "GUE" => Some(Self::Gue),
+            _ => None,
+        }
+    }
+}
+/// Verbosity of the logs of the datapath, which logs the messages of the level and of the levels
+/// before it. The per-packet logs are at INFO and DEBUG.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum LogLevel {
+    Off = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+}
+impl LogLevel {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            LogLevel::Off => "OFF",
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "OFF" => Some(Self::Off),
+            "ERROR" => Some(Self::Error),
+            "WARN" => Some(Self::Warn),
+            "INFO" => Some(Self::Info),
+            "DEBUG" => Some(Self::Debug),
+            _ => None,
+        }
+    }
+}
+/// Optional features of the dataplane, which a control plane checks before relying on them so that
+/// it can carry on without those an older dataplane lacks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum Feature {
+    Unspecified = 0,
+    /// IPv6 VIPs and targets, and NAT64 to the IPv4 targets.
+    Ipv6 = 1,
+    /// Direct server return, see Targets.dsr.
+    Dsr = 2,
+    /// Session affinity by client IP, see Targets.affinity_timeout.
+    SessionAffinity = 3,
+    /// Affinity of the QUIC connections by connection ID, see Targets.quic_cid_len.
+    QuicAffinity = 4,
+    /// PROXY protocol v2 headers, see Targets.proxy_protocol.
+    ProxyProtocol = 5,
+    /// TOA options, see Targets.toa.
+    Toa = 6,
+    /// Source NAT, see Targets.snat.
+    Snat = 7,
+    /// VIPs listening on ranges of ports, see Vip.port_end.
+    PortRanges = 8,
+    /// Splitting the traffic between groups of targets, see Targets.split_weights.
+    TrafficSplit = 9,
+    /// VIPs whose connections aren't tracked, see Targets.stateless.
+    Stateless = 10,
+    /// Health checks of the targets, see Targets.health_check.
+    HealthChecks = 11,
+    /// Programming the Gateways with the Sync stream of desired states.
+    StateSync = 12,
+    /// Exporting, importing and replicating the tracked connections.
+    ConnectionSync = 13,
+    /// ACLs of the VIPs, see SetAcl.
+    Acls = 14,
+    /// Connection limits of the VIPs, see SetConnectionLimit.
+    ConnectionLimits = 15,
+    /// SYN cookies, see SetSynCookies.
+    SynCookies = 16,
+    /// DSCP marking, see SetDscpMarking.
+    DscpMarking = 17,
+    /// Mirroring the packets of the VIPs, see SetMirror.
+    Mirroring = 18,
+    /// Handing the connections to a local transparent proxy, see SetTproxy.
+    Tproxy = 19,
+    /// Tunnels to the nodes of the targets, see SetTargetNode.
+    TargetNodes = 20,
+    /// Capturing the packets of the VIPs, see CapturePackets.
+    PacketCapture = 21,
+    /// Forwarding the pings of the VIPs to their targets, see SetPingForwarding.
+    PingForwarding = 22,
+}
+impl Feature {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Feature::Unspecified => "FEATURE_UNSPECIFIED",
+            Feature::Ipv6 => "FEATURE_IPV6",
+            Feature::Dsr => "FEATURE_DSR",
+            Feature::SessionAffinity => "FEATURE_SESSION_AFFINITY",
+            Feature::QuicAffinity => "FEATURE_QUIC_AFFINITY",
+            Feature::ProxyProtocol => "FEATURE_PROXY_PROTOCOL",
+            Feature::Toa => "FEATURE_TOA",
+            Feature::Snat => "FEATURE_SNAT",
+            Feature::PortRanges => "FEATURE_PORT_RANGES",
+            Feature::TrafficSplit => "FEATURE_TRAFFIC_SPLIT",
+            Feature::Stateless => "FEATURE_STATELESS",
+            Feature::HealthChecks => "FEATURE_HEALTH_CHECKS",
+            Feature::StateSync => "FEATURE_STATE_SYNC",
+            Feature::ConnectionSync => "FEATURE_CONNECTION_SYNC",
+            Feature::Acls => "FEATURE_ACLS",
+            Feature::ConnectionLimits => "FEATURE_CONNECTION_LIMITS",
+            Feature::SynCookies => "FEATURE_SYN_COOKIES",
+            Feature::DscpMarking => "FEATURE_DSCP_MARKING",
+            Feature::Mirroring => "FEATURE_MIRRORING",
+            Feature::Tproxy => "FEATURE_TPROXY",
+            Feature::TargetNodes => "FEATURE_TARGET_NODES",
+            Feature::PacketCapture => "FEATURE_PACKET_CAPTURE",
+            Feature::PingForwarding => "FEATURE_PING_FORWARDING",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "FEATURE_UNSPECIFIED" => Some(Self::Unspecified),
+            "FEATURE_IPV6" => Some(Self::Ipv6),
+            "FEATURE_DSR" => Some(Self::Dsr),
+            "FEATURE_SESSION_AFFINITY" => Some(Self::SessionAffinity),
+            "FEATURE_QUIC_AFFINITY" => Some(Self::QuicAffinity),
+            "FEATURE_PROXY_PROTOCOL" => Some(Self::ProxyProtocol),
+            "FEATURE_TOA" => Some(Self::Toa),
+            "FEATURE_SNAT" => Some(Self::Snat),
+            "FEATURE_PORT_RANGES" => Some(Self::PortRanges),
+            "FEATURE_TRAFFIC_SPLIT" => Some(Self::TrafficSplit),
+            "FEATURE_STATELESS" => Some(Self::Stateless),
+            "FEATURE_HEALTH_CHECKS" => Some(Self::HealthChecks),
+            "FEATURE_STATE_SYNC" => Some(Self::StateSync),
+            "FEATURE_CONNECTION_SYNC" => Some(Self::ConnectionSync),
+            "FEATURE_ACLS" => Some(Self::Acls),
+            "FEATURE_CONNECTION_LIMITS" => Some(Self::ConnectionLimits),
+            "FEATURE_SYN_COOKIES" => Some(Self::SynCookies),
+            "FEATURE_DSCP_MARKING" => Some(Self::DscpMarking),
+            "FEATURE_MIRRORING" => Some(Self::Mirroring),
+            "FEATURE_TPROXY" => Some(Self::Tproxy),
+            "FEATURE_TARGET_NODES" => Some(Self::TargetNodes),
+            "FEATURE_PACKET_CAPTURE" => Some(Self::PacketCapture),
+            "FEATURE_PING_FORWARDING" => Some(Self::PingForwarding),
+            _ => None,
+        }
+    }
+}
+/// State of a TCP connection, as tracked by the datapath.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum TcpState {
+    Established = 0,
+    FinWait1 = 1,
+    FinWait2 = 2,
+    Closing = 3,
+    LastAck = 4,
+    TimeWait = 5,
+    Closed = 6,
+    /// The client sent its SYN, the handshake hasn't gone through yet.
+    SynSent = 7,
+}
+impl TcpState {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            TcpState::Established => "ESTABLISHED",
+            TcpState::FinWait1 => "FIN_WAIT1",
+            TcpState::FinWait2 => "FIN_WAIT2",
+            TcpState::Closing => "CLOSING",
+            TcpState::LastAck => "LAST_ACK",
+            TcpState::TimeWait => "TIME_WAIT",
+            TcpState::Closed => "CLOSED",
+            TcpState::SynSent => "SYN_SENT",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "ESTABLISHED" => Some(Self::Established),
+            "FIN_WAIT1" => Some(Self::FinWait1),
+            "FIN_WAIT2" => Some(Self::FinWait2),
+            "CLOSING" => Some(Self::Closing),
+            "LAST_ACK" => Some(Self::LastAck),
+            "TIME_WAIT" => Some(Self::TimeWait),
+            "CLOSED" => Some(Self::Closed),
+            "SYN_SENT" => Some(Self::SynSent),
+            _ => None,
+        }
+    }
+}
 /// Generated client implementations.
 pub mod backends_client {
     #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
@@ -178,114 +1083,1301 @@ pub mod backends_client {
                 .insert(GrpcMethod::new("backends.backends", "Delete"));
             self.inner.unary(req, path, codec).await
         }
-    }
-}
-/// Generated server implementations.
-pub mod backends_server {
-    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
-    use tonic::codegen::*;
-    /// Generated trait containing gRPC methods that should be implemented for use with BackendsServer.
-    #[async_trait]
-    pub trait Backends: Send + Sync + 'static {
-        async fn get_interface_index(
-            &self,
-            request: tonic::Request<super::PodIp>,
-        ) -> std::result::Result<tonic::Response<super::InterfaceIndexConfirmation>, tonic::Status>;
-        async fn update(
-            &self,
-            request: tonic::Request<super::Targets>,
-        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status>;
-        async fn delete(
-            &self,
-            request: tonic::Request<super::Vip>,
-        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status>;
-    }
-    #[derive(Debug)]
-    pub struct BackendsServer<T: Backends> {
-        inner: _Inner<T>,
-        accept_compression_encodings: EnabledCompressionEncodings,
-        send_compression_encodings: EnabledCompressionEncodings,
-        max_decoding_message_size: Option<usize>,
-        max_encoding_message_size: Option<usize>,
-    }
-    struct _Inner<T>(Arc<T>);
-    impl<T: Backends> BackendsServer<T> {
-        pub fn new(inner: T) -> Self {
-            Self::from_arc(Arc::new(inner))
-        }
-        pub fn from_arc(inner: Arc<T>) -> Self {
-            let inner = _Inner(inner);
-            Self {
-                inner,
-                accept_compression_encodings: Default::default(),
-                send_compression_encodings: Default::default(),
-                max_decoding_message_size: None,
-                max_encoding_message_size: None,
-            }
+        /// Returns the VIPs programmed in the datapath, and their targets.
+        pub async fn list_gateways(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ListGatewaysRequest>,
+        ) -> std::result::Result<tonic::Response<super::GatewayList>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/backends.backends/ListGateways");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "ListGateways"));
+            self.inner.unary(req, path, codec).await
         }
-        pub fn with_interceptor<F>(inner: T, interceptor: F) -> InterceptedService<Self, F>
-        where
-            F: tonic::service::Interceptor,
-        {
-            InterceptedService::new(Self::new(inner), interceptor)
+        /// Streams the TCP connections tracked by the datapath, and the target each is pinned to.
+        pub async fn list_connections(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ConnectionsFilter>,
+        ) -> std::result::Result<
+            tonic::Response<tonic::codec::Streaming<super::Connection>>,
+            tonic::Status,
+        > {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/backends.backends/ListConnections");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "ListConnections"));
+            self.inner.server_streaming(req, path, codec).await
         }
-        /// Enable decompressing requests with the given encoding.
-        #[must_use]
-        pub fn accept_compressed(mut self, encoding: CompressionEncoding) -> Self {
-            self.accept_compression_encodings.enable(encoding);
-            self
+        /// Streams the connections the datapath opens and closes from now on, as they come. The
+        /// connections userspace expires and the events the watcher can't keep up with are missed.
+        pub async fn watch_connections(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ConnectionsFilter>,
+        ) -> std::result::Result<
+            tonic::Response<tonic::codec::Streaming<super::ConnectionChange>>,
+            tonic::Status,
+        > {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/backends.backends/WatchConnections");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "WatchConnections"));
+            self.inner.server_streaming(req, path, codec).await
         }
-        /// Compress responses with the given encoding, if the client supports it.
-        #[must_use]
-        pub fn send_compressed(mut self, encoding: CompressionEncoding) -> Self {
-            self.send_compression_encodings.enable(encoding);
-            self
+        /// Removes the connections and UDP flows to a VIP, or pinned to a target, from the datapath,
+        /// so that their packets are balanced again. At least one of them must be set.
+        pub async fn flush_connections(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ConnectionsFilter>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/backends.backends/FlushConnections");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "FlushConnections"));
+            self.inner.unary(req, path, codec).await
         }
-        /// Limits the maximum size of a decoded message.
-        ///
-        /// Default: `4MB`
-        #[must_use]
-        pub fn max_decoding_message_size(mut self, limit: usize) -> Self {
-            self.max_decoding_message_size = Some(limit);
-            self
+        /// Returns the connections and UDP flows to a VIP, or pinned to a target, or all of them when
+        /// neither is set, for another dataplane to take over when the VIP fails over to it.
+        pub async fn export_connections(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ConnectionsFilter>,
+        ) -> std::result::Result<tonic::Response<super::ConntrackSnapshot>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/backends.backends/ExportConnections");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "ExportConnections"));
+            self.inner.unary(req, path, codec).await
         }
-        /// Limits the maximum size of an encoded message.
-        ///
-        /// Default: `usize::MAX`
-        #[must_use]
-        pub fn max_encoding_message_size(mut self, limit: usize) -> Self {
-            self.max_encoding_message_size = Some(limit);
-            self
+        /// Adds the connections and UDP flows exported by another dataplane, pinned to the same targets
+        /// of the local Gateways. The connections which are already tracked, those to unknown targets
+        /// and the source NATed ones are skipped.
+        pub async fn import_connections(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ConntrackSnapshot>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/backends.backends/ImportConnections");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "ImportConnections"));
+            self.inner.unary(req, path, codec).await
         }
-    }
-    impl<T, B> tonic::codegen::Service<http::Request<B>> for BackendsServer<T>
-    where
-        T: Backends,
-        B: Body + Send + 'static,
-        B::Error: Into<StdError> + Send + 'static,
-    {
-        type Response = http::Response<tonic::body::BoxBody>;
-        type Error = std::convert::Infallible;
-        type Future = BoxFuture<Self::Response, Self::Error>;
-        fn poll_ready(
+        /// Replicates the connections and UDP flows opened and closed by a peer dataplane as they come,
+        /// so that their packets keep going to the same targets when they are routed to this dataplane
+        /// instead, as with ECMP or anycast VIPs. The connections are imported as by
+        /// ImportConnections.
+        pub async fn sync_connections(
             &mut self,
-            _cx: &mut Context<'_>,
-        ) -> Poll<std::result::Result<(), Self::Error>> {
-            Poll::Ready(Ok(()))
+            request: impl tonic::IntoStreamingRequest<Message = super::ConntrackUpdate>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/backends.backends/SyncConnections");
+            let mut req = request.into_streaming_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "SyncConnections"));
+            self.inner.client_streaming(req, path, codec).await
         }
-        fn call(&mut self, req: http::Request<B>) -> Self::Future {
-            let inner = self.inner.clone();
-            match req.uri().path() {
-                "/backends.backends/GetInterfaceIndex" => {
+        /// Applies the states streamed by the control plane in order, acknowledging each. The dataplane
+        /// asks for a full state as soon as the stream opens if it hasn't been sent one since it
+        /// started.
+        pub async fn sync(
+            &mut self,
+            request: impl tonic::IntoStreamingRequest<Message = super::DesiredState>,
+        ) -> std::result::Result<
+            tonic::Response<tonic::codec::Streaming<super::StateAck>>,
+            tonic::Status,
+        > {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/backends.backends/Sync");
+            let mut req = request.into_streaming_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "Sync"));
+            self.inner.streaming(req, path, codec).await
+        }
+        /// Returns the statistics of the targets of a VIP.
+        pub async fn get_backend_stats(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Vip>,
+        ) -> std::result::Result<tonic::Response<super::BackendStatsList>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/backends.backends/GetBackendStats");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "GetBackendStats"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Returns the health of the datapath's maps.
+        pub async fn get_dataplane_info(
+            &mut self,
+            request: impl tonic::IntoRequest<super::DataplaneInfoRequest>,
+        ) -> std::result::Result<tonic::Response<super::DataplaneInfo>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/backends.backends/GetDataplaneInfo");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "GetDataplaneInfo"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Returns the version of the API and the features the dataplane supports, for the control
+        /// planes of other versions to adapt to it.
+        pub async fn get_capabilities(
+            &mut self,
+            request: impl tonic::IntoRequest<super::CapabilitiesRequest>,
+        ) -> std::result::Result<tonic::Response<super::Capabilities>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/backends.backends/GetCapabilities");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "GetCapabilities"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Streams the packets sent to a VIP and forwarded to its targets, as they came in, until the
+        /// capture reaches its number of packets or its duration. A VIP is captured by one capture at a
+        /// time.
+        pub async fn capture_packets(
+            &mut self,
+            request: impl tonic::IntoRequest<super::CaptureRequest>,
+        ) -> std::result::Result<
+            tonic::Response<tonic::codec::Streaming<super::CapturedFrame>>,
+            tonic::Status,
+        > {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/backends.backends/CapturePackets");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "CapturePackets"));
+            self.inner.server_streaming(req, path, codec).await
+        }
+        /// Sets the limit of the live connections of an existing VIP, which is removed along with it.
+        pub async fn set_connection_limit(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ConnectionLimit>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path =
+                http::uri::PathAndQuery::from_static("/backends.backends/SetConnectionLimit");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "SetConnectionLimit"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Sets the ACL of the VIPs of an address, independently of the VIPs themselves.
+        pub async fn set_acl(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Acl>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/backends.backends/SetAcl");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "SetAcl"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Turns SYN cookies on or off for an existing VIP, which turns them off along with it.
+        pub async fn set_syn_cookies(
+            &mut self,
+            request: impl tonic::IntoRequest<super::SynCookies>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/backends.backends/SetSynCookies");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "SetSynCookies"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Sets the DSCP marking of an existing VIP, which stops along with it.
+        pub async fn set_dscp_marking(
+            &mut self,
+            request: impl tonic::IntoRequest<super::DscpMarking>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/backends.backends/SetDscpMarking");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "SetDscpMarking"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Sets the mirror of an existing VIP, which stops along with it.
+        pub async fn set_mirror(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Mirror>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/backends.backends/SetMirror");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "SetMirror"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Sets the local transparent proxy of an existing VIP, which stops along with it.
+        pub async fn set_tproxy(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Tproxy>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/backends.backends/SetTproxy");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "SetTproxy"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Turns the forwarding of the pings to the address of an existing VIP to its targets on or
+        /// off, which turns it off along with the VIP.
+        pub async fn set_ping_forwarding(
+            &mut self,
+            request: impl tonic::IntoRequest<super::PingForwarding>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/backends.backends/SetPingForwarding");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "SetPingForwarding"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Sets the node of a target which the packets to the target are encapsulated to, or removes it.
+        /// The node's tunnel comes from the dataplane's tunnel source address of the target's IP family,
+        /// without which the target's packets are routed to it as usual.
+        pub async fn set_target_node(
+            &mut self,
+            request: impl tonic::IntoRequest<super::TargetNode>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/backends.backends/SetTargetNode");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "SetTargetNode"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Sets the verbosity of the logs of the datapath, which takes effect on the next packet.
+        pub async fn set_log_level(
+            &mut self,
+            request: impl tonic::IntoRequest<super::DatapathLogging>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/backends.backends/SetLogLevel");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "SetLogLevel"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Sets the timeouts of the states of the TCP connections, which apply to the tracked
+        /// connections right away.
+        pub async fn set_tcp_timeouts(
+            &mut self,
+            request: impl tonic::IntoRequest<super::TcpTimeouts>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/backends.backends/SetTcpTimeouts");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("backends.backends", "SetTcpTimeouts"));
+            self.inner.unary(req, path, codec).await
+        }
+    }
+}
+/// Generated server implementations.
+pub mod backends_server {
+    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    use tonic::codegen::*;
+    /// Generated trait containing gRPC methods that should be implemented for use with BackendsServer.
+    #[async_trait]
+    pub trait Backends: Send + Sync + 'static {
+        async fn get_interface_index(
+            &self,
+            request: tonic::Request<super::PodIp>,
+        ) -> std::result::Result<tonic::Response<super::InterfaceIndexConfirmation>, tonic::Status>;
+        async fn update(
+            &self,
+            request: tonic::Request<super::Targets>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status>;
+        async fn delete(
+            &self,
+            request: tonic::Request<super::Vip>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status>;
+        /// Returns the VIPs programmed in the datapath, and their targets.
+        async fn list_gateways(
+            &self,
+            request: tonic::Request<super::ListGatewaysRequest>,
+        ) -> std::result::Result<tonic::Response<super::GatewayList>, tonic::Status>;
+        /// Server streaming response type for the ListConnections method.
+        type ListConnectionsStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::Connection, tonic::Status>,
+            > + Send
+            + 'static;
+        /// Streams the TCP connections tracked by the datapath, and the target each is pinned to.
+        async fn list_connections(
+            &self,
+            request: tonic::Request<super::ConnectionsFilter>,
+        ) -> std::result::Result<tonic::Response<Self::ListConnectionsStream>, tonic::Status>;
+        /// Server streaming response type for the WatchConnections method.
+        type WatchConnectionsStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::ConnectionChange, tonic::Status>,
+            > + Send
+            + 'static;
+        /// Streams the connections the datapath opens and closes from now on, as they come. The
+        /// connections userspace expires and the events the watcher can't keep up with are missed.
+        async fn watch_connections(
+            &self,
+            request: tonic::Request<super::ConnectionsFilter>,
+        ) -> std::result::Result<tonic::Response<Self::WatchConnectionsStream>, tonic::Status>;
+        /// Removes the connections and UDP flows to a VIP, or pinned to a target, from the datapath,
+        /// so that their packets are balanced again. At least one of them must be set.
+        async fn flush_connections(
+            &self,
+            request: tonic::Request<super::ConnectionsFilter>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status>;
+        /// Returns the connections and UDP flows to a VIP, or pinned to a target, or all of them when
+        /// neither is set, for another dataplane to take over when the VIP fails over to it.
+        async fn export_connections(
+            &self,
+            request: tonic::Request<super::ConnectionsFilter>,
+        ) -> std::result::Result<tonic::Response<super::ConntrackSnapshot>, tonic::Status>;
+        /// Adds the connections and UDP flows exported by another dataplane, pinned to the same targets
+        /// of the local Gateways. The connections which are already tracked, those to unknown targets
+        /// and the source NATed ones are skipped.
+        async fn import_connections(
+            &self,
+            request: tonic::Request<super::ConntrackSnapshot>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status>;
+        /// Replicates the connections and UDP flows opened and closed by a peer dataplane as they come,
+        /// so that their packets keep going to the same targets when they are routed to this dataplane
+        /// instead, as with ECMP or anycast VIPs. The connections are imported as by
+        /// ImportConnections.
+        async fn sync_connections(
+            &self,
+            request: tonic::Request<tonic::Streaming<super::ConntrackUpdate>>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status>;
+        /// Server streaming response type for the Sync method.
+        type SyncStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::StateAck, tonic::Status>,
+            > + Send
+            + 'static;
+        /// Applies the states streamed by the control plane in order, acknowledging each. The dataplane
+        /// asks for a full state as soon as the stream opens if it hasn't been sent one since it
+        /// started.
+        async fn sync(
+            &self,
+            request: tonic::Request<tonic::Streaming<super::DesiredState>>,
+        ) -> std::result::Result<tonic::Response<Self::SyncStream>, tonic::Status>;
+        /// Returns the statistics of the targets of a VIP.
+        async fn get_backend_stats(
+            &self,
+            request: tonic::Request<super::Vip>,
+        ) -> std::result::Result<tonic::Response<super::BackendStatsList>, tonic::Status>;
+        /// Returns the health of the datapath's maps.
+        async fn get_dataplane_info(
+            &self,
+            request: tonic::Request<super::DataplaneInfoRequest>,
+        ) -> std::result::Result<tonic::Response<super::DataplaneInfo>, tonic::Status>;
+        /// Returns the version of the API and the features the dataplane supports, for the control
+        /// planes of other versions to adapt to it.
+        async fn get_capabilities(
+            &self,
+            request: tonic::Request<super::CapabilitiesRequest>,
+        ) -> std::result::Result<tonic::Response<super::Capabilities>, tonic::Status>;
+        /// Server streaming response type for the CapturePackets method.
+        type CapturePacketsStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::CapturedFrame, tonic::Status>,
+            > + Send
+            + 'static;
+        /// Streams the packets sent to a VIP and forwarded to its targets, as they came in, until the
+        /// capture reaches its number of packets or its duration. A VIP is captured by one capture at a
+        /// time.
+        async fn capture_packets(
+            &self,
+            request: tonic::Request<super::CaptureRequest>,
+        ) -> std::result::Result<tonic::Response<Self::CapturePacketsStream>, tonic::Status>;
+        /// Sets the limit of the live connections of an existing VIP, which is removed along with it.
+        async fn set_connection_limit(
+            &self,
+            request: tonic::Request<super::ConnectionLimit>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status>;
+        /// Sets the ACL of the VIPs of an address, independently of the VIPs themselves.
+        async fn set_acl(
+            &self,
+            request: tonic::Request<super::Acl>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status>;
+        /// Turns SYN cookies on or off for an existing VIP, which turns them off along with it.
+        async fn set_syn_cookies(
+            &self,
+            request: tonic::Request<super::SynCookies>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status>;
+        /// Sets the DSCP marking of an existing VIP, which stops along with it.
+        async fn set_dscp_marking(
+            &self,
+            request: tonic::Request<super::DscpMarking>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status>;
+        /// Sets the mirror of an existing VIP, which stops along with it.
+        async fn set_mirror(
+            &self,
+            request: tonic::Request<super::Mirror>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status>;
+        /// Sets the local transparent proxy of an existing VIP, which stops along with it.
+        async fn set_tproxy(
+            &self,
+            request: tonic::Request<super::Tproxy>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status>;
+        /// Turns the forwarding of the pings to the address of an existing VIP to its targets on or
+        /// off, which turns it off along with the VIP.
+        async fn set_ping_forwarding(
+            &self,
+            request: tonic::Request<super::PingForwarding>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status>;
+        /// Sets the node of a target which the packets to the target are encapsulated to, or removes it.
+        /// The node's tunnel comes from the dataplane's tunnel source address of the target's IP family,
+        /// without which the target's packets are routed to it as usual.
+        async fn set_target_node(
+            &self,
+            request: tonic::Request<super::TargetNode>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status>;
+        /// Sets the verbosity of the logs of the datapath, which takes effect on the next packet.
+        async fn set_log_level(
+            &self,
+            request: tonic::Request<super::DatapathLogging>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status>;
+        /// Sets the timeouts of the states of the TCP connections, which apply to the tracked
+        /// connections right away.
+        async fn set_tcp_timeouts(
+            &self,
+            request: tonic::Request<super::TcpTimeouts>,
+        ) -> std::result::Result<tonic::Response<super::Confirmation>, tonic::Status>;
+    }
+    #[derive(Debug)]
+    pub struct BackendsServer<T: Backends> {
+        inner: _Inner<T>,
+        accept_compression_encodings: EnabledCompressionEncodings,
+        send_compression_encodings: EnabledCompressionEncodings,
+        max_decoding_message_size: Option<usize>,
+        max_encoding_message_size: Option<usize>,
+    }
+    struct _Inner<T>(Arc<T>);
+    impl<T: Backends> BackendsServer<T> {
+        pub fn new(inner: T) -> Self {
+            Self::from_arc(Arc::new(inner))
+        }
+        pub fn from_arc(inner: Arc<T>) -> Self {
+            let inner = _Inner(inner);
+            Self {
+                inner,
+                accept_compression_encodings: Default::default(),
+                send_compression_encodings: Default::default(),
+                max_decoding_message_size: None,
+                max_encoding_message_size: None,
+            }
+        }
+        pub fn with_interceptor<F>(inner: T, interceptor: F) -> InterceptedService<Self, F>
+        where
+            F: tonic::service::Interceptor,
+        {
+            InterceptedService::new(Self::new(inner), interceptor)
+        }
+        /// Enable decompressing requests with the given encoding.
+        #[must_use]
+        pub fn accept_compressed(mut self, encoding: CompressionEncoding) -> Self {
+            self.accept_compression_encodings.enable(encoding);
+            self
+        }
+        /// Compress responses with the given encoding, if the client supports it.
+        #[must_use]
+        pub fn send_compressed(mut self, encoding: CompressionEncoding) -> Self {
+            self.send_compression_encodings.enable(encoding);
+            self
+        }
+        /// Limits the maximum size of a decoded message.
+        ///
+        /// Default: `4MB`
+        #[must_use]
+        pub fn max_decoding_message_size(mut self, limit: usize) -> Self {
+            self.max_decoding_message_size = Some(limit);
+            self
+        }
+        /// Limits the maximum size of an encoded message.
+        ///
+        /// Default: `usize::MAX`
+        #[must_use]
+        pub fn max_encoding_message_size(mut self, limit: usize) -> Self {
+            self.max_encoding_message_size = Some(limit);
+            self
+        }
+    }
+    impl<T, B> tonic::codegen::Service<http::Request<B>> for BackendsServer<T>
+    where
+        T: Backends,
+        B: Body + Send + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        type Response = http::Response<tonic::body::BoxBody>;
+        type Error = std::convert::Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+        fn poll_ready(
+            &mut self,
+            _cx: &mut Context<'_>,
+        ) -> Poll<std::result::Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+        fn call(&mut self, req: http::Request<B>) -> Self::Future {
+            let inner = self.inner.clone();
+            match req.uri().path() {
+                "/backends.backends/GetInterfaceIndex" => {
+                    #[allow(non_camel_case_types)]
+                    struct GetInterfaceIndexSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::UnaryService<super::PodIp> for GetInterfaceIndexSvc<T> {
+                        type Response = super::InterfaceIndexConfirmation;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(&mut self, request: tonic::Request<super::PodIp>) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Backends>::get_interface_index(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = GetInterfaceIndexSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/Update" => {
+                    #[allow(non_camel_case_types)]
+                    struct UpdateSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::UnaryService<super::Targets> for UpdateSvc<T> {
+                        type Response = super::Confirmation;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Targets>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move { <T as Backends>::update(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = UpdateSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/Delete" => {
+                    #[allow(non_camel_case_types)]
+                    struct DeleteSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::UnaryService<super::Vip> for DeleteSvc<T> {
+                        type Response = super::Confirmation;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(&mut self, request: tonic::Request<super::Vip>) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move { <T as Backends>::delete(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = DeleteSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/ListGateways" => {
+                    #[allow(non_camel_case_types)]
+                    struct ListGatewaysSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::UnaryService<super::ListGatewaysRequest> for ListGatewaysSvc<T> {
+                        type Response = super::GatewayList;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ListGatewaysRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Backends>::list_gateways(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ListGatewaysSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/ListConnections" => {
+                    #[allow(non_camel_case_types)]
+                    struct ListConnectionsSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends>
+                        tonic::server::ServerStreamingService<super::ConnectionsFilter>
+                        for ListConnectionsSvc<T>
+                    {
+                        type Response = super::Connection;
+                        type ResponseStream = T::ListConnectionsStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ConnectionsFilter>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Backends>::list_connections(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ListConnectionsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/WatchConnections" => {
+                    #[allow(non_camel_case_types)]
+                    struct WatchConnectionsSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends>
+                        tonic::server::ServerStreamingService<super::ConnectionsFilter>
+                        for WatchConnectionsSvc<T>
+                    {
+                        type Response = super::ConnectionChange;
+                        type ResponseStream = T::WatchConnectionsStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ConnectionsFilter>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Backends>::watch_connections(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = WatchConnectionsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/FlushConnections" => {
+                    #[allow(non_camel_case_types)]
+                    struct FlushConnectionsSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::UnaryService<super::ConnectionsFilter> for FlushConnectionsSvc<T> {
+                        type Response = super::Confirmation;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ConnectionsFilter>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Backends>::flush_connections(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = FlushConnectionsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/ExportConnections" => {
+                    #[allow(non_camel_case_types)]
+                    struct ExportConnectionsSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::UnaryService<super::ConnectionsFilter>
+                        for ExportConnectionsSvc<T>
+                    {
+                        type Response = super::ConntrackSnapshot;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ConnectionsFilter>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Backends>::export_connections(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ExportConnectionsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/ImportConnections" => {
+                    #[allow(non_camel_case_types)]
+                    struct ImportConnectionsSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::UnaryService<super::ConntrackSnapshot>
+                        for ImportConnectionsSvc<T>
+                    {
+                        type Response = super::Confirmation;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ConntrackSnapshot>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Backends>::import_connections(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ImportConnectionsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/SyncConnections" => {
+                    #[allow(non_camel_case_types)]
+                    struct SyncConnectionsSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::ClientStreamingService<super::ConntrackUpdate>
+                        for SyncConnectionsSvc<T>
+                    {
+                        type Response = super::Confirmation;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<tonic::Streaming<super::ConntrackUpdate>>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Backends>::sync_connections(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SyncConnectionsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.client_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/Sync" => {
+                    #[allow(non_camel_case_types)]
+                    struct SyncSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::StreamingService<super::DesiredState> for SyncSvc<T> {
+                        type Response = super::StateAck;
+                        type ResponseStream = T::SyncStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<tonic::Streaming<super::DesiredState>>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move { <T as Backends>::sync(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SyncSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/GetBackendStats" => {
+                    #[allow(non_camel_case_types)]
+                    struct GetBackendStatsSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::UnaryService<super::Vip> for GetBackendStatsSvc<T> {
+                        type Response = super::BackendStatsList;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(&mut self, request: tonic::Request<super::Vip>) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Backends>::get_backend_stats(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = GetBackendStatsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/GetDataplaneInfo" => {
+                    #[allow(non_camel_case_types)]
+                    struct GetDataplaneInfoSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::UnaryService<super::DataplaneInfoRequest>
+                        for GetDataplaneInfoSvc<T>
+                    {
+                        type Response = super::DataplaneInfo;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::DataplaneInfoRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Backends>::get_dataplane_info(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = GetDataplaneInfoSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/GetCapabilities" => {
+                    #[allow(non_camel_case_types)]
+                    struct GetCapabilitiesSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::UnaryService<super::CapabilitiesRequest>
+                        for GetCapabilitiesSvc<T>
+                    {
+                        type Response = super::Capabilities;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::CapabilitiesRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Backends>::get_capabilities(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = GetCapabilitiesSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/CapturePackets" => {
+                    #[allow(non_camel_case_types)]
+                    struct CapturePacketsSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::ServerStreamingService<super::CaptureRequest>
+                        for CapturePacketsSvc<T>
+                    {
+                        type Response = super::CapturedFrame;
+                        type ResponseStream = T::CapturePacketsStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::CaptureRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Backends>::capture_packets(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = CapturePacketsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/SetConnectionLimit" => {
                     #[allow(non_camel_case_types)]
-                    struct GetInterfaceIndexSvc<T: Backends>(pub Arc<T>);
-                    impl<T: Backends> tonic::server::UnaryService<super::PodIp> for GetInterfaceIndexSvc<T> {
-                        type Response = super::InterfaceIndexConfirmation;
+                    struct SetConnectionLimitSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::UnaryService<super::ConnectionLimit> for SetConnectionLimitSvc<T> {
+                        type Response = super::Confirmation;
                         type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
-                        fn call(&mut self, request: tonic::Request<super::PodIp>) -> Self::Future {
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ConnectionLimit>,
+                        ) -> Self::Future {
                             let inner = Arc::clone(&self.0);
                             let fut = async move {
-                                <T as Backends>::get_interface_index(&inner, request).await
+                                <T as Backends>::set_connection_limit(&inner, request).await
                             };
                             Box::pin(fut)
                         }
@@ -297,7 +2389,7 @@ pub mod backends_server {
                     let inner = self.inner.clone();
                     let fut = async move {
                         let inner = inner.0;
-                        let method = GetInterfaceIndexSvc(inner);
+                        let method = SetConnectionLimitSvc(inner);
                         let codec = tonic::codec::ProstCodec::default();
                         let mut grpc = tonic::server::Grpc::new(codec)
                             .apply_compression_config(
@@ -313,18 +2405,56 @@ pub mod backends_server {
                     };
                     Box::pin(fut)
                 }
-                "/backends.backends/Update" => {
+                "/backends.backends/SetAcl" => {
                     #[allow(non_camel_case_types)]
-                    struct UpdateSvc<T: Backends>(pub Arc<T>);
-                    impl<T: Backends> tonic::server::UnaryService<super::Targets> for UpdateSvc<T> {
+                    struct SetAclSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::UnaryService<super::Acl> for SetAclSvc<T> {
+                        type Response = super::Confirmation;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(&mut self, request: tonic::Request<super::Acl>) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as Backends>::set_acl(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SetAclSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/SetSynCookies" => {
+                    #[allow(non_camel_case_types)]
+                    struct SetSynCookiesSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::UnaryService<super::SynCookies> for SetSynCookiesSvc<T> {
                         type Response = super::Confirmation;
                         type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
                         fn call(
                             &mut self,
-                            request: tonic::Request<super::Targets>,
+                            request: tonic::Request<super::SynCookies>,
                         ) -> Self::Future {
                             let inner = Arc::clone(&self.0);
-                            let fut = async move { <T as Backends>::update(&inner, request).await };
+                            let fut = async move {
+                                <T as Backends>::set_syn_cookies(&inner, request).await
+                            };
                             Box::pin(fut)
                         }
                     }
@@ -335,7 +2465,7 @@ pub mod backends_server {
                     let inner = self.inner.clone();
                     let fut = async move {
                         let inner = inner.0;
-                        let method = UpdateSvc(inner);
+                        let method = SetSynCookiesSvc(inner);
                         let codec = tonic::codec::ProstCodec::default();
                         let mut grpc = tonic::server::Grpc::new(codec)
                             .apply_compression_config(
@@ -351,15 +2481,20 @@ pub mod backends_server {
                     };
                     Box::pin(fut)
                 }
-                "/backends.backends/Delete" => {
+                "/backends.backends/SetDscpMarking" => {
                     #[allow(non_camel_case_types)]
-                    struct DeleteSvc<T: Backends>(pub Arc<T>);
-                    impl<T: Backends> tonic::server::UnaryService<super::Vip> for DeleteSvc<T> {
+                    struct SetDscpMarkingSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::UnaryService<super::DscpMarking> for SetDscpMarkingSvc<T> {
                         type Response = super::Confirmation;
                         type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
-                        fn call(&mut self, request: tonic::Request<super::Vip>) -> Self::Future {
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::DscpMarking>,
+                        ) -> Self::Future {
                             let inner = Arc::clone(&self.0);
-                            let fut = async move { <T as Backends>::delete(&inner, request).await };
+                            let fut = async move {
+                                <T as Backends>::set_dscp_marking(&inner, request).await
+                            };
                             Box::pin(fut)
                         }
                     }
@@ -370,7 +2505,239 @@ pub mod backends_server {
                     let inner = self.inner.clone();
                     let fut = async move {
                         let inner = inner.0;
-                        let method = DeleteSvc(inner);
+                        let method = SetDscpMarkingSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/SetMirror" => {
+                    #[allow(non_camel_case_types)]
+                    struct SetMirrorSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::UnaryService<super::Mirror> for SetMirrorSvc<T> {
+                        type Response = super::Confirmation;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(&mut self, request: tonic::Request<super::Mirror>) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as Backends>::set_mirror(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SetMirrorSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/SetTproxy" => {
+                    #[allow(non_camel_case_types)]
+                    struct SetTproxySvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::UnaryService<super::Tproxy> for SetTproxySvc<T> {
+                        type Response = super::Confirmation;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(&mut self, request: tonic::Request<super::Tproxy>) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as Backends>::set_tproxy(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SetTproxySvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/SetPingForwarding" => {
+                    #[allow(non_camel_case_types)]
+                    struct SetPingForwardingSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::UnaryService<super::PingForwarding> for SetPingForwardingSvc<T> {
+                        type Response = super::Confirmation;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::PingForwarding>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Backends>::set_ping_forwarding(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SetPingForwardingSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/SetTargetNode" => {
+                    #[allow(non_camel_case_types)]
+                    struct SetTargetNodeSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::UnaryService<super::TargetNode> for SetTargetNodeSvc<T> {
+                        type Response = super::Confirmation;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::TargetNode>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Backends>::set_target_node(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SetTargetNodeSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/SetLogLevel" => {
+                    #[allow(non_camel_case_types)]
+                    struct SetLogLevelSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::UnaryService<super::DatapathLogging> for SetLogLevelSvc<T> {
+                        type Response = super::Confirmation;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::DatapathLogging>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Backends>::set_log_level(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SetLogLevelSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/backends.backends/SetTcpTimeouts" => {
+                    #[allow(non_camel_case_types)]
+                    struct SetTcpTimeoutsSvc<T: Backends>(pub Arc<T>);
+                    impl<T: Backends> tonic::server::UnaryService<super::TcpTimeouts> for SetTcpTimeoutsSvc<T> {
+                        type Response = super::Confirmation;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::TcpTimeouts>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Backends>::set_tcp_timeouts(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SetTcpTimeoutsSvc(inner);
                         let codec = tonic::codec::ProstCodec::default();
                         let mut grpc = tonic::server::Grpc::new(codec)
                             .apply_compression_config(
@@ -0,0 +1,180 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::collections::HashMap as StdHashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{mem, ptr};
+
+use anyhow::Error;
+use aya::maps::{HashMap, MapData, RingBuf};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::{mpsc, Mutex};
+use tracing::warn;
+
+use crate::conntrack::monotonic_now_ns;
+use crate::server::is_key_not_found;
+use common::{BackendKey, CapturedPacket, CAPTURE_LEN};
+
+/// The most packets a capture takes, and the longest it lasts. Captures
+/// asking for no bound get these.
+pub const MAX_CAPTURE_PACKETS: u32 = 10_000;
+pub const MAX_CAPTURE_DURATION: Duration = Duration::from_secs(300);
+
+/// How many captured packets are buffered while the capture isn't taking
+/// them, past which they are dropped.
+const CAPTURE_BUFFER: usize = 1024;
+
+/// The link type of the captured packets in the pcap files, Ethernet.
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// The captures of the packets of the Gateways in progress. A Gateway is
+/// captured by one capture at a time.
+pub struct Captures {
+    captures_map: Mutex<HashMap<MapData, BackendKey, u8>>,
+    // Where the packets captured for each Gateway go.
+    sessions: Mutex<StdHashMap<BackendKey, mpsc::Sender<CapturedPacket>>>,
+}
+
+impl Captures {
+    pub fn new(captures_map: HashMap<MapData, BackendKey, u8>) -> Captures {
+        Captures {
+            captures_map: Mutex::new(captures_map),
+            sessions: Mutex::new(StdHashMap::new()),
+        }
+    }
+
+    /// Starts capturing the packets of the Gateway, which come out of the
+    /// returned receiver until the capture is stopped. Returns None if the
+    /// Gateway is already being captured.
+    pub async fn start(
+        &self,
+        key: BackendKey,
+    ) -> Result<Option<mpsc::Receiver<CapturedPacket>>, Error> {
+        let mut sessions = self.sessions.lock().await;
+        if sessions.contains_key(&key) {
+            return Ok(None);
+        }
+        let (packets, packets_rx) = mpsc::channel(CAPTURE_BUFFER);
+        self.captures_map.lock().await.insert(key, 0, 0)?;
+        sessions.insert(key, packets);
+        Ok(Some(packets_rx))
+    }
+
+    /// Stops capturing the packets of the Gateway.
+    pub async fn stop(&self, key: &BackendKey) -> Result<(), Error> {
+        self.sessions.lock().await.remove(key);
+        match self.captures_map.lock().await.remove(key) {
+            Err(err) if is_key_not_found(&err) => Ok(()),
+            result => Ok(result?),
+        }
+    }
+}
+
+/// Reads the packets the ingress program captures in the CAPTURED_PACKETS
+/// ring buffer as they come, and hands each to the capture of its Gateway.
+/// Runs until the ring buffer can't be polled anymore.
+pub async fn dispatch_captured_packets(
+    ring_buf: RingBuf<MapData>,
+    captures: Arc<Captures>,
+) -> Result<(), Error> {
+    let mut ring_buf = AsyncFd::new(ring_buf)?;
+    loop {
+        let mut guard = ring_buf.readable_mut().await?;
+        let ring_buf = guard.get_inner_mut();
+        let sessions = captures.sessions.lock().await;
+        while let Some(item) = ring_buf.next() {
+            let packet = match parse_captured_packet(&item) {
+                Some(packet) => packet,
+                None => {
+                    warn!(len = item.len(), "dropping a captured packet");
+                    continue;
+                }
+            };
+            // The packets of a stopped capture may still be in the ring buffer,
+            // and those of a capture falling behind are dropped.
+            if let Some(session) = sessions.get(&packet.backend_key) {
+                let _ = session.try_send(packet);
+            }
+        }
+        guard.clear_ready();
+    }
+}
+
+/// Returns the captured packet of a ring buffer item, if it is the size of
+/// one.
+fn parse_captured_packet(item: &[u8]) -> Option<CapturedPacket> {
+    if item.len() < mem::size_of::<CapturedPacket>() {
+        return None;
+    }
+    // The items of the ring buffer are only 8-byte aligned.
+    Some(unsafe { ptr::read_unaligned(item.as_ptr() as *const CapturedPacket) })
+}
+
+/// Returns the captured bytes of the packet.
+pub fn captured_data(packet: &CapturedPacket) -> &[u8] {
+    &packet.data[..(packet.captured_len as usize).min(CAPTURE_LEN)]
+}
+
+/// Returns the time in nanoseconds since the Unix epoch the packet was
+/// captured at.
+pub fn captured_at_unix_ns(packet: &CapturedPacket) -> Result<u64, Error> {
+    let elapsed = monotonic_now_ns()?.saturating_sub(packet.timestamp);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64;
+    Ok(now.saturating_sub(elapsed))
+}
+
+/// Writes captured packets to a file in the pcap format, which tcpdump and
+/// Wireshark read.
+/// Ref: https://datatracker.ietf.org/doc/draft-ietf-opsawg-pcap/
+pub struct PcapWriter {
+    file: BufWriter<File>,
+}
+
+impl PcapWriter {
+    /// Creates the file, replacing any file at the path, but not following a
+    /// symbolic link there.
+    pub fn create(path: &Path) -> Result<PcapWriter, Error> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)?;
+        let mut file = BufWriter::new(file);
+        // Magic number of microsecond timestamps, version 2.4, no time zone
+        // offset nor accuracy.
+        file.write_all(&0xa1b2c3d4_u32.to_ne_bytes())?;
+        file.write_all(&2_u16.to_ne_bytes())?;
+        file.write_all(&4_u16.to_ne_bytes())?;
+        file.write_all(&0_i32.to_ne_bytes())?;
+        file.write_all(&0_u32.to_ne_bytes())?;
+        file.write_all(&(CAPTURE_LEN as u32).to_ne_bytes())?;
+        file.write_all(&LINKTYPE_ETHERNET.to_ne_bytes())?;
+        Ok(PcapWriter { file })
+    }
+
+    /// Appends a packet captured at `unix_ns`.
+    pub fn write(&mut self, packet: &CapturedPacket, unix_ns: u64) -> Result<(), Error> {
+        let data = captured_data(packet);
+        self.file
+            .write_all(&((unix_ns / 1_000_000_000) as u32).to_ne_bytes())?;
+        self.file
+            .write_all(&((unix_ns % 1_000_000_000 / 1_000) as u32).to_ne_bytes())?;
+        self.file.write_all(&(data.len() as u32).to_ne_bytes())?;
+        self.file.write_all(&packet.len.to_ne_bytes())?;
+        self.file.write_all(data)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        Ok(self.file.flush()?)
+    }
+}
@@ -0,0 +1,429 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::collections::HashMap as StdHashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{mem, ptr, slice};
+
+use anyhow::Error;
+use aya::maps::{HashMap, MapData, MapError, PerCpuHashMap, PerCpuValues};
+use aya::util::nr_cpus;
+use aya::Pod;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{debug, warn};
+
+use crate::events::IPPROTO_TCP;
+use crate::flowtable::FlowTable;
+use crate::server::is_key_not_found;
+use crate::SharedMaps;
+use common::{
+    Backend, BackendConnections, BackendKey, ClientKey, CloseReason, ConnectionEvent,
+    ConnectionEventKind, FlowStats, LoadBalancerMapping, SnatKey, UdpLoadBalancerMapping,
+};
+
+/// How often the TCP connection tracking map is scanned for idle connections.
+const TCP_SCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Periodically removes the connections which have been idle for longer than
+/// the timeout of their TCP state, as found in the TCP_TIMEOUTS map at each
+/// scan, from the TCP connection tracking map. This catches the connections
+/// whose termination the datapath never saw. The entries of UDP flows, which
+/// have no TCP state, are given `udp_idle_timeout`. The expired connections are
+/// passed on to the export of the flows, if any. The live connections of the
+/// backends and of the clients are then reconciled with the tracking maps, see
+/// reconcile_live_connections. Runs forever.
+pub async fn expire_tcp_conns(
+    maps: SharedMaps,
+    flow_table: Arc<FlowTable>,
+    exporter: Option<mpsc::Sender<ConnectionEvent>>,
+    udp_idle_timeout: Duration,
+) {
+    let mut interval = tokio::time::interval(TCP_SCAN_INTERVAL);
+    loop {
+        interval.tick().await;
+        match prune_tcp_conns(&maps, exporter.as_ref(), udp_idle_timeout).await {
+            Ok(0) => {}
+            Ok(pruned) => {
+                flow_table.record_removals(pruned as u64);
+                debug!(pruned, "pruned idle TCP connections");
+            }
+            Err(err) => warn!(error = %err, "failed to prune idle TCP connections"),
+        }
+        if let Err(err) = reconcile_live_connections(&maps).await {
+            warn!(error = %err, "failed to reconcile the live connections");
+        }
+    }
+}
+
+async fn prune_tcp_conns(
+    maps: &SharedMaps,
+    exporter: Option<&mpsc::Sender<ConnectionEvent>>,
+    udp_idle_timeout: Duration,
+) -> Result<usize, Error> {
+    let now = monotonic_now_ns()?;
+    let tcp_timeouts = maps.tcp_timeouts.lock().await.get(&0, 0)?;
+
+    let mut tcp_conns_map = maps.tcp_conns.lock().await;
+    let mut released_conns_map = maps.released_conns.lock().await;
+    let mut snat_conns_map = maps.snat_conns.lock().await;
+    let mut client_conns_map = maps.client_conns.lock().await;
+    let mut pruned = 0;
+    for item in tcp_conns_map
+        .iter()
+        .collect::<Vec<Result<(ClientKey, LoadBalancerMapping), MapError>>>()
+    {
+        let (client_key, lb_mapping) = match item {
+            Ok(item) => item,
+            // The connection was removed by the datapath, or evicted, since its key was read.
+            Err(err) if is_key_not_found(&err) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let idle_timeout = match lb_mapping.tcp_state {
+            Some(state) => tcp_timeouts.of(state),
+            None => udp_idle_timeout.as_nanos() as u64,
+        };
+        if idle_timeout != 0 && now.saturating_sub(lb_mapping.last_seen) > idle_timeout {
+            match tcp_conns_map.remove(&client_key) {
+                Ok(()) => {
+                    // Only TCP connections are counted, the entries of UDP flows
+                    // are there for ICMP.
+                    if lb_mapping.tcp_state.is_some() {
+                        release_connections(&mut released_conns_map, &lb_mapping.backend, 1)?;
+                        release_client_connection(&mut client_conns_map, &client_key)?;
+                        export_expired(
+                            exporter,
+                            IPPROTO_TCP,
+                            &client_key,
+                            &lb_mapping.backend_key,
+                            &lb_mapping.backend,
+                            &lb_mapping.stats,
+                            now,
+                        );
+                    }
+                    release_snat_port(&mut snat_conns_map, &lb_mapping)?;
+                    pruned += 1;
+                }
+                // The entry may already be gone, which is what we wanted anyway.
+                Err(err) if is_key_not_found(&err) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+    Ok(pruned)
+}
+
+/// Recomputes the connections released by userspace from the connections left
+/// in the tracking maps, so that the live connections of the backends account
+/// for those the tracking maps evicted without the datapath seeing them close. The
+/// connections of the clients are lowered to those left in LB_CONNECTIONS too.
+/// The connections opened and closed during the scan skew the counts until the
+/// next one.
+async fn reconcile_live_connections(maps: &SharedMaps) -> Result<(), Error> {
+    let tcp_conns_map = maps.tcp_conns.lock().await;
+    let udp_conns_map = maps.udp_conns.lock().await;
+    let sctp_conns_map = maps.sctp_conns.lock().await;
+    let mut tracked: StdHashMap<BackendKey, u64> = StdHashMap::new();
+    let mut clients: StdHashMap<[u32; 4], u32> = StdHashMap::new();
+    for item in tcp_conns_map.iter() {
+        let (client_key, lb_mapping) = match item {
+            Ok(item) => item,
+            Err(err) if is_key_not_found(&err) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        // Only TCP connections are counted, the entries of UDP flows are there
+        // for ICMP.
+        if lb_mapping.tcp_state.is_some() {
+            *tracked.entry(lb_mapping.backend.key()).or_default() += 1;
+            *clients.entry(client_key.ip).or_default() += 1;
+        }
+    }
+    for map in [&*udp_conns_map, &*sctp_conns_map] {
+        for item in map.iter() {
+            let (_, udp_mapping) = match item {
+                Ok(item) => item,
+                Err(err) if is_key_not_found(&err) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            *tracked.entry(udp_mapping.backend.key()).or_default() += 1;
+        }
+    }
+
+    let backend_conns_map = maps.backend_conns.lock().await;
+    let mut released_conns_map = maps.released_conns.lock().await;
+    for item in backend_conns_map.iter() {
+        let (key, per_cpu_counters) = match item {
+            Ok(item) => item,
+            Err(err) if is_key_not_found(&err) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let (opened, closed) = per_cpu_counters
+            .iter()
+            .fold((0, 0), |(opened, closed), counters| {
+                (opened + counters.opened, closed + counters.closed)
+            });
+        let live = tracked.get(&key).copied().unwrap_or(0);
+        released_conns_map.insert(key, opened.saturating_sub(closed).saturating_sub(live), 0)?;
+    }
+
+    let mut client_conns_map = maps.client_conns.lock().await;
+    for item in client_conns_map
+        .iter()
+        .collect::<Vec<Result<([u32; 4], u32), MapError>>>()
+    {
+        let (ip, counted) = match item {
+            Ok(item) => item,
+            Err(err) if is_key_not_found(&err) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        // The connections the datapath counts during the scan are lost, erring
+        // on the side of the client.
+        let live = clients.get(&ip).copied().unwrap_or(0);
+        if counted > live {
+            client_conns_map.insert(ip, live, 0)?;
+        }
+    }
+    Ok(())
+}
+
+/// Periodically removes the UDP flows which have been idle for longer than
+/// their own timeout, which is the one of their VIP if it has any and
+/// `idle_timeout` otherwise, from the UDP connection tracking map. Flows with
+/// a shorter timeout than `idle_timeout` may outlive it until the next scan,
+/// unless their client comes back first. The SCTP associations,
+/// which are tracked the same way, are expired with it too, `proto` is the IP
+/// protocol of the entries and `kind` names them in the logs. The expired flows
+/// are passed on to the export of the flows, if any. Runs forever.
+pub async fn expire_udp_conns(
+    udp_conns_map: Arc<Mutex<HashMap<MapData, ClientKey, UdpLoadBalancerMapping>>>,
+    released_conns_map: Arc<Mutex<HashMap<MapData, BackendKey, u64>>>,
+    exporter: Option<mpsc::Sender<ConnectionEvent>>,
+    idle_timeout: Duration,
+    proto: u8,
+    kind: &'static str,
+) {
+    // Scanning twice per timeout bounds how long an idle flow can outlive it.
+    let mut interval = tokio::time::interval(idle_timeout / 2);
+    loop {
+        interval.tick().await;
+        match prune_udp_conns(
+            &udp_conns_map,
+            &released_conns_map,
+            exporter.as_ref(),
+            idle_timeout,
+            proto,
+        )
+        .await
+        {
+            Ok(0) => {}
+            Ok(pruned) => debug!(pruned, kind, "pruned idle entries"),
+            Err(err) => warn!(kind, error = %err, "failed to prune idle entries"),
+        }
+    }
+}
+
+async fn prune_udp_conns(
+    udp_conns_map: &Mutex<HashMap<MapData, ClientKey, UdpLoadBalancerMapping>>,
+    released_conns_map: &Mutex<HashMap<MapData, BackendKey, u64>>,
+    exporter: Option<&mpsc::Sender<ConnectionEvent>>,
+    idle_timeout: Duration,
+    proto: u8,
+) -> Result<usize, Error> {
+    let now = monotonic_now_ns()?;
+    let idle_timeout = idle_timeout.as_nanos() as u64;
+
+    let mut udp_conns_map = udp_conns_map.lock().await;
+    let mut released_conns_map = released_conns_map.lock().await;
+    let mut pruned = 0;
+    for item in udp_conns_map
+        .iter()
+        .collect::<Vec<Result<(ClientKey, UdpLoadBalancerMapping), MapError>>>()
+    {
+        let (client_key, udp_mapping) = match item {
+            Ok(item) => item,
+            // The flow was removed by the datapath since its key was read.
+            Err(err) if is_key_not_found(&err) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let flow_timeout = match udp_mapping.idle_timeout {
+            0 => idle_timeout,
+            flow_timeout => flow_timeout,
+        };
+        if now.saturating_sub(udp_mapping.last_seen) > flow_timeout {
+            match udp_conns_map.remove(&client_key) {
+                Ok(()) => {
+                    release_connections(&mut released_conns_map, &udp_mapping.backend, 1)?;
+                    export_expired(
+                        exporter,
+                        proto,
+                        &client_key,
+                        &udp_mapping.backend_key,
+                        &udp_mapping.backend,
+                        &udp_mapping.stats,
+                        now,
+                    );
+                    pruned += 1;
+                }
+                // The entry may already be gone, which is what we wanted anyway.
+                Err(err) if is_key_not_found(&err) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+    Ok(pruned)
+}
+
+/// Passes a connection expired at `now` on to the export of the flows, as the
+/// connection event the datapath would have reported.
+fn export_expired(
+    exporter: Option<&mpsc::Sender<ConnectionEvent>>,
+    proto: u8,
+    client_key: &ClientKey,
+    backend_key: &BackendKey,
+    backend: &Backend,
+    stats: &FlowStats,
+    now: u64,
+) {
+    let exporter = match exporter {
+        Some(exporter) => exporter,
+        None => return,
+    };
+    let event = ConnectionEvent {
+        timestamp: now,
+        client_key: *client_key,
+        backend_key: *backend_key,
+        backend: backend.key(),
+        proto,
+        kind: ConnectionEventKind::Closed,
+        reason: CloseReason::Timeout,
+        stats: *stats,
+    };
+    if exporter.try_send(event).is_err() {
+        warn!("dropping an expired connection, the flow export is falling behind");
+    }
+}
+
+/// Records that `count` connections of the backend were removed from the
+/// connection tracking maps by userspace, so that the datapath no longer
+/// counts them as live.
+pub(crate) fn release_connections(
+    released_conns_map: &mut HashMap<MapData, BackendKey, u64>,
+    backend: &Backend,
+    count: u64,
+) -> Result<(), MapError> {
+    let key = backend.key();
+    let released = match released_conns_map.get(&key, 0) {
+        Ok(released) => released,
+        Err(err) if is_key_not_found(&err) => 0,
+        Err(err) => return Err(err),
+    };
+    released_conns_map.insert(key, released + count, 0)
+}
+
+/// Records that a TCP connection of the client was removed from the connection
+/// tracking map by userspace, so that it no longer counts towards the client's
+/// cap. The datapath may count a new connection of the client in between, which
+/// is lost, erring on the side of the client.
+pub(crate) fn release_client_connection(
+    client_conns_map: &mut HashMap<MapData, [u32; 4], u32>,
+    client_key: &ClientKey,
+) -> Result<(), MapError> {
+    let live = match client_conns_map.get(&client_key.ip, 0) {
+        Ok(live) => live,
+        // The client isn't counted, or its entry was evicted.
+        Err(err) if is_key_not_found(&err) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    client_conns_map.insert(client_key.ip, live.saturating_sub(1), 0)
+}
+
+/// Frees the source port of a source NATed connection which was removed from
+/// the TCP connection tracking map.
+pub(crate) fn release_snat_port(
+    snat_conns_map: &mut HashMap<MapData, SnatKey, ClientKey>,
+    lb_mapping: &LoadBalancerMapping,
+) -> Result<(), MapError> {
+    let snat_key = match lb_mapping.snat_key() {
+        Some(snat_key) => snat_key,
+        None => return Ok(()),
+    };
+    match snat_conns_map.remove(&snat_key) {
+        // The entry may have been evicted already.
+        Err(err) if is_key_not_found(&err) => Ok(()),
+        result => result,
+    }
+}
+
+/// Records that `count` connections imported from another dataplane were
+/// assigned to the backend, so that the datapath counts them as live until they
+/// are closed or released. They are added to the counters of the first CPU,
+/// losing the connections the datapath counts on it in between.
+pub(crate) fn count_imported_connections(
+    backend_conns_map: &mut PerCpuHashMap<MapData, BackendKey, BackendConnections>,
+    key: &BackendKey,
+    count: u64,
+) -> Result<(), Error> {
+    let mut counters = match backend_conns_map.get(key, 0) {
+        Ok(counters) => counters.to_vec(),
+        Err(err) if is_key_not_found(&err) => vec![BackendConnections::default(); nr_cpus()?],
+        Err(err) => return Err(err.into()),
+    };
+    counters[0].opened += count;
+    backend_conns_map.insert(key, PerCpuValues::try_from(counters)?, 0)?;
+    Ok(())
+}
+
+/// Returns the bytes of a key or value of the connection tracking maps, as laid
+/// out in the maps.
+pub(crate) fn to_bytes<T: Pod>(value: &T) -> Vec<u8> {
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }.to_vec()
+}
+
+/// Returns the key or value of the connection tracking maps laid out in the
+/// bytes, or None if they are not of its size. The bytes are not otherwise
+/// checked, they must have been exported by a dataplane of the same version.
+pub(crate) fn from_bytes<T: Pod>(bytes: &[u8]) -> Option<T> {
+    if bytes.len() != mem::size_of::<T>() {
+        return None;
+    }
+    Some(unsafe { ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+/// Returns the number of live connections of every backend which has been
+/// assigned a connection, as counted by the datapath.
+pub fn live_connections(
+    backend_conns_map: &PerCpuHashMap<MapData, BackendKey, BackendConnections>,
+    released_conns_map: &HashMap<MapData, BackendKey, u64>,
+) -> Result<Vec<(BackendKey, u64)>, Error> {
+    let mut live = Vec::new();
+    for item in backend_conns_map.iter() {
+        let (key, per_cpu_counters) = item?;
+        let (opened, closed) = per_cpu_counters
+            .iter()
+            .fold((0, 0), |(opened, closed), counters| {
+                (opened + counters.opened, closed + counters.closed)
+            });
+        let released = match released_conns_map.get(&key, 0) {
+            Ok(released) => released,
+            Err(err) if is_key_not_found(&err) => 0,
+            Err(err) => return Err(err.into()),
+        };
+        live.push((key, opened.saturating_sub(closed).saturating_sub(released)));
+    }
+    Ok(live)
+}
+
+/// Returns the current time of the clock used by bpf_ktime_get_ns, in nanoseconds.
+pub(crate) fn monotonic_now_ns() -> Result<u64, Error> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64)
+}
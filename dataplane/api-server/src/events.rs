@@ -0,0 +1,130 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::mem;
+use std::net::SocketAddr;
+use std::ptr;
+
+use anyhow::Error;
+use aya::maps::{MapData, RingBuf};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{info, warn};
+
+use crate::netutils::words_to_ip;
+use common::{BackendKey, ClientKey, ConnectionEvent, ConnectionEventKind};
+
+/// The log target of the connection events, so that they can be filtered and
+/// routed apart from the rest of the logs.
+pub const CONNECTION_EVENTS_TARGET: &str = "blixt::connections";
+
+/// How many connection events are buffered for each watcher of the
+/// connections, which misses those it can't keep up with.
+pub const WATCHERS_CAPACITY: usize = 1024;
+
+pub(crate) const IPPROTO_TCP: u8 = libc::IPPROTO_TCP as u8;
+pub(crate) const IPPROTO_UDP: u8 = libc::IPPROTO_UDP as u8;
+pub(crate) const IPPROTO_SCTP: u8 = libc::IPPROTO_SCTP as u8;
+
+/// Reads the connection events the eBPF programs report in the
+/// CONNECTION_EVENTS ring buffer as they come, logs them and passes them on to
+/// the replication of the connections to the peer dataplanes and to the export
+/// of the flows, if any, and to the watchers of the connections. Runs until the
+/// ring buffer can't be polled anymore.
+pub async fn log_connection_events(
+    ring_buf: RingBuf<MapData>,
+    watchers: broadcast::Sender<ConnectionEvent>,
+    replication: Option<mpsc::Sender<ConnectionEvent>>,
+    exporter: Option<mpsc::Sender<ConnectionEvent>>,
+) -> Result<(), Error> {
+    let mut ring_buf = AsyncFd::new(ring_buf)?;
+    loop {
+        let mut guard = ring_buf.readable_mut().await?;
+        let ring_buf = guard.get_inner_mut();
+        while let Some(item) = ring_buf.next() {
+            let event = match parse_connection_event(&item) {
+                Some(event) => event,
+                None => {
+                    warn!(len = item.len(), "dropping a connection event");
+                    continue;
+                }
+            };
+            log_connection_event(&event);
+            // Sending fails when nobody is watching.
+            let _ = watchers.send(event);
+            // The ring buffer is drained regardless of the replication keeping up,
+            // which misses the connections it can't take.
+            if let Some(replication) = &replication {
+                if replication.try_send(event).is_err() {
+                    warn!("dropping a connection event, the replication is falling behind");
+                }
+            }
+            if let Some(exporter) = &exporter {
+                if exporter.try_send(event).is_err() {
+                    warn!("dropping a connection event, the flow export is falling behind");
+                }
+            }
+        }
+        guard.clear_ready();
+    }
+}
+
+/// Returns the connection event of a ring buffer item, if it is the size of
+/// one.
+fn parse_connection_event(item: &[u8]) -> Option<ConnectionEvent> {
+    if item.len() < mem::size_of::<ConnectionEvent>() {
+        return None;
+    }
+    // The items of the ring buffer are only 8-byte aligned.
+    Some(unsafe { ptr::read_unaligned(item.as_ptr() as *const ConnectionEvent) })
+}
+
+fn log_connection_event(event: &ConnectionEvent) {
+    let proto = proto_name(event.proto);
+    match event.kind {
+        ConnectionEventKind::Opened => info!(
+            target: CONNECTION_EVENTS_TARGET,
+            proto,
+            client = format_client(&event.client_key),
+            gateway = format_backend(&event.backend_key),
+            backend = format_backend(&event.backend),
+            timestamp = event.timestamp,
+            "connection opened"
+        ),
+        ConnectionEventKind::Closed => info!(
+            target: CONNECTION_EVENTS_TARGET,
+            proto,
+            client = format_client(&event.client_key),
+            gateway = format_backend(&event.backend_key),
+            backend = format_backend(&event.backend),
+            reason = ?event.reason,
+            timestamp = event.timestamp,
+            "connection closed"
+        ),
+    }
+}
+
+/// Returns the name of an IP protocol number of the connections.
+pub(crate) fn proto_name(proto: u8) -> &'static str {
+    match proto {
+        IPPROTO_TCP => "tcp",
+        IPPROTO_UDP => "udp",
+        IPPROTO_SCTP => "sctp",
+        _ => "unknown",
+    }
+}
+
+fn format_client(client_key: &ClientKey) -> String {
+    format_addr(client_key.ip, client_key.port)
+}
+
+pub(crate) fn format_backend(backend_key: &BackendKey) -> String {
+    format_addr(backend_key.ip, backend_key.port)
+}
+
+fn format_addr(ip: [u32; 4], port: u32) -> String {
+    SocketAddr::new(words_to_ip(ip), port as u16).to_string()
+}
@@ -0,0 +1,156 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+use aya::maps::{HashMap, MapData, PerCpuArray};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use common::{ClientKey, FlowTableStats, LoadBalancerMapping};
+
+/// How often the rates of insertions and removals of the flow table are
+/// sampled.
+const RATE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The health of LB_CONNECTIONS, the flow table of the TCP connections, whose
+/// least recently used entries are evicted once it is full. The counts start
+/// when the API server does.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FlowTableInfo {
+    pub entries: u64,
+    pub capacity: u64,
+    pub inserts: u64,
+    pub insert_failures: u64,
+    pub removals: u64,
+    pub evictions: u64,
+    pub insert_rate: f64,
+    pub removal_rate: f64,
+}
+
+/// Keeps track of the flow table. The datapath counts its own insertions and
+/// removals in FLOW_TABLE_STATS, those made by userspace are counted here.
+/// The evictions aren't seen by anyone: they are the entries which were
+/// inserted, never removed, and aren't in the table anymore. The estimate is
+/// thrown off by the removals racing with the datapath, which are rare.
+pub struct FlowTable {
+    tcp_conns_map: Arc<Mutex<HashMap<MapData, ClientKey, LoadBalancerMapping>>>,
+    flow_table_stats_map: Mutex<PerCpuArray<MapData, FlowTableStats>>,
+    // The entries the table holds, see --conntrack-capacity.
+    capacity: u32,
+    // The entries inserted and removed by userspace. Those found in the table
+    // at startup are counted as inserted.
+    user_inserts: AtomicU64,
+    user_removals: AtomicU64,
+    // The rates of insertions and removals per second over the last sampling
+    // interval.
+    rates: Mutex<(f64, f64)>,
+}
+
+impl FlowTable {
+    pub async fn new(
+        tcp_conns_map: Arc<Mutex<HashMap<MapData, ClientKey, LoadBalancerMapping>>>,
+        flow_table_stats_map: PerCpuArray<MapData, FlowTableStats>,
+        capacity: u32,
+    ) -> Result<FlowTable, Error> {
+        let entries = entries(&*tcp_conns_map.lock().await)?;
+        Ok(FlowTable {
+            tcp_conns_map,
+            flow_table_stats_map: Mutex::new(flow_table_stats_map),
+            capacity,
+            user_inserts: AtomicU64::new(entries),
+            user_removals: AtomicU64::new(0),
+            rates: Mutex::new((0.0, 0.0)),
+        })
+    }
+
+    /// Records that userspace inserted `count` entries into the flow table.
+    pub fn record_inserts(&self, count: u64) {
+        self.user_inserts.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Records that userspace removed `count` entries from the flow table.
+    pub fn record_removals(&self, count: u64) {
+        self.user_removals.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Returns the health of the flow table.
+    pub async fn info(&self) -> Result<FlowTableInfo, Error> {
+        let (inserts, insert_failures, removals) = self.counts().await?;
+        let entries = entries(&*self.tcp_conns_map.lock().await)?;
+        let (insert_rate, removal_rate) = *self.rates.lock().await;
+        Ok(FlowTableInfo {
+            entries,
+            capacity: self.capacity as u64,
+            inserts,
+            insert_failures,
+            removals,
+            evictions: inserts.saturating_sub(removals).saturating_sub(entries),
+            insert_rate,
+            removal_rate,
+        })
+    }
+
+    /// Returns the insertions, failed insertions and removals of the datapath
+    /// and of userspace.
+    async fn counts(&self) -> Result<(u64, u64, u64), Error> {
+        let stats = self
+            .flow_table_stats_map
+            .lock()
+            .await
+            .get(&0, 0)?
+            .iter()
+            .fold(FlowTableStats::default(), |total, stats| FlowTableStats {
+                inserts: total.inserts + stats.inserts,
+                insert_failures: total.insert_failures + stats.insert_failures,
+                removals: total.removals + stats.removals,
+            });
+        Ok((
+            stats.inserts + self.user_inserts.load(Ordering::Relaxed),
+            stats.insert_failures,
+            stats.removals + self.user_removals.load(Ordering::Relaxed),
+        ))
+    }
+}
+
+/// Periodically samples the insertions and removals of the flow table to
+/// derive their rates. Runs forever.
+pub async fn sample_rates(flow_table: Arc<FlowTable>) {
+    let mut interval = tokio::time::interval(RATE_INTERVAL);
+    let mut previous: Option<(Instant, u64, u64)> = None;
+    loop {
+        interval.tick().await;
+        let (inserts, _, removals) = match flow_table.counts().await {
+            Ok(counts) => counts,
+            Err(err) => {
+                warn!(error = %err, "failed to read the counts of the flow table");
+                continue;
+            }
+        };
+        let now = Instant::now();
+        if let Some((then, previous_inserts, previous_removals)) = previous {
+            let secs = now.duration_since(then).as_secs_f64();
+            *flow_table.rates.lock().await = (
+                inserts.saturating_sub(previous_inserts) as f64 / secs,
+                removals.saturating_sub(previous_removals) as f64 / secs,
+            );
+        }
+        previous = Some((now, inserts, removals));
+    }
+}
+
+/// Returns the number of entries of the flow table.
+fn entries(tcp_conns_map: &HashMap<MapData, ClientKey, LoadBalancerMapping>) -> Result<u64, Error> {
+    let mut entries = 0;
+    for key in tcp_conns_map.keys() {
+        key?;
+        entries += 1;
+    }
+    Ok(entries)
+}
@@ -0,0 +1,137 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::Error;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::timeout;
+
+use crate::backends::{HealthCheck, HealthCheckProtocol};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_UNHEALTHY_THRESHOLD: u32 = 3;
+const DEFAULT_HEALTHY_THRESHOLD: u32 = 2;
+
+/// How the targets of a Gateway are checked.
+#[derive(Clone, Copy, Debug)]
+pub struct HealthCheckConfig {
+    pub protocol: HealthCheckProtocol,
+    /// The port the targets are checked on instead of their own.
+    pub port: Option<u16>,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub unhealthy_threshold: u32,
+    pub healthy_threshold: u32,
+}
+
+impl HealthCheckConfig {
+    /// Returns the configuration of an API message.
+    pub fn from_message(health_check: &HealthCheck) -> Result<HealthCheckConfig, Error> {
+        let protocol = HealthCheckProtocol::try_from(health_check.protocol).map_err(|_| {
+            Error::msg(format!(
+                "unknown health check protocol {}",
+                health_check.protocol
+            ))
+        })?;
+        let port = match health_check.port {
+            Some(port) => Some(
+                u16::try_from(port)
+                    .map_err(|_| Error::msg(format!("invalid health check port {}", port)))?,
+            ),
+            None => None,
+        };
+        let seconds = |value: Option<u32>, default: Duration| {
+            value.map_or(default, |value| Duration::from_secs(value.max(1).into()))
+        };
+        Ok(HealthCheckConfig {
+            protocol,
+            port,
+            interval: seconds(health_check.interval, DEFAULT_INTERVAL),
+            timeout: seconds(health_check.timeout, DEFAULT_TIMEOUT),
+            unhealthy_threshold: health_check
+                .unhealthy_threshold
+                .unwrap_or(DEFAULT_UNHEALTHY_THRESHOLD)
+                .max(1),
+            healthy_threshold: health_check
+                .healthy_threshold
+                .unwrap_or(DEFAULT_HEALTHY_THRESHOLD)
+                .max(1),
+        })
+    }
+}
+
+/// Checks the target once, and returns whether it passed the check.
+pub async fn check(addr: SocketAddr, config: &HealthCheckConfig) -> bool {
+    match config.protocol {
+        HealthCheckProtocol::Tcp => matches!(
+            timeout(config.timeout, TcpStream::connect(addr)).await,
+            Ok(Ok(_))
+        ),
+        HealthCheckProtocol::Udp => check_udp(addr, config.timeout).await.unwrap_or(false),
+    }
+}
+
+// UDP has no handshake, so a target passes the check unless the datagram is
+// answered with an ICMP port unreachable message, which the connected socket
+// reports as a refused connection.
+async fn check_udp(addr: SocketAddr, check_timeout: Duration) -> Result<bool, io::Error> {
+    let local_addr: IpAddr = match addr {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(local_addr, 0)).await?;
+    socket.connect(addr).await?;
+    socket.send(&[]).await?;
+
+    let mut buf = [0; 1];
+    match timeout(check_timeout, socket.recv(&mut buf)).await {
+        Ok(Err(err)) if err.kind() == io::ErrorKind::ConnectionRefused => Ok(false),
+        Ok(Err(err)) => Err(err),
+        Ok(Ok(_)) | Err(_) => Ok(true),
+    }
+}
+
+/// The recent results of the checks of a target.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TargetHealth {
+    pub unhealthy: bool,
+    // The number of consecutive checks with the opposite result of the current
+    // state, which flips once it reaches the threshold.
+    streak: u32,
+}
+
+impl TargetHealth {
+    pub fn new(unhealthy: bool) -> TargetHealth {
+        TargetHealth {
+            unhealthy,
+            streak: 0,
+        }
+    }
+
+    /// Records the result of a check, and returns true if the target's state
+    /// changed.
+    pub fn record(&mut self, passed: bool, config: &HealthCheckConfig) -> bool {
+        if passed != self.unhealthy {
+            self.streak = 0;
+            return false;
+        }
+        self.streak += 1;
+        let threshold = match self.unhealthy {
+            true => config.healthy_threshold,
+            false => config.unhealthy_threshold,
+        };
+        if self.streak < threshold {
+            return false;
+        }
+        self.unhealthy = !self.unhealthy;
+        self.streak = 0;
+        true
+    }
+}
@@ -0,0 +1,438 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Error;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::stats::ktime_to_unix_ms;
+use common::{is_ipv4_mapped, CloseReason, ConnectionEvent, ConnectionEventKind};
+
+/// How many ended flows are buffered while the records are being sent.
+pub const FLOWS_CAPACITY: usize = 4096;
+
+/// The version number of IPFIX in the header of the messages.
+/// Ref: https://datatracker.ietf.org/doc/html/rfc7011#section-3.1
+const IPFIX_VERSION: u16 = 10;
+/// The ID of the sets of templates.
+const TEMPLATE_SET_ID: u16 = 2;
+/// The IDs of the templates of the records of the IPv4 flows, and of the IPv6
+/// flows, including those translated to IPv4 backends.
+const IPV4_TEMPLATE_ID: u16 = 256;
+const IPV6_TEMPLATE_ID: u16 = 257;
+/// The enterprise number of the Information Elements counting the reverse
+/// direction of the flows, which are the backend's replies.
+/// Ref: https://datatracker.ietf.org/doc/html/rfc5103#section-6.1
+const REVERSE_PEN: u32 = 29305;
+/// How often the templates are sent again, for the collectors which missed
+/// them or were restarted since.
+/// Ref: https://datatracker.ietf.org/doc/html/rfc7011#section-8.4
+const TEMPLATE_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+/// The most records a message carries, which keeps the messages with IPv6
+/// records and templates under an MTU of 1500 bytes.
+const MESSAGE_MAX_RECORDS: usize = 12;
+
+/// The Information Elements of the records, by ID and length. The addresses
+/// are those of the flows' families.
+/// Ref: https://www.iana.org/assignments/ipfix/ipfix.xhtml
+const SOURCE_IPV4_ADDRESS: (u16, u16) = (8, 4);
+const DESTINATION_IPV4_ADDRESS: (u16, u16) = (12, 4);
+const POST_NAT_DESTINATION_IPV4_ADDRESS: (u16, u16) = (226, 4);
+const SOURCE_IPV6_ADDRESS: (u16, u16) = (27, 16);
+const DESTINATION_IPV6_ADDRESS: (u16, u16) = (28, 16);
+const POST_NAT_DESTINATION_IPV6_ADDRESS: (u16, u16) = (282, 16);
+/// The fields of the records which follow the addresses, in order.
+const FLOW_FIELDS: [(u16, u16); 9] = [
+    // sourceTransportPort, destinationTransportPort, protocolIdentifier,
+    // postNAPTDestinationTransportPort
+    (7, 2),
+    (11, 2),
+    (4, 1),
+    (228, 2),
+    // flowStartMilliseconds, flowEndMilliseconds
+    (152, 8),
+    (153, 8),
+    // packetDeltaCount, octetDeltaCount
+    (2, 8),
+    (1, 8),
+    // flowEndReason
+    (136, 1),
+];
+/// The reverse packetDeltaCount and octetDeltaCount, which end the records.
+const REVERSE_FIELDS: [(u16, u16); 2] = [(2, 8), (1, 8)];
+
+/// The reasons of the end of the flows.
+/// Ref: https://www.iana.org/assignments/ipfix/ipfix.xhtml#ipfix-flow-end-reason
+const IDLE_TIMEOUT: u8 = 0x01;
+const END_OF_FLOW_DETECTED: u8 = 0x03;
+const FORCED_END: u8 = 0x04;
+
+/// Exports the flows which ended as IPFIX records sent over UDP to the
+/// collector, in messages of up to MESSAGE_MAX_RECORDS records. The records
+/// carry the client, the Gateway and the backend of each flow, when it started
+/// and ended, and the packets and bytes of each direction, those of the
+/// backend as reverse Information Elements. The flows are received as the
+/// connection events which closed them, the other events are ignored. Runs
+/// until the events stop coming.
+pub async fn export_flows(
+    collector: SocketAddr,
+    mut events: mpsc::Receiver<ConnectionEvent>,
+) -> Result<(), Error> {
+    let local: SocketAddr = match collector {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(collector).await?;
+
+    let mut sequence: u32 = 0;
+    let mut templates_sent: Option<Instant> = None;
+    let mut batch = Vec::new();
+    while let Some(event) = events.recv().await {
+        batch.push(event);
+        while batch.len() < MESSAGE_MAX_RECORDS {
+            match events.try_recv() {
+                Ok(event) => batch.push(event),
+                Err(_) => break,
+            }
+        }
+        batch.retain(|event| event.kind == ConnectionEventKind::Closed);
+        if batch.is_empty() {
+            continue;
+        }
+
+        let with_templates =
+            templates_sent.is_none_or(|sent| sent.elapsed() >= TEMPLATE_REFRESH_INTERVAL);
+        let message = match encode_message(&batch, sequence, with_templates) {
+            Ok(message) => message,
+            Err(err) => {
+                warn!(flows = batch.len(), error = %err, "failed to encode flow records");
+                batch.clear();
+                continue;
+            }
+        };
+        // The sequence number counts the records sent before, whether or not
+        // they reached the collector.
+        sequence = sequence.wrapping_add(batch.len() as u32);
+        batch.clear();
+        match socket.send(&message).await {
+            Ok(_) if with_templates => templates_sent = Some(Instant::now()),
+            Ok(_) => {}
+            Err(err) => warn!(%collector, error = %err, "failed to send flow records"),
+        }
+    }
+    Ok(())
+}
+
+/// Returns the message carrying the records of the flows, and the templates
+/// first if `with_templates`.
+fn encode_message(
+    events: &[ConnectionEvent],
+    sequence: u32,
+    with_templates: bool,
+) -> Result<Vec<u8>, Error> {
+    let export_time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32;
+    let mut message = Vec::new();
+    message.extend_from_slice(&IPFIX_VERSION.to_be_bytes());
+    // The length is filled in once the sets are written.
+    message.extend_from_slice(&0_u16.to_be_bytes());
+    message.extend_from_slice(&export_time.to_be_bytes());
+    message.extend_from_slice(&sequence.to_be_bytes());
+    // Observation Domain ID
+    message.extend_from_slice(&0_u32.to_be_bytes());
+
+    if with_templates {
+        let set = start_set(&mut message, TEMPLATE_SET_ID);
+        encode_template(&mut message, IPV4_TEMPLATE_ID, false);
+        encode_template(&mut message, IPV6_TEMPLATE_ID, true);
+        end_set(&mut message, set);
+    }
+    for (template_id, ipv6) in [(IPV4_TEMPLATE_ID, false), (IPV6_TEMPLATE_ID, true)] {
+        let records: Vec<_> = events
+            .iter()
+            .filter(|event| is_ipv6_flow(event) == ipv6)
+            .collect();
+        if records.is_empty() {
+            continue;
+        }
+        let set = start_set(&mut message, template_id);
+        for event in records {
+            encode_record(&mut message, event, ipv6)?;
+        }
+        end_set(&mut message, set);
+    }
+
+    let len = message.len() as u16;
+    message[2..4].copy_from_slice(&len.to_be_bytes());
+    Ok(message)
+}
+
+/// Writes the template of the records of the IPv4 flows, or of the IPv6 ones.
+fn encode_template(message: &mut Vec<u8>, template_id: u16, ipv6: bool) {
+    let addresses = match ipv6 {
+        false => [
+            SOURCE_IPV4_ADDRESS,
+            DESTINATION_IPV4_ADDRESS,
+            POST_NAT_DESTINATION_IPV4_ADDRESS,
+        ],
+        true => [
+            SOURCE_IPV6_ADDRESS,
+            DESTINATION_IPV6_ADDRESS,
+            POST_NAT_DESTINATION_IPV6_ADDRESS,
+        ],
+    };
+    let field_count = addresses.len() + FLOW_FIELDS.len() + REVERSE_FIELDS.len();
+    message.extend_from_slice(&template_id.to_be_bytes());
+    message.extend_from_slice(&(field_count as u16).to_be_bytes());
+    for (id, len) in addresses.into_iter().chain(FLOW_FIELDS) {
+        message.extend_from_slice(&id.to_be_bytes());
+        message.extend_from_slice(&len.to_be_bytes());
+    }
+    for (id, len) in REVERSE_FIELDS {
+        // The enterprise bit announces the enterprise number which follows.
+        message.extend_from_slice(&(id | 0x8000).to_be_bytes());
+        message.extend_from_slice(&len.to_be_bytes());
+        message.extend_from_slice(&REVERSE_PEN.to_be_bytes());
+    }
+}
+
+/// Writes the record of the flow the connection event closed, in the order of
+/// the fields of its template.
+fn encode_record(message: &mut Vec<u8>, event: &ConnectionEvent, ipv6: bool) -> Result<(), Error> {
+    for ip in [event.client_key.ip, event.backend_key.ip, event.backend.ip] {
+        match ipv6 {
+            false => message.extend_from_slice(&ip[3].to_be_bytes()),
+            true => {
+                for word in ip {
+                    message.extend_from_slice(&word.to_be_bytes());
+                }
+            }
+        }
+    }
+    let stats = &event.stats;
+    message.extend_from_slice(&(event.client_key.port as u16).to_be_bytes());
+    message.extend_from_slice(&(event.backend_key.port as u16).to_be_bytes());
+    message.push(event.proto);
+    message.extend_from_slice(&(event.backend.port as u16).to_be_bytes());
+    message.extend_from_slice(&ktime_to_unix_ms(stats.opened)?.to_be_bytes());
+    message.extend_from_slice(&ktime_to_unix_ms(event.timestamp)?.to_be_bytes());
+    message.extend_from_slice(&stats.packets.to_be_bytes());
+    message.extend_from_slice(&stats.bytes.to_be_bytes());
+    message.push(match event.reason {
+        CloseReason::Fin | CloseReason::Rst => END_OF_FLOW_DETECTED,
+        CloseReason::Timeout => IDLE_TIMEOUT,
+        CloseReason::None | CloseReason::Replaced => FORCED_END,
+    });
+    message.extend_from_slice(&stats.reply_packets.to_be_bytes());
+    message.extend_from_slice(&stats.reply_bytes.to_be_bytes());
+    Ok(())
+}
+
+/// Returns whether the flow is recorded with the IPv6 template, which is the
+/// case of the IPv6 clients whatever the family of their backend.
+fn is_ipv6_flow(event: &ConnectionEvent) -> bool {
+    !is_ipv4_mapped(&event.client_key.ip) || !is_ipv4_mapped(&event.backend.ip)
+}
+
+/// Writes the header of a set, and returns where it starts.
+fn start_set(message: &mut Vec<u8>, set_id: u16) -> usize {
+    let start = message.len();
+    message.extend_from_slice(&set_id.to_be_bytes());
+    // The length is filled in by end_set.
+    message.extend_from_slice(&0_u16.to_be_bytes());
+    start
+}
+
+/// Fills in the length of the set which starts at `start`.
+fn end_set(message: &mut [u8], start: usize) {
+    let len = (message.len() - start) as u16;
+    message[start + 2..start + 4].copy_from_slice(&len.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use common::{BackendKey, ClientKey, FlowStats};
+
+    use super::*;
+    use crate::conntrack::monotonic_now_ns;
+    use crate::netutils::ip_to_words;
+
+    /// The length of the records of the IPv4 flows, and of the IPv6 ones.
+    const IPV4_RECORD_LEN: usize = 3 * 4 + 40 + 16;
+    const IPV6_RECORD_LEN: usize = 3 * 16 + 40 + 16;
+
+    fn event(client: IpAddr, vip: IpAddr, backend: IpAddr, reason: CloseReason) -> ConnectionEvent {
+        let now = monotonic_now_ns().unwrap();
+        ConnectionEvent {
+            timestamp: now,
+            client_key: ClientKey {
+                ip: ip_to_words(client),
+                port: 40000,
+            },
+            backend_key: BackendKey {
+                ip: ip_to_words(vip),
+                port: 80,
+            },
+            backend: BackendKey {
+                ip: ip_to_words(backend),
+                port: 8080,
+            },
+            proto: 6,
+            kind: ConnectionEventKind::Closed,
+            reason,
+            stats: FlowStats {
+                opened: now - 1_000_000_000,
+                packets: 10,
+                bytes: 1000,
+                reply_packets: 8,
+                reply_bytes: 4000,
+            },
+        }
+    }
+
+    fn ipv4_event(reason: CloseReason) -> ConnectionEvent {
+        event(
+            "192.0.2.1".parse().unwrap(),
+            "198.51.100.1".parse().unwrap(),
+            "10.0.0.1".parse().unwrap(),
+            reason,
+        )
+    }
+
+    fn u16_at(message: &[u8], offset: usize) -> u16 {
+        u16::from_be_bytes(message[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u64_at(message: &[u8], offset: usize) -> u64 {
+        u64::from_be_bytes(message[offset..offset + 8].try_into().unwrap())
+    }
+
+    /// Returns the IDs and the contents of the sets of the message.
+    fn sets(message: &[u8]) -> Vec<(u16, &[u8])> {
+        let mut sets = Vec::new();
+        let mut offset = 16;
+        while offset < message.len() {
+            let len = u16_at(message, offset + 2) as usize;
+            sets.push((u16_at(message, offset), &message[offset + 4..offset + len]));
+            offset += len;
+        }
+        assert_eq!(offset, message.len());
+        sets
+    }
+
+    #[test]
+    fn header_carries_the_length_and_the_sequence() {
+        let message = encode_message(&[ipv4_event(CloseReason::Fin)], 42, true).unwrap();
+        assert_eq!(u16_at(&message, 0), IPFIX_VERSION);
+        assert_eq!(u16_at(&message, 2) as usize, message.len());
+        assert_eq!(message[8..12], 42_u32.to_be_bytes());
+        assert_eq!(message[12..16], 0_u32.to_be_bytes());
+    }
+
+    #[test]
+    fn templates_announce_every_field() {
+        let message = encode_message(&[], 0, true).unwrap();
+        let sets = sets(&message);
+        assert_eq!(sets.len(), 1);
+        let (set_id, templates) = sets[0];
+        assert_eq!(set_id, TEMPLATE_SET_ID);
+        // The 3 addresses and the flow fields take 4 bytes each, the reverse
+        // fields 8 with their enterprise number.
+        let template_len = 4 + 12 * 4 + 2 * 8;
+        assert_eq!(templates.len(), 2 * template_len);
+        for (template, (template_id, address_len)) in templates
+            .chunks(template_len)
+            .zip([(IPV4_TEMPLATE_ID, 4), (IPV6_TEMPLATE_ID, 16)])
+        {
+            assert_eq!(u16_at(template, 0), template_id);
+            assert_eq!(u16_at(template, 2), 14);
+            let field_lens: u16 = (0..12).map(|field| u16_at(template, 6 + 4 * field)).sum();
+            assert_eq!(field_lens, 3 * address_len + 40);
+            let reverse = &template[4 + 12 * 4..];
+            assert_eq!(u16_at(reverse, 0), 2 | 0x8000);
+            assert_eq!(reverse[4..8], REVERSE_PEN.to_be_bytes());
+            assert_eq!(u16_at(reverse, 8), 1 | 0x8000);
+        }
+    }
+
+    #[test]
+    fn templates_are_only_sent_when_asked() {
+        let message = encode_message(&[ipv4_event(CloseReason::Fin)], 0, false).unwrap();
+        let set_ids: Vec<_> = sets(&message).into_iter().map(|(id, _)| id).collect();
+        assert_eq!(set_ids, [IPV4_TEMPLATE_ID]);
+    }
+
+    #[test]
+    fn ipv4_records_follow_their_template() {
+        let message = encode_message(&[ipv4_event(CloseReason::Fin)], 0, false).unwrap();
+        let (_, record) = sets(&message)[0];
+        assert_eq!(record.len(), IPV4_RECORD_LEN);
+        assert_eq!(record[0..4], [192, 0, 2, 1]);
+        assert_eq!(record[4..8], [198, 51, 100, 1]);
+        assert_eq!(record[8..12], [10, 0, 0, 1]);
+        assert_eq!(u16_at(record, 12), 40000);
+        assert_eq!(u16_at(record, 14), 80);
+        assert_eq!(record[16], 6);
+        assert_eq!(u16_at(record, 17), 8080);
+        let (start, end) = (u64_at(record, 19), u64_at(record, 27));
+        // The flow lasted a second, give or take the rounding to milliseconds.
+        assert!((999..1100).contains(&(end - start)));
+        assert_eq!(u64_at(record, 35), 10);
+        assert_eq!(u64_at(record, 43), 1000);
+        assert_eq!(record[51], END_OF_FLOW_DETECTED);
+        assert_eq!(u64_at(record, 52), 8);
+        assert_eq!(u64_at(record, 60), 4000);
+    }
+
+    #[test]
+    fn close_reasons_are_flow_end_reasons() {
+        for (reason, flow_end_reason) in [
+            (CloseReason::Fin, END_OF_FLOW_DETECTED),
+            (CloseReason::Rst, END_OF_FLOW_DETECTED),
+            (CloseReason::Timeout, IDLE_TIMEOUT),
+            (CloseReason::Replaced, FORCED_END),
+        ] {
+            let message = encode_message(&[ipv4_event(reason)], 0, false).unwrap();
+            let (_, record) = sets(&message)[0];
+            assert_eq!(record[51], flow_end_reason, "{:?}", reason);
+        }
+    }
+
+    #[test]
+    fn flows_of_ipv6_clients_use_the_ipv6_template() {
+        let client: IpAddr = "2001:db8::1".parse().unwrap();
+        let ipv6 = event(
+            client,
+            "2001:db8::100".parse().unwrap(),
+            "10.0.0.1".parse().unwrap(),
+            CloseReason::Timeout,
+        );
+        let events = [ipv4_event(CloseReason::Fin), ipv6];
+        let message = encode_message(&events, 0, false).unwrap();
+        let sets = sets(&message);
+        assert_eq!(sets.len(), 2);
+        assert_eq!(sets[0].0, IPV4_TEMPLATE_ID);
+        assert_eq!(sets[0].1.len(), IPV4_RECORD_LEN);
+        let (set_id, record) = sets[1];
+        assert_eq!(set_id, IPV6_TEMPLATE_ID);
+        assert_eq!(record.len(), IPV6_RECORD_LEN);
+        let IpAddr::V6(client) = client else {
+            unreachable!()
+        };
+        assert_eq!(record[0..16], client.octets());
+        // The IPv4 backend is written in its IPv4-mapped form.
+        assert_eq!(
+            record[32..48],
+            Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped().octets()
+        );
+    }
+}
@@ -4,34 +4,380 @@ Copyright 2023 The Kubernetes Authors.
 SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
 */
 
+// tonic::Status is the error of every RPC, the helpers returning it are not
+// worth boxing it for.
+#![allow(clippy::result_large_err)]
+
+pub mod announce;
+pub mod auth;
 pub mod backends;
+pub mod capture;
+pub mod conntrack;
+pub mod events;
+pub mod flowtable;
+pub mod health;
+pub mod ipfix;
+pub mod maglev;
+pub mod metrics;
 pub mod netutils;
+pub mod peers;
+pub mod probes;
+pub mod samples;
 pub mod server;
+pub mod settings;
+pub mod stats;
+pub mod telemetry;
+pub mod tls;
 
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::fs;
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Error;
-use aya::maps::{HashMap, MapData};
+use anyhow::{Context, Error};
+use aya::maps::{Array, HashMap, LpmTrie, MapData, PerCpuArray, PerCpuHashMap, RingBuf};
+use tokio::sync::{broadcast, mpsc, Mutex};
 use tonic::transport::Server;
+use tracing::error;
 
 use backends::backends_server::BackendsServer;
-use common::{BackendKey, BackendList, ClientKey, LoadBalancerMapping};
+use common::{
+    AclAction, AclKey, BackendConnections, BackendFailures, BackendKey, BackendList,
+    BackendTraffic, ClientKey, Config, ConnectionLimit, FlowTableStats, GatewayIndex,
+    GatewaySlotKey, LoadBalancerMapping, LogLevel, MaglevTable, Mirror, PortRangeList, SnatKey,
+    SynLatency, TcpTimeouts, Tunnel, UdpLoadBalancerMapping,
+};
+
+/// The BPF maps shared between the eBPF programs and the API server.
+pub struct BpfMaps {
+    pub config: Array<MapData, Config>,
+    pub backends: HashMap<MapData, BackendKey, BackendList>,
+    pub port_ranges: HashMap<MapData, [u32; 4], PortRangeList>,
+    pub gateway_aliases: HashMap<MapData, BackendKey, BackendKey>,
+    pub vip_addresses: HashMap<MapData, [u32; 4], u32>,
+    pub gateway_indexes: HashMap<MapData, GatewaySlotKey, GatewayIndex>,
+    pub tcp_conns: HashMap<MapData, ClientKey, LoadBalancerMapping>,
+    pub udp_conns: HashMap<MapData, ClientKey, UdpLoadBalancerMapping>,
+    pub sctp_conns: HashMap<MapData, ClientKey, UdpLoadBalancerMapping>,
+    pub maglev_tables: HashMap<MapData, GatewaySlotKey, MaglevTable>,
+    pub backend_conns: PerCpuHashMap<MapData, BackendKey, BackendConnections>,
+    pub backend_traffic: PerCpuHashMap<MapData, BackendKey, BackendTraffic>,
+    pub syn_latencies: PerCpuHashMap<MapData, BackendKey, SynLatency>,
+    pub backend_failures: HashMap<MapData, BackendKey, BackendFailures>,
+    pub connection_limits: HashMap<MapData, BackendKey, ConnectionLimit>,
+    pub acls: LpmTrie<MapData, AclKey, AclAction>,
+    pub syn_cookies: HashMap<MapData, BackendKey, u32>,
+    pub dscp_marks: HashMap<MapData, BackendKey, u8>,
+    pub mirrors: HashMap<MapData, BackendKey, Mirror>,
+    pub tproxy_ports: HashMap<MapData, BackendKey, u16>,
+    pub ping_gateways: HashMap<MapData, [u32; 4], BackendKey>,
+    pub stateless_targets: HashMap<MapData, BackendKey, BackendKey>,
+    pub tunnels: HashMap<MapData, [u32; 4], Tunnel>,
+    pub log_level: Array<MapData, LogLevel>,
+    pub tcp_timeouts: Array<MapData, TcpTimeouts>,
+    pub limited_conns: PerCpuHashMap<MapData, BackendKey, u64>,
+    pub released_conns: HashMap<MapData, BackendKey, u64>,
+    pub snat_conns: HashMap<MapData, SnatKey, ClientKey>,
+    pub client_conns: HashMap<MapData, [u32; 4], u32>,
+    pub redirect_errors: PerCpuArray<MapData, u64>,
+    pub tcp_drops: PerCpuArray<MapData, u64>,
+    pub tcp_passes: PerCpuArray<MapData, u64>,
+    pub flow_table_stats: PerCpuArray<MapData, FlowTableStats>,
+    pub connection_events: RingBuf<MapData>,
+    pub packet_samples: RingBuf<MapData>,
+    pub captures: HashMap<MapData, BackendKey, u8>,
+    pub captured_packets: RingBuf<MapData>,
+}
+
+/// The BPF maps the API server and the tasks it runs share, each behind a lock
+/// of its own.
+#[derive(Clone)]
+pub struct SharedMaps {
+    pub backends: Arc<Mutex<HashMap<MapData, BackendKey, BackendList>>>,
+    pub port_ranges: Arc<Mutex<HashMap<MapData, [u32; 4], PortRangeList>>>,
+    pub gateway_aliases: Arc<Mutex<HashMap<MapData, BackendKey, BackendKey>>>,
+    pub vip_addresses: Arc<Mutex<HashMap<MapData, [u32; 4], u32>>>,
+    pub gateway_indexes: Arc<Mutex<HashMap<MapData, GatewaySlotKey, GatewayIndex>>>,
+    pub tcp_conns: Arc<Mutex<HashMap<MapData, ClientKey, LoadBalancerMapping>>>,
+    pub udp_conns: Arc<Mutex<HashMap<MapData, ClientKey, UdpLoadBalancerMapping>>>,
+    pub sctp_conns: Arc<Mutex<HashMap<MapData, ClientKey, UdpLoadBalancerMapping>>>,
+    pub maglev_tables: Arc<Mutex<HashMap<MapData, GatewaySlotKey, MaglevTable>>>,
+    pub backend_conns: Arc<Mutex<PerCpuHashMap<MapData, BackendKey, BackendConnections>>>,
+    pub backend_traffic: Arc<Mutex<PerCpuHashMap<MapData, BackendKey, BackendTraffic>>>,
+    pub backend_failures: Arc<Mutex<HashMap<MapData, BackendKey, BackendFailures>>>,
+    pub connection_limits: Arc<Mutex<HashMap<MapData, BackendKey, ConnectionLimit>>>,
+    pub acls: Arc<Mutex<LpmTrie<MapData, AclKey, AclAction>>>,
+    pub syn_cookies: Arc<Mutex<HashMap<MapData, BackendKey, u32>>>,
+    pub dscp_marks: Arc<Mutex<HashMap<MapData, BackendKey, u8>>>,
+    pub mirrors: Arc<Mutex<HashMap<MapData, BackendKey, Mirror>>>,
+    pub tproxy_ports: Arc<Mutex<HashMap<MapData, BackendKey, u16>>>,
+    pub ping_gateways: Arc<Mutex<HashMap<MapData, [u32; 4], BackendKey>>>,
+    pub stateless_targets: Arc<Mutex<HashMap<MapData, BackendKey, BackendKey>>>,
+    pub tunnels: Arc<Mutex<HashMap<MapData, [u32; 4], Tunnel>>>,
+    pub log_level: Arc<Mutex<Array<MapData, LogLevel>>>,
+    pub tcp_timeouts: Arc<Mutex<Array<MapData, TcpTimeouts>>>,
+    pub limited_conns: Arc<Mutex<PerCpuHashMap<MapData, BackendKey, u64>>>,
+    pub released_conns: Arc<Mutex<HashMap<MapData, BackendKey, u64>>>,
+    pub snat_conns: Arc<Mutex<HashMap<MapData, SnatKey, ClientKey>>>,
+    pub client_conns: Arc<Mutex<HashMap<MapData, [u32; 4], u32>>>,
+}
+
+/// The settings of the API server and of the servers and tasks it runs
+/// alongside.
+pub struct ServerOptions {
+    /// The address the API, metrics and health servers listen on.
+    pub addr: Ipv4Addr,
+    /// The port of the API.
+    pub port: u16,
+    pub udp_idle_timeout: Duration,
+    pub sctp_idle_timeout: Duration,
+    pub metrics_port: Option<u16>,
+    pub health_port: Option<u16>,
+    /// The names of the eBPF programs the readiness probe checks.
+    pub programs: Vec<String>,
+    /// The entries each of the connection tracking maps holds.
+    pub conntrack_capacity: u32,
+    /// The API addresses of the dataplanes the connections are replicated to.
+    pub sync_peers: Vec<String>,
+    /// The file holding the bearer token the peers authorize the replicated
+    /// connections with.
+    pub sync_token_file: Option<PathBuf>,
+    /// The directory the pcap files of the packet captures are written in,
+    /// without which the captures are only streamed to the clients.
+    pub capture_dir: Option<PathBuf>,
+    /// The interface the IPv4 addresses of the Gateways are announced on.
+    pub announce_iface: Option<String>,
+    pub ipfix_collector: Option<SocketAddr>,
+    pub otlp_endpoint: Option<String>,
+    pub tls: Option<tls::TlsConfig>,
+    pub auth: Option<auth::AuthConfig>,
+}
 
 pub async fn start(
-    addr: Ipv4Addr,
-    port: u16,
-    backends_map: HashMap<MapData, BackendKey, BackendList>,
-    gateway_indexes_map: HashMap<MapData, BackendKey, u16>,
-    tcp_conns_map: HashMap<MapData, ClientKey, LoadBalancerMapping>,
+    options: ServerOptions,
+    maps: BpfMaps,
+    settings: mpsc::Receiver<settings::DatapathSettings>,
+    shutdown: impl Future<Output = ()>,
 ) -> Result<(), Error> {
+    let ServerOptions {
+        addr,
+        port,
+        udp_idle_timeout,
+        sctp_idle_timeout,
+        metrics_port,
+        health_port,
+        programs,
+        conntrack_capacity,
+        sync_peers,
+        sync_token_file,
+        capture_dir,
+        announce_iface,
+        ipfix_collector,
+        otlp_endpoint,
+        tls,
+        auth,
+    } = options;
+    if let Some(otlp_endpoint) = otlp_endpoint {
+        telemetry::init(&otlp_endpoint)?;
+    }
     let (_, health_service) = tonic_health::server::health_reporter();
 
-    let server = server::BackendService::new(backends_map, gateway_indexes_map, tcp_conns_map);
-    // TODO: mTLS https://github.com/Kong/blixt/issues/50
-    Server::builder()
+    let shared = SharedMaps {
+        backends: Arc::new(Mutex::new(maps.backends)),
+        port_ranges: Arc::new(Mutex::new(maps.port_ranges)),
+        gateway_aliases: Arc::new(Mutex::new(maps.gateway_aliases)),
+        vip_addresses: Arc::new(Mutex::new(maps.vip_addresses)),
+        gateway_indexes: Arc::new(Mutex::new(maps.gateway_indexes)),
+        tcp_conns: Arc::new(Mutex::new(maps.tcp_conns)),
+        udp_conns: Arc::new(Mutex::new(maps.udp_conns)),
+        sctp_conns: Arc::new(Mutex::new(maps.sctp_conns)),
+        maglev_tables: Arc::new(Mutex::new(maps.maglev_tables)),
+        backend_conns: Arc::new(Mutex::new(maps.backend_conns)),
+        backend_traffic: Arc::new(Mutex::new(maps.backend_traffic)),
+        backend_failures: Arc::new(Mutex::new(maps.backend_failures)),
+        connection_limits: Arc::new(Mutex::new(maps.connection_limits)),
+        acls: Arc::new(Mutex::new(maps.acls)),
+        syn_cookies: Arc::new(Mutex::new(maps.syn_cookies)),
+        dscp_marks: Arc::new(Mutex::new(maps.dscp_marks)),
+        mirrors: Arc::new(Mutex::new(maps.mirrors)),
+        tproxy_ports: Arc::new(Mutex::new(maps.tproxy_ports)),
+        ping_gateways: Arc::new(Mutex::new(maps.ping_gateways)),
+        stateless_targets: Arc::new(Mutex::new(maps.stateless_targets)),
+        tunnels: Arc::new(Mutex::new(maps.tunnels)),
+        log_level: Arc::new(Mutex::new(maps.log_level)),
+        tcp_timeouts: Arc::new(Mutex::new(maps.tcp_timeouts)),
+        limited_conns: Arc::new(Mutex::new(maps.limited_conns)),
+        released_conns: Arc::new(Mutex::new(maps.released_conns)),
+        snat_conns: Arc::new(Mutex::new(maps.snat_conns)),
+        client_conns: Arc::new(Mutex::new(maps.client_conns)),
+    };
+    tokio::spawn(settings::apply_settings(
+        settings,
+        maps.config,
+        shared.log_level.clone(),
+        shared.tcp_timeouts.clone(),
+    ));
+    let flow_table = Arc::new(
+        flowtable::FlowTable::new(
+            shared.tcp_conns.clone(),
+            maps.flow_table_stats,
+            conntrack_capacity,
+        )
+        .await?,
+    );
+    tokio::spawn(flowtable::sample_rates(flow_table.clone()));
+    let exporter = match ipfix_collector {
+        None => None,
+        Some(collector) => {
+            let (sender, receiver) = mpsc::channel(ipfix::FLOWS_CAPACITY);
+            tokio::spawn(async move {
+                if let Err(err) = ipfix::export_flows(collector, receiver).await {
+                    error!(%collector, error = %err, "failed to export flows");
+                }
+            });
+            Some(sender)
+        }
+    };
+    tokio::spawn(conntrack::expire_tcp_conns(
+        shared.clone(),
+        flow_table.clone(),
+        exporter.clone(),
+        udp_idle_timeout,
+    ));
+    tokio::spawn(conntrack::expire_udp_conns(
+        shared.udp_conns.clone(),
+        shared.released_conns.clone(),
+        exporter.clone(),
+        udp_idle_timeout,
+        events::IPPROTO_UDP,
+        "UDP flows",
+    ));
+    tokio::spawn(conntrack::expire_udp_conns(
+        shared.sctp_conns.clone(),
+        shared.released_conns.clone(),
+        exporter.clone(),
+        sctp_idle_timeout,
+        events::IPPROTO_SCTP,
+        "SCTP associations",
+    ));
+
+    if let Some(metrics_port) = metrics_port {
+        let metrics = metrics::Metrics {
+            tcp_conns_map: shared.tcp_conns.clone(),
+            udp_conns_map: shared.udp_conns.clone(),
+            snat_conns_map: shared.snat_conns.clone(),
+            backend_conns_map: shared.backend_conns.clone(),
+            released_conns_map: shared.released_conns.clone(),
+            backend_traffic_map: shared.backend_traffic.clone(),
+            limited_conns_map: shared.limited_conns.clone(),
+            syn_latencies_map: maps.syn_latencies,
+            redirect_errors_map: maps.redirect_errors,
+            tcp_drops_map: maps.tcp_drops,
+            tcp_passes_map: maps.tcp_passes,
+            flow_table: flow_table.clone(),
+            conntrack_capacity,
+        };
+        let metrics_addr = SocketAddrV4::new(addr, metrics_port).into();
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(metrics_addr, metrics).await {
+                error!(error = %err, "metrics server failed");
+            }
+        });
+    }
+
+    if let Some(health_port) = health_port {
+        let probes = probes::Probes {
+            programs,
+            vip_addresses_map: shared.vip_addresses.clone(),
+            tcp_conns_map: shared.tcp_conns.clone(),
+        };
+        let health_addr = SocketAddrV4::new(addr, health_port).into();
+        tokio::spawn(async move {
+            if let Err(err) = probes::serve(health_addr, probes).await {
+                error!(error = %err, "health server failed");
+            }
+        });
+    }
+
+    if let Some(announce_iface) = announce_iface {
+        let vip_addresses_map = shared.vip_addresses.clone();
+        tokio::spawn(async move {
+            if let Err(err) = announce::announce_vips(announce_iface, vip_addresses_map).await {
+                error!(error = %err, "failed to announce the gateways");
+            }
+        });
+    }
+
+    if let Some(capture_dir) = &capture_dir {
+        fs::create_dir_all(capture_dir)
+            .with_context(|| format!("failed to create {}", capture_dir.display()))?;
+    }
+    let captures = Arc::new(capture::Captures::new(maps.captures));
+    let captured_packets = maps.captured_packets;
+    let dispatched_captures = captures.clone();
+    tokio::spawn(async move {
+        if let Err(err) =
+            capture::dispatch_captured_packets(captured_packets, dispatched_captures).await
+        {
+            error!(error = %err, "failed to read captured packets");
+        }
+    });
+
+    let (watchers, _) = broadcast::channel(events::WATCHERS_CAPACITY);
+    let validator = auth.as_ref().map(|auth| auth.validator()).transpose()?;
+    let server = server::BackendService::new(
+        shared,
+        flow_table,
+        captures,
+        capture_dir,
+        watchers.clone(),
+        validator.is_some(),
+    );
+
+    let replication = match sync_peers.is_empty() {
+        true => None,
+        false => {
+            let (sender, receiver) = mpsc::channel(peers::EVENTS_CAPACITY);
+            let peer_tls = tls.as_ref().map(|tls| tls.client_config()).transpose()?;
+            tokio::spawn(peers::replicate_connections(
+                server.clone(),
+                sync_peers,
+                peer_tls,
+                sync_token_file,
+                receiver,
+            ));
+            Some(sender)
+        }
+    };
+    let connection_events = maps.connection_events;
+    tokio::spawn(async move {
+        if let Err(err) =
+            events::log_connection_events(connection_events, watchers, replication, exporter).await
+        {
+            error!(error = %err, "failed to read connection events");
+        }
+    });
+    let packet_samples = maps.packet_samples;
+    tokio::spawn(async move {
+        if let Err(err) = samples::log_packet_samples(packet_samples).await {
+            error!(error = %err, "failed to read packet samples");
+        }
+    });
+
+    let mut builder = Server::builder();
+    if let Some(tls) = &tls {
+        builder = builder.tls_config(tls.server_config()?)?;
+    }
+    builder
         .add_service(health_service)
-        .add_service(BackendsServer::new(server))
-        .serve(SocketAddrV4::new(addr, port).into())
+        .add_service(BackendsServer::with_interceptor(
+            server,
+            auth::Authenticator::new(validator),
+        ))
+        // The calls in flight are finished once shutdown resolves, the new ones refused.
+        .serve_with_shutdown(SocketAddrV4::new(addr, port).into(), shutdown)
         .await?;
     Ok(())
 }
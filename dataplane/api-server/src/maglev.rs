@@ -0,0 +1,228 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use common::{Backend, MaglevTable, MAGLEV_TABLE_SIZE};
+
+const EMPTY_ENTRY: u16 = u16::MAX;
+
+/// Populates the Maglev lookup table of a Gateway's backends, as described in
+/// section 3.4 of https://research.google/pubs/pub44824/. Each backend walks
+/// its own permutation of the table, derived from its address and port, so
+/// that adding or removing a backend only moves a small share of the entries.
+///
+/// Backends get as many turns per round as their weight, once reduced by the
+/// weights' greatest common divisor. Backends being drained or ejected by the
+/// health checks get none. Returns None if no backend accepts new connections.
+pub fn maglev_table(backends: &[Backend]) -> Option<MaglevTable> {
+    let size = MAGLEV_TABLE_SIZE as u64;
+    let divisor = backends
+        .iter()
+        .filter(|backend| backend.accepts_new_connections())
+        .map(|backend| backend.weight)
+        .reduce(gcd)?;
+
+    let mut permutations: Vec<Permutation> = backends
+        .iter()
+        .enumerate()
+        .filter(|(_, backend)| backend.accepts_new_connections())
+        .map(|(index, backend)| Permutation {
+            index: index as u16,
+            turns: backend.weight / divisor,
+            offset: hash(backend, 0) % size,
+            skip: hash(backend, 1) % (size - 1) + 1,
+            next: 0,
+        })
+        .collect();
+
+    let mut table = MaglevTable {
+        entries: [EMPTY_ENTRY; MAGLEV_TABLE_SIZE],
+    };
+    let mut filled = 0;
+    loop {
+        for permutation in permutations.iter_mut() {
+            for _ in 0..permutation.turns {
+                let mut entry = permutation.next_entry(size);
+                while table.entries[entry] != EMPTY_ENTRY {
+                    entry = permutation.next_entry(size);
+                }
+                table.entries[entry] = permutation.index;
+
+                filled += 1;
+                if filled == MAGLEV_TABLE_SIZE {
+                    return Some(table);
+                }
+            }
+        }
+    }
+}
+
+struct Permutation {
+    index: u16,
+    turns: u16,
+    offset: u64,
+    skip: u64,
+    next: u64,
+}
+
+impl Permutation {
+    fn next_entry(&mut self, size: u64) -> usize {
+        let entry = (self.offset + self.next * self.skip) % size;
+        self.next += 1;
+        entry as usize
+    }
+}
+
+// FNV-1a hash of a backend's address and port. The hash has to be stable
+// across restarts and nodes, which rules out std's randomly seeded hasher.
+fn hash(backend: &Backend, seed: u8) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut write = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    };
+    write(&[seed]);
+    for word in backend.daddr {
+        write(&word.to_be_bytes());
+    }
+    write(&backend.dport.to_be_bytes());
+    hash
+}
+
+fn gcd(a: u16, b: u16) -> u16 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+    use crate::netutils::ip_to_words;
+
+    fn backend(host: u8, weight: u16) -> Backend {
+        Backend {
+            daddr: ip_to_words(IpAddr::V4(Ipv4Addr::new(10, 0, 0, host))),
+            dport: 8080,
+            weight,
+            ..Default::default()
+        }
+    }
+
+    // Returns the number of entries of the table of each backend, by index.
+    fn shares(table: &MaglevTable, backends: usize) -> Vec<usize> {
+        let mut shares = vec![0; backends];
+        for entry in table.entries {
+            shares[entry as usize] += 1;
+        }
+        shares
+    }
+
+    #[test]
+    fn gcd_of_weights() {
+        assert_eq!(gcd(12, 18), 6);
+        assert_eq!(gcd(18, 12), 6);
+        assert_eq!(gcd(7, 5), 1);
+        assert_eq!(gcd(7, 0), 7);
+        assert_eq!(gcd(0, 7), 7);
+    }
+
+    #[test]
+    fn permutations_visit_every_entry_once() {
+        let size = MAGLEV_TABLE_SIZE as u64;
+        for host in 1..=8 {
+            let backend = backend(host, 1);
+            let mut permutation = Permutation {
+                index: 0,
+                turns: 1,
+                offset: hash(&backend, 0) % size,
+                skip: hash(&backend, 1) % (size - 1) + 1,
+                next: 0,
+            };
+            let mut visited = vec![false; MAGLEV_TABLE_SIZE];
+            for _ in 0..MAGLEV_TABLE_SIZE {
+                let entry = permutation.next_entry(size);
+                assert!(!visited[entry], "entry {} visited twice", entry);
+                visited[entry] = true;
+            }
+        }
+    }
+
+    #[test]
+    fn table_is_filled_evenly() {
+        let backends: Vec<Backend> = (1..=3).map(|host| backend(host, 1)).collect();
+
+        let table = maglev_table(&backends).unwrap();
+
+        // The backends take turns, until the table is full.
+        let shares = shares(&table, backends.len());
+        assert_eq!(shares.iter().sum::<usize>(), MAGLEV_TABLE_SIZE);
+        assert!(shares.iter().max().unwrap() - shares.iter().min().unwrap() <= 1);
+    }
+
+    #[test]
+    fn table_is_filled_by_weight() {
+        let backends = [backend(1, 3), backend(2, 1)];
+
+        let table = maglev_table(&backends).unwrap();
+
+        let shares = shares(&table, backends.len());
+        assert_eq!(shares[0] + shares[1], MAGLEV_TABLE_SIZE);
+        assert!(shares[0].abs_diff(3 * shares[1]) <= 3);
+    }
+
+    #[test]
+    fn weights_are_reduced_by_their_gcd() {
+        let reduced = maglev_table(&[backend(1, 2), backend(2, 1)]).unwrap();
+        let scaled = maglev_table(&[backend(1, 200), backend(2, 100)]).unwrap();
+
+        assert_eq!(reduced.entries, scaled.entries);
+    }
+
+    #[test]
+    fn backends_not_accepting_connections_get_no_entries() {
+        let mut drained = backend(2, 1);
+        drained.drain = true;
+        let mut unhealthy = backend(3, 1);
+        unhealthy.unhealthy = true;
+        let backends = [backend(1, 1), drained, unhealthy, backend(4, 0)];
+
+        let table = maglev_table(&backends).unwrap();
+
+        assert_eq!(shares(&table, backends.len()), [MAGLEV_TABLE_SIZE, 0, 0, 0]);
+    }
+
+    #[test]
+    fn no_table_without_backends_accepting_connections() {
+        let mut drained = backend(1, 1);
+        drained.drain = true;
+
+        assert!(maglev_table(&[]).is_none());
+        assert!(maglev_table(&[drained, backend(2, 0)]).is_none());
+    }
+
+    #[test]
+    fn removing_a_backend_moves_few_other_entries() {
+        let backends: Vec<Backend> = (1..=5).map(|host| backend(host, 1)).collect();
+        let before = maglev_table(&backends).unwrap();
+        let after = maglev_table(&backends[..4]).unwrap();
+
+        // The entries of the removed backend go to the others, which mostly
+        // keep their own.
+        let moved = before
+            .entries
+            .iter()
+            .zip(after.entries.iter())
+            .filter(|(before, after)| **before != 4 && before != after)
+            .count();
+        assert!(moved < MAGLEV_TABLE_SIZE / 20, "{} entries moved", moved);
+    }
+}
@@ -0,0 +1,397 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::convert::Infallible;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Error;
+use aya::maps::{HashMap, MapData, PerCpuArray, PerCpuHashMap};
+use aya::Pod;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Request, Response, Server, StatusCode};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::conntrack::live_connections;
+use crate::flowtable::FlowTable;
+use crate::netutils::words_to_ip;
+use crate::stats::{backend_traffic, syn_latencies};
+use common::{
+    BackendConnections, BackendKey, BackendTraffic, ClientKey, DropReason, LoadBalancerMapping,
+    PassReason, SnatKey, SynLatency, UdpLoadBalancerMapping, DROP_REASONS, PASS_REASONS,
+    SYN_LATENCY_BUCKETS, SYN_LATENCY_FIRST_BUCKET_NS,
+};
+
+/// The labels of the reasons the TCP packets are dropped or let through to the
+/// host for.
+const DROP_REASON_LABELS: [(DropReason, &str); DROP_REASONS as usize] = [
+    (DropReason::Denied, "denied"),
+    (DropReason::InvalidSynCookie, "invalid_syn_cookie"),
+    (DropReason::Untracked, "untracked"),
+    (DropReason::RateLimited, "rate_limited"),
+    (DropReason::ClientCap, "client_cap"),
+    (DropReason::ConnectionLimit, "connection_limit"),
+    (DropReason::NoNat64Address, "no_nat64_address"),
+    (DropReason::NoSnatPort, "no_snat_port"),
+];
+const PASS_REASON_LABELS: [(PassReason, &str); PASS_REASONS as usize] = [
+    (PassReason::SnatReply, "snat_reply"),
+    (PassReason::Untracked, "untracked"),
+    (PassReason::NoBackend, "no_backend"),
+    (PassReason::NotGateway, "not_gateway"),
+    (PassReason::OutOfBounds, "out_of_bounds"),
+    (PassReason::Error, "error"),
+];
+
+// A counter of the traffic of the backends: its name, its help and its value.
+type TrafficCounter = (&'static str, &'static str, fn(&BackendTraffic) -> u64);
+
+/// The maps the metrics are derived from.
+pub struct Metrics {
+    pub tcp_conns_map: Arc<Mutex<HashMap<MapData, ClientKey, LoadBalancerMapping>>>,
+    pub udp_conns_map: Arc<Mutex<HashMap<MapData, ClientKey, UdpLoadBalancerMapping>>>,
+    pub snat_conns_map: Arc<Mutex<HashMap<MapData, SnatKey, ClientKey>>>,
+    pub backend_conns_map: Arc<Mutex<PerCpuHashMap<MapData, BackendKey, BackendConnections>>>,
+    pub released_conns_map: Arc<Mutex<HashMap<MapData, BackendKey, u64>>>,
+    pub backend_traffic_map: Arc<Mutex<PerCpuHashMap<MapData, BackendKey, BackendTraffic>>>,
+    pub limited_conns_map: Arc<Mutex<PerCpuHashMap<MapData, BackendKey, u64>>>,
+    pub syn_latencies_map: PerCpuHashMap<MapData, BackendKey, SynLatency>,
+    pub redirect_errors_map: PerCpuArray<MapData, u64>,
+    pub tcp_drops_map: PerCpuArray<MapData, u64>,
+    pub tcp_passes_map: PerCpuArray<MapData, u64>,
+    pub flow_table: Arc<FlowTable>,
+    /// The entries each of the connection tracking maps holds.
+    pub conntrack_capacity: u32,
+}
+
+impl Metrics {
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub async fn render(&self) -> Result<String, Error> {
+        let mut out = String::new();
+
+        let live = {
+            let backend_conns_map = self.backend_conns_map.lock().await;
+            let released_conns_map = self.released_conns_map.lock().await;
+            live_connections(&backend_conns_map, &released_conns_map)?
+        };
+        write_header(
+            &mut out,
+            "blixt_backend_active_connections",
+            "gauge",
+            "Live connections of the backend.",
+        );
+        for (key, live) in live {
+            write_backend_sample(&mut out, "blixt_backend_active_connections", &key, live);
+        }
+
+        let mut opened = Vec::new();
+        for item in self.backend_conns_map.lock().await.iter() {
+            let (key, per_cpu_counters) = item?;
+            opened.push((key, per_cpu_counters.iter().map(|c| c.opened).sum::<u64>()));
+        }
+        write_header(
+            &mut out,
+            "blixt_backend_connections_total",
+            "counter",
+            "Connections assigned to the backend.",
+        );
+        for (key, opened) in opened {
+            write_backend_sample(&mut out, "blixt_backend_connections_total", &key, opened);
+        }
+
+        let traffic = backend_traffic(&*self.backend_traffic_map.lock().await)?;
+        let counters: [TrafficCounter; 4] = [
+            (
+                "blixt_backend_forwarded_packets_total",
+                "Packets forwarded to the backend.",
+                |t| t.forwarded_packets,
+            ),
+            (
+                "blixt_backend_forwarded_bytes_total",
+                "Bytes forwarded to the backend.",
+                |t| t.forwarded_bytes,
+            ),
+            (
+                "blixt_backend_reply_packets_total",
+                "Reply packets of the backend.",
+                |t| t.reply_packets,
+            ),
+            (
+                "blixt_backend_reply_bytes_total",
+                "Reply bytes of the backend.",
+                |t| t.reply_bytes,
+            ),
+        ];
+        for (name, help, value) in counters {
+            write_header(&mut out, name, "counter", help);
+            for (key, traffic) in &traffic {
+                write_backend_sample(&mut out, name, key, value(traffic));
+            }
+        }
+
+        write_header(
+            &mut out,
+            "blixt_backend_syn_latency_seconds",
+            "histogram",
+            "Time the backend took to answer the SYN of a new connection.",
+        );
+        for (key, latency) in syn_latencies(&self.syn_latencies_map)? {
+            write_latency_histogram(
+                &mut out,
+                "blixt_backend_syn_latency_seconds",
+                &key,
+                &latency,
+            );
+        }
+
+        let mut limited = Vec::new();
+        for item in self.limited_conns_map.lock().await.iter() {
+            let (key, per_cpu_counters) = item?;
+            limited.push((key, per_cpu_counters.iter().sum::<u64>()));
+        }
+        write_header(
+            &mut out,
+            "blixt_gateway_limited_connections_total",
+            "counter",
+            "New connections rejected for being over the connection limit of the VIP.",
+        );
+        for (key, limited) in limited {
+            let _ = writeln!(
+                out,
+                "blixt_gateway_limited_connections_total{{vip_ip=\"{}\",vip_port=\"{}\"}} {}",
+                words_to_ip(key.ip),
+                key.port,
+                limited
+            );
+        }
+
+        write_header(
+            &mut out,
+            "blixt_conntrack_entries",
+            "gauge",
+            "Entries of the connection tracking maps.",
+        );
+        let tcp = entries(&*self.tcp_conns_map.lock().await)?;
+        let udp = entries(&*self.udp_conns_map.lock().await)?;
+        let snat = entries(&*self.snat_conns_map.lock().await)?;
+        for (map, entries) in [("tcp", tcp), ("udp", udp), ("snat", snat)] {
+            let _ = writeln!(
+                out,
+                "blixt_conntrack_entries{{map=\"{}\"}} {}",
+                map, entries
+            );
+        }
+        write_header(
+            &mut out,
+            "blixt_conntrack_capacity",
+            "gauge",
+            "Capacity of the connection tracking maps.",
+        );
+        for (map, capacity) in [
+            ("tcp", self.conntrack_capacity),
+            ("udp", self.conntrack_capacity),
+            ("snat", self.conntrack_capacity),
+        ] {
+            let _ = writeln!(
+                out,
+                "blixt_conntrack_capacity{{map=\"{}\"}} {}",
+                map, capacity
+            );
+        }
+
+        let redirect_errors: u64 = self.redirect_errors_map.get(&0, 0)?.iter().sum();
+        write_header(
+            &mut out,
+            "blixt_redirect_errors_total",
+            "counter",
+            "Packets which couldn't be redirected.",
+        );
+        let _ = writeln!(out, "blixt_redirect_errors_total {}", redirect_errors);
+
+        write_header(
+            &mut out,
+            "blixt_tcp_drops_total",
+            "counter",
+            "TCP packets dropped by the ingress program, by reason.",
+        );
+        for (reason, label) in DROP_REASON_LABELS {
+            let drops: u64 = self.tcp_drops_map.get(&(reason as u32), 0)?.iter().sum();
+            let _ = writeln!(
+                out,
+                "blixt_tcp_drops_total{{reason=\"{}\"}} {}",
+                label, drops
+            );
+        }
+        write_header(
+            &mut out,
+            "blixt_tcp_passes_total",
+            "counter",
+            "TCP packets let through to the host by the ingress program, by reason.",
+        );
+        for (reason, label) in PASS_REASON_LABELS {
+            let passes: u64 = self.tcp_passes_map.get(&(reason as u32), 0)?.iter().sum();
+            let _ = writeln!(
+                out,
+                "blixt_tcp_passes_total{{reason=\"{}\"}} {}",
+                label, passes
+            );
+        }
+
+        let flow_table = self.flow_table.info().await?;
+        let samples = [
+            (
+                "blixt_flow_table_occupancy_ratio",
+                "gauge",
+                "Share of the capacity of the TCP connection tracking map in use.",
+                flow_table.entries as f64 / flow_table.capacity as f64,
+            ),
+            (
+                "blixt_flow_table_inserts_total",
+                "counter",
+                "Entries inserted into the TCP connection tracking map.",
+                flow_table.inserts as f64,
+            ),
+            (
+                "blixt_flow_table_insert_failures_total",
+                "counter",
+                "New connections which couldn't be inserted into the TCP connection tracking map.",
+                flow_table.insert_failures as f64,
+            ),
+            (
+                "blixt_flow_table_removals_total",
+                "counter",
+                "Entries removed from the TCP connection tracking map.",
+                flow_table.removals as f64,
+            ),
+            (
+                "blixt_flow_table_evictions_total",
+                "counter",
+                "Entries evicted from the full TCP connection tracking map, estimated.",
+                flow_table.evictions as f64,
+            ),
+            (
+                "blixt_flow_table_insert_rate",
+                "gauge",
+                "Entries inserted into the TCP connection tracking map per second.",
+                flow_table.insert_rate,
+            ),
+            (
+                "blixt_flow_table_removal_rate",
+                "gauge",
+                "Entries removed from the TCP connection tracking map per second.",
+                flow_table.removal_rate,
+            ),
+        ];
+        for (name, kind, help, value) in samples {
+            write_header(&mut out, name, kind, help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        Ok(out)
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        if req.uri().path() != "/metrics" {
+            return response(StatusCode::NOT_FOUND, Body::empty());
+        }
+        match self.render().await {
+            Ok(metrics) => {
+                let mut response = response(StatusCode::OK, metrics.into());
+                response.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    header::HeaderValue::from_static("text/plain; version=0.0.4"),
+                );
+                response
+            }
+            Err(err) => {
+                warn!(error = %err, "failed to render metrics");
+                response(StatusCode::INTERNAL_SERVER_ERROR, err.to_string().into())
+            }
+        }
+    }
+}
+
+/// Serves the metrics on `/metrics` over HTTP.
+pub async fn serve(addr: SocketAddr, metrics: Metrics) -> Result<(), Error> {
+    let metrics = Arc::new(metrics);
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let metrics = metrics.clone();
+                async move { Ok::<_, Infallible>(metrics.handle(req).await) }
+            }))
+        }
+    });
+    Server::bind(&addr).serve(make_service).await?;
+    Ok(())
+}
+
+fn response(status: StatusCode, body: Body) -> Response<Body> {
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn write_backend_sample(out: &mut String, name: &str, key: &BackendKey, value: u64) {
+    let _ = writeln!(
+        out,
+        "{}{{backend_ip=\"{}\",backend_port=\"{}\"}} {}",
+        name,
+        words_to_ip(key.ip),
+        key.port,
+        value
+    );
+}
+
+/// Writes the buckets, sum and count of the latency histogram of a backend.
+fn write_latency_histogram(out: &mut String, name: &str, key: &BackendKey, latency: &SynLatency) {
+    let ip = words_to_ip(key.ip);
+    let mut count = 0;
+    for (bucket, bucket_count) in latency.buckets.iter().enumerate() {
+        count += bucket_count;
+        let le = if bucket == SYN_LATENCY_BUCKETS - 1 {
+            "+Inf".to_string()
+        } else {
+            ((SYN_LATENCY_FIRST_BUCKET_NS << bucket) as f64 / 1e9).to_string()
+        };
+        let _ = writeln!(
+            out,
+            "{}_bucket{{backend_ip=\"{}\",backend_port=\"{}\",le=\"{}\"}} {}",
+            name, ip, key.port, le, count
+        );
+    }
+    let _ = writeln!(
+        out,
+        "{}_sum{{backend_ip=\"{}\",backend_port=\"{}\"}} {}",
+        name,
+        ip,
+        key.port,
+        latency.sum_ns as f64 / 1e9
+    );
+    let _ = writeln!(
+        out,
+        "{}_count{{backend_ip=\"{}\",backend_port=\"{}\"}} {}",
+        name, ip, key.port, count
+    );
+}
+
+/// Returns the number of entries of the map.
+fn entries<K: Pod, V: Pod>(map: &HashMap<MapData, K, V>) -> Result<usize, Error> {
+    let mut entries = 0;
+    for key in map.keys() {
+        key?;
+        entries += 1;
+    }
+    Ok(entries)
+}
@@ -5,13 +5,48 @@ SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
 */
 
 use anyhow::Error;
+use common::ipv4_mapped;
 use libc::if_nametoindex as libc_if_nametoindex;
 use regex::Regex;
-use std::ffi::CString;
-use std::net::Ipv4Addr;
+use std::collections::HashMap;
+use std::ffi::{c_char, CStr, CString};
+use std::io::Write;
+use std::net::{IpAddr, Ipv6Addr};
 use std::process::{Command, Stdio};
 use std::str::from_utf8;
 
+/// Returns the representation of an IP address used in the BPF maps, which
+/// stores IPv4 addresses in their IPv4-mapped IPv6 form.
+pub fn ip_to_words(ip_addr: IpAddr) -> [u32; 4] {
+    match ip_addr {
+        IpAddr::V4(ip) => ipv4_mapped(ip.into()),
+        IpAddr::V6(ip) => {
+            let ip = u128::from(ip);
+            [
+                (ip >> 96) as u32,
+                (ip >> 64) as u32,
+                (ip >> 32) as u32,
+                ip as u32,
+            ]
+        }
+    }
+}
+
+/// Returns the IP address represented by the words of the BPF maps, see
+/// ip_to_words.
+pub fn words_to_ip(words: [u32; 4]) -> IpAddr {
+    let ip = Ipv6Addr::from(
+        (words[0] as u128) << 96
+            | (words[1] as u128) << 64
+            | (words[2] as u128) << 32
+            | words[3] as u128,
+    );
+    match ip.to_ipv4_mapped() {
+        Some(ip) => IpAddr::V4(ip),
+        None => IpAddr::V6(ip),
+    }
+}
+
 /// Returns an ifindex for a provided ifname. Wraps libc.
 pub fn if_nametoindex(ifname: String) -> Result<u32, Error> {
     let ifname_c = CString::new(ifname)?;
@@ -19,12 +54,45 @@ pub fn if_nametoindex(ifname: String) -> Result<u32, Error> {
     Ok(ifindex)
 }
 
-/// Given an IPv4 address will return the local system's network interface
+/// Returns whether the network interface with the provided ifindex is a veth,
+/// as the host side of the interfaces of the pods on the node are. Not
+/// portable: only works on Linux systems with iproute2 installed.
+pub fn is_veth(ifindex: u32) -> Result<bool, Error> {
+    let mut buf = [0 as c_char; libc::IF_NAMESIZE];
+    let ifname = unsafe { libc::if_indextoname(ifindex, buf.as_mut_ptr()) };
+    if ifname.is_null() {
+        return Err(Error::msg(format!(
+            "no device found with ifindex {}",
+            ifindex
+        )));
+    }
+    let ifname = unsafe { CStr::from_ptr(ifname) }.to_str()?;
+
+    // run the linux command "ip -details link show" to get the kind of the
+    // device.
+    let mut cmd = Command::new("ip");
+    let child = cmd
+        .arg("-details")
+        .arg("link")
+        .arg("show")
+        .arg("dev")
+        .arg(ifname)
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let output = child.wait_with_output()?;
+    let stdout = from_utf8(output.stdout.as_slice())?;
+
+    // the kind of the device starts one of the lines of details.
+    let re = Regex::new(r"(?m)^\s+veth\b")?;
+    Ok(re.is_match(stdout))
+}
+
+/// Given an IP address will return the local system's network interface
 /// which is responsible for routing that address. Not portable: only works on
 /// Linux systems with iproute2 installed.
 ///
 /// TODO: replace this https://github.com/Kong/blixt/issues/49
-pub fn if_name_for_routing_ip(ip_addr: Ipv4Addr) -> Result<String, Error> {
+pub fn if_name_for_routing_ip(ip_addr: IpAddr) -> Result<String, Error> {
     // run the linux command "ip route" to get the device responsible for
     // routing the given IP address.
     let ip = ip_addr.to_string();
@@ -41,9 +109,63 @@ pub fn if_name_for_routing_ip(ip_addr: Ipv4Addr) -> Result<String, Error> {
     let output = child.wait_with_output()?;
     let stdout = from_utf8(output.stdout.as_slice())?;
 
-    // construct a regex to match the output
-    let mut regex_str = ip.clone();
-    regex_str.push_str(r" (via [0-9]+\.[0-9]+\.[0-9]+\.[0-9]+ )?dev ([a-zA-Z0-9]+)\s+");
+    route_device(stdout, ip_addr)
+}
+
+/// Returns the local system's network interfaces which are responsible for
+/// routing each of the IP addresses, like if_name_for_routing_ip but with a
+/// single "ip" command for all of them. Not portable: only works on Linux
+/// systems with iproute2 installed.
+pub fn if_names_for_routing_ips(ip_addrs: &[IpAddr]) -> Result<HashMap<IpAddr, String>, Error> {
+    let mut unique_addrs = ip_addrs.to_vec();
+    unique_addrs.sort();
+    unique_addrs.dedup();
+    if unique_addrs.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    // run one "ip route get" per address in batch mode, carrying on past the
+    // addresses which can't be routed.
+    let mut cmd = Command::new("ip");
+    let mut child = cmd
+        .arg("-force")
+        .arg("-batch")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    // The output of the few hundred addresses of a Gateway fits in the pipe,
+    // so the whole input can be written before the output is read.
+    let mut input = String::new();
+    for ip_addr in &unique_addrs {
+        input.push_str(&format!("route get to {}\n", ip_addr));
+    }
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| Error::msg("no stdin for the ip command"))?;
+    stdin.write_all(input.as_bytes())?;
+    drop(stdin);
+
+    let output = child.wait_with_output()?;
+    let stdout = from_utf8(output.stdout.as_slice())?;
+
+    let mut devices = HashMap::new();
+    for ip_addr in unique_addrs {
+        devices.insert(ip_addr, route_device(stdout, ip_addr)?);
+    }
+    Ok(devices)
+}
+
+/// Returns the network device of the route of the IP address in the output of
+/// "ip route get".
+fn route_device(stdout: &str, ip_addr: IpAddr) -> Result<String, Error> {
+    // construct a regex to match the output, IPv6 routes additionally report
+    // the source they were selected for.
+    let ip = ip_addr.to_string();
+    let mut regex_str = String::from("(?m)^");
+    regex_str.push_str(&regex::escape(&ip));
+    regex_str.push_str(r" (from \S+ )?(via \S+ )?dev ([a-zA-Z0-9]+)\s+");
     let re = Regex::new(&regex_str)?;
 
     // match on the output to find the network device responsible for routing
@@ -0,0 +1,178 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Error};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::{ClientTlsConfig, Endpoint};
+use tonic::Request;
+use tracing::{info, warn};
+
+use crate::backends::backends_client::BackendsClient;
+use crate::backends::ConntrackUpdate;
+use crate::server::BackendService;
+use common::ConnectionEvent;
+
+/// How many connection events are buffered while the updates are being sent.
+pub const EVENTS_CAPACITY: usize = 4096;
+/// How many updates are buffered for a peer which isn't keeping up, past which
+/// the oldest are dropped.
+const PEER_UPDATES_CAPACITY: usize = 1024;
+/// The most connections an update carries, which keeps the messages well
+/// under the default size limit of gRPC.
+const UPDATE_MAX_ENTRIES: usize = 1024;
+/// How long to wait before reconnecting to a peer.
+const PEER_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Streams the connections and UDP flows the datapath reports opening and
+/// closing to the peer dataplanes at the addresses (`host:port` of their API),
+/// for them to replicate. Peers are sent all the tracked connections whenever
+/// the stream to them (re)opens. The peers are connected to over mutual TLS
+/// if `tls` is set, and sent the bearer token in `token_file` if set, which is
+/// read again on every connection as it may be rotated. Runs until the events
+/// stop coming.
+pub async fn replicate_connections(
+    service: BackendService,
+    peers: Vec<String>,
+    tls: Option<ClientTlsConfig>,
+    token_file: Option<PathBuf>,
+    mut events: mpsc::Receiver<ConnectionEvent>,
+) {
+    let (updates, _) = broadcast::channel(PEER_UPDATES_CAPACITY);
+    for peer in peers {
+        tokio::spawn(stream_to_peer(
+            service.clone(),
+            peer,
+            tls.clone(),
+            token_file.clone(),
+            updates.subscribe(),
+        ));
+    }
+
+    let mut batch = Vec::new();
+    while let Some(event) = events.recv().await {
+        batch.push(event);
+        while batch.len() < UPDATE_MAX_ENTRIES {
+            match events.try_recv() {
+                Ok(event) => batch.push(event),
+                Err(_) => break,
+            }
+        }
+        match service.connection_update(&batch).await {
+            // The peers which are disconnected are sent everything again when
+            // they reconnect.
+            Ok(update) => {
+                let _ = updates.send(update);
+            }
+            Err(err) => warn!(error = %err, "failed to read the opened connections"),
+        }
+        batch.clear();
+    }
+}
+
+/// Streams the updates to the peer, reconnecting whenever the stream fails.
+async fn stream_to_peer(
+    service: BackendService,
+    peer: String,
+    tls: Option<ClientTlsConfig>,
+    token_file: Option<PathBuf>,
+    mut updates: broadcast::Receiver<ConntrackUpdate>,
+) {
+    loop {
+        let result = sync_with_peer(
+            &service,
+            &peer,
+            tls.as_ref(),
+            token_file.as_deref(),
+            &mut updates,
+        );
+        match result.await {
+            Ok(()) => return,
+            Err(err) => warn!(%peer, error = %err, "failed to sync the connections with peer"),
+        }
+        tokio::time::sleep(PEER_RETRY_INTERVAL).await;
+    }
+}
+
+/// Sends all the tracked connections to the peer, then the updates as they
+/// come, until there are no more updates or the stream fails.
+async fn sync_with_peer(
+    service: &BackendService,
+    peer: &str,
+    tls: Option<&ClientTlsConfig>,
+    token_file: Option<&Path>,
+    updates: &mut broadcast::Receiver<ConntrackUpdate>,
+) -> Result<(), Error> {
+    let endpoint = match tls {
+        Some(tls) => Endpoint::from_shared(format!("https://{}", peer))?.tls_config(tls.clone())?,
+        None => Endpoint::from_shared(format!("http://{}", peer))?,
+    };
+    let mut client = BackendsClient::new(endpoint.connect().await?);
+    let (sender, receiver) = mpsc::channel(PEER_UPDATES_CAPACITY);
+    let mut request = Request::new(ReceiverStream::new(receiver));
+    if let Some(path) = token_file {
+        let token = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        request
+            .metadata_mut()
+            .insert("authorization", format!("Bearer {}", token.trim()).parse()?);
+    }
+    let call = client.sync_connections(request);
+    tokio::pin!(call);
+    info!(%peer, "syncing the connections with peer");
+
+    // The updates broadcast meanwhile are buffered, the peer skips the
+    // connections it has already.
+    for update in split(service.full_update().await?) {
+        if sender.try_send(update).is_err() {
+            return Err(Error::msg("too many connections to send at once"));
+        }
+    }
+
+    loop {
+        tokio::select! {
+            result = &mut call => {
+                result?;
+                return Err(Error::msg("the peer ended the stream"));
+            }
+            update = updates.recv() => match update {
+                Ok(update) => {
+                    if sender.try_send(update).is_err() {
+                        warn!(%peer, "dropping a connection update, the peer is falling behind");
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(%peer, skipped, "dropped connection updates for peer")
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+        }
+    }
+}
+
+/// Splits an update of the opened connections into updates of at most
+/// UPDATE_MAX_ENTRIES connections each.
+fn split(update: ConntrackUpdate) -> Vec<ConntrackUpdate> {
+    let mut updates = Vec::new();
+    for tcp_opened in update.tcp_opened.chunks(UPDATE_MAX_ENTRIES) {
+        updates.push(ConntrackUpdate {
+            tcp_opened: tcp_opened.to_vec(),
+            ..Default::default()
+        });
+    }
+    for udp_opened in update.udp_opened.chunks(UPDATE_MAX_ENTRIES) {
+        updates.push(ConntrackUpdate {
+            udp_opened: udp_opened.to_vec(),
+            ..Default::default()
+        });
+    }
+    updates
+}
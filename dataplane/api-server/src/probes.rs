@@ -0,0 +1,100 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error};
+use aya::maps::{HashMap, MapData};
+use aya::programs::loaded_programs;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use common::{ClientKey, LoadBalancerMapping};
+
+/// What the liveness and readiness of the dataplane are checked against.
+pub struct Probes {
+    /// The names of the eBPF programs the dataplane attached, which have to be
+    /// loaded in the kernel for the dataplane to be alive.
+    pub programs: Vec<String>,
+    /// Maps which have to be readable for the dataplane to be ready.
+    pub vip_addresses_map: Arc<Mutex<HashMap<MapData, [u32; 4], u32>>>,
+    pub tcp_conns_map: Arc<Mutex<HashMap<MapData, ClientKey, LoadBalancerMapping>>>,
+}
+
+impl Probes {
+    /// Checks that the eBPF programs are still loaded. They are unloaded by
+    /// the kernel once nothing holds them anymore, e.g. when their links were
+    /// dropped, which detaches them from the interfaces.
+    fn check_programs(&self) -> Result<(), Error> {
+        let mut missing: Vec<&str> = self.programs.iter().map(String::as_str).collect();
+        for program in loaded_programs() {
+            let program = program?;
+            if let Some(name) = program.name_as_str() {
+                missing.retain(|missing| *missing != name);
+            }
+        }
+        match missing.is_empty() {
+            true => Ok(()),
+            false => Err(anyhow!("programs not loaded: {}", missing.join(", "))),
+        }
+    }
+
+    /// Checks that the maps the Gateways are programmed in can be read.
+    async fn check_maps(&self) -> Result<(), Error> {
+        if let Some(key) = self.vip_addresses_map.lock().await.keys().next() {
+            key?;
+        }
+        if let Some(key) = self.tcp_conns_map.lock().await.keys().next() {
+            key?;
+        }
+        Ok(())
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let result = match req.uri().path() {
+            "/healthz" => self.check_programs(),
+            "/readyz" => match self.check_programs() {
+                Ok(()) => self.check_maps().await,
+                Err(err) => Err(err),
+            },
+            _ => return response(StatusCode::NOT_FOUND, Body::empty()),
+        };
+        match result {
+            Ok(()) => response(StatusCode::OK, "ok".into()),
+            Err(err) => {
+                warn!(path = req.uri().path(), error = %err, "probe failed");
+                response(StatusCode::SERVICE_UNAVAILABLE, err.to_string().into())
+            }
+        }
+    }
+}
+
+/// Serves the liveness of the dataplane on `/healthz` and its readiness on
+/// `/readyz` over HTTP, for the probes of its pod.
+pub async fn serve(addr: SocketAddr, probes: Probes) -> Result<(), Error> {
+    let probes = Arc::new(probes);
+    let make_service = make_service_fn(move |_| {
+        let probes = probes.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let probes = probes.clone();
+                async move { Ok::<_, Infallible>(probes.handle(req).await) }
+            }))
+        }
+    });
+    Server::bind(&addr).serve(make_service).await?;
+    Ok(())
+}
+
+fn response(status: StatusCode, body: Body) -> Response<Body> {
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response
+}
@@ -0,0 +1,69 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::fmt::Write as _;
+use std::mem;
+use std::ptr;
+
+use anyhow::Error;
+use aya::maps::{MapData, RingBuf};
+use tokio::io::unix::AsyncFd;
+use tracing::{info, warn};
+
+use crate::events::{format_backend, proto_name};
+use common::PacketSample;
+
+/// The log target of the packet samples, so that they can be filtered and
+/// routed apart from the rest of the logs.
+pub const PACKET_SAMPLES_TARGET: &str = "blixt::samples";
+
+/// Reads the packets the ingress program samples in the PACKET_SAMPLES ring
+/// buffer as they come, and logs them with the backend they were forwarded
+/// to. Nothing comes unless the sampling is enabled, see
+/// Config.sample_every. Runs until the ring buffer can't be polled anymore.
+pub async fn log_packet_samples(ring_buf: RingBuf<MapData>) -> Result<(), Error> {
+    let mut ring_buf = AsyncFd::new(ring_buf)?;
+    loop {
+        let mut guard = ring_buf.readable_mut().await?;
+        let ring_buf = guard.get_inner_mut();
+        while let Some(item) = ring_buf.next() {
+            match parse_packet_sample(&item) {
+                Some(sample) => log_packet_sample(&sample),
+                None => warn!(len = item.len(), "dropping a packet sample"),
+            }
+        }
+        guard.clear_ready();
+    }
+}
+
+/// Returns the packet sample of a ring buffer item, if it is the size of one.
+fn parse_packet_sample(item: &[u8]) -> Option<PacketSample> {
+    if item.len() < mem::size_of::<PacketSample>() {
+        return None;
+    }
+    // The items of the ring buffer are only 8-byte aligned.
+    Some(unsafe { ptr::read_unaligned(item.as_ptr() as *const PacketSample) })
+}
+
+fn log_packet_sample(sample: &PacketSample) {
+    let headers_len = (sample.headers_len as usize).min(sample.headers.len());
+    let mut headers = String::with_capacity(headers_len * 2);
+    for byte in &sample.headers[..headers_len] {
+        let _ = write!(headers, "{:02x}", byte);
+    }
+    info!(
+        target: PACKET_SAMPLES_TARGET,
+        proto = proto_name(sample.proto),
+        gateway = format_backend(&sample.backend_key),
+        backend = format_backend(&sample.backend),
+        forwarding = ?sample.forwarding,
+        new_conn = sample.new_conn,
+        len = sample.len,
+        headers,
+        timestamp = sample.timestamp,
+        "packet sampled"
+    );
+}
@@ -4,58 +4,689 @@ Copyright 2023 The Kubernetes Authors.
 SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
 */
 
-use std::net::Ipv4Addr;
+use std::collections::HashMap as StdHashMap;
+use std::error::Error as _;
+use std::io;
+use std::iter;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Error;
-use aya::maps::{HashMap, MapData, MapError};
-use tokio::sync::Mutex;
-use tonic::{Request, Response, Status};
+use aya::maps::lpm_trie::Key;
+use aya::maps::{Array, HashMap, LpmTrie, MapData, MapError, PerCpuHashMap};
+use aya::Pod;
+use opentelemetry::Context;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{debug, info, warn};
 
+use crate::auth::authorize;
+use crate::backends;
 use crate::backends::backends_server::Backends;
-use crate::backends::{Confirmation, InterfaceIndexConfirmation, PodIp, Targets, Vip};
-use crate::netutils::{if_name_for_routing_ip, if_nametoindex};
+use crate::backends::{
+    AclRule, Algorithm, BackendStats, BackendStatsList, Capabilities, CapabilitiesRequest,
+    CaptureRequest, CapturedFrame, Confirmation, Connection, ConnectionChange, ConnectionsFilter,
+    ConntrackEntry, ConntrackSnapshot, ConntrackUpdate, DataplaneInfo, DataplaneInfoRequest,
+    DesiredState, Feature, FlowTableInfo, Gateway, GatewayList, InterfaceIndexConfirmation,
+    ListGatewaysRequest, PodIp, StateAck, Target, Targets, TcpState, Vip,
+};
+use crate::capture::{
+    captured_at_unix_ns, captured_data, Captures, PcapWriter, MAX_CAPTURE_DURATION,
+    MAX_CAPTURE_PACKETS,
+};
+use crate::conntrack::{
+    count_imported_connections, from_bytes, live_connections, monotonic_now_ns,
+    release_client_connection, release_connections, release_snat_port, to_bytes,
+};
+use crate::events::{format_backend, proto_name, IPPROTO_TCP, IPPROTO_UDP};
+use crate::flowtable::FlowTable;
+use crate::health::{check, HealthCheckConfig, TargetHealth};
+use crate::maglev::maglev_table;
+use crate::netutils::{
+    if_name_for_routing_ip, if_names_for_routing_ips, if_nametoindex, ip_to_words, is_veth,
+    words_to_ip,
+};
+use crate::stats::{backend_stats, ktime_to_unix_ms};
+use crate::telemetry::{remote_context, set_vip, trace_programming, trace_step};
+use crate::SharedMaps;
 use common::{
-    Backend, BackendKey, BackendList, ClientKey, LoadBalancerMapping, BACKENDS_ARRAY_CAPACITY,
+    is_ipv4_mapped, AclAction, AclKey, Backend, BackendConnections, BackendFailures, BackendKey,
+    BackendList, BackendTraffic, BalancingAlgorithm, ClientKey, CloseReason, ConnectionEvent,
+    ConnectionEventKind, ConnectionLimit, Encapsulation, ForwardingMode, GatewayIndex,
+    GatewaySlotKey, LimitAction, LoadBalancerMapping, LogLevel, MaglevTable, Mirror, PortRange,
+    PortRangeList, SnatKey, TCPState, TcpTimeouts, Tunnel, UdpLoadBalancerMapping,
+    ACL_VIP_PREFIX_LEN, BACKENDS_ARRAY_CAPACITY, GATEWAY_SLOTS, MAX_SPLIT_GROUPS,
+    PORT_RANGES_CAPACITY, QUIC_MAX_CID_LEN,
 };
 
+// ConnectionSelector selects tracked connections by the VIP they were made to,
+// and the backend they are pinned to. Unset fields match any connection.
+#[derive(Clone, Copy)]
+struct ConnectionSelector {
+    vip: Option<BackendKey>,
+    backend: Option<BackendKey>,
+}
+
+impl ConnectionSelector {
+    // Returns the selector of an API message.
+    fn from_message(filter: ConnectionsFilter) -> Result<ConnectionSelector, Error> {
+        let vip = match filter.vip {
+            Some(vip) => Some(BackendKey {
+                ip: ip_to_words(ip_from_message(vip.ip, vip.ipv6.as_deref())?),
+                port: vip.port,
+            }),
+            None => None,
+        };
+        let backend = match filter.target {
+            Some(target) => Some(BackendKey {
+                ip: ip_to_words(ip_from_message(target.daddr, target.daddr_ipv6.as_deref())?),
+                port: target.dport,
+            }),
+            None => None,
+        };
+        Ok(ConnectionSelector { vip, backend })
+    }
+
+    fn matches(&self, vip: &BackendKey, backend: &Backend) -> bool {
+        self.matches_key(vip, &backend.key())
+    }
+
+    // Same as matches, for a backend known by its address and port only.
+    fn matches_key(&self, vip: &BackendKey, backend: &BackendKey) -> bool {
+        self.vip.is_none_or(|key| key == *vip) && self.backend.is_none_or(|key| key == *backend)
+    }
+}
+
+/// The version of the API reported by GetCapabilities, incremented by the
+/// changes the control planes written for an earlier version can't ignore.
+pub const API_VERSION: u32 = 1;
+
+/// The optional features reported by GetCapabilities.
+const FEATURES: &[Feature] = &[
+    Feature::Ipv6,
+    Feature::Dsr,
+    Feature::SessionAffinity,
+    Feature::QuicAffinity,
+    Feature::ProxyProtocol,
+    Feature::Toa,
+    Feature::Snat,
+    Feature::PortRanges,
+    Feature::TrafficSplit,
+    Feature::Stateless,
+    Feature::HealthChecks,
+    Feature::StateSync,
+    Feature::ConnectionSync,
+    Feature::Acls,
+    Feature::ConnectionLimits,
+    Feature::SynCookies,
+    Feature::DscpMarking,
+    Feature::Mirroring,
+    Feature::Tproxy,
+    Feature::TargetNodes,
+    Feature::PacketCapture,
+    Feature::PingForwarding,
+];
+
+/// The algorithms reported by GetCapabilities.
+const ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RoundRobin,
+    Algorithm::Maglev,
+    Algorithm::LeastConn,
+    Algorithm::PowerOfTwo,
+    Algorithm::Random,
+];
+
+/// How many acknowledgements of the states streamed through Sync are buffered
+/// while the control plane isn't reading them.
+const SYNC_ACKS_CAPACITY: usize = 16;
+
+/// How many captured packets are buffered while the client of CapturePackets
+/// isn't reading them.
+const CAPTURE_FRAMES_CAPACITY: usize = 64;
+
+/// How many connection changes are buffered while the client of
+/// WatchConnections isn't reading them.
+const WATCH_CHANGES_CAPACITY: usize = 256;
+
+/// The UDP ports the nodes of the targets receive the encapsulated packets on
+/// by default: Geneve's IANA port, and the port usually given to GUE.
+const GENEVE_PORT: u16 = 6081;
+const GUE_PORT: u16 = 6080;
+
+/// The largest DSCP, which is 6 bits long.
+const MAX_DSCP: u8 = 63;
+
+#[derive(Clone)]
 pub struct BackendService {
     backends_map: Arc<Mutex<HashMap<MapData, BackendKey, BackendList>>>,
-    gateway_indexes_map: Arc<Mutex<HashMap<MapData, BackendKey, u16>>>,
+    port_ranges_map: Arc<Mutex<HashMap<MapData, [u32; 4], PortRangeList>>>,
+    gateway_aliases_map: Arc<Mutex<HashMap<MapData, BackendKey, BackendKey>>>,
+    vip_addresses_map: Arc<Mutex<HashMap<MapData, [u32; 4], u32>>>,
+    gateway_indexes_map: Arc<Mutex<HashMap<MapData, GatewaySlotKey, GatewayIndex>>>,
     tcp_conns_map: Arc<Mutex<HashMap<MapData, ClientKey, LoadBalancerMapping>>>,
+    udp_conns_map: Arc<Mutex<HashMap<MapData, ClientKey, UdpLoadBalancerMapping>>>,
+    sctp_conns_map: Arc<Mutex<HashMap<MapData, ClientKey, UdpLoadBalancerMapping>>>,
+    maglev_tables_map: Arc<Mutex<HashMap<MapData, GatewaySlotKey, MaglevTable>>>,
+    backend_conns_map: Arc<Mutex<PerCpuHashMap<MapData, BackendKey, BackendConnections>>>,
+    backend_traffic_map: Arc<Mutex<PerCpuHashMap<MapData, BackendKey, BackendTraffic>>>,
+    backend_failures_map: Arc<Mutex<HashMap<MapData, BackendKey, BackendFailures>>>,
+    connection_limits_map: Arc<Mutex<HashMap<MapData, BackendKey, ConnectionLimit>>>,
+    acls_map: Arc<Mutex<LpmTrie<MapData, AclKey, AclAction>>>,
+    syn_cookies_map: Arc<Mutex<HashMap<MapData, BackendKey, u32>>>,
+    dscp_marks_map: Arc<Mutex<HashMap<MapData, BackendKey, u8>>>,
+    mirrors_map: Arc<Mutex<HashMap<MapData, BackendKey, Mirror>>>,
+    tproxy_ports_map: Arc<Mutex<HashMap<MapData, BackendKey, u16>>>,
+    ping_gateways_map: Arc<Mutex<HashMap<MapData, [u32; 4], BackendKey>>>,
+    stateless_targets_map: Arc<Mutex<HashMap<MapData, BackendKey, BackendKey>>>,
+    tunnels_map: Arc<Mutex<HashMap<MapData, [u32; 4], Tunnel>>>,
+    log_level_map: Arc<Mutex<Array<MapData, LogLevel>>>,
+    tcp_timeouts_map: Arc<Mutex<Array<MapData, TcpTimeouts>>>,
+    released_conns_map: Arc<Mutex<HashMap<MapData, BackendKey, u64>>>,
+    snat_conns_map: Arc<Mutex<HashMap<MapData, SnatKey, ClientKey>>>,
+    client_conns_map: Arc<Mutex<HashMap<MapData, [u32; 4], u32>>>,
+    flow_table: Arc<FlowTable>,
+    captures: Arc<Captures>,
+    // The directory the pcap files of the captures are written in, if any.
+    capture_dir: Option<PathBuf>,
+    // The connection events of the datapath, which WatchConnections streams.
+    connection_events: broadcast::Sender<ConnectionEvent>,
+    // Whether the calls which change the state of the dataplane require a bearer token.
+    token_required: bool,
+    // The generation of the last state applied through Sync, or None if the
+    // dataplane hasn't been sent a full state since it started.
+    generation: Arc<Mutex<Option<u64>>>,
+    // The tasks checking the health of the targets of the Gateways.
+    health_checkers: Arc<Mutex<StdHashMap<BackendKey, JoinHandle<()>>>>,
 }
 
 impl BackendService {
     pub fn new(
-        backends_map: HashMap<MapData, BackendKey, BackendList>,
-        gateway_indexes_map: HashMap<MapData, BackendKey, u16>,
-        tcp_conns_map: HashMap<MapData, ClientKey, LoadBalancerMapping>,
+        maps: SharedMaps,
+        flow_table: Arc<FlowTable>,
+        captures: Arc<Captures>,
+        capture_dir: Option<PathBuf>,
+        connection_events: broadcast::Sender<ConnectionEvent>,
+        token_required: bool,
     ) -> BackendService {
         BackendService {
-            backends_map: Arc::new(Mutex::new(backends_map)),
-            gateway_indexes_map: Arc::new(Mutex::new(gateway_indexes_map)),
-            tcp_conns_map: Arc::new(Mutex::new(tcp_conns_map)),
+            backends_map: maps.backends,
+            port_ranges_map: maps.port_ranges,
+            gateway_aliases_map: maps.gateway_aliases,
+            vip_addresses_map: maps.vip_addresses,
+            gateway_indexes_map: maps.gateway_indexes,
+            tcp_conns_map: maps.tcp_conns,
+            udp_conns_map: maps.udp_conns,
+            sctp_conns_map: maps.sctp_conns,
+            maglev_tables_map: maps.maglev_tables,
+            backend_conns_map: maps.backend_conns,
+            backend_traffic_map: maps.backend_traffic,
+            backend_failures_map: maps.backend_failures,
+            connection_limits_map: maps.connection_limits,
+            acls_map: maps.acls,
+            syn_cookies_map: maps.syn_cookies,
+            dscp_marks_map: maps.dscp_marks,
+            mirrors_map: maps.mirrors,
+            tproxy_ports_map: maps.tproxy_ports,
+            ping_gateways_map: maps.ping_gateways,
+            stateless_targets_map: maps.stateless_targets,
+            tunnels_map: maps.tunnels,
+            log_level_map: maps.log_level,
+            tcp_timeouts_map: maps.tcp_timeouts,
+            released_conns_map: maps.released_conns,
+            snat_conns_map: maps.snat_conns,
+            client_conns_map: maps.client_conns,
+            flow_table,
+            captures,
+            capture_dir,
+            connection_events,
+            token_required,
+            generation: Arc::new(Mutex::new(None)),
+            health_checkers: Arc::new(Mutex::new(StdHashMap::new())),
+        }
+    }
+
+    /// Returns the number of live connections of every backend which has
+    /// been assigned a connection.
+    pub async fn live_connections(&self) -> Result<Vec<(BackendKey, u64)>, Error> {
+        let backend_conns_map = self.backend_conns_map.lock().await;
+        let released_conns_map = self.released_conns_map.lock().await;
+        live_connections(&backend_conns_map, &released_conns_map)
+    }
+
+    /// Returns the statistics of the backends of the list.
+    async fn backend_stats(&self, backend_list: &BackendList) -> Result<Vec<BackendStats>, Error> {
+        let backend_conns_map = self.backend_conns_map.lock().await;
+        let released_conns_map = self.released_conns_map.lock().await;
+        let backend_traffic_map = self.backend_traffic_map.lock().await;
+        let backend_failures_map = self.backend_failures_map.lock().await;
+        let now = monotonic_now_ns()?;
+        let mut stats = Vec::new();
+        for backend in &backend_list.backends[..backend_list.backends_len as usize] {
+            let counters = backend_stats(
+                &backend.key(),
+                &backend_conns_map,
+                &released_conns_map,
+                &backend_traffic_map,
+            )?;
+            let last_selected = match counters.last_opened {
+                0 => None,
+                last_opened => Some(ktime_to_unix_ms(last_opened)?),
+            };
+            // The datapath ejects the targets which fail too many new
+            // connections for a while, on top of those failing the health
+            // checks.
+            let failing = match backend_failures_map.get(&backend.key(), 0) {
+                Ok(failures) => failures.ejected_until > now,
+                Err(err) if is_key_not_found(&err) => false,
+                Err(err) => return Err(err.into()),
+            };
+            stats.push(BackendStats {
+                target: Some(target_message(backend)),
+                active_connections: counters.active_connections,
+                total_connections: counters.total_connections,
+                bytes_in: counters.traffic.forwarded_bytes,
+                bytes_out: counters.traffic.reply_bytes,
+                last_selected,
+                ejected: backend.unhealthy || failing,
+            });
+        }
+        Ok(stats)
+    }
+
+    /// Returns the TCP connections tracked by the datapath which the selector
+    /// matches.
+    async fn connections(&self, selector: &ConnectionSelector) -> Result<Vec<Connection>, Error> {
+        let tcp_conns_map = self.tcp_conns_map.lock().await;
+        let mut connections = Vec::new();
+        for item in tcp_conns_map.iter() {
+            let (client_key, lb_mapping) = match item {
+                Ok(item) => item,
+                // The connection was removed by the datapath since its key was read.
+                Err(err) if is_key_not_found(&err) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            if !selector.matches(&lb_mapping.backend_key, &lb_mapping.backend) {
+                continue;
+            }
+            connections.push(connection_message(&client_key, &lb_mapping));
         }
+        Ok(connections)
     }
 
-    async fn insert(&self, key: BackendKey, bks: BackendList) -> Result<(), Error> {
+    async fn insert_and_reset_index(&self, key: BackendKey, bks: BackendList) -> Result<(), Error> {
+        let mut maglev_tables_map = self.maglev_tables_map.lock().await;
         let mut backends_map = self.backends_map.lock().await;
-        backends_map.insert(key, bks, 0)?;
+        let mut gateway_indexes_map = self.gateway_indexes_map.lock().await;
+        swap_backend_list(
+            &mut backends_map,
+            &mut maglev_tables_map,
+            &mut gateway_indexes_map,
+            key,
+            bks,
+            true,
+        )
+    }
+
+    /// Records the range of ports the Gateway listens on, or that it listens
+    /// on its single port when there is none.
+    async fn set_port_range(
+        &self,
+        key: &BackendKey,
+        range: Option<PortRange>,
+    ) -> Result<(), Status> {
+        let mut port_ranges_map = self.port_ranges_map.lock().await;
+        let mut ranges = match port_ranges_map.get(&key.ip, 0) {
+            Ok(range_list) => range_list.ranges[..range_list.ranges_len as usize].to_vec(),
+            Err(err) if is_key_not_found(&err) => Vec::new(),
+            Err(err) => return Err(Status::internal(format!("failure: {}", err))),
+        };
+        ranges.retain(|other| other.start as u32 != key.port);
+        if let Some(range) = range {
+            if let Some(other) = ranges.iter().find(|other| other.overlaps(&range)) {
+                return Err(Status::invalid_argument(format!(
+                    "port range {}-{} overlaps with port range {}-{}",
+                    range.start, range.end, other.start, other.end,
+                )));
+            }
+            if ranges.len() >= PORT_RANGES_CAPACITY {
+                return Err(Status::resource_exhausted(format!(
+                    "BPF map value capacity exceeded, only {} port ranges supported per VIP",
+                    PORT_RANGES_CAPACITY,
+                )));
+            }
+            ranges.push(range);
+        }
+
+        let result = if ranges.is_empty() {
+            remove_if_present(&mut port_ranges_map, &key.ip)
+        } else {
+            let mut range_list = PortRangeList {
+                ranges: [PortRange::default(); PORT_RANGES_CAPACITY],
+                ranges_len: ranges.len() as u16,
+            };
+            range_list.ranges[..ranges.len()].copy_from_slice(&ranges);
+            port_ranges_map.insert(key.ip, range_list, 0)
+        };
+        result.map_err(|err| Status::internal(format!("failure: {}", err)))
+    }
+
+    /// Makes the aliases share the backends and balancing state of the
+    /// Gateway, in place of the aliases it had, whose connections are flushed.
+    async fn set_aliases(
+        &self,
+        key: &BackendKey,
+        aliases: &[(BackendKey, Option<PortRange>)],
+    ) -> Result<(), Status> {
+        for (alias, _) in aliases {
+            if alias == key || self.backends_map.lock().await.get(alias, 0).is_ok() {
+                return Err(Status::invalid_argument(format!(
+                    "alias {} is a vip of its own",
+                    SocketAddr::new(words_to_ip(alias.ip), alias.port as u16),
+                )));
+            }
+        }
+
+        let mut gateway_aliases_map = self.gateway_aliases_map.lock().await;
+        let mut previous = Vec::new();
+        for item in gateway_aliases_map.iter() {
+            let (alias, group_key) =
+                item.map_err(|err| Status::internal(format!("failure: {}", err)))?;
+            if group_key == *key {
+                previous.push(alias);
+            } else if aliases.iter().any(|(other, _)| *other == alias) {
+                return Err(Status::invalid_argument(format!(
+                    "alias {} already is an alias of vip {}",
+                    SocketAddr::new(words_to_ip(alias.ip), alias.port as u16),
+                    SocketAddr::new(words_to_ip(group_key.ip), group_key.port as u16),
+                )));
+            }
+        }
+
+        for (alias, port_range) in aliases {
+            self.set_port_range(alias, *port_range).await?;
+            gateway_aliases_map
+                .insert(alias, key, 0)
+                .map_err(|err| Status::internal(format!("failure: {}", err)))?;
+        }
+        let mut removed = Vec::new();
+        for alias in previous {
+            if aliases.iter().any(|(other, _)| *other == alias) {
+                continue;
+            }
+            gateway_aliases_map
+                .remove(&alias)
+                .map_err(|err| Status::internal(format!("failure: {}", err)))?;
+            self.set_port_range(&alias, None).await?;
+            removed.push(alias);
+        }
+        drop(gateway_aliases_map);
+
+        for alias in aliases.iter().map(|(alias, _)| alias).chain(&removed) {
+            self.refresh_vip_address(alias.ip)
+                .await
+                .map_err(|err| Status::internal(format!("failure: {}", err)))?;
+        }
+
+        for alias in removed {
+            self.flush(&ConnectionSelector {
+                vip: Some(alias),
+                backend: None,
+            })
+            .await
+            .map_err(|err| Status::internal(format!("failure: {}", err)))?;
+        }
         Ok(())
     }
 
-    async fn insert_and_reset_index(&self, key: BackendKey, bks: BackendList) -> Result<(), Error> {
-        self.insert(key, bks).await?;
+    /// Makes the Gateway the one of the targets for the replies of their
+    /// untracked connections to be translated back, in place of the targets
+    /// it had. A target can only be the target of a single stateless Gateway.
+    async fn set_stateless_targets(
+        &self,
+        key: &BackendKey,
+        targets: &[BackendKey],
+    ) -> Result<(), Status> {
+        let mut stateless_targets_map = self.stateless_targets_map.lock().await;
+        let mut previous = Vec::new();
+        for item in stateless_targets_map.iter() {
+            let (target, gateway_key) =
+                item.map_err(|err| Status::internal(format!("failure: {}", err)))?;
+            if gateway_key == *key {
+                previous.push(target);
+            } else if targets.contains(&target) {
+                return Err(Status::invalid_argument(format!(
+                    "target {} already is a target of stateless vip {}",
+                    SocketAddr::new(words_to_ip(target.ip), target.port as u16),
+                    SocketAddr::new(words_to_ip(gateway_key.ip), gateway_key.port as u16),
+                )));
+            }
+        }
+
+        for target in targets {
+            stateless_targets_map
+                .insert(target, key, 0)
+                .map_err(|err| Status::internal(format!("failure: {}", err)))?;
+        }
+        for target in previous {
+            if !targets.contains(&target) {
+                stateless_targets_map
+                    .remove(&target)
+                    .map_err(|err| Status::internal(format!("failure: {}", err)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Records how many Gateways and aliases listen on the address, for the
+    /// datapath to answer the pings to it as long as there is any.
+    async fn refresh_vip_address(&self, ip: [u32; 4]) -> Result<(), Error> {
+        let mut count = 0;
+        for key in self.backends_map.lock().await.keys() {
+            if key?.ip == ip {
+                count += 1;
+            }
+        }
+        for key in self.gateway_aliases_map.lock().await.keys() {
+            if key?.ip == ip {
+                count += 1;
+            }
+        }
+
+        let mut vip_addresses_map = self.vip_addresses_map.lock().await;
+        match count {
+            0 => remove_if_present(&mut vip_addresses_map, &ip)?,
+            count => vip_addresses_map.insert(ip, count, 0)?,
+        }
+        Ok(())
+    }
+
+    /// Starts checking the health of the targets of the Gateway, in place of
+    /// the checks it had.
+    async fn set_health_check(&self, key: BackendKey, config: Option<HealthCheckConfig>) {
+        let mut health_checkers = self.health_checkers.lock().await;
+        if let Some(health_checker) = health_checkers.remove(&key) {
+            health_checker.abort();
+        }
+        if let Some(config) = config {
+            let health_checker = tokio::spawn(self.clone().check_health(key, config));
+            health_checkers.insert(key, health_checker);
+        }
+    }
+
+    /// Checks the health of the targets of the Gateway at every interval until
+    /// it is removed, ejecting the targets which fail their checks from new
+    /// connection selection and reinstating them once they pass them again.
+    async fn check_health(self, key: BackendKey, config: HealthCheckConfig) {
+        let mut health: StdHashMap<BackendKey, TargetHealth> = StdHashMap::new();
+        let mut interval = tokio::time::interval(config.interval);
+        loop {
+            interval.tick().await;
+            let backend_list = match self.backends_map.lock().await.get(&key, 0) {
+                Ok(backend_list) => backend_list,
+                Err(err) if is_key_not_found(&err) => return,
+                Err(err) => {
+                    warn!(
+                        vip = %format_backend(&key),
+                        error = %err,
+                        "failed to read the targets to check"
+                    );
+                    continue;
+                }
+            };
+            let backends = &backend_list.backends[..backend_list.backends_len as usize];
+            health.retain(|target, _| backends.iter().any(|backend| backend.key() == *target));
+
+            let mut checks = JoinSet::new();
+            for backend in backends {
+                let port = match (config.port, backend.dport) {
+                    (Some(port), _) => port,
+                    // The targets of VIPs listening on any port have none.
+                    (None, 0) => continue,
+                    (None, dport) => dport as u16,
+                };
+                let target = backend.key();
+                health
+                    .entry(target)
+                    .or_insert_with(|| TargetHealth::new(backend.unhealthy));
+                let addr = SocketAddr::new(words_to_ip(backend.daddr), port);
+                checks.spawn(async move { (target, check(addr, &config).await) });
+            }
+
+            while let Some(result) = checks.join_next().await {
+                let (target, passed) = match result {
+                    Ok(result) => result,
+                    Err(_) => continue,
+                };
+                let target_health = match health.get_mut(&target) {
+                    Some(target_health) => target_health,
+                    None => continue,
+                };
+                if !target_health.record(passed, &config) {
+                    continue;
+                }
+                let vip = format_backend(&key);
+                let backend_key = format_backend(&target);
+                if target_health.unhealthy {
+                    info!(%vip, %backend_key, "target failed its health checks, ejecting it");
+                } else {
+                    info!(%vip, %backend_key, "target passed its health checks, reinstating it");
+                }
+                if let Err(err) = self.eject(&key, &target, target_health.unhealthy).await {
+                    warn!(
+                        %vip,
+                        %backend_key,
+                        error = %err,
+                        "failed to update the health of target"
+                    );
+                }
+            }
+        }
+    }
+
+    /// Ejects the target of the Gateway from new connection selection, or
+    /// reinstates it.
+    async fn eject(
+        &self,
+        key: &BackendKey,
+        target: &BackendKey,
+        unhealthy: bool,
+    ) -> Result<(), Error> {
+        let mut maglev_tables_map = self.maglev_tables_map.lock().await;
+        let mut backends_map = self.backends_map.lock().await;
         let mut gateway_indexes_map = self.gateway_indexes_map.lock().await;
-        gateway_indexes_map.insert(key, 0, 0)?;
+        let mut backend_list = backends_map.get(key, 0)?;
+        let backends_len = backend_list.backends_len as usize;
+        let mut changed = false;
+        for backend in &mut backend_list.backends[..backends_len] {
+            if backend.key() == *target && backend.unhealthy != unhealthy {
+                backend.unhealthy = unhealthy;
+                changed = true;
+            }
+        }
+        if !changed {
+            return Ok(());
+        }
+
+        // The round robin carries on where it was.
+        swap_backend_list(
+            &mut backends_map,
+            &mut maglev_tables_map,
+            &mut gateway_indexes_map,
+            *key,
+            backend_list,
+            false,
+        )
+    }
+
+    /// Replaces the ACL of a VIP address. The new rules are in place before the
+    /// stale ones are removed, so that no client slips through in between.
+    async fn replace_acl(
+        &self,
+        vip_ip: [u32; 4],
+        rules: &[(Key<AclKey>, AclAction)],
+    ) -> Result<(), Error> {
+        let mut acls_map = self.acls_map.lock().await;
+        let mut stale = Vec::new();
+        for key in acls_map.keys() {
+            let key = key?;
+            if key.data().vip_ip != vip_ip {
+                continue;
+            }
+            let replaced = rules.iter().any(|(rule_key, _)| {
+                rule_key.prefix_len() == key.prefix_len() && rule_key.data() == key.data()
+            });
+            if !replaced {
+                stale.push(key);
+            }
+        }
+        for (key, action) in rules {
+            acls_map.insert(key, action, 0)?;
+        }
+        for key in stale {
+            acls_map.remove(&key)?;
+        }
         Ok(())
     }
 
     async fn remove(&self, key: BackendKey) -> Result<(), Error> {
-        let mut backends_map = self.backends_map.lock().await;
-        backends_map.remove(&key)?;
+        self.set_health_check(key, None).await;
+        self.set_port_range(&key, None)
+            .await
+            .map_err(|status| Error::msg(status.message().to_string()))?;
+        self.set_aliases(&key, &[])
+            .await
+            .map_err(|status| Error::msg(status.message().to_string()))?;
+        self.set_stateless_targets(&key, &[])
+            .await
+            .map_err(|status| Error::msg(status.message().to_string()))?;
+        self.backends_map.lock().await.remove(&key)?;
+        self.refresh_vip_address(key.ip).await?;
         let mut gateway_indexes_map = self.gateway_indexes_map.lock().await;
-        gateway_indexes_map.remove(&key)?;
+        let mut maglev_tables_map = self.maglev_tables_map.lock().await;
+        for slot in 0..GATEWAY_SLOTS {
+            let slot_key = GatewaySlotKey { key, slot };
+            remove_if_present(&mut gateway_indexes_map, &slot_key)?;
+            remove_if_present(&mut maglev_tables_map, &slot_key)?;
+        }
+        let mut connection_limits_map = self.connection_limits_map.lock().await;
+        remove_if_present(&mut connection_limits_map, &key)?;
+        let mut syn_cookies_map = self.syn_cookies_map.lock().await;
+        remove_if_present(&mut syn_cookies_map, &key)?;
+        let mut dscp_marks_map = self.dscp_marks_map.lock().await;
+        remove_if_present(&mut dscp_marks_map, &key)?;
+        let mut mirrors_map = self.mirrors_map.lock().await;
+        remove_if_present(&mut mirrors_map, &key)?;
+        let mut tproxy_ports_map = self.tproxy_ports_map.lock().await;
+        remove_if_present(&mut tproxy_ports_map, &key)?;
+        let mut ping_gateways_map = self.ping_gateways_map.lock().await;
+        if ping_gateways_map.get(&key.ip, 0).ok() == Some(key) {
+            ping_gateways_map.remove(&key.ip)?;
+        }
 
         // Delete all entries in our tcp connection tracking map that this backend
         // key was related to. This is needed because the TCPRoute might have been
@@ -64,141 +695,402 @@ impl BackendService {
         // Its better to do this rather than maintain a reverse index because the index
         // would need to be updated with each new connection. With remove being a less
         // frequently used operation, the performance cost is less visible.
-        let mut tcp_conns_map = self.tcp_conns_map.lock().await;
-        for item in tcp_conns_map
-            .iter()
-            .collect::<Vec<Result<(ClientKey, LoadBalancerMapping), MapError>>>()
-        {
-            match item {
-                Ok((
-                    client_key,
-                    LoadBalancerMapping {
-                        backend: _,
-                        backend_key,
-                        tcp_state: _,
-                    },
-                )) => {
-                    if backend_key == key {
-                        tcp_conns_map.remove(&client_key)?;
-                    };
-                }
-                Err(err) => return Err(err.into()),
-            };
-        }
+        self.flush(&ConnectionSelector {
+            vip: Some(key),
+            backend: None,
+        })
+        .await?;
         Ok(())
     }
-}
-
-#[tonic::async_trait]
-impl Backends for BackendService {
-    async fn get_interface_index(
-        &self,
-        request: Request<PodIp>,
-    ) -> Result<Response<InterfaceIndexConfirmation>, Status> {
-        let pod = request.into_inner();
-        let ip = pod.ip;
-        let ip_addr = std::net::Ipv4Addr::from(ip);
-
-        let device = match if_name_for_routing_ip(ip_addr) {
-            Ok(device) => device,
-            Err(err) => return Err(Status::internal(err.to_string())),
-        };
-
-        let ifindex = match if_nametoindex(device) {
-            Ok(ifindex) => ifindex,
-            Err(err) => return Err(Status::internal(err.to_string())),
-        };
-
-        Ok(Response::new(InterfaceIndexConfirmation { ifindex }))
-    }
-
-    async fn update(&self, request: Request<Targets>) -> Result<Response<Confirmation>, Status> {
-        let targets = request.into_inner();
 
+    /// Programs the Gateway of the targets, see Update.
+    async fn update_gateway(&self, targets: Targets) -> Result<Response<Confirmation>, Status> {
         let vip = match targets.vip {
             Some(vip) => vip,
             None => return Err(Status::invalid_argument("missing vip ip and port")),
         };
 
+        let vip_addr = ip_from_message(vip.ip, vip.ipv6.as_deref())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
         let key = BackendKey {
-            ip: vip.ip,
+            ip: ip_to_words(vip_addr),
             port: vip.port,
         };
+        set_vip(vip_addr, vip.port);
+        let vip_port_range = port_range(&vip)?;
+        let health_check = match &targets.health_check {
+            Some(health_check) => Some(
+                HealthCheckConfig::from_message(health_check)
+                    .map_err(|err| Status::invalid_argument(err.to_string()))?,
+            ),
+            None => None,
+        };
+        let mut aliases = Vec::new();
+        // Aliases of the other IP family make a dual-stack Gateway, whose clients are forwarded to
+        // the targets' address of their own family.
+        let mut dual_stack = false;
+        for alias in &targets.aliases {
+            let alias_addr = ip_from_message(alias.ip, alias.ipv6.as_deref())
+                .map_err(|err| Status::invalid_argument(err.to_string()))?;
+            if alias_addr.is_ipv4() != vip_addr.is_ipv4() {
+                dual_stack = true;
+            }
+            let alias_key = BackendKey {
+                ip: ip_to_words(alias_addr),
+                port: alias.port,
+            };
+            aliases.push((alias_key, port_range(alias)?));
+        }
+        let algorithm = match Algorithm::try_from(targets.algorithm) {
+            Ok(Algorithm::RoundRobin) => BalancingAlgorithm::RoundRobin,
+            Ok(Algorithm::Maglev) => BalancingAlgorithm::Maglev,
+            Ok(Algorithm::LeastConn) => BalancingAlgorithm::LeastConn,
+            Ok(Algorithm::PowerOfTwo) => BalancingAlgorithm::PowerOfTwo,
+            Ok(Algorithm::Random) => BalancingAlgorithm::Random,
+            Err(_) => {
+                return Err(Status::invalid_argument(format!(
+                    "unknown algorithm {}",
+                    targets.algorithm
+                )))
+            }
+        };
+        let forwarding = match (targets.dsr, targets.snat) {
+            (false, false) => ForwardingMode::Nat,
+            (true, false) => ForwardingMode::Dsr,
+            (false, true) => ForwardingMode::Snat,
+            (true, true) => {
+                return Err(Status::invalid_argument(
+                    "direct server return and source NAT are mutually exclusive",
+                ))
+            }
+        };
+        // The header shifts the sequence numbers of the connections, which can only be fixed up in
+        // the replies if they go through the dataplane.
+        if targets.proxy_protocol && forwarding == ForwardingMode::Dsr {
+            return Err(Status::invalid_argument(
+                "the PROXY protocol is not supported with direct server return",
+            ));
+        }
+        if targets.toa && forwarding == ForwardingMode::Dsr {
+            return Err(Status::invalid_argument(
+                "TOA is not supported with direct server return",
+            ));
+        }
+        // The connection IDs are learnt from the targets' replies, which must go through the
+        // dataplane.
+        let quic_cid_len = targets.quic_cid_len.unwrap_or(0);
+        if quic_cid_len as usize > QUIC_MAX_CID_LEN {
+            return Err(Status::invalid_argument(format!(
+                "invalid QUIC connection ID length {}, the longest is {}",
+                quic_cid_len, QUIC_MAX_CID_LEN
+            )));
+        }
+        if quic_cid_len != 0 && forwarding == ForwardingMode::Dsr {
+            return Err(Status::invalid_argument(
+                "QUIC affinity is not supported with direct server return",
+            ));
+        }
+        // The connections of stateless vips aren't tracked, which rules out whatever needs their
+        // state, and the replies of their targets are translated back from the targets' address
+        // and port alone.
+        if targets.stateless {
+            if algorithm != BalancingAlgorithm::Maglev {
+                return Err(Status::invalid_argument(
+                    "stateless vips are balanced with MAGLEV",
+                ));
+            }
+            if forwarding == ForwardingMode::Snat
+                || targets.proxy_protocol
+                || targets.toa
+                || quic_cid_len != 0
+                || targets.affinity_timeout.unwrap_or(0) != 0
+                || !targets.split_weights.is_empty()
+            {
+                return Err(Status::invalid_argument(
+                    "source NAT, the PROXY protocol, TOA, QUIC and session affinities, and split \
+                     weights are not supported with stateless vips",
+                ));
+            }
+            if vip.port == 0 || vip_port_range.is_some() || !aliases.is_empty() {
+                return Err(Status::invalid_argument(
+                    "stateless vips listen on a single port and have no aliases",
+                ));
+            }
+        }
+        let mut split_weights = [0; MAX_SPLIT_GROUPS];
+        if !targets.split_weights.is_empty() {
+            if targets.split_weights.len() > MAX_SPLIT_GROUPS {
+                return Err(Status::invalid_argument(format!(
+                    "too many split groups, only {} supported",
+                    MAX_SPLIT_GROUPS
+                )));
+            }
+            if targets.split_weights.iter().sum::<u32>() != 100 {
+                return Err(Status::invalid_argument("split weights must add up to 100"));
+            }
+            for (weight, split_weight) in split_weights.iter_mut().zip(&targets.split_weights) {
+                *weight = *split_weight as u8;
+            }
+        }
+        let split_groups = targets.split_weights.len().max(1);
         let mut backends: [Backend; BACKENDS_ARRAY_CAPACITY] =
             [Backend::default(); BACKENDS_ARRAY_CAPACITY];
         let mut count: u16 = 0;
         let backend_targets = targets.targets;
+        let max_weight = backend_targets
+            .iter()
+            .map(|backend_target| backend_target.weight.unwrap_or(1))
+            .max()
+            .unwrap_or(1);
+        if backend_targets.len() > BACKENDS_ARRAY_CAPACITY {
+            return Err(Status::resource_exhausted(
+                "BPF map value capacity exceeded, only 128 backends supported per Gateway",
+            ));
+        }
+
+        // The interfaces of Gateways with hundreds of targets are looked up all at once, rather
+        // than with a few commands per target. Whatever the number of targets, the Gateway's
+        // backends are then written to the maps as a single BackendList.
+        let mut unresolved = Vec::new();
+        for backend_target in &backend_targets {
+            if backend_target.ifindex.is_none() {
+                let (ip_addr, _) = target_addrs(backend_target, vip_addr.is_ipv4())
+                    .map_err(|err| Status::invalid_argument(err.to_string()))?;
+                unresolved.push(ip_addr);
+            }
+        }
+        let ifnames = if_names_for_routing_ips(&unresolved)
+            .map_err(|err| Status::internal(format!("failed to determine ifname: {}", err)))?;
+        let mut ifindexes: StdHashMap<String, u32> = StdHashMap::new();
+        let mut veths: StdHashMap<u32, bool> = StdHashMap::new();
 
         for backend_target in backend_targets {
+            let (ip_addr, alt_addr) = target_addrs(&backend_target, vip_addr.is_ipv4())
+                .map_err(|err| Status::invalid_argument(err.to_string()))?;
+            // The connections of IPv6 clients to IPv4 targets are translated by the dataplane
+            // (NAT64), whose replies have to come back through it to be translated too. The
+            // translated SYNs don't get TOA options, which can't carry IPv6 addresses.
+            if ip_addr.is_ipv6() && vip_addr.is_ipv4() {
+                return Err(Status::invalid_argument(format!(
+                    "target {} is not of the same IP family as vip {}",
+                    ip_addr, vip_addr,
+                )));
+            }
+            // Only IPv6 clients can be translated, the IPv4 ones need an IPv4 address.
+            if dual_stack && ip_addr.is_ipv6() && alt_addr.is_none() {
+                return Err(Status::invalid_argument(format!(
+                    "target {} of dual-stack vip {} has no IPv4 address",
+                    ip_addr, vip_addr,
+                )));
+            }
+            if ip_addr.is_ipv4()
+                && alt_addr.is_none()
+                && (vip_addr.is_ipv6() || dual_stack)
+                && (forwarding == ForwardingMode::Dsr || targets.toa)
+            {
+                return Err(Status::invalid_argument(format!(
+                    "IPv4 target {} of IPv6 vip {} is not supported with direct server return \
+                     or TOA",
+                    ip_addr, vip_addr,
+                )));
+            }
+
             let ifindex = match backend_target.ifindex {
                 Some(ifindex) => ifindex,
                 None => {
-                    let ip_addr = Ipv4Addr::from(backend_target.daddr);
-                    let ifname = match if_name_for_routing_ip(ip_addr) {
-                        Ok(ifname) => ifname,
-                        Err(err) => {
+                    let ifname = match ifnames.get(&ip_addr) {
+                        Some(ifname) => ifname,
+                        None => {
                             return Err(Status::internal(format!(
-                                "failed to determine ifname: {}",
-                                err
+                                "failed to determine ifname: no device found to route {}",
+                                ip_addr
                             )))
                         }
                     };
 
-                    match if_nametoindex(ifname) {
-                        Ok(ifindex) => ifindex,
-                        Err(err) => {
-                            return Err(Status::internal(format!(
-                                "failed to determine ifindex: {}",
-                                err
-                            )))
-                        }
+                    match ifindexes.get(ifname) {
+                        Some(ifindex) => *ifindex,
+                        None => match if_nametoindex(ifname.clone()) {
+                            Ok(ifindex) => {
+                                ifindexes.insert(ifname.clone(), ifindex);
+                                ifindex
+                            }
+                            Err(err) => {
+                                return Err(Status::internal(format!(
+                                    "failed to determine ifindex: {}",
+                                    err
+                                )))
+                            }
+                        },
                     }
                 }
             };
 
-            if (count as usize) < BACKENDS_ARRAY_CAPACITY {
-                let bk = Backend {
-                    daddr: backend_target.daddr,
-                    dport: backend_target.dport,
-                    ifindex: ifindex as u16,
-                };
-                backends[count as usize] = bk;
-                count += 1;
-            } else {
-                return Err(Status::resource_exhausted(
-                    "BPF map value capacity exceeded, only 128 backends supported per Gateway",
-                ));
+            // Interfaces which can't be inspected are not assumed to lead to a local pod.
+            let local = match backend_target.local {
+                Some(local) => local,
+                None => *veths
+                    .entry(ifindex)
+                    .or_insert_with(|| is_veth(ifindex).unwrap_or(false)),
+            };
+
+            let weight = scale_weight(backend_target.weight.unwrap_or(1), max_weight);
+
+            if backend_target.split_group as usize >= split_groups {
+                return Err(Status::invalid_argument(format!(
+                    "target {} is in split group {}, which has no weight",
+                    ip_addr, backend_target.split_group
+                )));
+            }
+
+            let mac: [u8; 6] = match backend_target.mac.as_deref() {
+                Some(mac) => mac.try_into().map_err(|_| {
+                    Status::invalid_argument(format!(
+                        "target {} MAC address must be 6 bytes long",
+                        ip_addr
+                    ))
+                })?,
+                None if forwarding == ForwardingMode::Dsr => {
+                    return Err(Status::invalid_argument(format!(
+                        "target {} needs a MAC address for direct server return",
+                        ip_addr
+                    )))
+                }
+                None => [0; 6],
+            };
+
+            // The datapath shifts the targets' ports by the offset of the client's port from
+            // the vip's, which is the port itself when the vip listens on any port.
+            let dport = match vip.port {
+                0 => 0,
+                _ => backend_target.dport,
+            };
+            let bk = Backend {
+                daddr: ip_to_words(ip_addr),
+                alt_daddr: alt_addr.map_or([0; 4], ip_to_words),
+                dport,
+                max_conns: backend_target.max_conns.unwrap_or(0),
+                ifindex: ifindex as u16,
+                weight,
+                mac,
+                forwarding,
+                proxy_protocol: targets.proxy_protocol,
+                toa: targets.toa,
+                local,
+                drain: backend_target.drain,
+                unhealthy: false,
+                split_group: backend_target.split_group as u8,
+            };
+            backends[count as usize] = bk;
+            count += 1;
+        }
+
+        // Targets which were ejected stay so until they pass their checks again.
+        if health_check.is_some() {
+            if let Ok(previous) = self.backends_map.lock().await.get(&key, 0) {
+                let previous = &previous.backends[..previous.backends_len as usize];
+                for backend in &mut backends[..count as usize] {
+                    backend.unhealthy = previous
+                        .iter()
+                        .any(|other| other.key() == backend.key() && other.unhealthy);
+                }
+            }
+        }
+
+        // The connections of the targets which are gone are flushed once the VIP is updated if
+        // asked to, rather than left to be reset or to time out.
+        let mut removed = Vec::new();
+        if targets.flush_removed {
+            if let Ok(previous) = self.backends_map.lock().await.get(&key, 0) {
+                let current = &backends[..count as usize];
+                removed = previous.backends[..previous.backends_len as usize]
+                    .iter()
+                    .map(Backend::key)
+                    .filter(|key| !current.iter().any(|backend| backend.key() == *key))
+                    .collect();
             }
         }
 
         let backend_list = BackendList {
             backends,
             backends_len: count,
+            algorithm,
+            affinity_timeout: Duration::from_secs(targets.affinity_timeout.unwrap_or(0).into())
+                .as_nanos() as u64,
+            slot: 0,
+            quic_cid_len: quic_cid_len as u8,
+            stateless: targets.stateless,
+            split_weights,
+            udp_idle_timeout: Duration::from_secs(targets.udp_idle_timeout.unwrap_or(0).into())
+                .as_nanos() as u64,
         };
-        match self.insert_and_reset_index(key, backend_list).await {
-            Ok(_) => Ok(Response::new(Confirmation {
-                confirmation: format!(
-                    "success, vip {}:{} was updated with {} backends",
-                    Ipv4Addr::from(vip.ip),
-                    vip.port,
-                    count,
-                ),
-            })),
-            Err(err) => Err(Status::internal(format!("failure: {}", err))),
+        self.set_port_range(&key, vip_port_range).await?;
+        self.set_aliases(&key, &aliases).await?;
+        // The replies of DSR targets don't go through the dataplane.
+        let stateless_targets: Vec<_> = match forwarding {
+            ForwardingMode::Nat if targets.stateless => backends[..count as usize]
+                .iter()
+                .map(|backend| BackendKey {
+                    ip: backend.daddr,
+                    port: backend.dport,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        self.set_stateless_targets(&key, &stateless_targets).await?;
+        match trace_step(
+            "insert backends",
+            self.insert_and_reset_index(key, backend_list),
+        )
+        .await
+        {
+            Ok(_) => {
+                self.set_health_check(key, health_check).await;
+            }
+            Err(err) => return Err(Status::internal(format!("failure: {}", err))),
         }
-    }
+        trace_step("refresh vip address", self.refresh_vip_address(key.ip))
+            .await
+            .map_err(|err| Status::internal(format!("failure: {}", err)))?;
 
-    async fn delete(&self, request: Request<Vip>) -> Result<Response<Confirmation>, Status> {
-        let vip = request.into_inner();
+        // The connections to the aliases are tracked under the aliases.
+        let mut flushed = 0;
+        for vip_key in iter::once(key).chain(aliases.iter().map(|(alias_key, _)| *alias_key)) {
+            for backend_key in &removed {
+                let selector = ConnectionSelector {
+                    vip: Some(vip_key),
+                    backend: Some(*backend_key),
+                };
+                flushed += trace_step("flush connections", self.flush(&selector))
+                    .await
+                    .map_err(|err| Status::internal(format!("failure: {}", err)))?;
+            }
+        }
 
+        let mut confirmation = format!(
+            "success, vip {}:{} was updated with {} backends",
+            vip_addr, vip.port, count,
+        );
+        if targets.flush_removed {
+            confirmation += &format!(
+                ", {} connections of the removed backends were flushed",
+                flushed
+            );
+        }
+        Ok(Response::new(Confirmation { confirmation }))
+    }
+
+    /// Removes the Gateway of the VIP, see Delete.
+    async fn delete_gateway(&self, vip: Vip) -> Result<Response<Confirmation>, Status> {
+        let addr_ddn = ip_from_message(vip.ip, vip.ipv6.as_deref())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
         let key = BackendKey {
-            ip: vip.ip,
+            ip: ip_to_words(addr_ddn),
             port: vip.port,
         };
+        set_vip(addr_ddn, vip.port);
 
-        let addr_ddn = Ipv4Addr::from(vip.ip);
-
-        match self.remove(key).await {
+        match trace_step("remove gateway", self.remove(key)).await {
             Ok(()) => Ok(Response::new(Confirmation {
                 confirmation: format!("success, vip {}:{} was deleted", addr_ddn, vip.port),
             })),
@@ -210,4 +1102,1716 @@ impl Backends for BackendService {
             Err(err) => Err(Status::internal(format!("failure: {}", err))),
         }
     }
+
+    /// Applies a state streamed through Sync, and returns its acknowledgement.
+    async fn apply_state(&self, state: DesiredState) -> StateAck {
+        // States are applied one at a time, whichever stream they come from.
+        let mut generation = self.generation.lock().await;
+        if !state.full && *generation != Some(state.base_generation) {
+            debug!(
+                generation = state.generation,
+                base_generation = state.base_generation,
+                "state does not follow the applied one, asking for a resync"
+            );
+            return StateAck {
+                generation: state.generation,
+                error: None,
+                resync: true,
+            };
+        }
+
+        let mut errors = Vec::new();
+        let mut updated = Vec::new();
+        for targets in state.updates {
+            if let Some(vip) = &targets.vip {
+                if let Ok(vip_addr) = ip_from_message(vip.ip, vip.ipv6.as_deref()) {
+                    updated.push(BackendKey {
+                        ip: ip_to_words(vip_addr),
+                        port: vip.port,
+                    });
+                }
+            }
+            let update = self.update_gateway(targets);
+            if let Err(status) = trace_programming(Context::current(), "Update", update).await {
+                errors.push(status.message().to_string());
+            }
+        }
+
+        let mut deletes = state.deletes;
+        if state.full {
+            // Whatever the control plane didn't mention is gone.
+            match self.vips().await {
+                Ok(vips) => {
+                    deletes = vips
+                        .into_iter()
+                        .filter(|key| !updated.contains(key))
+                        .map(|key| {
+                            let (ip, ipv6) = ip_to_message(words_to_ip(key.ip));
+                            Vip {
+                                ip,
+                                port: key.port,
+                                ipv6,
+                                port_end: None,
+                            }
+                        })
+                        .collect()
+                }
+                Err(err) => errors.push(err.to_string()),
+            }
+        }
+        let deleted = deletes.len();
+        for vip in deletes {
+            let delete = self.delete_gateway(vip);
+            if let Err(status) = trace_programming(Context::current(), "Delete", delete).await {
+                errors.push(status.message().to_string());
+            }
+        }
+
+        info!(
+            generation = state.generation,
+            full = state.full,
+            updates = updated.len(),
+            deletes = deleted,
+            errors = errors.len(),
+            "applied state"
+        );
+        *generation = Some(state.generation);
+        StateAck {
+            generation: state.generation,
+            error: (!errors.is_empty()).then(|| errors.join("; ")),
+            resync: false,
+        }
+    }
+
+    /// Returns the Gateways in the dataplane, with their targets.
+    async fn gateways(&self) -> Result<Vec<Gateway>, Error> {
+        let backends_map = self.backends_map.lock().await;
+        let mut gateways = Vec::new();
+        for item in backends_map.iter() {
+            let (key, backend_list) = item?;
+            let (ip, ipv6) = ip_to_message(words_to_ip(key.ip));
+            gateways.push(Gateway {
+                vip: Some(Vip {
+                    ip,
+                    port: key.port,
+                    ipv6,
+                    port_end: None,
+                }),
+                targets: backend_list.backends[..backend_list.backends_len as usize]
+                    .iter()
+                    .map(target_message)
+                    .collect(),
+                algorithm: algorithm_message(backend_list.algorithm) as i32,
+            });
+        }
+        Ok(gateways)
+    }
+
+    /// Returns the VIPs of the Gateways in the dataplane.
+    async fn vips(&self) -> Result<Vec<BackendKey>, Error> {
+        let backends_map = self.backends_map.lock().await;
+        let mut vips = Vec::new();
+        for key in backends_map.keys() {
+            vips.push(key?);
+        }
+        Ok(vips)
+    }
+
+    /// Removes the connections, UDP flows and SCTP associations the selector
+    /// matches from the connection tracking maps, and returns how many were
+    /// removed.
+    async fn flush(&self, selector: &ConnectionSelector) -> Result<usize, Error> {
+        let mut tcp_conns_map = self.tcp_conns_map.lock().await;
+        let mut udp_conns_map = self.udp_conns_map.lock().await;
+        let mut sctp_conns_map = self.sctp_conns_map.lock().await;
+        let mut released_conns_map = self.released_conns_map.lock().await;
+        let mut snat_conns_map = self.snat_conns_map.lock().await;
+        let mut client_conns_map = self.client_conns_map.lock().await;
+        let mut flushed = 0;
+        for item in tcp_conns_map
+            .iter()
+            .collect::<Vec<Result<(ClientKey, LoadBalancerMapping), MapError>>>()
+        {
+            match item {
+                Ok((client_key, lb_mapping)) => {
+                    if selector.matches(&lb_mapping.backend_key, &lb_mapping.backend) {
+                        tcp_conns_map.remove(&client_key)?;
+                        self.flow_table.record_removals(1);
+                        // Only TCP connections are counted, the entries of UDP flows
+                        // are there for ICMP.
+                        if lb_mapping.tcp_state.is_some() {
+                            release_connections(&mut released_conns_map, &lb_mapping.backend, 1)?;
+                            release_client_connection(&mut client_conns_map, &client_key)?;
+                        }
+                        release_snat_port(&mut snat_conns_map, &lb_mapping)?;
+                        flushed += 1;
+                    };
+                }
+                Err(err) => return Err(err.into()),
+            };
+        }
+
+        // The same goes for UDP flows and SCTP associations, which would otherwise
+        // only be cleaned up once they've been idle for long enough.
+        for flows_map in [&mut *udp_conns_map, &mut *sctp_conns_map] {
+            for item in flows_map
+                .iter()
+                .collect::<Vec<Result<(ClientKey, UdpLoadBalancerMapping), MapError>>>()
+            {
+                match item {
+                    Ok((client_key, udp_mapping)) => {
+                        if selector.matches(&udp_mapping.backend_key, &udp_mapping.backend) {
+                            flows_map.remove(&client_key)?;
+                            release_connections(&mut released_conns_map, &udp_mapping.backend, 1)?;
+                            flushed += 1;
+                        };
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+            }
+        }
+        Ok(flushed)
+    }
+
+    /// Returns the connections and UDP flows the selector matches, with how
+    /// long they have been idle.
+    async fn export(&self, selector: &ConnectionSelector) -> Result<ConntrackSnapshot, Error> {
+        let now = monotonic_now_ns()?;
+        let tcp_conns_map = self.tcp_conns_map.lock().await;
+        let udp_conns_map = self.udp_conns_map.lock().await;
+        let mut snapshot = ConntrackSnapshot::default();
+        for item in tcp_conns_map.iter() {
+            let (client_key, lb_mapping) = match item {
+                Ok(item) => item,
+                // The connection was removed by the datapath since its key was read.
+                Err(err) if is_key_not_found(&err) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            if selector.matches(&lb_mapping.backend_key, &lb_mapping.backend) {
+                snapshot.tcp.push(ConntrackEntry {
+                    key: to_bytes(&client_key),
+                    value: to_bytes(&lb_mapping),
+                    idle_ns: now.saturating_sub(lb_mapping.last_seen),
+                });
+            }
+        }
+        for item in udp_conns_map.iter() {
+            let (client_key, udp_mapping) = match item {
+                Ok(item) => item,
+                Err(err) if is_key_not_found(&err) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            if selector.matches(&udp_mapping.backend_key, &udp_mapping.backend) {
+                snapshot.udp.push(ConntrackEntry {
+                    key: to_bytes(&client_key),
+                    value: to_bytes(&udp_mapping),
+                    idle_ns: now.saturating_sub(udp_mapping.last_seen),
+                });
+            }
+        }
+        Ok(snapshot)
+    }
+
+    /// Adds the connections and UDP flows exported by another dataplane to the
+    /// connection tracking maps, and returns how many were added. They are
+    /// pinned to the local version of their targets, whose interface and MAC
+    /// address may differ from the other node's.
+    async fn import(
+        &self,
+        tcp_entries: Vec<ConntrackEntry>,
+        udp_entries: Vec<ConntrackEntry>,
+    ) -> Result<usize, Error> {
+        let mut gateways = StdHashMap::new();
+        for item in self.backends_map.lock().await.iter() {
+            let (key, backend_list) = item?;
+            gateways.insert(key, backend_list);
+        }
+        let local_backend = |key: &BackendKey, backend: &Backend| {
+            let backend_list: &BackendList = gateways.get(key)?;
+            backend_list.backends[..backend_list.backends_len as usize]
+                .iter()
+                .find(|other| other.key() == backend.key())
+                .map(|other| other.for_family(is_ipv4_mapped(&backend.daddr)))
+        };
+
+        let now = monotonic_now_ns()?;
+        let mut tcp_conns_map = self.tcp_conns_map.lock().await;
+        let mut udp_conns_map = self.udp_conns_map.lock().await;
+        let mut backend_conns_map = self.backend_conns_map.lock().await;
+        let mut counts: StdHashMap<BackendKey, u64> = StdHashMap::new();
+        let mut imported = 0;
+        for entry in tcp_entries {
+            let (client_key, mut lb_mapping) = match (
+                from_bytes::<ClientKey>(&entry.key),
+                from_bytes::<LoadBalancerMapping>(&entry.value),
+            ) {
+                (Some(client_key), Some(lb_mapping)) => (client_key, lb_mapping),
+                _ => return Err(Error::msg("malformed TCP connection in the snapshot")),
+            };
+            // Source NATed connections are bound to the other node's address.
+            if lb_mapping.snat_port != 0 {
+                continue;
+            }
+            lb_mapping.backend = match local_backend(&lb_mapping.backend_key, &lb_mapping.backend) {
+                Some(backend) => backend,
+                None => continue,
+            };
+            match tcp_conns_map.get(&client_key, 0) {
+                Ok(_) => continue,
+                Err(err) if is_key_not_found(&err) => {}
+                Err(err) => return Err(err.into()),
+            }
+            lb_mapping.last_seen = now.saturating_sub(entry.idle_ns);
+            tcp_conns_map.insert(client_key, lb_mapping, 0)?;
+            self.flow_table.record_inserts(1);
+            // Only TCP connections are counted, the entries of UDP flows are there for ICMP.
+            if lb_mapping.tcp_state.is_some() {
+                *counts.entry(lb_mapping.backend.key()).or_default() += 1;
+            }
+            imported += 1;
+        }
+        for entry in udp_entries {
+            let (client_key, mut udp_mapping) = match (
+                from_bytes::<ClientKey>(&entry.key),
+                from_bytes::<UdpLoadBalancerMapping>(&entry.value),
+            ) {
+                (Some(client_key), Some(udp_mapping)) => (client_key, udp_mapping),
+                _ => return Err(Error::msg("malformed UDP flow in the snapshot")),
+            };
+            udp_mapping.backend =
+                match local_backend(&udp_mapping.backend_key, &udp_mapping.backend) {
+                    Some(backend) => backend,
+                    None => continue,
+                };
+            match udp_conns_map.get(&client_key, 0) {
+                Ok(_) => continue,
+                Err(err) if is_key_not_found(&err) => {}
+                Err(err) => return Err(err.into()),
+            }
+            udp_mapping.last_seen = now.saturating_sub(entry.idle_ns);
+            udp_conns_map.insert(client_key, udp_mapping, 0)?;
+            *counts.entry(udp_mapping.backend.key()).or_default() += 1;
+            imported += 1;
+        }
+
+        for (key, count) in counts {
+            count_imported_connections(&mut backend_conns_map, &key, count)?;
+        }
+        Ok(imported)
+    }
+
+    /// Removes the connections and UDP flows closed by another dataplane from
+    /// the connection tracking maps, and returns how many were removed.
+    async fn remove_closed(
+        &self,
+        tcp_keys: Vec<Vec<u8>>,
+        udp_keys: Vec<Vec<u8>>,
+    ) -> Result<usize, Error> {
+        let mut tcp_conns_map = self.tcp_conns_map.lock().await;
+        let mut udp_conns_map = self.udp_conns_map.lock().await;
+        let mut released_conns_map = self.released_conns_map.lock().await;
+        let mut snat_conns_map = self.snat_conns_map.lock().await;
+        let mut client_conns_map = self.client_conns_map.lock().await;
+        let mut removed = 0;
+        for key in tcp_keys {
+            let client_key = from_bytes::<ClientKey>(&key)
+                .ok_or_else(|| Error::msg("malformed TCP connection key in the update"))?;
+            let lb_mapping = match tcp_conns_map.get(&client_key, 0) {
+                Ok(lb_mapping) => lb_mapping,
+                Err(err) if is_key_not_found(&err) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            tcp_conns_map.remove(&client_key)?;
+            self.flow_table.record_removals(1);
+            if lb_mapping.tcp_state.is_some() {
+                release_connections(&mut released_conns_map, &lb_mapping.backend, 1)?;
+                release_client_connection(&mut client_conns_map, &client_key)?;
+            }
+            release_snat_port(&mut snat_conns_map, &lb_mapping)?;
+            removed += 1;
+        }
+        for key in udp_keys {
+            let client_key = from_bytes::<ClientKey>(&key)
+                .ok_or_else(|| Error::msg("malformed UDP flow key in the update"))?;
+            let udp_mapping = match udp_conns_map.get(&client_key, 0) {
+                Ok(udp_mapping) => udp_mapping,
+                Err(err) if is_key_not_found(&err) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            udp_conns_map.remove(&client_key)?;
+            release_connections(&mut released_conns_map, &udp_mapping.backend, 1)?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Returns the update of the connections and UDP flows the datapath
+    /// reported opening and closing, for the peer dataplanes to replicate.
+    /// The connections which were closed since they were opened are left out.
+    pub(crate) async fn connection_update(
+        &self,
+        events: &[ConnectionEvent],
+    ) -> Result<ConntrackUpdate, Error> {
+        let now = monotonic_now_ns()?;
+        let tcp_conns_map = self.tcp_conns_map.lock().await;
+        let udp_conns_map = self.udp_conns_map.lock().await;
+        let mut update = ConntrackUpdate::default();
+        for event in events {
+            let key = to_bytes(&event.client_key);
+            match (event.proto, event.kind) {
+                (IPPROTO_TCP, ConnectionEventKind::Opened) => {
+                    match tcp_conns_map.get(&event.client_key, 0) {
+                        Ok(lb_mapping) => update.tcp_opened.push(ConntrackEntry {
+                            key,
+                            value: to_bytes(&lb_mapping),
+                            idle_ns: now.saturating_sub(lb_mapping.last_seen),
+                        }),
+                        Err(err) if is_key_not_found(&err) => {}
+                        Err(err) => return Err(err.into()),
+                    }
+                }
+                (IPPROTO_UDP, ConnectionEventKind::Opened) => {
+                    match udp_conns_map.get(&event.client_key, 0) {
+                        Ok(udp_mapping) => update.udp_opened.push(ConntrackEntry {
+                            key,
+                            value: to_bytes(&udp_mapping),
+                            idle_ns: now.saturating_sub(udp_mapping.last_seen),
+                        }),
+                        Err(err) if is_key_not_found(&err) => {}
+                        Err(err) => return Err(err.into()),
+                    }
+                }
+                (IPPROTO_TCP, ConnectionEventKind::Closed) => update.tcp_closed.push(key),
+                (IPPROTO_UDP, ConnectionEventKind::Closed) => update.udp_closed.push(key),
+                _ => {}
+            }
+        }
+        Ok(update)
+    }
+
+    /// Returns all the connections and UDP flows tracked by the datapath, for a
+    /// peer dataplane to catch up with those opened while it was unreachable.
+    pub(crate) async fn full_update(&self) -> Result<ConntrackUpdate, Error> {
+        let snapshot = self
+            .export(&ConnectionSelector {
+                vip: None,
+                backend: None,
+            })
+            .await?;
+        Ok(ConntrackUpdate {
+            tcp_opened: snapshot.tcp,
+            udp_opened: snapshot.udp,
+            ..Default::default()
+        })
+    }
+}
+
+// Returns true if the map operation failed because the key was not in the map.
+pub(crate) fn is_key_not_found(err: &MapError) -> bool {
+    match err {
+        MapError::KeyNotFound => true,
+        MapError::SyscallError(err) => err
+            .source()
+            .and_then(|source| source.downcast_ref::<io::Error>())
+            .is_some_and(|io_error| io_error.raw_os_error() == Some(libc::ENOENT)),
+        _ => false,
+    }
+}
+
+// Removes the key from the map, if it's there.
+fn remove_if_present<K: Pod, V: Pod>(
+    map: &mut HashMap<MapData, K, V>,
+    key: &K,
+) -> Result<(), MapError> {
+    match map.remove(key) {
+        Err(err) if is_key_not_found(&err) => Ok(()),
+        result => result,
+    }
+}
+
+// Replaces the backend list of the Gateway, whose balancing state is first
+// written to the slot its current list doesn't use, see BackendList.slot. The
+// round robin starts over from the first backend if reset_index is set, and
+// carries on where it was otherwise. The split groups of the list have their
+// own balancing state, which follows the slot of the list.
+fn swap_backend_list(
+    backends_map: &mut HashMap<MapData, BackendKey, BackendList>,
+    maglev_tables_map: &mut HashMap<MapData, GatewaySlotKey, MaglevTable>,
+    gateway_indexes_map: &mut HashMap<MapData, GatewaySlotKey, GatewayIndex>,
+    key: BackendKey,
+    mut backend_list: BackendList,
+    reset_index: bool,
+) -> Result<(), Error> {
+    let current_slot = match backends_map.get(&key, 0) {
+        Ok(current) => Some(current.slot),
+        Err(err) if is_key_not_found(&err) => None,
+        Err(err) => return Err(err.into()),
+    };
+    let slot = current_slot.map_or(0, |slot| slot ^ 1);
+    let backends = &backend_list.backends[..backend_list.backends_len as usize];
+
+    let split_groups = (0..MAX_SPLIT_GROUPS as u8).map(Some);
+    for split_group in iter::once(None).chain(split_groups) {
+        let slot_key = |slot| match split_group {
+            Some(split_group) => GatewaySlotKey::split(key, slot, split_group),
+            None => GatewaySlotKey { key, slot },
+        };
+        // The groups without weight get no connections to balance.
+        let split_backends = match split_group {
+            Some(split_group) if backend_list.split_weights[split_group as usize] == 0 => {
+                remove_if_present(gateway_indexes_map, &slot_key(slot))?;
+                remove_if_present(maglev_tables_map, &slot_key(slot))?;
+                continue;
+            }
+            // The backends of the other groups are left out of the group's table.
+            Some(split_group) => backends
+                .iter()
+                .map(|backend| Backend {
+                    drain: backend.drain || backend.split_group != split_group,
+                    ..*backend
+                })
+                .collect(),
+            None => backends.to_vec(),
+        };
+
+        let index = match current_slot {
+            Some(current_slot) if !reset_index => {
+                match gateway_indexes_map.get(&slot_key(current_slot), 0) {
+                    Ok(index) => index,
+                    Err(err) if is_key_not_found(&err) => GatewayIndex::default(),
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            _ => GatewayIndex::default(),
+        };
+        gateway_indexes_map.insert(slot_key(slot), index, 0)?;
+
+        let table = match backend_list.algorithm {
+            BalancingAlgorithm::Maglev | BalancingAlgorithm::PowerOfTwo => {
+                maglev_table(&split_backends)
+            }
+            BalancingAlgorithm::RoundRobin
+            | BalancingAlgorithm::LeastConn
+            | BalancingAlgorithm::Random => None,
+        };
+        match table {
+            Some(table) => maglev_tables_map.insert(slot_key(slot), table, 0)?,
+            None => remove_if_present(maglev_tables_map, &slot_key(slot))?,
+        }
+    }
+
+    backend_list.slot = slot;
+    backends_map.insert(key, backend_list, 0)?;
+    Ok(())
+}
+
+// Returns the address carried by an API message, which holds either an IPv4
+// address in host byte order or an IPv6 address in network byte order.
+fn ip_from_message(ip: u32, ipv6: Option<&[u8]>) -> Result<IpAddr, Error> {
+    match ipv6 {
+        Some(octets) => {
+            let octets: [u8; 16] = octets
+                .try_into()
+                .map_err(|_| Error::msg("IPv6 addresses must be 16 bytes long"))?;
+            Ok(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        None => Ok(IpAddr::V4(Ipv4Addr::from(ip))),
+    }
+}
+
+// Returns the address of the target of the vip's IP family, or of the other one
+// if it has a single address, followed by its address of the other family when
+// it is dual-stack, i.e. when both daddr and daddr_ipv6 are set.
+fn target_addrs(target: &Target, vip_ipv4: bool) -> Result<(IpAddr, Option<IpAddr>), Error> {
+    let addr = ip_from_message(target.daddr, target.daddr_ipv6.as_deref())?;
+    if target.daddr == 0 || target.daddr_ipv6.is_none() {
+        return Ok((addr, None));
+    }
+    let ipv4_addr = IpAddr::V4(Ipv4Addr::from(target.daddr));
+    match vip_ipv4 {
+        true => Ok((ipv4_addr, Some(addr))),
+        false => Ok((addr, Some(ipv4_addr))),
+    }
+}
+
+// Returns the key of the rule of the ACL of a VIP address in the ACLS trie,
+// which matches on the VIP address first and then on the client's prefix.
+fn acl_key(vip_ip: [u32; 4], rule: &AclRule) -> Result<Key<AclKey>, Status> {
+    let prefix = ip_from_message(rule.ip, rule.ipv6.as_deref())
+        .map_err(|err| Status::invalid_argument(err.to_string()))?;
+    // IPv4 prefixes are matched against IPv4-mapped addresses.
+    let (max_len, mapped_len) = match prefix {
+        IpAddr::V4(_) => (32, 96),
+        IpAddr::V6(_) => (128, 0),
+    };
+    if rule.prefix_len > max_len {
+        return Err(Status::invalid_argument(format!(
+            "invalid prefix length {} of {}",
+            rule.prefix_len, prefix
+        )));
+    }
+    let prefix_len = mapped_len + rule.prefix_len;
+
+    let addr = ip_to_words(prefix)
+        .iter()
+        .fold(0_u128, |addr, word| addr << 32 | *word as u128);
+    let masked = match prefix_len {
+        0 => 0,
+        len => addr & (u128::MAX << (128 - len)),
+    };
+    let src_ip = [
+        (masked >> 96) as u32,
+        (masked >> 64) as u32,
+        (masked >> 32) as u32,
+        masked as u32,
+    ];
+    Ok(Key::new(
+        ACL_VIP_PREFIX_LEN + prefix_len,
+        AclKey {
+            vip_ip,
+            src_ip: src_ip.map(u32::to_be),
+        },
+    ))
+}
+
+// Returns the range of ports the VIP listens on, if it listens on more than one.
+fn port_range(vip: &Vip) -> Result<Option<PortRange>, Status> {
+    match vip.port_end {
+        Some(port_end) if vip.port == 0 && port_end != 0 => Err(Status::invalid_argument(
+            "a vip listening on any port has no port range",
+        )),
+        Some(port_end) if port_end < vip.port || port_end > u16::MAX as u32 => Err(
+            Status::invalid_argument(format!("invalid port range {}-{}", vip.port, port_end)),
+        ),
+        Some(port_end) if port_end > vip.port => Ok(Some(PortRange {
+            start: vip.port as u16,
+            end: port_end as u16,
+        })),
+        _ => Ok(None),
+    }
+}
+
+// Returns the path of a pcap file in the capture directory, the clients naming
+// files in it only, not paths anywhere else on the node.
+fn pcap_path(capture_dir: Option<&Path>, name: &str) -> Result<PathBuf, Status> {
+    let capture_dir = capture_dir.ok_or_else(|| {
+        Status::failed_precondition("the dataplane has no capture directory to write pcap files in")
+    })?;
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(file)), None) => Ok(capture_dir.join(file)),
+        _ => Err(Status::invalid_argument(format!(
+            "invalid pcap file {}, which must be a file name",
+            name
+        ))),
+    }
+}
+
+// Returns the weight of a target in the datapath, which holds 16-bit weights.
+// When the largest weight of the Gateway's targets doesn't fit, the weights are
+// all scaled down in proportion, the targets with a non-zero weight keeping at
+// least 1.
+fn scale_weight(weight: u32, max_weight: u32) -> u16 {
+    if max_weight <= u16::MAX as u32 {
+        return weight as u16;
+    }
+    if weight == 0 {
+        return 0;
+    }
+    (weight as u64 * u16::MAX as u64 / max_weight as u64).max(1) as u16
+}
+
+// Returns the fields of an API message holding the address, the inverse of
+// ip_from_message.
+fn ip_to_message(ip: IpAddr) -> (u32, Option<Vec<u8>>) {
+    match ip {
+        IpAddr::V4(ip) => (ip.into(), None),
+        IpAddr::V6(ip) => (0, Some(ip.octets().to_vec())),
+    }
+}
+
+// Returns the API message of a tracked TCP connection.
+fn connection_message(client_key: &ClientKey, lb_mapping: &LoadBalancerMapping) -> Connection {
+    let (client_ip, client_ipv6) = ip_to_message(words_to_ip(client_key.ip));
+    let (vip_ip, vip_ipv6) = ip_to_message(words_to_ip(lb_mapping.backend_key.ip));
+    Connection {
+        client_ip,
+        client_port: client_key.port,
+        client_ipv6,
+        vip: Some(Vip {
+            ip: vip_ip,
+            port: lb_mapping.gateway_port() as u32,
+            ipv6: vip_ipv6,
+            port_end: None,
+        }),
+        target: Some(Target {
+            dport: lb_mapping.backend_port() as u32,
+            ..target_message(&lb_mapping.backend)
+        }),
+        tcp_state: lb_mapping
+            .tcp_state
+            .map(|state| tcp_state_message(state) as i32),
+        snat_port: lb_mapping.snat_key().map(|snat_key| snat_key.snat_port),
+    }
+}
+
+// Returns the API message of a backend.
+fn target_message(backend: &Backend) -> Target {
+    let (mut daddr, mut daddr_ipv6) = ip_to_message(words_to_ip(backend.daddr));
+    // Dual-stack backends have an address of each IP family.
+    if backend.alt_daddr != [0; 4] {
+        let (alt_daddr, alt_daddr_ipv6) = ip_to_message(words_to_ip(backend.alt_daddr));
+        daddr |= alt_daddr;
+        daddr_ipv6 = daddr_ipv6.or(alt_daddr_ipv6);
+    }
+    Target {
+        daddr,
+        dport: backend.dport,
+        ifindex: Some(backend.ifindex as u32),
+        daddr_ipv6,
+        weight: Some(backend.weight as u32),
+        mac: Some(backend.mac.to_vec()),
+        local: Some(backend.local),
+        drain: backend.drain,
+        split_group: backend.split_group as u32,
+        max_conns: Some(backend.max_conns),
+    }
+}
+
+fn algorithm_message(algorithm: BalancingAlgorithm) -> Algorithm {
+    match algorithm {
+        BalancingAlgorithm::RoundRobin => Algorithm::RoundRobin,
+        BalancingAlgorithm::Maglev => Algorithm::Maglev,
+        BalancingAlgorithm::LeastConn => Algorithm::LeastConn,
+        BalancingAlgorithm::PowerOfTwo => Algorithm::PowerOfTwo,
+        BalancingAlgorithm::Random => Algorithm::Random,
+    }
+}
+
+// Returns the API message of a connection event of the datapath.
+fn connection_change(event: &ConnectionEvent) -> ConnectionChange {
+    let (client_ip, client_ipv6) = ip_to_message(words_to_ip(event.client_key.ip));
+    let (vip_ip, vip_ipv6) = ip_to_message(words_to_ip(event.backend_key.ip));
+    let (daddr, daddr_ipv6) = ip_to_message(words_to_ip(event.backend.ip));
+    let reason = match event.reason {
+        CloseReason::None => "",
+        CloseReason::Fin => "fin",
+        CloseReason::Rst => "rst",
+        CloseReason::Replaced => "replaced",
+        CloseReason::Timeout => "timeout",
+    };
+    ConnectionChange {
+        connection: Some(Connection {
+            client_ip,
+            client_port: event.client_key.port,
+            client_ipv6,
+            vip: Some(Vip {
+                ip: vip_ip,
+                port: event.backend_key.port,
+                ipv6: vip_ipv6,
+                port_end: None,
+            }),
+            target: Some(Target {
+                daddr,
+                dport: event.backend.port,
+                daddr_ipv6,
+                ..Default::default()
+            }),
+            tcp_state: None,
+            snat_port: None,
+        }),
+        protocol: proto_name(event.proto).to_string(),
+        closed: event.kind == ConnectionEventKind::Closed,
+        reason: reason.to_string(),
+        timestamp: ktime_to_unix_ms(event.timestamp).unwrap_or_default(),
+    }
+}
+
+fn tcp_state_message(state: TCPState) -> TcpState {
+    match state {
+        TCPState::Established => TcpState::Established,
+        TCPState::FinWait1 => TcpState::FinWait1,
+        TCPState::FinWait2 => TcpState::FinWait2,
+        TCPState::Closing => TcpState::Closing,
+        TCPState::LastAck => TcpState::LastAck,
+        TCPState::TimeWait => TcpState::TimeWait,
+        TCPState::Closed => TcpState::Closed,
+        TCPState::SynSent => TcpState::SynSent,
+    }
+}
+
+#[tonic::async_trait]
+impl Backends for BackendService {
+    type ListConnectionsStream = tokio_stream::Iter<std::vec::IntoIter<Result<Connection, Status>>>;
+    type WatchConnectionsStream = ReceiverStream<Result<ConnectionChange, Status>>;
+    type SyncStream = ReceiverStream<Result<StateAck, Status>>;
+    type CapturePacketsStream = ReceiverStream<Result<CapturedFrame, Status>>;
+
+    async fn get_interface_index(
+        &self,
+        request: Request<PodIp>,
+    ) -> Result<Response<InterfaceIndexConfirmation>, Status> {
+        let pod = request.into_inner();
+        let ip_addr = ip_from_message(pod.ip, pod.ipv6.as_deref())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+
+        let device = match if_name_for_routing_ip(ip_addr) {
+            Ok(device) => device,
+            Err(err) => return Err(Status::internal(err.to_string())),
+        };
+
+        let ifindex = match if_nametoindex(device) {
+            Ok(ifindex) => ifindex,
+            Err(err) => return Err(Status::internal(err.to_string())),
+        };
+
+        Ok(Response::new(InterfaceIndexConfirmation { ifindex }))
+    }
+
+    async fn update(&self, request: Request<Targets>) -> Result<Response<Confirmation>, Status> {
+        authorize(&request)?;
+        let parent = remote_context(request.metadata());
+        trace_programming(parent, "Update", self.update_gateway(request.into_inner())).await
+    }
+
+    async fn delete(&self, request: Request<Vip>) -> Result<Response<Confirmation>, Status> {
+        authorize(&request)?;
+        let parent = remote_context(request.metadata());
+        trace_programming(parent, "Delete", self.delete_gateway(request.into_inner())).await
+    }
+
+    async fn list_gateways(
+        &self,
+        _request: Request<ListGatewaysRequest>,
+    ) -> Result<Response<GatewayList>, Status> {
+        match self.gateways().await {
+            Ok(gateways) => Ok(Response::new(GatewayList { gateways })),
+            Err(err) => Err(Status::internal(format!("failure: {}", err))),
+        }
+    }
+
+    async fn list_connections(
+        &self,
+        request: Request<ConnectionsFilter>,
+    ) -> Result<Response<Self::ListConnectionsStream>, Status> {
+        let selector = ConnectionSelector::from_message(request.into_inner())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+
+        let connections = self
+            .connections(&selector)
+            .await
+            .map_err(|err| Status::internal(format!("failure: {}", err)))?;
+        let connections: Vec<_> = connections.into_iter().map(Ok).collect();
+        Ok(Response::new(tokio_stream::iter(connections)))
+    }
+
+    async fn watch_connections(
+        &self,
+        request: Request<ConnectionsFilter>,
+    ) -> Result<Response<Self::WatchConnectionsStream>, Status> {
+        let selector = ConnectionSelector::from_message(request.into_inner())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+
+        let mut events = self.connection_events.subscribe();
+        let (changes, changes_rx) = mpsc::channel(WATCH_CHANGES_CAPACITY);
+        tokio::spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    event = events.recv() => match event {
+                        Ok(event) => event,
+                        Err(RecvError::Lagged(missed)) => {
+                            warn!(missed, "a connection watcher is falling behind");
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    },
+                    _ = changes.closed() => break,
+                };
+                if !selector.matches_key(&event.backend_key, &event.backend) {
+                    continue;
+                }
+                if changes.send(Ok(connection_change(&event))).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(changes_rx)))
+    }
+
+    async fn flush_connections(
+        &self,
+        request: Request<ConnectionsFilter>,
+    ) -> Result<Response<Confirmation>, Status> {
+        authorize(&request)?;
+        let selector = ConnectionSelector::from_message(request.into_inner())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        if selector.vip.is_none() && selector.backend.is_none() {
+            return Err(Status::invalid_argument("missing vip or target"));
+        }
+
+        match self.flush(&selector).await {
+            Ok(flushed) => Ok(Response::new(Confirmation {
+                confirmation: format!("success, {} connections were flushed", flushed),
+            })),
+            Err(err) => Err(Status::internal(format!("failure: {}", err))),
+        }
+    }
+
+    async fn export_connections(
+        &self,
+        request: Request<ConnectionsFilter>,
+    ) -> Result<Response<ConntrackSnapshot>, Status> {
+        let selector = ConnectionSelector::from_message(request.into_inner())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+
+        match self.export(&selector).await {
+            Ok(snapshot) => Ok(Response::new(snapshot)),
+            Err(err) => Err(Status::internal(format!("failure: {}", err))),
+        }
+    }
+
+    async fn import_connections(
+        &self,
+        request: Request<ConntrackSnapshot>,
+    ) -> Result<Response<Confirmation>, Status> {
+        authorize(&request)?;
+        let snapshot = request.into_inner();
+        let total = snapshot.tcp.len() + snapshot.udp.len();
+
+        match self.import(snapshot.tcp, snapshot.udp).await {
+            Ok(imported) => Ok(Response::new(Confirmation {
+                confirmation: format!(
+                    "success, {} of {} connections were imported",
+                    imported, total
+                ),
+            })),
+            Err(err) => Err(Status::internal(format!("failure: {}", err))),
+        }
+    }
+
+    async fn sync_connections(
+        &self,
+        request: Request<Streaming<ConntrackUpdate>>,
+    ) -> Result<Response<Confirmation>, Status> {
+        authorize(&request)?;
+        let mut updates = request.into_inner();
+        let mut opened = 0;
+        let mut closed = 0;
+        while let Some(update) = updates.message().await? {
+            opened += self
+                .import(update.tcp_opened, update.udp_opened)
+                .await
+                .map_err(|err| Status::internal(format!("failure: {}", err)))?;
+            closed += self
+                .remove_closed(update.tcp_closed, update.udp_closed)
+                .await
+                .map_err(|err| Status::internal(format!("failure: {}", err)))?;
+        }
+        Ok(Response::new(Confirmation {
+            confirmation: format!(
+                "success, {} connections were opened and {} closed",
+                opened, closed
+            ),
+        }))
+    }
+
+    async fn sync(
+        &self,
+        request: Request<Streaming<DesiredState>>,
+    ) -> Result<Response<Self::SyncStream>, Status> {
+        authorize(&request)?;
+        let mut states = request.into_inner();
+        let (acks, acks_rx) = mpsc::channel(SYNC_ACKS_CAPACITY);
+        let service = self.clone();
+        tokio::spawn(async move {
+            if service.generation.lock().await.is_none() {
+                let ack = StateAck {
+                    generation: 0,
+                    error: None,
+                    resync: true,
+                };
+                if acks.send(Ok(ack)).await.is_err() {
+                    return;
+                }
+            }
+            loop {
+                let state = match states.message().await {
+                    Ok(Some(state)) => state,
+                    Ok(None) => return,
+                    Err(status) => {
+                        debug!(%status, "sync stream closed");
+                        return;
+                    }
+                };
+                let ack = service.apply_state(state).await;
+                if acks.send(Ok(ack)).await.is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(acks_rx)))
+    }
+
+    async fn get_backend_stats(
+        &self,
+        request: Request<Vip>,
+    ) -> Result<Response<BackendStatsList>, Status> {
+        let vip = request.into_inner();
+
+        let vip_addr = ip_from_message(vip.ip, vip.ipv6.as_deref())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let key = BackendKey {
+            ip: ip_to_words(vip_addr),
+            port: vip.port,
+        };
+
+        let backend_list = match self.backends_map.lock().await.get(&key, 0) {
+            Ok(backend_list) => backend_list,
+            Err(err) if is_key_not_found(&err) => {
+                return Err(Status::not_found(format!(
+                    "vip {}:{} does not exist",
+                    vip_addr, vip.port
+                )))
+            }
+            Err(err) => return Err(Status::internal(format!("failure: {}", err))),
+        };
+        match self.backend_stats(&backend_list).await {
+            Ok(backends) => Ok(Response::new(BackendStatsList { backends })),
+            Err(err) => Err(Status::internal(format!("failure: {}", err))),
+        }
+    }
+
+    async fn get_dataplane_info(
+        &self,
+        _request: Request<DataplaneInfoRequest>,
+    ) -> Result<Response<DataplaneInfo>, Status> {
+        let info = match self.flow_table.info().await {
+            Ok(info) => info,
+            Err(err) => return Err(Status::internal(format!("failure: {}", err))),
+        };
+        Ok(Response::new(DataplaneInfo {
+            flow_table: Some(FlowTableInfo {
+                entries: info.entries,
+                capacity: info.capacity,
+                inserts: info.inserts,
+                insert_failures: info.insert_failures,
+                removals: info.removals,
+                evictions: info.evictions,
+                insert_rate: info.insert_rate,
+                removal_rate: info.removal_rate,
+            }),
+        }))
+    }
+
+    async fn get_capabilities(
+        &self,
+        _request: Request<CapabilitiesRequest>,
+    ) -> Result<Response<Capabilities>, Status> {
+        Ok(Response::new(Capabilities {
+            api_version: API_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            features: FEATURES.iter().map(|feature| *feature as i32).collect(),
+            algorithms: ALGORITHMS
+                .iter()
+                .map(|algorithm| *algorithm as i32)
+                .collect(),
+            token_required: self.token_required,
+        }))
+    }
+
+    async fn capture_packets(
+        &self,
+        request: Request<CaptureRequest>,
+    ) -> Result<Response<Self::CapturePacketsStream>, Status> {
+        authorize(&request)?;
+        let capture = request.into_inner();
+        let vip = match capture.vip {
+            Some(vip) => vip,
+            None => return Err(Status::invalid_argument("missing vip ip")),
+        };
+        let vip_addr = ip_from_message(vip.ip, vip.ipv6.as_deref())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let key = BackendKey {
+            ip: ip_to_words(vip_addr),
+            port: vip.port,
+        };
+        match self.backends_map.lock().await.get(&key, 0) {
+            Ok(_) => {}
+            Err(err) if is_key_not_found(&err) => {
+                return Err(Status::not_found(format!(
+                    "vip {}:{} does not exist",
+                    vip_addr, vip.port
+                )))
+            }
+            Err(err) => return Err(Status::internal(format!("failure: {}", err))),
+        }
+
+        let max_packets = match capture.max_packets {
+            0 => MAX_CAPTURE_PACKETS,
+            max_packets => max_packets.min(MAX_CAPTURE_PACKETS),
+        };
+        let duration = match capture.duration_secs {
+            0 => MAX_CAPTURE_DURATION,
+            secs => Duration::from_secs(secs.into()).min(MAX_CAPTURE_DURATION),
+        };
+        let mut pcap = match capture.pcap_file {
+            Some(name) => {
+                let path = pcap_path(self.capture_dir.as_deref(), &name)?;
+                Some(PcapWriter::create(&path).map_err(|err| {
+                    Status::invalid_argument(format!("failed to create {}: {}", name, err))
+                })?)
+            }
+            None => None,
+        };
+        let mut packets = match self.captures.start(key).await {
+            Ok(Some(packets)) => packets,
+            Ok(None) => {
+                return Err(Status::already_exists(format!(
+                    "vip {}:{} is already being captured",
+                    vip_addr, vip.port
+                )))
+            }
+            Err(err) => return Err(Status::internal(format!("failure: {}", err))),
+        };
+
+        let (frames, frames_rx) = mpsc::channel(CAPTURE_FRAMES_CAPACITY);
+        let captures = self.captures.clone();
+        tokio::spawn(async move {
+            let deadline = tokio::time::sleep(duration);
+            tokio::pin!(deadline);
+            let mut captured = 0;
+            while captured < max_packets {
+                // The capture goes on into the pcap file once the client is gone.
+                let packet = tokio::select! {
+                    packet = packets.recv() => match packet {
+                        Some(packet) => packet,
+                        None => break,
+                    },
+                    _ = &mut deadline => break,
+                    _ = frames.closed(), if pcap.is_none() => break,
+                };
+                captured += 1;
+                let timestamp = captured_at_unix_ns(&packet).unwrap_or_default();
+                let failed = match &mut pcap {
+                    Some(pcap) => pcap.write(&packet, timestamp).err(),
+                    None => None,
+                };
+                if let Some(err) = failed {
+                    warn!(
+                        vip = %format_backend(&key),
+                        error = %err,
+                        "failed to write a captured packet, stopping the pcap file"
+                    );
+                    pcap = None;
+                }
+                let frame = CapturedFrame {
+                    timestamp,
+                    len: packet.len,
+                    data: captured_data(&packet).to_vec(),
+                };
+                let _ = frames.send(Ok(frame)).await;
+            }
+            if let Some(mut pcap) = pcap {
+                if let Err(err) = pcap.flush() {
+                    warn!(
+                        vip = %format_backend(&key),
+                        error = %err,
+                        "failed to write the pcap file"
+                    );
+                }
+            }
+            if let Err(err) = captures.stop(&key).await {
+                warn!(vip = %format_backend(&key), error = %err, "failed to stop capturing");
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(frames_rx)))
+    }
+
+    async fn set_acl(
+        &self,
+        request: Request<backends::Acl>,
+    ) -> Result<Response<Confirmation>, Status> {
+        authorize(&request)?;
+        let acl = request.into_inner();
+        let vip = match acl.vip {
+            Some(vip) => vip,
+            None => return Err(Status::invalid_argument("missing vip ip")),
+        };
+        let vip_addr = ip_from_message(vip.ip, vip.ipv6.as_deref())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let vip_ip = ip_to_words(vip_addr);
+
+        let mut rules = Vec::new();
+        for rule in &acl.rules {
+            let action = match backends::AclAction::try_from(rule.action) {
+                Ok(backends::AclAction::Allow) => AclAction::Allow,
+                Ok(backends::AclAction::Deny) => AclAction::Deny,
+                Err(_) => {
+                    return Err(Status::invalid_argument(format!(
+                        "unknown acl action {}",
+                        rule.action
+                    )))
+                }
+            };
+            rules.push((acl_key(vip_ip, rule)?, action));
+        }
+
+        match self.replace_acl(vip_ip, &rules).await {
+            Ok(()) => Ok(Response::new(Confirmation {
+                confirmation: format!(
+                    "success, vip {} acl was set with {} rules",
+                    vip_addr,
+                    rules.len()
+                ),
+            })),
+            Err(err) => Err(Status::internal(format!("failure: {}", err))),
+        }
+    }
+
+    async fn set_syn_cookies(
+        &self,
+        request: Request<backends::SynCookies>,
+    ) -> Result<Response<Confirmation>, Status> {
+        authorize(&request)?;
+        let syn_cookies = request.into_inner();
+        let vip = match syn_cookies.vip {
+            Some(vip) => vip,
+            None => return Err(Status::invalid_argument("missing vip ip and port")),
+        };
+
+        let vip_addr = ip_from_message(vip.ip, vip.ipv6.as_deref())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let key = BackendKey {
+            ip: ip_to_words(vip_addr),
+            port: vip.port,
+        };
+
+        // The setting goes away with the VIP, so it can't be set before it.
+        match self.backends_map.lock().await.get(&key, 0) {
+            Ok(_) => {}
+            Err(err) if is_key_not_found(&err) => {
+                return Err(Status::not_found(format!(
+                    "vip {}:{} does not exist",
+                    vip_addr, vip.port
+                )))
+            }
+            Err(err) => return Err(Status::internal(format!("failure: {}", err))),
+        }
+
+        let mut syn_cookies_map = self.syn_cookies_map.lock().await;
+        let result = match syn_cookies.enabled {
+            true => syn_cookies_map.insert(key, 1, 0),
+            false => remove_if_present(&mut syn_cookies_map, &key),
+        };
+        match result {
+            Ok(()) => Ok(Response::new(Confirmation {
+                confirmation: format!(
+                    "success, vip {}:{} syn cookies were turned {}",
+                    vip_addr,
+                    vip.port,
+                    if syn_cookies.enabled { "on" } else { "off" }
+                ),
+            })),
+            Err(err) => Err(Status::internal(format!("failure: {}", err))),
+        }
+    }
+
+    async fn set_dscp_marking(
+        &self,
+        request: Request<backends::DscpMarking>,
+    ) -> Result<Response<Confirmation>, Status> {
+        authorize(&request)?;
+        let marking = request.into_inner();
+        let vip = match marking.vip {
+            Some(vip) => vip,
+            None => return Err(Status::invalid_argument("missing vip ip and port")),
+        };
+        let dscp = match marking.dscp {
+            Some(dscp) if dscp > MAX_DSCP as u32 => {
+                return Err(Status::invalid_argument(format!("invalid dscp {}", dscp)))
+            }
+            dscp => dscp.map(|dscp| dscp as u8),
+        };
+
+        let vip_addr = ip_from_message(vip.ip, vip.ipv6.as_deref())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let key = BackendKey {
+            ip: ip_to_words(vip_addr),
+            port: vip.port,
+        };
+
+        // The marking goes away with the VIP, so it can't be set before it.
+        match self.backends_map.lock().await.get(&key, 0) {
+            Ok(_) => {}
+            Err(err) if is_key_not_found(&err) => {
+                return Err(Status::not_found(format!(
+                    "vip {}:{} does not exist",
+                    vip_addr, vip.port
+                )))
+            }
+            Err(err) => return Err(Status::internal(format!("failure: {}", err))),
+        }
+
+        let mut dscp_marks_map = self.dscp_marks_map.lock().await;
+        let result = match dscp {
+            Some(dscp) => dscp_marks_map.insert(key, dscp, 0),
+            None => remove_if_present(&mut dscp_marks_map, &key),
+        };
+        match result {
+            Ok(()) => Ok(Response::new(Confirmation {
+                confirmation: match dscp {
+                    Some(dscp) => format!(
+                        "success, vip {}:{} packets are marked with dscp {}",
+                        vip_addr, vip.port, dscp
+                    ),
+                    None => format!(
+                        "success, vip {}:{} packets are no longer marked",
+                        vip_addr, vip.port
+                    ),
+                },
+            })),
+            Err(err) => Err(Status::internal(format!("failure: {}", err))),
+        }
+    }
+
+    async fn set_mirror(
+        &self,
+        request: Request<backends::Mirror>,
+    ) -> Result<Response<Confirmation>, Status> {
+        authorize(&request)?;
+        let mirror = request.into_inner();
+        let vip = match mirror.vip {
+            Some(vip) => vip,
+            None => return Err(Status::invalid_argument("missing vip ip and port")),
+        };
+        let mac: Option<[u8; 6]> = match mirror.mac.as_deref() {
+            Some(mac) => Some(mac.try_into().map_err(|_| {
+                Status::invalid_argument("mirror MAC address must be 6 bytes long")
+            })?),
+            None => None,
+        };
+        let ifindex = match u16::try_from(mirror.ifindex) {
+            Ok(ifindex) if ifindex != 0 || mac.is_none() => ifindex,
+            _ => {
+                return Err(Status::invalid_argument(format!(
+                    "invalid mirror ifindex {}",
+                    mirror.ifindex
+                )))
+            }
+        };
+
+        let vip_addr = ip_from_message(vip.ip, vip.ipv6.as_deref())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let key = BackendKey {
+            ip: ip_to_words(vip_addr),
+            port: vip.port,
+        };
+
+        // The mirror goes away with the VIP, so it can't be set before it.
+        match self.backends_map.lock().await.get(&key, 0) {
+            Ok(_) => {}
+            Err(err) if is_key_not_found(&err) => {
+                return Err(Status::not_found(format!(
+                    "vip {}:{} does not exist",
+                    vip_addr, vip.port
+                )))
+            }
+            Err(err) => return Err(Status::internal(format!("failure: {}", err))),
+        }
+
+        let mut mirrors_map = self.mirrors_map.lock().await;
+        let result = match mac {
+            Some(mac) => mirrors_map.insert(key, Mirror { mac, ifindex }, 0),
+            None => remove_if_present(&mut mirrors_map, &key),
+        };
+        match result {
+            Ok(()) => Ok(Response::new(Confirmation {
+                confirmation: match mac {
+                    Some(_) => format!(
+                        "success, vip {}:{} packets are mirrored to ifindex {}",
+                        vip_addr, vip.port, ifindex
+                    ),
+                    None => format!(
+                        "success, vip {}:{} packets are no longer mirrored",
+                        vip_addr, vip.port
+                    ),
+                },
+            })),
+            Err(err) => Err(Status::internal(format!("failure: {}", err))),
+        }
+    }
+
+    async fn set_tproxy(
+        &self,
+        request: Request<backends::Tproxy>,
+    ) -> Result<Response<Confirmation>, Status> {
+        authorize(&request)?;
+        let tproxy = request.into_inner();
+        let vip = match tproxy.vip {
+            Some(vip) => vip,
+            None => return Err(Status::invalid_argument("missing vip ip and port")),
+        };
+        let port = match tproxy.port {
+            Some(port) => match u16::try_from(port) {
+                Ok(port) if port != 0 => Some(port),
+                _ => {
+                    return Err(Status::invalid_argument(format!(
+                        "invalid proxy port {}",
+                        port
+                    )))
+                }
+            },
+            None => None,
+        };
+
+        let vip_addr = ip_from_message(vip.ip, vip.ipv6.as_deref())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let key = BackendKey {
+            ip: ip_to_words(vip_addr),
+            port: vip.port,
+        };
+
+        // The proxy goes away with the VIP, so it can't be set before it.
+        match self.backends_map.lock().await.get(&key, 0) {
+            Ok(_) => {}
+            Err(err) if is_key_not_found(&err) => {
+                return Err(Status::not_found(format!(
+                    "vip {}:{} does not exist",
+                    vip_addr, vip.port
+                )))
+            }
+            Err(err) => return Err(Status::internal(format!("failure: {}", err))),
+        }
+
+        let mut tproxy_ports_map = self.tproxy_ports_map.lock().await;
+        let result = match port {
+            Some(port) => tproxy_ports_map.insert(key, port, 0),
+            None => remove_if_present(&mut tproxy_ports_map, &key),
+        };
+        match result {
+            Ok(()) => Ok(Response::new(Confirmation {
+                confirmation: match port {
+                    Some(port) => format!(
+                        "success, vip {}:{} connections are handed to the proxy on port {}",
+                        vip_addr, vip.port, port
+                    ),
+                    None => format!(
+                        "success, vip {}:{} connections are no longer handed to a proxy",
+                        vip_addr, vip.port
+                    ),
+                },
+            })),
+            Err(err) => Err(Status::internal(format!("failure: {}", err))),
+        }
+    }
+
+    async fn set_ping_forwarding(
+        &self,
+        request: Request<backends::PingForwarding>,
+    ) -> Result<Response<Confirmation>, Status> {
+        authorize(&request)?;
+        let forwarding = request.into_inner();
+        let vip = match forwarding.vip {
+            Some(vip) => vip,
+            None => return Err(Status::invalid_argument("missing vip ip and port")),
+        };
+
+        let vip_addr = ip_from_message(vip.ip, vip.ipv6.as_deref())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let key = BackendKey {
+            ip: ip_to_words(vip_addr),
+            port: vip.port,
+        };
+
+        // The forwarding goes away with the VIP, so it can't be turned on before it.
+        match self.backends_map.lock().await.get(&key, 0) {
+            Ok(_) => {}
+            Err(err) if is_key_not_found(&err) => {
+                return Err(Status::not_found(format!(
+                    "vip {}:{} does not exist",
+                    vip_addr, vip.port
+                )))
+            }
+            Err(err) => return Err(Status::internal(format!("failure: {}", err))),
+        }
+
+        // The address forwards its pings to a single VIP, which only stops it itself.
+        let mut ping_gateways_map = self.ping_gateways_map.lock().await;
+        let result = match forwarding.enabled {
+            true => ping_gateways_map.insert(key.ip, key, 0),
+            false => match ping_gateways_map.get(&key.ip, 0) {
+                Ok(current) if current == key => ping_gateways_map.remove(&key.ip),
+                Ok(_) => Ok(()),
+                Err(err) if is_key_not_found(&err) => Ok(()),
+                Err(err) => Err(err),
+            },
+        };
+        match result {
+            Ok(()) => Ok(Response::new(Confirmation {
+                confirmation: format!(
+                    "success, vip {}:{} pings {}",
+                    vip_addr,
+                    vip.port,
+                    if forwarding.enabled {
+                        "are forwarded to its targets"
+                    } else {
+                        "are no longer forwarded to its targets"
+                    }
+                ),
+            })),
+            Err(err) => Err(Status::internal(format!("failure: {}", err))),
+        }
+    }
+
+    async fn set_target_node(
+        &self,
+        request: Request<backends::TargetNode>,
+    ) -> Result<Response<Confirmation>, Status> {
+        authorize(&request)?;
+        let node = request.into_inner();
+        let target_addr = ip_from_message(node.daddr, node.daddr_ipv6.as_deref())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let node_addr = ip_from_message(node.node_ip, node.node_ipv6.as_deref())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let key = ip_to_words(target_addr);
+
+        let mut tunnels_map = self.tunnels_map.lock().await;
+        if node_addr.is_unspecified() {
+            return match remove_if_present(&mut tunnels_map, &key) {
+                Ok(()) => Ok(Response::new(Confirmation {
+                    confirmation: format!("success, target {} node was removed", target_addr),
+                })),
+                Err(err) => Err(Status::internal(format!("failure: {}", err))),
+            };
+        }
+
+        // The packets keep their IP family through the tunnel.
+        if node_addr.is_ipv4() != target_addr.is_ipv4() {
+            return Err(Status::invalid_argument(format!(
+                "node {} is not of the IP family of target {}",
+                node_addr, target_addr
+            )));
+        }
+        let (encapsulation, default_port) =
+            match backends::Encapsulation::try_from(node.encapsulation) {
+                Ok(backends::Encapsulation::Geneve) => (Encapsulation::Geneve, GENEVE_PORT),
+                Ok(backends::Encapsulation::Gue) => (Encapsulation::Gue, GUE_PORT),
+                Err(_) => {
+                    return Err(Status::invalid_argument(format!(
+                        "unknown encapsulation {}",
+                        node.encapsulation
+                    )))
+                }
+            };
+        let port = match node.port {
+            Some(port) => match u16::try_from(port) {
+                Ok(port) if port != 0 => port,
+                _ => {
+                    return Err(Status::invalid_argument(format!(
+                        "invalid tunnel port {}",
+                        port
+                    )))
+                }
+            },
+            None => default_port,
+        };
+
+        let tunnel = Tunnel {
+            node_addr: ip_to_words(node_addr),
+            encapsulation,
+            port,
+        };
+        match tunnels_map.insert(key, tunnel, 0) {
+            Ok(()) => Ok(Response::new(Confirmation {
+                confirmation: format!(
+                    "success, target {} node was set to {}:{} with {:?}",
+                    target_addr, node_addr, port, encapsulation
+                ),
+            })),
+            Err(err) => Err(Status::internal(format!("failure: {}", err))),
+        }
+    }
+
+    async fn set_log_level(
+        &self,
+        request: Request<backends::DatapathLogging>,
+    ) -> Result<Response<Confirmation>, Status> {
+        authorize(&request)?;
+        let logging = request.into_inner();
+        let level = match backends::LogLevel::try_from(logging.level) {
+            Ok(backends::LogLevel::Off) => LogLevel::Off,
+            Ok(backends::LogLevel::Error) => LogLevel::Error,
+            Ok(backends::LogLevel::Warn) => LogLevel::Warn,
+            Ok(backends::LogLevel::Info) => LogLevel::Info,
+            Ok(backends::LogLevel::Debug) => LogLevel::Debug,
+            Err(_) => {
+                return Err(Status::invalid_argument(format!(
+                    "unknown log level {}",
+                    logging.level
+                )))
+            }
+        };
+
+        match self.log_level_map.lock().await.set(0, level, 0) {
+            Ok(()) => Ok(Response::new(Confirmation {
+                confirmation: format!("success, datapath log level was set to {:?}", level),
+            })),
+            Err(err) => Err(Status::internal(format!("failure: {}", err))),
+        }
+    }
+
+    async fn set_tcp_timeouts(
+        &self,
+        request: Request<backends::TcpTimeouts>,
+    ) -> Result<Response<Confirmation>, Status> {
+        authorize(&request)?;
+        let timeouts = request.into_inner();
+        let mut tcp_timeouts_map = self.tcp_timeouts_map.lock().await;
+        let mut tcp_timeouts = match tcp_timeouts_map.get(&0, 0) {
+            Ok(tcp_timeouts) => tcp_timeouts,
+            Err(err) => return Err(Status::internal(format!("failure: {}", err))),
+        };
+        for (timeout, secs) in [
+            (&mut tcp_timeouts.syn_sent, timeouts.syn_sent),
+            (&mut tcp_timeouts.established, timeouts.established),
+            (&mut tcp_timeouts.fin_wait, timeouts.fin_wait),
+            (&mut tcp_timeouts.time_wait, timeouts.time_wait),
+        ] {
+            if let Some(secs) = secs {
+                *timeout = Duration::from_secs(secs.into()).as_nanos() as u64;
+            }
+        }
+
+        match tcp_timeouts_map.set(0, tcp_timeouts, 0) {
+            Ok(()) => Ok(Response::new(Confirmation {
+                confirmation: format!(
+                    "success, TCP timeouts were set to {}s SYN_SENT, {}s ESTABLISHED, \
+                     {}s FIN_WAIT, {}s TIME_WAIT",
+                    Duration::from_nanos(tcp_timeouts.syn_sent).as_secs(),
+                    Duration::from_nanos(tcp_timeouts.established).as_secs(),
+                    Duration::from_nanos(tcp_timeouts.fin_wait).as_secs(),
+                    Duration::from_nanos(tcp_timeouts.time_wait).as_secs(),
+                ),
+            })),
+            Err(err) => Err(Status::internal(format!("failure: {}", err))),
+        }
+    }
+
+    async fn set_connection_limit(
+        &self,
+        request: Request<backends::ConnectionLimit>,
+    ) -> Result<Response<Confirmation>, Status> {
+        authorize(&request)?;
+        let limit = request.into_inner();
+        let vip = match limit.vip {
+            Some(vip) => vip,
+            None => return Err(Status::invalid_argument("missing vip ip and port")),
+        };
+        let action = match backends::LimitAction::try_from(limit.action) {
+            Ok(backends::LimitAction::Drop) => LimitAction::Drop,
+            Ok(backends::LimitAction::Reset) => LimitAction::Reset,
+            Err(_) => {
+                return Err(Status::invalid_argument(format!(
+                    "unknown limit action {}",
+                    limit.action
+                )))
+            }
+        };
+
+        let vip_addr = ip_from_message(vip.ip, vip.ipv6.as_deref())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let key = BackendKey {
+            ip: ip_to_words(vip_addr),
+            port: vip.port,
+        };
+
+        // The limit goes away with the VIP, so it can't be set before it.
+        match self.backends_map.lock().await.get(&key, 0) {
+            Ok(_) => {}
+            Err(err) if is_key_not_found(&err) => {
+                return Err(Status::not_found(format!(
+                    "vip {}:{} does not exist",
+                    vip_addr, vip.port
+                )))
+            }
+            Err(err) => return Err(Status::internal(format!("failure: {}", err))),
+        }
+
+        let mut connection_limits_map = self.connection_limits_map.lock().await;
+        let result = match limit.max_connections {
+            0 => remove_if_present(&mut connection_limits_map, &key),
+            max_connections => connection_limits_map.insert(
+                key,
+                ConnectionLimit {
+                    max_connections: max_connections.into(),
+                    action,
+                },
+                0,
+            ),
+        };
+        match result {
+            Ok(()) => Ok(Response::new(Confirmation {
+                confirmation: format!(
+                    "success, vip {}:{} connection limit was set to {}",
+                    vip_addr, vip.port, limit.max_connections
+                ),
+            })),
+            Err(err) => Err(Status::internal(format!("failure: {}", err))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Code;
+
+    use super::*;
+
+    #[test]
+    fn weights_which_fit_are_kept() {
+        assert_eq!(scale_weight(0, 100), 0);
+        assert_eq!(scale_weight(100, 100), 100);
+        assert_eq!(scale_weight(65535, 65535), 65535);
+    }
+
+    #[test]
+    fn weights_which_overflow_are_scaled_in_proportion() {
+        let max_weight = 4 * 65535;
+
+        assert_eq!(scale_weight(max_weight, max_weight), 65535);
+        assert_eq!(scale_weight(max_weight / 2, max_weight), 32767);
+        assert_eq!(scale_weight(4, max_weight), 1);
+    }
+
+    #[test]
+    fn scaled_weights_keep_their_targets_in_rotation() {
+        let max_weight = u32::MAX;
+
+        assert_eq!(scale_weight(1, max_weight), 1);
+        assert_eq!(scale_weight(0, max_weight), 0);
+        assert_eq!(scale_weight(max_weight, max_weight), 65535);
+    }
+
+    #[test]
+    fn pcap_files_are_written_in_the_capture_directory() {
+        let path = pcap_path(Some(Path::new("/var/lib/blixt/captures")), "vip.pcap").unwrap();
+
+        assert_eq!(path, Path::new("/var/lib/blixt/captures/vip.pcap"));
+    }
+
+    #[test]
+    fn pcap_files_outside_the_capture_directory_are_rejected() {
+        let capture_dir = Some(Path::new("/var/lib/blixt/captures"));
+        for name in ["/etc/shadow", "../vip.pcap", "dir/vip.pcap", "..", ".", ""] {
+            let status = pcap_path(capture_dir, name).unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument, "{}", name);
+        }
+    }
+
+    #[test]
+    fn pcap_files_are_rejected_without_capture_directory() {
+        let status = pcap_path(None, "vip.pcap").unwrap_err();
+
+        assert_eq!(status.code(), Code::FailedPrecondition);
+    }
 }
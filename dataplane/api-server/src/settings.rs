@@ -0,0 +1,64 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::sync::Arc;
+
+use anyhow::Error;
+use aya::maps::{Array, MapData};
+use tokio::sync::{mpsc, Mutex};
+use tracing::{info, warn};
+
+use common::{Config, LogLevel, TcpTimeouts};
+
+/// The settings of the datapath which are applied while it runs, e.g. when
+/// the configuration file of the dataplane is reloaded.
+#[derive(Clone, Copy, Debug)]
+pub struct DatapathSettings {
+    pub config: Config,
+    pub log_level: LogLevel,
+    pub tcp_timeouts: TcpTimeouts,
+}
+
+/// Applies the settings of the datapath as they come, replacing those set
+/// through the API. The secret of the SYN cookies is kept, so that the cookies
+/// sent already stay valid. Runs until the settings stop coming.
+pub async fn apply_settings(
+    mut settings: mpsc::Receiver<DatapathSettings>,
+    mut config_map: Array<MapData, Config>,
+    log_level_map: Arc<Mutex<Array<MapData, LogLevel>>>,
+    tcp_timeouts_map: Arc<Mutex<Array<MapData, TcpTimeouts>>>,
+) {
+    while let Some(settings) = settings.recv().await {
+        match apply(
+            &settings,
+            &mut config_map,
+            &log_level_map,
+            &tcp_timeouts_map,
+        )
+        .await
+        {
+            Ok(()) => info!("applied the settings of the datapath"),
+            Err(err) => warn!(error = %err, "failed to apply the settings of the datapath"),
+        }
+    }
+}
+
+async fn apply(
+    settings: &DatapathSettings,
+    config_map: &mut Array<MapData, Config>,
+    log_level_map: &Mutex<Array<MapData, LogLevel>>,
+    tcp_timeouts_map: &Mutex<Array<MapData, TcpTimeouts>>,
+) -> Result<(), Error> {
+    let mut config = settings.config;
+    config.syn_cookie_secret = config_map.get(&0, 0)?.syn_cookie_secret;
+    config_map.set(0, config, 0)?;
+    log_level_map.lock().await.set(0, settings.log_level, 0)?;
+    tcp_timeouts_map
+        .lock()
+        .await
+        .set(0, settings.tcp_timeouts, 0)?;
+    Ok(())
+}
@@ -0,0 +1,124 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Error;
+use aya::maps::{HashMap, MapData, PerCpuHashMap};
+
+use crate::conntrack::monotonic_now_ns;
+use crate::server::is_key_not_found;
+use common::{BackendConnections, BackendKey, BackendTraffic, SynLatency};
+
+/// The statistics of a backend, summed over all CPUs.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BackendStats {
+    pub active_connections: u64,
+    pub total_connections: u64,
+    pub traffic: BackendTraffic,
+    /// When the backend was last assigned a connection, in nanoseconds since
+    /// boot, or 0 if it never was.
+    pub last_opened: u64,
+}
+
+/// Returns the traffic of every backend which has been forwarded a packet, as
+/// counted by the datapath on all CPUs.
+pub fn backend_traffic(
+    backend_traffic_map: &PerCpuHashMap<MapData, BackendKey, BackendTraffic>,
+) -> Result<Vec<(BackendKey, BackendTraffic)>, Error> {
+    let mut traffic = Vec::new();
+    for item in backend_traffic_map.iter() {
+        let (key, per_cpu_counters) = item?;
+        let total = per_cpu_counters
+            .iter()
+            .fold(BackendTraffic::default(), |total, counters| {
+                BackendTraffic {
+                    forwarded_packets: total.forwarded_packets + counters.forwarded_packets,
+                    forwarded_bytes: total.forwarded_bytes + counters.forwarded_bytes,
+                    reply_packets: total.reply_packets + counters.reply_packets,
+                    reply_bytes: total.reply_bytes + counters.reply_bytes,
+                }
+            });
+        traffic.push((key, total));
+    }
+    Ok(traffic)
+}
+
+/// Returns the histogram of the SYN latencies of every backend which answered
+/// a SYN, summed over all CPUs.
+pub fn syn_latencies(
+    syn_latencies_map: &PerCpuHashMap<MapData, BackendKey, SynLatency>,
+) -> Result<Vec<(BackendKey, SynLatency)>, Error> {
+    let mut latencies = Vec::new();
+    for item in syn_latencies_map.iter() {
+        let (key, per_cpu_histograms) = item?;
+        let mut total = SynLatency::default();
+        for histogram in per_cpu_histograms.iter() {
+            for (count, bucket) in total.buckets.iter_mut().zip(histogram.buckets) {
+                *count += bucket;
+            }
+            total.sum_ns += histogram.sum_ns;
+        }
+        latencies.push((key, total));
+    }
+    Ok(latencies)
+}
+
+/// Returns the statistics of the backend, as counted by the datapath on all
+/// CPUs. Backends which were never assigned a connection have none.
+pub fn backend_stats(
+    key: &BackendKey,
+    backend_conns_map: &PerCpuHashMap<MapData, BackendKey, BackendConnections>,
+    released_conns_map: &HashMap<MapData, BackendKey, u64>,
+    backend_traffic_map: &PerCpuHashMap<MapData, BackendKey, BackendTraffic>,
+) -> Result<BackendStats, Error> {
+    let mut stats = BackendStats::default();
+
+    let mut closed = 0;
+    match backend_conns_map.get(key, 0) {
+        Ok(per_cpu_counters) => {
+            for counters in per_cpu_counters.iter() {
+                stats.total_connections += counters.opened;
+                closed += counters.closed;
+                stats.last_opened = stats.last_opened.max(counters.last_opened);
+            }
+        }
+        Err(err) if is_key_not_found(&err) => {}
+        Err(err) => return Err(err.into()),
+    }
+    let released = match released_conns_map.get(key, 0) {
+        Ok(released) => released,
+        Err(err) if is_key_not_found(&err) => 0,
+        Err(err) => return Err(err.into()),
+    };
+    stats.active_connections = stats
+        .total_connections
+        .saturating_sub(closed)
+        .saturating_sub(released);
+
+    match backend_traffic_map.get(key, 0) {
+        Ok(per_cpu_counters) => {
+            for counters in per_cpu_counters.iter() {
+                stats.traffic.forwarded_packets += counters.forwarded_packets;
+                stats.traffic.forwarded_bytes += counters.forwarded_bytes;
+                stats.traffic.reply_packets += counters.reply_packets;
+                stats.traffic.reply_bytes += counters.reply_bytes;
+            }
+        }
+        Err(err) if is_key_not_found(&err) => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    Ok(stats)
+}
+
+/// Returns the time in milliseconds since the Unix epoch of a time of the
+/// clock used by bpf_ktime_get_ns.
+pub fn ktime_to_unix_ms(ktime: u64) -> Result<u64, Error> {
+    let elapsed = monotonic_now_ns()?.saturating_sub(ktime);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64;
+    Ok(now.saturating_sub(elapsed) / 1_000_000)
+}
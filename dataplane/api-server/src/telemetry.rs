@@ -0,0 +1,170 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use anyhow::Error;
+use opentelemetry::metrics::{Counter, Histogram};
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::{FutureExt, Status as SpanStatus, TraceContextExt, Tracer};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use tonic::metadata::{KeyRef, MetadataMap};
+use tonic::Status;
+
+/// The name the dataplane reports its traces and metrics under.
+const SERVICE_NAME: &str = "blixt-dataplane";
+
+/// The gRPC service whose calls are traced, as named in the spans.
+const RPC_SERVICE: &str = "backends.backends";
+
+/// The instruments of the programming of the Gateways.
+struct Instruments {
+    // The changes applied, by operation and result.
+    changes: Counter<u64>,
+    // How long the changes took to apply, in seconds.
+    duration: Histogram<f64>,
+}
+
+static INSTRUMENTS: OnceLock<Instruments> = OnceLock::new();
+
+/// Exports the traces and metrics of the dataplane over OTLP/gRPC to the
+/// collector at `endpoint` (e.g. `http://localhost:4317`). The spans continue
+/// the traces of the control plane when its calls carry a W3C `traceparent`.
+/// Without it the spans and metrics are recorded by no-op providers.
+pub fn init(endpoint: &str) -> Result<(), Error> {
+    let resource = Resource::new([KeyValue::new("service.name", SERVICE_NAME)]);
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(trace::config().with_resource(resource.clone()))
+        .install_batch(runtime::Tokio)?;
+    let meter_provider = opentelemetry_otlp::new_pipeline()
+        .metrics(runtime::Tokio)
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_resource(resource)
+        .build()?;
+    global::set_meter_provider(meter_provider);
+    Ok(())
+}
+
+/// Returns the trace context the caller sent in the metadata of the request,
+/// or the current one.
+pub fn remote_context(metadata: &MetadataMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&MetadataExtractor(metadata)))
+}
+
+/// Runs the programming of a change of the Gateways requested by a call of
+/// `method` in a span continuing the trace of `parent`, and counts the change
+/// along with how long it took.
+pub async fn trace_programming<T>(
+    parent: Context,
+    method: &'static str,
+    programming: impl Future<Output = Result<T, Status>>,
+) -> Result<T, Status> {
+    let span = global::tracer(SERVICE_NAME)
+        .start_with_context(format!("{}/{}", RPC_SERVICE, method), &parent);
+    let cx = parent.with_span(span);
+    cx.span().set_attribute(KeyValue::new("rpc.system", "grpc"));
+    cx.span()
+        .set_attribute(KeyValue::new("rpc.service", RPC_SERVICE));
+    cx.span().set_attribute(KeyValue::new("rpc.method", method));
+
+    let start = Instant::now();
+    let result = programming.with_context(cx.clone()).await;
+    let outcome = match &result {
+        Ok(_) => "ok",
+        Err(status) => {
+            cx.span()
+                .set_status(SpanStatus::error(status.message().to_string()));
+            "error"
+        }
+    };
+    cx.span().end();
+
+    let instruments = instruments();
+    let attributes = [
+        KeyValue::new("method", method),
+        KeyValue::new("result", outcome),
+    ];
+    instruments.changes.add(1, &attributes);
+    instruments
+        .duration
+        .record(start.elapsed().as_secs_f64(), &attributes);
+    result
+}
+
+/// Runs a step of the programming of the Gateways in a child span of the
+/// current one.
+pub async fn trace_step<T>(
+    name: &'static str,
+    step: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    let cx = Context::current_with_span(global::tracer(SERVICE_NAME).start(name));
+    let result = step.with_context(cx.clone()).await;
+    if let Err(err) = &result {
+        cx.span().set_status(SpanStatus::error(err.to_string()));
+    }
+    cx.span().end();
+    result
+}
+
+/// Records the VIP a change is about on the current span.
+pub fn set_vip(ip: IpAddr, port: u32) {
+    Context::current()
+        .span()
+        .set_attribute(KeyValue::new("blixt.vip", format!("{}:{}", ip, port)));
+}
+
+fn instruments() -> &'static Instruments {
+    INSTRUMENTS.get_or_init(|| {
+        let meter = global::meter(SERVICE_NAME);
+        Instruments {
+            changes: meter
+                .u64_counter("blixt.programming.changes")
+                .with_description("Changes of the Gateways applied through the API.")
+                .init(),
+            duration: meter
+                .f64_histogram("blixt.programming.duration")
+                .with_description("Time taken to apply the changes of the Gateways.")
+                .with_unit(opentelemetry::metrics::Unit::new("s"))
+                .init(),
+        }
+    })
+}
+
+/// Reads the trace context from the metadata of a gRPC request.
+struct MetadataExtractor<'a>(&'a MetadataMap);
+
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .keys()
+            .filter_map(|key| match key {
+                KeyRef::Ascii(key) => Some(key.as_str()),
+                KeyRef::Binary(_) => None,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,48 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error};
+use tonic::transport::{Certificate, ClientTlsConfig, Identity, ServerTlsConfig};
+
+/// The PEM files the API is secured with mutual TLS by, typically mounted
+/// from a Secret. The clients have to present a certificate signed by the
+/// client CA, which the certificates of the peer dataplanes are verified
+/// against too.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub client_ca: PathBuf,
+}
+
+impl TlsConfig {
+    /// Returns the configuration of the API server, which only accepts the
+    /// clients presenting a certificate signed by the client CA.
+    pub fn server_config(&self) -> Result<ServerTlsConfig, Error> {
+        Ok(ServerTlsConfig::new()
+            .identity(self.identity()?)
+            .client_ca_root(Certificate::from_pem(read(&self.client_ca)?)))
+    }
+
+    /// Returns the configuration of the clients of the peer dataplanes, which
+    /// present the certificate of this dataplane.
+    pub fn client_config(&self) -> Result<ClientTlsConfig, Error> {
+        Ok(ClientTlsConfig::new()
+            .identity(self.identity()?)
+            .ca_certificate(Certificate::from_pem(read(&self.client_ca)?)))
+    }
+
+    fn identity(&self) -> Result<Identity, Error> {
+        Ok(Identity::from_pem(read(&self.cert)?, read(&self.key)?))
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, Error> {
+    fs::read(path).with_context(|| format!("failed to read {}", path.display()))
+}
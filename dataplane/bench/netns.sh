@@ -0,0 +1,112 @@
+#!/usr/bin/env bash
+# Copyright 2023 The Kubernetes Authors.
+#
+# SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+
+# Measures the throughput (bits and packets per second), the connections per second and the p99
+# latency of the dataplane between network namespaces, and appends them to a results file with the
+# commit they were measured at, so that changes can be compared:
+#
+#   client (10.90.0.2) <-> (10.90.0.1) blixt (10.91.0.1) <-> (10.91.0.2) backend
+#
+# The client reaches the backend through the VIP 10.92.0.1, which the loader of the blixt
+# namespace balances to the backend. It takes root, iperf3 and neper (tcp_rr and tcp_crr in the
+# PATH), and the release build of `make build.release`.
+#
+#   sudo -E ./bench/netns.sh [results file]
+
+set -euo pipefail
+
+cd "$(dirname "$0")/.."
+
+RESULTS=${1:-bench/results.tsv}
+DURATION=${DURATION:-10}
+VIP=10.92.0.1
+LOADER=target/release/loader
+BLIXTCTL=target/release/blixtctl
+
+for bin in iperf3 tcp_rr tcp_crr "$LOADER" "$BLIXTCTL"; do
+    if ! command -v "$bin" >/dev/null; then
+        echo "missing $bin" >&2
+        exit 1
+    fi
+done
+
+cleanup() {
+    kill "${PIDS[@]}" 2>/dev/null || true
+    wait 2>/dev/null || true
+    for ns in client blixt backend; do
+        ip netns del "bench-$ns" 2>/dev/null || true
+    done
+}
+PIDS=()
+trap cleanup EXIT
+
+for ns in client blixt backend; do
+    ip netns add "bench-$ns"
+    ip -n "bench-$ns" link set lo up
+done
+ip link add veth-client netns bench-client type veth peer name veth-client netns bench-blixt
+ip link add veth-backend netns bench-backend type veth peer name veth-backend netns bench-blixt
+ip -n bench-client addr add 10.90.0.2/24 dev veth-client
+ip -n bench-blixt addr add 10.90.0.1/24 dev veth-client
+ip -n bench-blixt addr add 10.91.0.1/24 dev veth-backend
+ip -n bench-backend addr add 10.91.0.2/24 dev veth-backend
+for link in bench-client:veth-client bench-blixt:veth-client bench-blixt:veth-backend \
+    bench-backend:veth-backend; do
+    ip -n "${link%%:*}" link set "${link#*:}" up
+done
+ip -n bench-client route add default via 10.90.0.1
+ip -n bench-backend route add default via 10.91.0.1
+ip netns exec bench-blixt sysctl -qw net.ipv4.ip_forward=1
+
+ip netns exec bench-blixt "$LOADER" --iface veth-client,veth-backend >/dev/null 2>&1 &
+PIDS+=($!)
+for _ in $(seq 50); do
+    ip netns exec bench-blixt "$BLIXTCTL" info >/dev/null 2>&1 && break
+    sleep 0.2
+done
+
+# iperf3 listens on 5201, neper on 12866 for its control connection and 12867 for the data.
+for port in 5201 12866 12867; do
+    ip netns exec bench-blixt "$BLIXTCTL" backend add --vip "$VIP:$port" \
+        --target "10.91.0.2:$port" >/dev/null
+done
+
+ip netns exec bench-backend iperf3 --server >/dev/null 2>&1 &
+PIDS+=($!)
+sleep 1
+
+client() {
+    ip netns exec bench-client "$@"
+}
+
+# Prints the value of a key of the key=value lines of neper.
+neper_value() {
+    sed -n "s/^$1=//p"
+}
+
+bps=$(client iperf3 --client "$VIP" --time "$DURATION" --json |
+    python3 -c 'import json, sys; print(int(json.load(sys.stdin)["end"]["sum_received"]["bits_per_second"]))')
+pps=$(client iperf3 --client "$VIP" --udp --length 64 --bitrate 0 --time "$DURATION" --json |
+    python3 -c 'import json, sys; s = json.load(sys.stdin)["end"]["sum"]; print(int(s["packets"] / s["seconds"]))')
+
+ip netns exec bench-backend tcp_crr --num-flows 8 --num-threads 2 >/dev/null 2>&1 &
+PIDS+=($!)
+sleep 1
+cps=$(client tcp_crr --client --host "$VIP" --num-flows 8 --num-threads 2 \
+    --test-length "$DURATION" | neper_value throughput)
+wait "${PIDS[-1]}" || true
+
+ip netns exec bench-backend tcp_rr >/dev/null 2>&1 &
+PIDS+=($!)
+sleep 1
+p99=$(client tcp_rr --client --host "$VIP" --test-length "$DURATION" --percentiles 99 |
+    neper_value latency_p99)
+wait "${PIDS[-1]}" || true
+
+if [ ! -s "$RESULTS" ]; then
+    printf 'commit\tdate\tbits_per_second\tpackets_per_second\tconnections_per_second\tp99_latency_seconds\n' >"$RESULTS"
+fi
+printf '%s\t%s\t%s\t%s\t%s\t%s\n' "$(git rev-parse --short HEAD)" "$(date -u +%FT%TZ)" \
+    "$bps" "$pps" "$cps" "$p99" | tee -a "$RESULTS"
@@ -0,0 +1,100 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::net::SocketAddr;
+
+use anyhow::Error;
+use api_server::backends::backends_client::BackendsClient;
+use api_server::backends::{ConnectionLimit, DatapathLogging, LimitAction, LogLevel, SynCookies};
+use clap::Parser;
+use tonic::transport::Channel;
+
+use crate::{authorized, vip_message};
+
+#[derive(Debug, Parser)]
+pub struct LogLevelOptions {
+    /// The verbosity: off, error, warn, info or debug.
+    #[clap(value_parser = parse_log_level)]
+    level: LogLevel,
+}
+
+#[derive(Debug, Parser)]
+pub struct SynCookiesOptions {
+    /// The VIP, as `ip:port`.
+    #[clap(long)]
+    vip: SocketAddr,
+    /// Whether the SYN cookies are on.
+    #[clap(long, action = clap::ArgAction::Set)]
+    enabled: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct ConnectionLimitOptions {
+    /// The VIP, as `ip:port`.
+    #[clap(long)]
+    vip: SocketAddr,
+    /// Most live connections of the VIP, 0 removing the limit.
+    #[clap(long)]
+    max_connections: u32,
+    /// Reset the new connections over the limit instead of dropping them.
+    #[clap(long, action)]
+    reset: bool,
+}
+
+pub async fn log_level(
+    client: &mut BackendsClient<Channel>,
+    token: Option<String>,
+    opts: LogLevelOptions,
+) -> Result<(), Error> {
+    let logging = DatapathLogging {
+        level: opts.level.into(),
+    };
+    let res = client.set_log_level(authorized(logging, &token)?).await?;
+    println!("{}", res.into_inner().confirmation);
+    Ok(())
+}
+
+pub async fn syn_cookies(
+    client: &mut BackendsClient<Channel>,
+    token: Option<String>,
+    opts: SynCookiesOptions,
+) -> Result<(), Error> {
+    let syn_cookies = SynCookies {
+        vip: Some(vip_message(opts.vip)),
+        enabled: opts.enabled,
+    };
+    let res = client
+        .set_syn_cookies(authorized(syn_cookies, &token)?)
+        .await?;
+    println!("{}", res.into_inner().confirmation);
+    Ok(())
+}
+
+pub async fn connection_limit(
+    client: &mut BackendsClient<Channel>,
+    token: Option<String>,
+    opts: ConnectionLimitOptions,
+) -> Result<(), Error> {
+    let action = match opts.reset {
+        true => LimitAction::Reset,
+        false => LimitAction::Drop,
+    };
+    let limit = ConnectionLimit {
+        vip: Some(vip_message(opts.vip)),
+        max_connections: opts.max_connections,
+        action: action.into(),
+    };
+    let res = client
+        .set_connection_limit(authorized(limit, &token)?)
+        .await?;
+    println!("{}", res.into_inner().confirmation);
+    Ok(())
+}
+
+fn parse_log_level(level: &str) -> Result<LogLevel, String> {
+    LogLevel::from_str_name(&level.to_uppercase())
+        .ok_or_else(|| format!("unknown log level {}", level))
+}
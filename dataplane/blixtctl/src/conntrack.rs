@@ -0,0 +1,83 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Context, Error};
+use api_server::backends::backends_client::BackendsClient;
+use api_server::backends::ConntrackSnapshot;
+use clap::Parser;
+use prost::Message;
+use tonic::transport::Channel;
+
+use crate::inspect::ConnectionsOptions;
+use crate::{authorized, read};
+
+#[derive(Debug, Parser)]
+pub enum ConntrackCommand {
+    /// Removes the connections and UDP flows to a VIP, or pinned to a target,
+    /// so that their packets are balanced again.
+    Flush(ConnectionsOptions),
+    /// Writes the connections and UDP flows to a VIP, or pinned to a target,
+    /// or all of them, to a file another dataplane can import.
+    Export(ExportOptions),
+    /// Adds the connections and UDP flows written to a file by export.
+    Import(ImportOptions),
+}
+
+#[derive(Debug, Parser)]
+pub struct ExportOptions {
+    #[clap(flatten)]
+    connections: ConnectionsOptions,
+    /// The file the connections are written to.
+    #[clap(long)]
+    file: PathBuf,
+}
+
+#[derive(Debug, Parser)]
+pub struct ImportOptions {
+    /// The file the connections are read from.
+    #[clap(long)]
+    file: PathBuf,
+}
+
+pub async fn conntrack(
+    client: &mut BackendsClient<Channel>,
+    token: Option<String>,
+    command: ConntrackCommand,
+) -> Result<(), Error> {
+    match command {
+        ConntrackCommand::Flush(opts) => {
+            let res = client
+                .flush_connections(authorized(opts.filter(), &token)?)
+                .await?;
+            println!("{}", res.into_inner().confirmation);
+        }
+        ConntrackCommand::Export(opts) => {
+            let snapshot = client
+                .export_connections(opts.connections.filter())
+                .await?
+                .into_inner();
+            fs::write(&opts.file, snapshot.encode_to_vec())
+                .with_context(|| format!("failed to write {}", opts.file.display()))?;
+            println!(
+                "exported {} connections and {} UDP flows to {}",
+                snapshot.tcp.len(),
+                snapshot.udp.len(),
+                opts.file.display()
+            );
+        }
+        ConntrackCommand::Import(opts) => {
+            let snapshot = ConntrackSnapshot::decode(read(&opts.file)?.as_slice())?;
+            let res = client
+                .import_connections(authorized(snapshot, &token)?)
+                .await?;
+            println!("{}", res.into_inner().confirmation);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,211 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::net::SocketAddr;
+
+use anyhow::Error;
+use api_server::backends::backends_client::BackendsClient;
+use api_server::backends::{
+    Algorithm, CapabilitiesRequest, Connection, ConnectionsFilter, DataplaneInfoRequest, Feature,
+    ListGatewaysRequest, Target, TcpState,
+};
+use clap::Parser;
+use tonic::transport::Channel;
+
+use crate::{join_addr, split_ip, vip_message};
+
+#[derive(Debug, Parser)]
+pub struct ConnectionsOptions {
+    /// Only the connections to this VIP, as `ip:port`.
+    #[clap(long)]
+    vip: Option<SocketAddr>,
+    /// Only the connections pinned to this target, as `ip:port`.
+    #[clap(long)]
+    target: Option<SocketAddr>,
+}
+
+impl ConnectionsOptions {
+    pub fn filter(&self) -> ConnectionsFilter {
+        ConnectionsFilter {
+            vip: self.vip.map(vip_message),
+            target: self.target.map(|target| {
+                let (daddr, daddr_ipv6) = split_ip(target.ip());
+                Target {
+                    daddr,
+                    dport: target.port().into(),
+                    daddr_ipv6,
+                    ..Default::default()
+                }
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+pub struct StatsOptions {
+    /// The VIP, as `ip:port`.
+    vip: SocketAddr,
+}
+
+pub async fn gateways(client: &mut BackendsClient<Channel>) -> Result<(), Error> {
+    let gateways = client
+        .list_gateways(ListGatewaysRequest {})
+        .await?
+        .into_inner()
+        .gateways;
+    for gateway in gateways {
+        let vip = gateway.vip.unwrap_or_default();
+        let algorithm = Algorithm::try_from(gateway.algorithm)
+            .map_or("UNKNOWN", |algorithm| algorithm.as_str_name());
+        println!(
+            "{} {}",
+            join_addr(vip.ip, vip.ipv6.as_deref(), vip.port)?,
+            algorithm
+        );
+        for target in gateway.targets {
+            let mut flags = Vec::new();
+            if target.local == Some(true) {
+                flags.push("local");
+            }
+            if target.drain {
+                flags.push("drain");
+            }
+            println!(
+                "  {} weight={} max_conns={} split_group={} {}",
+                join_addr(target.daddr, target.daddr_ipv6.as_deref(), target.dport)?,
+                target.weight.unwrap_or_default(),
+                target.max_conns.unwrap_or_default(),
+                target.split_group,
+                flags.join(" ")
+            );
+        }
+    }
+    Ok(())
+}
+
+pub async fn connections(
+    client: &mut BackendsClient<Channel>,
+    opts: ConnectionsOptions,
+) -> Result<(), Error> {
+    let mut connections = client.list_connections(opts.filter()).await?.into_inner();
+    while let Some(connection) = connections.message().await? {
+        let tcp_state = connection
+            .tcp_state
+            .and_then(|state| TcpState::try_from(state).ok())
+            .map_or("UNKNOWN", |state| state.as_str_name());
+        let snat_port = match connection.snat_port {
+            Some(snat_port) => format!(" snat_port={}", snat_port),
+            None => String::new(),
+        };
+        println!(
+            "{} {}{}",
+            format_connection(&connection)?,
+            tcp_state,
+            snat_port
+        );
+    }
+    Ok(())
+}
+
+pub async fn stats(client: &mut BackendsClient<Channel>, opts: StatsOptions) -> Result<(), Error> {
+    let stats = client.get_backend_stats(vip_message(opts.vip)).await?;
+    for stats in stats.into_inner().backends {
+        let target = stats.target.unwrap_or_default();
+        println!(
+            "{}: active={} total={} bytes_in={} bytes_out={} last_selected={} ejected={}",
+            join_addr(target.daddr, target.daddr_ipv6.as_deref(), target.dport)?,
+            stats.active_connections,
+            stats.total_connections,
+            stats.bytes_in,
+            stats.bytes_out,
+            stats
+                .last_selected
+                .map_or("never".to_string(), |ms| ms.to_string()),
+            stats.ejected
+        );
+    }
+    Ok(())
+}
+
+pub async fn info(client: &mut BackendsClient<Channel>) -> Result<(), Error> {
+    let capabilities = client
+        .get_capabilities(CapabilitiesRequest {})
+        .await?
+        .into_inner();
+    println!("version: {}", capabilities.version);
+    println!("api version: {}", capabilities.api_version);
+    let features: Vec<&str> = capabilities
+        .features()
+        .filter(|feature| *feature != Feature::Unspecified)
+        .map(|feature| feature.as_str_name())
+        .collect();
+    println!("features: {}", features.join(", "));
+    let algorithms: Vec<&str> = capabilities
+        .algorithms()
+        .map(|algorithm| algorithm.as_str_name())
+        .collect();
+    println!("algorithms: {}", algorithms.join(", "));
+    println!("token required: {}", capabilities.token_required);
+
+    let info = client
+        .get_dataplane_info(DataplaneInfoRequest {})
+        .await?
+        .into_inner();
+    if let Some(flow_table) = info.flow_table {
+        println!(
+            "flow table: entries={}/{} inserts={} insert_failures={} removals={} evictions={} \
+             insert_rate={:.1}/s removal_rate={:.1}/s",
+            flow_table.entries,
+            flow_table.capacity,
+            flow_table.inserts,
+            flow_table.insert_failures,
+            flow_table.removals,
+            flow_table.evictions,
+            flow_table.insert_rate,
+            flow_table.removal_rate
+        );
+    }
+    Ok(())
+}
+
+pub async fn watch(
+    client: &mut BackendsClient<Channel>,
+    opts: ConnectionsOptions,
+) -> Result<(), Error> {
+    let mut changes = client.watch_connections(opts.filter()).await?.into_inner();
+    while let Some(change) = changes.message().await? {
+        let connection = change.connection.unwrap_or_default();
+        let event = match change.closed {
+            true => format!("closed ({})", change.reason),
+            false => "opened".to_string(),
+        };
+        println!(
+            "{} {} {} {}",
+            change.timestamp,
+            change.protocol,
+            format_connection(&connection)?,
+            event
+        );
+    }
+    Ok(())
+}
+
+// Formats the client, VIP and target of a connection.
+fn format_connection(connection: &Connection) -> Result<String, Error> {
+    let client = join_addr(
+        connection.client_ip,
+        connection.client_ipv6.as_deref(),
+        connection.client_port,
+    )?;
+    let vip = connection.vip.clone().unwrap_or_default();
+    let target = connection.target.clone().unwrap_or_default();
+    Ok(format!(
+        "{} -> {} -> {}",
+        client,
+        join_addr(vip.ip, vip.ipv6.as_deref(), vip.port)?,
+        join_addr(target.daddr, target.daddr_ipv6.as_deref(), target.dport)?
+    ))
+}
@@ -0,0 +1,182 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+mod configure;
+mod conntrack;
+mod inspect;
+mod program;
+
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use anyhow::{Context, Error};
+use api_server::backends::backends_client::BackendsClient;
+use api_server::backends::Vip;
+use clap::Parser;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+use tonic::Request;
+
+/// Inspects and programs a Blixt dataplane through its API.
+#[derive(Debug, Parser)]
+struct Options {
+    /// URL of the API of the dataplane, with https when it is served over TLS.
+    #[clap(long, default_value = "http://127.0.0.1:9874")]
+    server: String,
+    /// PEM certificate the client authenticates to the dataplane with, which
+    /// the client CA of the dataplane signed.
+    #[clap(long, requires_all = ["tls_key", "tls_ca"])]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of --tls-cert.
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// PEM certificate of the CA the certificate of the dataplane is verified
+    /// against.
+    #[clap(long, requires = "tls_cert")]
+    tls_ca: Option<PathBuf>,
+    /// Name the certificate of the dataplane is verified for, the host of
+    /// --server by default.
+    #[clap(long, requires = "tls_cert")]
+    tls_domain: Option<String>,
+    /// File holding the bearer token the writes are authorized with, when the
+    /// dataplane requires one (e.g. a projected ServiceAccount token).
+    #[clap(long)]
+    token_file: Option<PathBuf>,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Parser)]
+enum Command {
+    /// Lists the VIPs programmed in the datapath, and their targets.
+    Gateways,
+    /// Lists the TCP connections tracked by the datapath.
+    Connections(inspect::ConnectionsOptions),
+    /// Shows the statistics of the targets of a VIP.
+    Stats(inspect::StatsOptions),
+    /// Shows the version and features of the dataplane, and the health of its
+    /// flow table.
+    Info,
+    /// Tails the connections the datapath opens and closes.
+    Watch(inspect::ConnectionsOptions),
+    /// Programs the targets of the VIPs by hand, without a control plane.
+    #[clap(subcommand)]
+    Backend(program::BackendCommand),
+    /// Flushes, exports and imports the connections tracked by the datapath.
+    #[clap(subcommand)]
+    Conntrack(conntrack::ConntrackCommand),
+    /// Sets the verbosity of the logs of the datapath.
+    LogLevel(configure::LogLevelOptions),
+    /// Turns SYN cookies on or off for a VIP.
+    SynCookies(configure::SynCookiesOptions),
+    /// Sets the limit of the live connections of a VIP.
+    ConnectionLimit(configure::ConnectionLimitOptions),
+}
+
+#[tokio::main]
+async fn main() {
+    let opts = Options::parse();
+
+    if let Err(err) = run(opts).await {
+        eprintln!("{:#}", err);
+        exit(1);
+    }
+}
+
+async fn run(opts: Options) -> Result<(), Error> {
+    let mut client = connect(&opts).await?;
+    let token = match &opts.token_file {
+        Some(path) => Some(String::from_utf8(read(path)?)?.trim().to_string()),
+        None => None,
+    };
+
+    use Command::*;
+    match opts.command {
+        Gateways => inspect::gateways(&mut client).await,
+        Connections(opts) => inspect::connections(&mut client, opts).await,
+        Stats(opts) => inspect::stats(&mut client, opts).await,
+        Info => inspect::info(&mut client).await,
+        Watch(opts) => inspect::watch(&mut client, opts).await,
+        Backend(command) => program::backend(&mut client, token, command).await,
+        Conntrack(command) => conntrack::conntrack(&mut client, token, command).await,
+        LogLevel(opts) => configure::log_level(&mut client, token, opts).await,
+        SynCookies(opts) => configure::syn_cookies(&mut client, token, opts).await,
+        ConnectionLimit(opts) => configure::connection_limit(&mut client, token, opts).await,
+    }
+}
+
+async fn connect(opts: &Options) -> Result<BackendsClient<Channel>, Error> {
+    let mut endpoint = Endpoint::from_shared(opts.server.clone())
+        .with_context(|| format!("invalid server {}", opts.server))?;
+    if let (Some(cert), Some(key), Some(ca)) = (&opts.tls_cert, &opts.tls_key, &opts.tls_ca) {
+        let mut tls = ClientTlsConfig::new()
+            .identity(Identity::from_pem(read(cert)?, read(key)?))
+            .ca_certificate(Certificate::from_pem(read(ca)?));
+        if let Some(domain) = &opts.tls_domain {
+            tls = tls.domain_name(domain);
+        }
+        endpoint = endpoint.tls_config(tls)?;
+    }
+    let channel = endpoint
+        .connect()
+        .await
+        .with_context(|| format!("failed to connect to {}", opts.server))?;
+    Ok(BackendsClient::new(channel))
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, Error> {
+    fs::read(path).with_context(|| format!("failed to read {}", path.display()))
+}
+
+// Splits an address into the IPv4 and IPv6 fields that the API messages use.
+fn split_ip(addr: IpAddr) -> (u32, Option<Vec<u8>>) {
+    match addr {
+        IpAddr::V4(ip) => (ip.into(), None),
+        IpAddr::V6(ip) => (0, Some(ip.octets().to_vec())),
+    }
+}
+
+// Joins the IPv4 and IPv6 fields of the API messages into an address.
+fn join_ip(ip: u32, ipv6: Option<&[u8]>) -> Result<IpAddr, Error> {
+    match ipv6 {
+        Some(octets) => {
+            let octets: [u8; 16] = octets
+                .try_into()
+                .map_err(|_| Error::msg("IPv6 addresses must be 16 bytes long"))?;
+            Ok(IpAddr::from(octets))
+        }
+        None => Ok(IpAddr::from(Ipv4Addr::from(ip))),
+    }
+}
+
+// Returns the API message of a VIP.
+fn vip_message(vip: SocketAddr) -> Vip {
+    let (ip, ipv6) = split_ip(vip.ip());
+    Vip {
+        ip,
+        port: vip.port().into(),
+        ipv6,
+        port_end: None,
+    }
+}
+
+// Returns the request of a write, with the bearer token the dataplane
+// authorizes it with, if any.
+fn authorized<T>(message: T, token: &Option<String>) -> Result<Request<T>, Error> {
+    let mut request = Request::new(message);
+    if let Some(token) = token {
+        request
+            .metadata_mut()
+            .insert("authorization", format!("Bearer {}", token).parse()?);
+    }
+    Ok(request)
+}
+
+// Joins the address and port fields of the API messages into a socket address.
+fn join_addr(ip: u32, ipv6: Option<&[u8]>, port: u32) -> Result<SocketAddr, Error> {
+    Ok(SocketAddr::new(join_ip(ip, ipv6)?, port as u16))
+}
@@ -0,0 +1,277 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::Error;
+use api_server::backends::backends_client::BackendsClient;
+use api_server::backends::{
+    Algorithm, Gateway, HealthCheck, ListGatewaysRequest, Target, Targets, Vip,
+};
+use clap::Parser;
+use tonic::transport::Channel;
+
+use crate::{authorized, join_addr, split_ip, vip_message};
+
+#[derive(Debug, Parser)]
+pub enum BackendCommand {
+    /// Adds a target to a VIP, creating the VIP if it doesn't exist, or
+    /// replaces the target if the VIP has it already. The options of the VIP
+    /// itself, such as its forwarding mode, are those of the command line,
+    /// which replace those the VIP was programmed with.
+    Add(AddOptions),
+    /// Removes a target from a VIP, or the VIP itself when no target is given.
+    Remove(RemoveOptions),
+}
+
+#[derive(Debug, Parser)]
+pub struct AddOptions {
+    /// The VIP, as `ip:port`.
+    #[clap(long)]
+    vip: SocketAddr,
+    /// Last port of the range of the VIP, which listens on its port only when
+    /// unset.
+    #[clap(long)]
+    port_end: Option<u16>,
+    /// Other addresses of the VIP, listening on the same ports.
+    #[clap(long)]
+    alias: Vec<IpAddr>,
+    /// The target, as `ip:port`.
+    #[clap(long)]
+    target: SocketAddr,
+    #[clap(long, default_value = "1")]
+    weight: u32,
+    /// Index of the interface the target is reached through, 0 to route the
+    /// packets to it.
+    #[clap(long, default_value = "0")]
+    ifindex: u32,
+    /// MAC address of the target, as six colon-separated hex bytes.
+    #[clap(long)]
+    mac: Option<String>,
+    /// Whether the target is a pod on this node, detected from --ifindex when
+    /// unset.
+    #[clap(long)]
+    local: Option<bool>,
+    /// Drain the target, which then receives no new connections.
+    #[clap(long, action)]
+    drain: bool,
+    /// Most live connections of the target, 0 for no limit.
+    #[clap(long, default_value = "0")]
+    max_conns: u32,
+    /// Algorithm of the VIP: round-robin, maglev, least-conn, power-of-two or
+    /// random. Existing VIPs keep theirs when unset, new ones use round-robin.
+    #[clap(long, value_parser = parse_algorithm)]
+    algorithm: Option<Algorithm>,
+    /// Seconds during which new connections from a client go to the target of
+    /// its previous ones, 0 disabling session affinity.
+    #[clap(long, default_value = "0")]
+    affinity_timeout: u32,
+    /// Seconds after which an idle UDP flow is no longer pinned to its target,
+    /// 0 for the node's default.
+    #[clap(long, default_value = "0")]
+    udp_idle_timeout: u32,
+    /// Forward the packets with direct server return.
+    #[clap(long, action, requires = "mac", conflicts_with = "snat")]
+    dsr: bool,
+    /// Source NAT the connections forwarded to the targets.
+    #[clap(long, action)]
+    snat: bool,
+    /// Inject a PROXY protocol v2 header in the TCP connections.
+    #[clap(long, action, conflicts_with = "dsr")]
+    proxy_protocol: bool,
+    /// Add the address of the clients as a TOA option to the SYNs.
+    #[clap(long, action, conflicts_with = "dsr")]
+    toa: bool,
+    /// Pin the QUIC connections to their target by connection ID, of this
+    /// length.
+    #[clap(long, default_value = "0", conflicts_with = "dsr")]
+    quic_cid_len: u32,
+    /// Don't track the connections, hashing their 5-tuple to the target with
+    /// Maglev.
+    #[clap(long, action)]
+    stateless: bool,
+    /// Check the health of the targets with TCP connections.
+    #[clap(long, action)]
+    health_check: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct RemoveOptions {
+    /// The VIP, as `ip:port`.
+    #[clap(long)]
+    vip: SocketAddr,
+    /// The target, as `ip:port`. The VIP is deleted when unset.
+    #[clap(long)]
+    target: Option<SocketAddr>,
+    /// Flush the tracked connections of the target.
+    #[clap(long, action, requires = "target")]
+    flush: bool,
+}
+
+pub async fn backend(
+    client: &mut BackendsClient<Channel>,
+    token: Option<String>,
+    command: BackendCommand,
+) -> Result<(), Error> {
+    match command {
+        BackendCommand::Add(opts) => add(client, token, opts).await,
+        BackendCommand::Remove(opts) => remove(client, token, opts).await,
+    }
+}
+
+async fn add(
+    client: &mut BackendsClient<Channel>,
+    token: Option<String>,
+    opts: AddOptions,
+) -> Result<(), Error> {
+    let gateway = find_gateway(client, opts.vip).await?;
+    let (daddr, daddr_ipv6) = split_ip(opts.target.ip());
+    let target = Target {
+        daddr,
+        dport: opts.target.port().into(),
+        ifindex: Some(opts.ifindex),
+        daddr_ipv6,
+        weight: Some(opts.weight),
+        mac: opts.mac.as_deref().map(parse_mac).transpose()?,
+        local: opts.local,
+        drain: opts.drain,
+        split_group: 0,
+        max_conns: Some(opts.max_conns),
+    };
+    let (targets, algorithm) = match gateway {
+        Some(gateway) => (gateway.targets, gateway.algorithm),
+        None => (Vec::new(), Algorithm::RoundRobin.into()),
+    };
+    let mut kept = Vec::new();
+    for existing in targets {
+        if !is_target(&existing, opts.target)? {
+            kept.push(existing);
+        }
+    }
+    kept.push(target);
+
+    let vip = Vip {
+        port_end: opts.port_end.map(Into::into),
+        ..vip_message(opts.vip)
+    };
+    let aliases = opts
+        .alias
+        .iter()
+        .map(|alias| {
+            let (ip, ipv6) = split_ip(*alias);
+            Vip {
+                ip,
+                ipv6,
+                ..vip.clone()
+            }
+        })
+        .collect();
+    let targets = Targets {
+        vip: Some(vip),
+        targets: kept,
+        algorithm: opts.algorithm.map_or(algorithm, Into::into),
+        affinity_timeout: Some(opts.affinity_timeout),
+        quic_cid_len: Some(opts.quic_cid_len),
+        stateless: opts.stateless,
+        dsr: opts.dsr,
+        proxy_protocol: opts.proxy_protocol,
+        toa: opts.toa,
+        snat: opts.snat,
+        aliases,
+        health_check: opts.health_check.then(HealthCheck::default),
+        udp_idle_timeout: Some(opts.udp_idle_timeout),
+        ..Default::default()
+    };
+    let res = client.update(authorized(targets, &token)?).await?;
+    println!("{}", res.into_inner().confirmation);
+    Ok(())
+}
+
+async fn remove(
+    client: &mut BackendsClient<Channel>,
+    token: Option<String>,
+    opts: RemoveOptions,
+) -> Result<(), Error> {
+    let target = match opts.target {
+        Some(target) => target,
+        None => {
+            let res = client
+                .delete(authorized(vip_message(opts.vip), &token)?)
+                .await?;
+            println!("{}", res.into_inner().confirmation);
+            return Ok(());
+        }
+    };
+
+    let gateway = find_gateway(client, opts.vip)
+        .await?
+        .ok_or_else(|| Error::msg(format!("vip {} does not exist", opts.vip)))?;
+    let len = gateway.targets.len();
+    let mut kept = Vec::new();
+    for existing in gateway.targets {
+        if !is_target(&existing, target)? {
+            kept.push(existing);
+        }
+    }
+    if kept.len() == len {
+        return Err(Error::msg(format!(
+            "vip {} has no target {}",
+            opts.vip, target
+        )));
+    }
+
+    // The other options of the VIP can't be read back, they are reset.
+    let targets = Targets {
+        vip: Some(vip_message(opts.vip)),
+        targets: kept,
+        algorithm: gateway.algorithm,
+        flush_removed: opts.flush,
+        ..Default::default()
+    };
+    let res = client.update(authorized(targets, &token)?).await?;
+    println!("{}", res.into_inner().confirmation);
+    Ok(())
+}
+
+// Returns the Gateway of a VIP, if it is programmed.
+async fn find_gateway(
+    client: &mut BackendsClient<Channel>,
+    vip: SocketAddr,
+) -> Result<Option<Gateway>, Error> {
+    let gateways = client
+        .list_gateways(ListGatewaysRequest {})
+        .await?
+        .into_inner()
+        .gateways;
+    for gateway in gateways {
+        let key = gateway.vip.clone().unwrap_or_default();
+        if join_addr(key.ip, key.ipv6.as_deref(), key.port)? == vip {
+            return Ok(Some(gateway));
+        }
+    }
+    Ok(None)
+}
+
+fn is_target(target: &Target, addr: SocketAddr) -> Result<bool, Error> {
+    Ok(join_addr(target.daddr, target.daddr_ipv6.as_deref(), target.dport)? == addr)
+}
+
+fn parse_algorithm(algorithm: &str) -> Result<Algorithm, String> {
+    Algorithm::from_str_name(&algorithm.to_uppercase().replace('-', "_"))
+        .ok_or_else(|| format!("unknown algorithm {}", algorithm))
+}
+
+// Parses a MAC address written as six colon-separated hex bytes.
+fn parse_mac(mac: &str) -> Result<Vec<u8>, Error> {
+    let bytes = mac
+        .split(':')
+        .map(|byte| u8::from_str_radix(byte, 16))
+        .collect::<Result<Vec<u8>, _>>()?;
+    if bytes.len() != 6 {
+        return Err(Error::msg(format!("invalid MAC address {}", mac)));
+    }
+    Ok(bytes)
+}
@@ -8,49 +8,911 @@ SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
 
 pub const BACKENDS_ARRAY_CAPACITY: usize = 128;
 pub const BPF_MAPS_CAPACITY: u32 = 128;
+// LB_CONNECTIONS_CAPACITY is the number of connections tracked at once, past which the least
+// recently used ones are evicted. The loader resizes the connection tracking maps to its
+// --conntrack-capacity, which defaults to this.
+pub const LB_CONNECTIONS_CAPACITY: u32 = 65536;
+// MAGLEV_TABLE_SIZE is the number of entries of a Maglev lookup table. It has to be a prime number,
+// and much larger than BACKENDS_ARRAY_CAPACITY for the backends to get an even share of entries.
+pub const MAGLEV_TABLE_SIZE: usize = 16381;
+// MAX_SPLIT_GROUPS is the number of groups the backends of a Gateway can be split into, e.g. the
+// stable and canary versions of a service, see BackendList.split_weights.
+pub const MAX_SPLIT_GROUPS: usize = 4;
+// PORT_RANGES_CAPACITY is the number of Gateways listening on a range of ports that an address can
+// have.
+pub const PORT_RANGES_CAPACITY: usize = 16;
+// ACL_RULES_CAPACITY is the number of prefixes the ACLs of all the Gateways can hold together.
+pub const ACL_RULES_CAPACITY: u32 = 4096;
+// MAX_CPUS is the number of CPUs whose per-CPU connection counters are summed when picking the
+// backend with the fewest connections, at most, see Config.nr_cpus. Counts held by CPUs past this
+// one are not taken into account.
+pub const MAX_CPUS: u32 = 64;
+// PINGS_CAPACITY is the number of pings forwarded to backends that are tracked at once, past which
+// the least recently used ones are evicted.
+pub const PINGS_CAPACITY: u32 = 4096;
+
+// Addresses shared between the eBPF programs and userspace are stored as four 32-bit words in host
+// byte order, so that IPv4 and IPv6 can use the same key and value types. IPv4 addresses are
+// stored in their IPv4-mapped IPv6 form (::ffff:a.b.c.d), which this returns for an IPv4 address
+// in host byte order.
+#[inline(always)]
+pub const fn ipv4_mapped(ip: u32) -> [u32; 4] {
+    [0, 0, 0xffff, ip]
+}
+
+// Returns true if the address is the IPv4-mapped form of an IPv4 address.
+#[inline(always)]
+pub const fn is_ipv4_mapped(ip: &[u32; 4]) -> bool {
+    ip[0] == 0 && ip[1] == 0 && ip[2] == 0xffff
+}
+
+// UntrackedTCPAction is what the ingress program does with the TCP packets sent to a Gateway which
+// neither start a new connection (SYN) nor belong to a tracked one.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub enum UntrackedTCPAction {
+    // Assign them a backend as if they started a new connection.
+    #[default]
+    Track,
+    // Let them through to the host unmodified.
+    Pass,
+    // Drop them.
+    Drop,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for UntrackedTCPAction {}
+
+// Config holds the settings of the eBPF programs, which userspace stores as the only entry of the
+// CONFIG map.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct Config {
+    pub untracked_tcp: UntrackedTCPAction,
+    // reset_without_backend makes the ingress program answer new TCP connections to a Gateway
+    // which has no backend to offer with a RST, instead of letting them through to the host.
+    pub reset_without_backend: bool,
+    // snat_ipv4 and snat_ipv6 are the addresses the source of the connections to the Gateways in
+    // Snat mode is rewritten to, per IP family. Connections of a family without an address (all
+    // zeroes) are not source NATed. The connections of IPv6 clients to IPv4 backends are translated
+    // to connections from snat_ipv4, and dropped without it.
+    pub snat_ipv4: [u32; 4],
+    pub snat_ipv6: [u32; 4],
+    // snat_port_min and snat_port_max bound the source ports allocated to the source NATed
+    // connections. They should be kept out of the host's ephemeral port range.
+    pub snat_port_min: u16,
+    pub snat_port_max: u16,
+    // max_mss_ipv4 and max_mss_ipv6 are the values the MSS option of the TCP SYNs going through
+    // the programs is clamped to, per IP family. 0 disables the clamping.
+    pub max_mss_ipv4: u16,
+    pub max_mss_ipv6: u16,
+    // failure_threshold is the number of failed new connections (refused or unanswered SYNs) after
+    // which a backend is skipped by the selection of new connections for failure_ejection_time
+    // nanoseconds. 0 disables the passive failure detection.
+    pub failure_threshold: u32,
+    pub failure_ejection_time: u64,
+    // syn_rate is the number of new TCP connections per second a client address may open, past
+    // which its SYNs are dropped. Clients can momentarily exceed the rate by up to syn_burst
+    // connections. 0 disables the rate limiting.
+    pub syn_rate: u32,
+    pub syn_burst: u32,
+    // max_client_connections is the number of live TCP connections a client address may have, past
+    // which its new connections are dropped. 0 disables the cap.
+    pub max_client_connections: u32,
+    // syn_cookie_secret keys the hash of the SYN cookies the Gateways answer new connections with
+    // when enabled. It is drawn at random when the programs are loaded, so that the cookies can't
+    // be forged by clients which never received them.
+    pub syn_cookie_secret: [u32; 4],
+    // tc_chain makes the programs hand the packets they let through to the filters of lower
+    // priority on the same hook (e.g. another eBPF datapath's), instead of accepting them.
+    pub tc_chain: bool,
+    // tunnel_src_ipv4 and tunnel_src_ipv6 are the addresses of this node which the packets
+    // encapsulated to the nodes of the backends in TUNNELS come from, per IP family. The packets
+    // of a family without an address (all zeroes) are routed to the backends instead.
+    pub tunnel_src_ipv4: [u32; 4],
+    pub tunnel_src_ipv6: [u32; 4],
+    // tproxy_mark is the mark of the packets handed to the local transparent proxies of the
+    // Gateways, which the host's routing rules deliver locally. 0 leaves the packets unmarked.
+    pub tproxy_mark: u32,
+    // sample_every makes the ingress program copy the headers of one in sample_every of the packets
+    // it forwards to the backends to userspace, see PacketSample. 0 disables the sampling.
+    pub sample_every: u32,
+    // nr_cpus is the number of possible CPUs of the node, which bounds the per-CPU connection
+    // counters the programs sum for each backend. 0 sums those of up to MAX_CPUS CPUs.
+    pub nr_cpus: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Config {}
+
+// LogLevel is the verbosity of the logs of the eBPF programs, which userspace stores as the only
+// entry of the LOG_LEVEL map so that it can be changed while they run. The programs log the
+// messages of the level and of the levels before it, and nothing when it's Off.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(C)]
+pub enum LogLevel {
+    #[default]
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for LogLevel {}
+
+// ForwardingMode selects how packets are sent to the backends of a Gateway.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub enum ForwardingMode {
+    // The destination of the packets is rewritten to the backend, and the source of the replies
+    // back to the Gateway.
+    #[default]
+    Nat,
+    // Direct Server Return: only the destination MAC address of the packets is rewritten, the
+    // backend (which has to hold the Gateway's address) replies directly to the client.
+    Dsr,
+    // Like Nat, but the source of the TCP packets is also rewritten to the node's address and a
+    // port allocated to the connection, for backends which have no route back to the clients.
+    Snat,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ForwardingMode {}
+
+// Encapsulation is the protocol the packets to the backends on other nodes are tunneled with.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub enum Encapsulation {
+    // Geneve without options, whose payload is the IP packet, see RFC 8926.
+    #[default]
+    Geneve,
+    // Generic UDP Encapsulation with a variant 0 header and no optional fields, whose payload is
+    // the IP packet, see draft-ietf-intarea-gue.
+    Gue,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Encapsulation {}
+
+// Tunnel is the node a backend runs on, when the packets to the backend are encapsulated to its
+// node instead of being routed to the backend itself.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct Tunnel {
+    // node_addr is the address of the node, of the IP family of the backend's address.
+    pub node_addr: [u32; 4],
+    pub encapsulation: Encapsulation,
+    // port is the UDP port the node receives the encapsulated packets on.
+    pub port: u16,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Tunnel {}
+
+// Mirror is where copies of the packets to a Gateway are sent, for a shadow backend to receive the
+// Gateway's traffic alongside its actual backends. Like the backends reached with direct server
+// return, the mirror holds the Gateway's address and gets the packets as they came in.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct Mirror {
+    // mac is the MAC address of the mirror, on the same L2 segment as the interface at ifindex.
+    pub mac: [u8; 6],
+    pub ifindex: u16,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Mirror {}
 
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
 pub struct Backend {
-    pub daddr: u32,
+    pub daddr: [u32; 4],
+    // alt_daddr is the address of the other IP family of a dual-stack backend, which the clients of
+    // that family are forwarded to. All zeroes for the backends with a single address.
+    pub alt_daddr: [u32; 4],
     pub dport: u32,
+    // max_conns is the most live connections the backend is assigned, the new connections going to
+    // the other backends while it has them. 0 for no limit.
+    pub max_conns: u32,
     pub ifindex: u16,
+    // weight is the number of consecutive new connections assigned to this backend per round of
+    // the weighted round robin. Backends with a weight of 0 receive no new connections.
+    pub weight: u16,
+    // mac is the MAC address of the backend, which packets are sent to in DSR mode.
+    pub mac: [u8; 6],
+    // forwarding is the forwarding mode of the backend's Gateway.
+    pub forwarding: ForwardingMode,
+    // proxy_protocol is set when a PROXY protocol v2 header carrying the client's address is
+    // injected in front of the data of the TCP connections forwarded to the backend.
+    pub proxy_protocol: bool,
+    // toa is set when the client's address and port are added as a TOA (TCP Option Address)
+    // option to the SYNs forwarded to the backend.
+    pub toa: bool,
+    // local is set when the backend is a pod on this node, whose host side veth is at ifindex.
+    pub local: bool,
+    // drain is set when the backend is being taken out of service: it receives no new connections,
+    // but keeps those it was assigned until they terminate.
+    pub drain: bool,
+    // unhealthy is set by the dataplane's health checker while the backend fails its health checks,
+    // which takes it out of new connection selection like drain.
+    pub unhealthy: bool,
+    // split_group is the group of the backend when its Gateway splits its traffic between groups
+    // of backends, see BackendList.split_weights.
+    pub split_group: u8,
+}
+
+impl Backend {
+    // Returns the key of the backend's address and port in BACKEND_CONNECTIONS and
+    // RELEASED_CONNECTIONS. Dual-stack backends are keyed by their IPv4 address, whichever family
+    // their connections use.
+    #[inline(always)]
+    pub fn key(&self) -> BackendKey {
+        let ip = match is_ipv4_mapped(&self.alt_daddr) {
+            true => self.alt_daddr,
+            false => self.daddr,
+        };
+        BackendKey {
+            ip,
+            port: self.dport,
+        }
+    }
+
+    // Returns the backend as seen by a client of the given IP family, i.e. with the address of that
+    // family as its address when it is dual-stack.
+    #[inline(always)]
+    pub fn for_family(&self, ipv4: bool) -> Backend {
+        if self.alt_daddr == [0; 4] || is_ipv4_mapped(&self.daddr) == ipv4 {
+            return *self;
+        }
+        Backend {
+            daddr: self.alt_daddr,
+            alt_daddr: self.daddr,
+            ..*self
+        }
+    }
+
+    // Returns whether the backend can be assigned new connections.
+    #[inline(always)]
+    pub fn accepts_new_connections(&self) -> bool {
+        self.weight != 0 && !self.drain && !self.unhealthy
+    }
 }
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for Backend {}
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 #[repr(C)]
 pub struct BackendKey {
-    pub ip: u32,
+    pub ip: [u32; 4],
     pub port: u32,
 }
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for BackendKey {}
 
+// PortRange is the range of ports a Gateway listens on, from start (the port of its BackendKey) to
+// end included.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    #[inline(always)]
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+
+    #[inline(always)]
+    pub fn overlaps(&self, other: &PortRange) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for PortRange {}
+
+// PortRangeList holds the port ranges of the Gateways of an address which listen on a range of
+// ports. The ports of a range are forwarded to the Gateway of its first port, unless a Gateway
+// listens on that single port.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct PortRangeList {
+    pub ranges: [PortRange; PORT_RANGES_CAPACITY],
+    // ranges_len is the length of the ranges array
+    pub ranges_len: u16,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for PortRangeList {}
+
+// BalancingAlgorithm selects how new connections to a Gateway are assigned to its backends.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub enum BalancingAlgorithm {
+    // Weighted round robin over the backends, tracked in GATEWAY_INDEXES.
+    #[default]
+    RoundRobin,
+    // Consistent hashing of the client address and port through the Gateway's table in
+    // MAGLEV_TABLES.
+    Maglev,
+    // The backend with the fewest live connections, as counted in BACKEND_CONNECTIONS.
+    LeastConn,
+    // Power of two choices: the backend with the fewer live connections of the two which consistent
+    // hashing of the client address and port picks through the Gateway's table in MAGLEV_TABLES.
+    PowerOfTwo,
+    // A backend drawn at random, which leaves GATEWAY_INDEXES alone.
+    Random,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for BalancingAlgorithm {}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct BackendList {
     pub backends: [Backend; BACKENDS_ARRAY_CAPACITY],
     // backends_len is the length of the backends array
     pub backends_len: u16,
+    pub algorithm: BalancingAlgorithm,
+    // affinity_timeout is the time (in nanoseconds) during which new connections from a client IP
+    // keep going to the backend its previous connections went to, see AFFINITIES. 0 disables
+    // session affinity.
+    pub affinity_timeout: u64,
+    // slot is which of the Gateway's two sets of balancing state, in MAGLEV_TABLES and
+    // GATEWAY_INDEXES, goes with the list. Userspace fills the set of the other slot before
+    // replacing the list with one using it, so that the packets see either the old list and state
+    // or the new ones, never a mix of both.
+    pub slot: u32,
+    // quic_cid_len is the length of the connection IDs the backends choose for their QUIC
+    // connections, whose UDP flows are then pinned to a backend by connection ID, see
+    // QUIC_CONNECTIONS. 0 disables the QUIC affinity.
+    pub quic_cid_len: u8,
+    // stateless is set when the connections to the Gateway aren't tracked: each packet goes to the
+    // backend its 5-tuple hashes to through the Gateway's Maglev table, and the replies of the
+    // backends are translated back with STATELESS_TARGETS. Connections move to other backends when
+    // the backends change.
+    pub stateless: bool,
+    // split_weights are the percentages of the new connections that go to each group of backends,
+    // indexed by Backend.split_group, which have their own balancing state. All zeroes when the
+    // traffic isn't split.
+    pub split_weights: [u8; MAX_SPLIT_GROUPS],
+    // udp_idle_timeout is the time (in nanoseconds) after which an idle UDP flow to the Gateway is
+    // no longer pinned to its backend, see UdpLoadBalancerMapping.idle_timeout. 0 uses the
+    // node's default.
+    pub udp_idle_timeout: u64,
 }
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for BackendList {}
 
+// MaglevTable is the Maglev lookup table of a Gateway, see https://research.google/pubs/pub44824/.
+// Each entry is the index in the Gateway's BackendList of the backend that the flows hashing to it
+// are assigned to. Populating the table so that backend changes only remap a small share of the
+// entries is done in userspace.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct MaglevTable {
+    pub entries: [u16; MAGLEV_TABLE_SIZE],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for MaglevTable {}
+
+// AffinityKey identifies the clients of a Gateway that share a session affinity, by their IP.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct AffinityKey {
+    pub backend_key: BackendKey,
+    pub client_ip: [u32; 4],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for AffinityKey {}
+
+// Affinity is the backend that new connections from a client IP are assigned to.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct Affinity {
+    pub backend: Backend,
+    // last_seen is the time (in nanoseconds since boot, see bpf_ktime_get_ns) at which the last
+    // connection from the client was assigned to the backend.
+    pub last_seen: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Affinity {}
+
+// The longest connection ID of QUIC version 1.
+pub const QUIC_MAX_CID_LEN: usize = 20;
+
+// QuicCidKey identifies a QUIC connection to a Gateway by the connection ID its backend chose,
+// which the client keeps sending to when its address changes.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct QuicCidKey {
+    pub backend_key: BackendKey,
+    // cid is zero-padded past the length of the Gateway's connection IDs.
+    pub cid: [u8; QUIC_MAX_CID_LEN],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for QuicCidKey {}
+
+// GatewaySlotKey identifies one of the two sets of balancing state of a Gateway, see
+// BackendList.slot.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct GatewaySlotKey {
+    pub key: BackendKey,
+    pub slot: u32,
+}
+
+// GATEWAY_SLOTS is the number of slots of balancing state a Gateway can have: two for its whole
+// backend list and two for each of its split groups.
+pub const GATEWAY_SLOTS: u32 = 2 * (MAX_SPLIT_GROUPS as u32 + 1);
+
+impl GatewaySlotKey {
+    // Returns the key of the balancing state of a split group of the Gateway's backend list using
+    // slot, which follows the state of the whole list.
+    #[inline(always)]
+    pub fn split(key: BackendKey, slot: u32, split_group: u8) -> GatewaySlotKey {
+        GatewaySlotKey {
+            key,
+            slot: slot + 2 * (split_group as u32 + 1),
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for GatewaySlotKey {}
+
+// GatewayIndex is the weighted round robin position of a Gateway.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct GatewayIndex {
+    // index is the position in the BackendList of the backend currently receiving new connections.
+    pub index: u16,
+    // assigned is the number of new connections assigned to that backend so far in this round.
+    pub assigned: u16,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for GatewayIndex {}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct ClientKey {
-    pub ip: u32,
+    pub ip: [u32; 4],
     pub port: u32,
 }
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for ClientKey {}
 
+// SnatKey identifies the backend's side of a source NATed connection: the backend's address and
+// port, and the source port allocated to the connection. It maps to the ClientKey of the
+// connection in SNAT_CONNECTIONS.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct SnatKey {
+    pub ip: [u32; 4],
+    pub port: u32,
+    pub snat_port: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for SnatKey {}
+
+// SockKey identifies a TCP socket of the node by its local and remote addresses and ports, in host
+// byte order with the IPv4 addresses IPv4-mapped. It maps to the socket in SOCK_PAIRS.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct SockKey {
+    pub local_ip: [u32; 4],
+    pub remote_ip: [u32; 4],
+    pub local_port: u32,
+    pub remote_port: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for SockKey {}
+
+// FragmentKey identifies the fragments of an IP packet: its source and destination addresses, and
+// its identification. It maps to the backend the first fragment was forwarded to in FRAGMENTS.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct FragmentKey {
+    pub src_addr: [u32; 4],
+    pub dst_addr: [u32; 4],
+    pub id: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for FragmentKey {}
+
+// BackendConnections counts the connections the datapath assigned to a backend, and those it saw
+// terminate. It is kept per CPU, so the live connections of a backend are the sum of opened over
+// all CPUs, minus the sum of closed and the connections userspace released or the tracking maps
+// evicted, see RELEASED_CONNECTIONS.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct BackendConnections {
+    pub opened: u64,
+    pub closed: u64,
+    // last_opened is when the backend was last assigned a connection on this CPU, in nanoseconds
+    // since boot (see bpf_ktime_get_ns), or 0 if it never was.
+    pub last_opened: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for BackendConnections {}
+
+// BackendTraffic counts the packets and bytes the datapath forwarded to a backend, and those of
+// the replies of the backend it translated back. It is kept per CPU, the traffic of a backend is
+// the sum over all CPUs.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct BackendTraffic {
+    pub forwarded_packets: u64,
+    pub forwarded_bytes: u64,
+    pub reply_packets: u64,
+    pub reply_bytes: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for BackendTraffic {}
+
+// FlowTableStats counts the connections the datapath inserted into and removed from
+// LB_CONNECTIONS, and the insertions which failed. It is kept per CPU, the counts are the sum over
+// all CPUs. The evictions of the map aren't seen by the programs, userspace derives them from these
+// counts and the entries left in the map.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct FlowTableStats {
+    pub inserts: u64,
+    pub insert_failures: u64,
+    pub removals: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for FlowTableStats {}
+
+// SYN_LATENCY_BUCKETS is the number of buckets of the histograms of SynLatency. Bucket i counts the
+// latencies under SYN_LATENCY_FIRST_BUCKET_NS << i, the last bucket all the longer ones.
+pub const SYN_LATENCY_BUCKETS: usize = 16;
+// SYN_LATENCY_FIRST_BUCKET_SHIFT is the log2 of SYN_LATENCY_FIRST_BUCKET_NS, which is about 33µs.
+// The last bounded bucket is thus about 537ms.
+pub const SYN_LATENCY_FIRST_BUCKET_SHIFT: u32 = 15;
+pub const SYN_LATENCY_FIRST_BUCKET_NS: u64 = 1 << SYN_LATENCY_FIRST_BUCKET_SHIFT;
+
+// SynLatency is the histogram of the times a backend took to answer the SYNs of its new
+// connections with a SYN-ACK, as seen by the egress program. It is kept per CPU, the histogram of
+// a backend is the sum over all CPUs. The number of latencies is the sum of the buckets.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct SynLatency {
+    pub buckets: [u64; SYN_LATENCY_BUCKETS],
+    pub sum_ns: u64,
+}
+
+impl SynLatency {
+    // Returns the bucket a latency falls in.
+    #[inline(always)]
+    pub fn bucket(latency_ns: u64) -> usize {
+        let bucket = 64 - (latency_ns >> SYN_LATENCY_FIRST_BUCKET_SHIFT).leading_zeros() as usize;
+        if bucket < SYN_LATENCY_BUCKETS {
+            bucket
+        } else {
+            SYN_LATENCY_BUCKETS - 1
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for SynLatency {}
+
+// TOKEN_COST is the amount of a TokenBucket's tokens a new connection takes.
+pub const TOKEN_COST: u64 = 1_000_000_000;
+
+// TokenBucket holds the new connections a client may still open, in billionths of a connection so
+// that it refills by the rate every nanosecond.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct TokenBucket {
+    pub tokens: u64,
+    // last_refill is when the bucket was last refilled, in nanoseconds since boot (see
+    // bpf_ktime_get_ns).
+    pub last_refill: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for TokenBucket {}
+
+// AclAction is what is done with the packets of the clients matching a prefix of an ACL.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub enum AclAction {
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for AclAction {}
+
+// AclKey is the data of the keys of the ACLS trie, which is matched by the longest prefix: the
+// address of the Gateways the ACL applies to, always matched in full, followed by a prefix of the
+// clients' addresses. src_ip is in network byte order, for its prefix to be matched bit by bit.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct AclKey {
+    pub vip_ip: [u32; 4],
+    pub src_ip: [u32; 4],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for AclKey {}
+
+// ACL_VIP_PREFIX_LEN is the length in bits of the AclKey's vip_ip, which the prefix of the clients'
+// addresses is added to.
+pub const ACL_VIP_PREFIX_LEN: u32 = 128;
+
+// LimitAction is what is done with the new connections to a Gateway over its connection limit.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub enum LimitAction {
+    // Drop the SYN, so that the client retries later.
+    #[default]
+    Drop,
+    // Reset the connection, so that the client fails fast.
+    Reset,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for LimitAction {}
+
+// ConnectionLimit caps the live connections of the backends of a Gateway, past which its new
+// connections are rejected.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct ConnectionLimit {
+    pub max_connections: u64,
+    pub action: LimitAction,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ConnectionLimit {}
+
+// BackendFailures tracks the new connections a backend failed in a row, as seen by the datapath.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct BackendFailures {
+    pub failures: u32,
+    // ejected_until is when the backend is assigned new connections again after it reached the
+    // failure threshold, in nanoseconds since boot (see bpf_ktime_get_ns), or 0 if it never did.
+    pub ejected_until: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for BackendFailures {}
+
+// ConnectionEventKind is what happened to the connection a ConnectionEvent is about.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub enum ConnectionEventKind {
+    // The connection was assigned a backend.
+    Opened,
+    // The datapath stopped tracking the connection, see CloseReason.
+    Closed,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ConnectionEventKind {}
+
+// CloseReason is why the datapath stopped tracking a connection.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub enum CloseReason {
+    // The connection was opened, not closed.
+    None,
+    // Both sides of the TCP connection sent a FIN, and had it acknowledged.
+    Fin,
+    // A side of the TCP connection sent a RST.
+    Rst,
+    // The client of the UDP flow moved on to another Gateway.
+    Replaced,
+    // The TCP connection was idle for longer than the timeout of its state, see TcpTimeouts, or
+    // the UDP flow for longer than the one of its Gateway.
+    Timeout,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for CloseReason {}
+
+// DROP_REASONS is the number of DropReasons, and of the entries of TCP_DROPS.
+pub const DROP_REASONS: u32 = 8;
+
+// DropReason is why the ingress program dropped a TCP packet, which indexes the per-CPU counters of
+// TCP_DROPS.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum DropReason {
+    // The client is denied by the ACL of the Gateway's address.
+    Denied,
+    // The client acknowledged a SYN cookie it wasn't sent.
+    InvalidSynCookie,
+    // The packet neither starts a connection nor belongs to a tracked one, see
+    // UntrackedTCPAction::Drop.
+    Untracked,
+    // The client is over its new connection rate.
+    RateLimited,
+    // The client is over its connection cap, see Config.max_client_connections.
+    ClientCap,
+    // The Gateway is over its connection limit, see LimitAction::Drop.
+    ConnectionLimit,
+    // The connection of an IPv6 client to an IPv4 backend has no IPv4 address to be translated
+    // from.
+    NoNat64Address,
+    // The source NATed connection has no source port left to be given.
+    NoSnatPort,
+}
+
+// PASS_REASONS is the number of PassReasons, and of the entries of TCP_PASSES.
+pub const PASS_REASONS: u32 = 6;
+
+// PassReason is why the ingress program let a TCP packet through to the host, which indexes the
+// per-CPU counters of TCP_PASSES.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum PassReason {
+    // The packet is a reply of a backend to a source NATed connection, turned back into a reply to
+    // the client for the host to route.
+    SnatReply,
+    // The packet neither starts a connection nor belongs to a tracked one, see
+    // UntrackedTCPAction::Pass.
+    Untracked,
+    // The Gateway has no backend to take new connections, see Config.reset_without_backend.
+    NoBackend,
+    // The packet belongs to no tracked connection, and is destined for no Gateway.
+    NotGateway,
+    // The packet is shorter than its headers.
+    OutOfBounds,
+    // A map couldn't be updated, or a helper failed.
+    Error,
+}
+
+// FlowStats counts the packets and bytes of a connection in each direction, as seen by the TC
+// programs and the XDP fast path. The packets spliced between local sockets (see sockmap) aren't
+// counted. The counts of a connection are updated by several CPUs without synchronization, and may
+// miss a few of its packets.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct FlowStats {
+    // opened is the time (in nanoseconds since boot) at which the connection was opened.
+    pub opened: u64,
+    // packets and bytes count what the client sent, reply_packets and reply_bytes what the
+    // backend answered.
+    pub packets: u64,
+    pub bytes: u64,
+    pub reply_packets: u64,
+    pub reply_bytes: u64,
+}
+
+impl FlowStats {
+    // Returns the counts of a connection opened at `now` by a packet of `len` bytes.
+    #[inline(always)]
+    pub fn new(now: u64, len: u32) -> FlowStats {
+        FlowStats {
+            opened: now,
+            packets: 1,
+            bytes: len as u64,
+            ..Default::default()
+        }
+    }
+
+    // Counts a packet of `len` bytes of the client.
+    #[inline(always)]
+    pub fn count(&mut self, len: u32) {
+        self.packets += 1;
+        self.bytes += len as u64;
+    }
+
+    // Counts a reply of `len` bytes of the backend.
+    #[inline(always)]
+    pub fn count_reply(&mut self, len: u32) {
+        self.reply_packets += 1;
+        self.reply_bytes += len as u64;
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for FlowStats {}
+
+// ConnectionEvent is what the eBPF programs report to userspace through the CONNECTION_EVENTS ring
+// buffer when a connection is opened or closed. The connections userspace expires are not
+// reported, since userspace knows about those already.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct ConnectionEvent {
+    // timestamp is when the event happened, in nanoseconds since boot (see bpf_ktime_get_ns).
+    pub timestamp: u64,
+    pub client_key: ClientKey,
+    // backend_key is the Gateway the client connected to.
+    pub backend_key: BackendKey,
+    // backend is the address and port of the backend selected for the connection.
+    pub backend: BackendKey,
+    // proto is the IP protocol number of the connection.
+    pub proto: u8,
+    pub kind: ConnectionEventKind,
+    pub reason: CloseReason,
+    // stats are the counts of the connection when it was closed. Only their opening time is set
+    // for the opened connections.
+    pub stats: FlowStats,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ConnectionEvent {}
+
+// SAMPLE_HEADERS_LEN is the number of bytes of a packet copied into its PacketSample, enough for
+// the Ethernet, IPv6 and TCP headers with options.
+pub const SAMPLE_HEADERS_LEN: usize = 128;
+
+// PacketSample is what the ingress program reports to userspace through the PACKET_SAMPLES ring
+// buffer for the packets it samples (see Config.sample_every): the start of the packet as it came
+// in, and where it was forwarded.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct PacketSample {
+    // timestamp is when the packet was sampled, in nanoseconds since boot (see bpf_ktime_get_ns).
+    pub timestamp: u64,
+    // backend_key is the Gateway the packet was sent to.
+    pub backend_key: BackendKey,
+    // backend is the address and port of the backend the packet was forwarded to.
+    pub backend: BackendKey,
+    // len is the length of the packet, whose first headers_len bytes are in headers.
+    pub len: u32,
+    pub headers_len: u32,
+    // proto is the IP protocol number of the packet.
+    pub proto: u8,
+    // new_conn is whether the packet started its connection, and thus had the backend selected,
+    // rather than belonging to a tracked one.
+    pub new_conn: bool,
+    pub forwarding: ForwardingMode,
+    pub headers: [u8; SAMPLE_HEADERS_LEN],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for PacketSample {}
+
+// CAPTURE_LEN is the number of bytes of a packet copied into its CapturedPacket, which is the snap
+// length of the captures.
+pub const CAPTURE_LEN: usize = 256;
+
+// CapturedPacket is what the ingress program reports to userspace through the CAPTURED_PACKETS
+// ring buffer for the packets sent to the Gateways in CAPTURES: the start of the packet as it came
+// in.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct CapturedPacket {
+    // timestamp is when the packet was captured, in nanoseconds since boot (see bpf_ktime_get_ns).
+    pub timestamp: u64,
+    // backend_key is the Gateway the packet was sent to.
+    pub backend_key: BackendKey,
+    // len is the length of the packet, whose first captured_len bytes are in data.
+    pub len: u32,
+    pub captured_len: u32,
+    pub data: [u8; CAPTURE_LEN],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for CapturedPacket {}
+
 // TCPState contains variants that represent the current phase of the TCP connection at a point in
 // time during the connection's termination.
 #[derive(Copy, Clone, Debug, Default)]
@@ -58,23 +920,220 @@ unsafe impl aya::Pod for ClientKey {}
 pub enum TCPState {
     #[default]
     Established,
+    // The closer sent a FIN, which its peer hasn't acknowledged yet.
     FinWait1,
+    // The closer's FIN was acknowledged, its peer hasn't sent a FIN yet.
     FinWait2,
+    // Both sides sent a FIN before either was acknowledged.
     Closing,
+    // Both sides sent a FIN, only the acknowledgement of the closer is missing.
+    LastAck,
     TimeWait,
     Closed,
+    // The client sent its SYN, the handshake hasn't gone through yet. Connections tracked from the
+    // middle of their stream or opened with a SYN cookie start Established instead.
+    SynSent,
 }
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for TCPState {}
 
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// TcpTimeouts are how long (in nanoseconds) the TCP connections may stay idle in each state before
+// they're expired, by the ingress program when their client comes back and by userspace otherwise.
+// They're the only entry of the TCP_TIMEOUTS map, kept apart from CONFIG for userspace to change
+// them while the programs run. 0 never expires the connections of a state.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct TcpTimeouts {
+    pub syn_sent: u64,
+    pub established: u64,
+    // fin_wait is the timeout of the connections being closed, until the last FIN is acknowledged.
+    pub fin_wait: u64,
+    pub time_wait: u64,
+}
+
+impl TcpTimeouts {
+    // Returns the timeout of the connections in the state.
+    #[inline(always)]
+    pub fn of(&self, state: TCPState) -> u64 {
+        match state {
+            TCPState::SynSent => self.syn_sent,
+            TCPState::Established => self.established,
+            TCPState::FinWait1 | TCPState::FinWait2 | TCPState::Closing | TCPState::LastAck => {
+                self.fin_wait
+            }
+            TCPState::TimeWait | TCPState::Closed => self.time_wait,
+        }
+    }
+}
+
+impl Default for TcpTimeouts {
+    fn default() -> Self {
+        TcpTimeouts {
+            syn_sent: 120 * NANOS_PER_SEC,
+            established: 60 * 60 * NANOS_PER_SEC,
+            fin_wait: 120 * NANOS_PER_SEC,
+            time_wait: 60 * NANOS_PER_SEC,
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for TcpTimeouts {}
+
+// TCPSide is one of the two ends of a TCP connection going through the load balancer.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub enum TCPSide {
+    #[default]
+    Client,
+    Backend,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for TCPSide {}
+
+// SynCookieState is where a connection opened with a SYN cookie stands. The client is done with
+// its handshake before a backend is even selected, the SYN it sent is then replayed to the backend
+// and the backend's SYN-ACK is answered on the client's behalf.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub enum SynCookieState {
+    // The connection was opened with a regular handshake.
+    #[default]
+    Off,
+    // The client's SYN was replayed to the backend, which hasn't answered yet.
+    Replayed,
+    // The backend answered, the sequence numbers of its side are shifted by seq_offset.
+    Established,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for SynCookieState {}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct LoadBalancerMapping {
     pub backend: Backend,
     pub backend_key: BackendKey,
     pub tcp_state: Option<TCPState>,
+    // tcp_closer is the side that sent the first FIN of the connection, or in the LastAck state the
+    // side whose acknowledgement is missing to finish the termination.
+    pub tcp_closer: TCPSide,
+    // last_seen is the time (in nanoseconds since boot, see bpf_ktime_get_ns) at which the last
+    // packet of the connection was processed. Userspace removes the connections which have been
+    // idle for too long for their state.
+    pub last_seen: u64,
+    // proxy_seq is the client's sequence number of the first byte of data of the connection, in
+    // front of which the PROXY protocol header is injected.
+    pub proxy_seq: u32,
+    // proxy_len is the length of the injected PROXY protocol header, by which the client's sequence
+    // numbers and the backend's acknowledgement numbers are shifted past proxy_seq. It is 0 until
+    // the header has been injected.
+    pub proxy_len: u16,
+    // snat_port is the source port allocated to the connection if it is source NATed, or 0.
+    pub snat_port: u16,
+    // port_offset is the offset of the port the client connected to in the Gateway's port range,
+    // by which the ports of the Gateway and of the backend are shifted. It is 0 for the Gateways
+    // which listen on a single port.
+    pub port_offset: u16,
+    // syn_cookie is where the handshake with the backend stands if the connection was opened with
+    // a SYN cookie.
+    pub syn_cookie: SynCookieState,
+    // cookie_seq is the SYN cookie the client was answered with, which is the initial sequence
+    // number it knows the backend by.
+    pub cookie_seq: u32,
+    // seq_offset is the difference between the backend's initial sequence number and cookie_seq,
+    // by which the backend's sequence numbers and the client's acknowledgement numbers are
+    // shifted once the handshake with the backend is complete.
+    pub seq_offset: u32,
+    // syn_sent is the time (in nanoseconds since boot) at which the SYN opening the connection was
+    // forwarded to the backend, until the backend answers it, after which it is 0. The SYNs the
+    // client retransmits keep the time of its first one.
+    pub syn_sent: u64,
+    pub stats: FlowStats,
+}
+
+impl LoadBalancerMapping {
+    // Returns the key of the connection in SNAT_CONNECTIONS, if it is source NATed.
+    #[inline(always)]
+    pub fn snat_key(&self) -> Option<SnatKey> {
+        if self.snat_port == 0 {
+            return None;
+        }
+        Some(SnatKey {
+            ip: self.backend.daddr,
+            port: self.backend_port() as u32,
+            snat_port: self.snat_port as u32,
+        })
+    }
+
+    // Returns the port the client connected to.
+    #[inline(always)]
+    pub fn gateway_port(&self) -> u16 {
+        (self.backend_key.port as u16).wrapping_add(self.port_offset)
+    }
+
+    // Returns the port the connection is forwarded to on the backend.
+    #[inline(always)]
+    pub fn backend_port(&self) -> u16 {
+        (self.backend.dport as u16).wrapping_add(self.port_offset)
+    }
 }
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for LoadBalancerMapping {}
+
+// UdpLoadBalancerMapping is the UDP variant of LoadBalancerMapping. Since UDP has no notion of
+// connection termination, a flow is considered finished once it has been idle for longer than
+// its timeout, at which point userspace removes it, or the ingress program replaces it when the
+// client comes back first.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct UdpLoadBalancerMapping {
+    pub backend: Backend,
+    pub backend_key: BackendKey,
+    // last_seen is the time (in nanoseconds since boot, see bpf_ktime_get_ns) at which the last
+    // packet of the flow was received.
+    pub last_seen: u64,
+    // idle_timeout is the timeout (in nanoseconds) of the flow, the Gateway's
+    // BackendList.udp_idle_timeout as of its last packet. 0 uses the node's default, which is
+    // also the timeout of the SCTP associations.
+    pub idle_timeout: u64,
+    // port_offset is the offset of the flow's port in the Gateway's port range, see
+    // LoadBalancerMapping.
+    pub port_offset: u16,
+    pub stats: FlowStats,
+}
+
+impl UdpLoadBalancerMapping {
+    // Returns the port the client sent the flow to.
+    #[inline(always)]
+    pub fn gateway_port(&self) -> u16 {
+        (self.backend_key.port as u16).wrapping_add(self.port_offset)
+    }
+
+    // Returns the port the flow is forwarded to on the backend.
+    #[inline(always)]
+    pub fn backend_port(&self) -> u16 {
+        (self.backend.dport as u16).wrapping_add(self.port_offset)
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for UdpLoadBalancerMapping {}
+
+// PingMapping is a ping of a client which was forwarded to a backend of a Gateway, keyed by the
+// client's address and the identifier of its echo requests, so that the echo replies of the
+// backend are translated back to come from the Gateway.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct PingMapping {
+    pub backend: [u32; 4],
+    pub gateway: [u32; 4],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for PingMapping {}
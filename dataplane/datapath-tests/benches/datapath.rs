@@ -0,0 +1,114 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+// Benchmarks of the paths of the TC programs, timed by the kernel with BPF_PROG_TEST_RUN, and of
+// the map operations of userspace. They load the programs, run them with `make bench`.
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use datapath_tests::packet::{tcp_packet, ACK, SYN};
+use datapath_tests::{backend, Datapath};
+
+const VIP: &str = "198.51.100.1:80";
+const BACKEND: &str = "10.0.0.2:8080";
+const CLIENT: &str = "192.0.2.10:40000";
+
+fn addr(addr: &str) -> SocketAddrV4 {
+    addr.parse().unwrap()
+}
+
+// Returns a distinct client for each n, from the 198.18.0.0/15 benchmarking range.
+fn client(n: u64) -> SocketAddrV4 {
+    let ip = Ipv4Addr::from(u32::from(Ipv4Addr::new(198, 18, 0, 0)) + (n / 60000) as u32);
+    SocketAddrV4::new(ip, 1024 + (n % 60000) as u16)
+}
+
+// Returns a datapath with a Gateway, and a tracked connection of CLIENT.
+fn datapath() -> Datapath {
+    let mut datapath = Datapath::load().unwrap();
+    datapath
+        .add_gateway(
+            SocketAddr::V4(addr(VIP)),
+            &[backend(SocketAddr::V4(addr(BACKEND)))],
+        )
+        .unwrap();
+    datapath
+        .ingress(&tcp_packet(addr(CLIENT), addr(VIP), SYN, 1000))
+        .unwrap();
+    datapath
+}
+
+fn programs(c: &mut Criterion) {
+    let mut datapath = datapath();
+
+    c.bench_function("ingress/new_connection", |b| {
+        let mut n = 0;
+        b.iter_custom(|iters| {
+            let mut total = Duration::ZERO;
+            for _ in 0..iters {
+                let packet = tcp_packet(client(n), addr(VIP), SYN, 1000);
+                total += datapath.ingress(&packet).unwrap().duration;
+                n += 1;
+            }
+            total
+        })
+    });
+
+    let packet = tcp_packet(addr(CLIENT), addr(VIP), ACK, 1001);
+    c.bench_function("ingress/tracked_connection", |b| {
+        b.iter_custom(|iters| {
+            (0..iters)
+                .map(|_| datapath.ingress(&packet).unwrap().duration)
+                .sum()
+        })
+    });
+
+    let packet = tcp_packet(addr(BACKEND), addr(CLIENT), ACK, 5001);
+    c.bench_function("egress/reply", |b| {
+        b.iter_custom(|iters| {
+            (0..iters)
+                .map(|_| datapath.egress(&packet).unwrap().duration)
+                .sum()
+        })
+    });
+
+    let packet = tcp_packet(addr(CLIENT), addr("203.0.113.1:80"), ACK, 1001);
+    c.bench_function("ingress/not_gateway", |b| {
+        b.iter_custom(|iters| {
+            (0..iters)
+                .map(|_| datapath.ingress(&packet).unwrap().duration)
+                .sum()
+        })
+    });
+}
+
+fn maps(c: &mut Criterion) {
+    let mut datapath = datapath();
+
+    c.bench_function("maps/lookup_connection", |b| {
+        b.iter(|| {
+            datapath
+                .tcp_connection(SocketAddr::V4(addr(CLIENT)))
+                .unwrap()
+        })
+    });
+
+    let backends: Vec<_> = (0..16)
+        .map(|n| backend(SocketAddr::V4(client(n))))
+        .collect();
+    c.bench_function("maps/add_gateway", |b| {
+        b.iter(|| {
+            datapath
+                .add_gateway(SocketAddr::V4(addr(VIP)), &backends)
+                .unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, programs, maps);
+criterion_main!(benches);
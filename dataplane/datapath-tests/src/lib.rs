@@ -0,0 +1,284 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+// Runs packets through the eBPF programs of the dataplane with BPF_PROG_TEST_RUN, without attaching
+// them to any interface, for the tests of the datapath. Loading the programs takes root (or CAP_BPF
+// and CAP_NET_ADMIN), and the eBPF programs built with `cargo xtask build-ebpf`.
+
+pub mod packet;
+
+use std::fs;
+use std::mem;
+use std::net::SocketAddr;
+use std::os::fd::{AsFd, AsRawFd};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Error};
+use aya::maps::{Array, HashMap, Map, MapError, PerCpuArray};
+use aya::programs::SchedClassifier;
+use aya::{include_bytes_aligned, Bpf, BpfLoader, Pod};
+
+use api_server::netutils::ip_to_words;
+use common::{
+    Backend, BackendKey, BackendList, BalancingAlgorithm, ClientKey, Config, GatewayIndex,
+    GatewaySlotKey, LoadBalancerMapping, UdpLoadBalancerMapping, BACKENDS_ARRAY_CAPACITY,
+    MAX_SPLIT_GROUPS,
+};
+
+/// The directory of bpffs the maps pinned by name are pinned under, a
+/// directory of its own for each Datapath so that the tests don't share their
+/// maps.
+const PIN_ROOT: &str = "/sys/fs/bpf";
+
+/// The actions of the TC programs, see `include/uapi/linux/pkt_cls.h`.
+pub const TC_ACT_OK: u32 = 0;
+pub const TC_ACT_SHOT: u32 = 2;
+pub const TC_ACT_REDIRECT: u32 = 7;
+
+/// The BPF_PROG_TEST_RUN command of the bpf syscall.
+const BPF_PROG_TEST_RUN: libc::c_long = 10;
+
+/// How much the programs may grow a packet by, e.g. with a PROXY protocol
+/// header or a tunnel.
+const MAX_PACKET_GROWTH: usize = 256;
+
+static DATAPATHS: AtomicUsize = AtomicUsize::new(0);
+
+/// The test run part of `union bpf_attr`, see `include/uapi/linux/bpf.h`.
+#[repr(C)]
+#[derive(Default)]
+struct TestRunAttr {
+    prog_fd: u32,
+    retval: u32,
+    data_size_in: u32,
+    data_size_out: u32,
+    data_in: u64,
+    data_out: u64,
+    repeat: u32,
+    duration: u32,
+    ctx_size_in: u32,
+    ctx_size_out: u32,
+    ctx_in: u64,
+    ctx_out: u64,
+    flags: u32,
+    cpu: u32,
+    batch_size: u32,
+    _pad: u32,
+}
+
+/// What a program did with a packet.
+#[derive(Debug)]
+pub struct TestRun {
+    /// The action the program returned, e.g. TC_ACT_REDIRECT.
+    pub action: u32,
+    /// The packet as the program left it.
+    pub packet: Vec<u8>,
+    /// How long the program took to run, as measured by the kernel.
+    pub duration: Duration,
+}
+
+/// The eBPF programs of the dataplane loaded in the kernel, with maps of their
+/// own.
+pub struct Datapath {
+    bpf: Bpf,
+    pin_path: PathBuf,
+}
+
+impl Datapath {
+    /// Loads the TC programs, leaving their maps empty but for the default
+    /// settings.
+    pub fn load() -> Result<Datapath, Error> {
+        let pin_path = PathBuf::from(PIN_ROOT).join(format!(
+            "blixt-tests-{}-{}",
+            process::id(),
+            DATAPATHS.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&pin_path)
+            .with_context(|| format!("failed to create {}", pin_path.display()))?;
+        let mut loader = BpfLoader::new();
+        loader.map_pin_path(&pin_path);
+        #[cfg(debug_assertions)]
+        let bpf = loader.load(include_bytes_aligned!(
+            "../../target/bpfel-unknown-none/debug/loader"
+        ));
+        #[cfg(not(debug_assertions))]
+        let bpf = loader.load(include_bytes_aligned!(
+            "../../target/bpfel-unknown-none/release/loader"
+        ));
+        let mut datapath = Datapath {
+            bpf: bpf?,
+            pin_path,
+        };
+        for name in ["tc_ingress", "tc_egress"] {
+            let program: &mut SchedClassifier = datapath
+                .bpf
+                .program_mut(name)
+                .ok_or_else(|| anyhow!("no program {}", name))?
+                .try_into()?;
+            program.load()?;
+        }
+        datapath.set_config(Config::default())?;
+        Ok(datapath)
+    }
+
+    /// Runs a packet, from its Ethernet header on, through the ingress
+    /// program.
+    pub fn ingress(&mut self, packet: &[u8]) -> Result<TestRun, Error> {
+        self.run("tc_ingress", packet)
+    }
+
+    /// Runs a packet, from its Ethernet header on, through the egress program.
+    pub fn egress(&mut self, packet: &[u8]) -> Result<TestRun, Error> {
+        self.run("tc_egress", packet)
+    }
+
+    fn run(&mut self, name: &str, packet: &[u8]) -> Result<TestRun, Error> {
+        let program: &SchedClassifier = self
+            .bpf
+            .program(name)
+            .ok_or_else(|| anyhow!("no program {}", name))?
+            .try_into()?;
+        let mut data_out = vec![0; packet.len() + MAX_PACKET_GROWTH];
+        let mut attr = TestRunAttr {
+            prog_fd: program.fd()?.as_fd().as_raw_fd() as u32,
+            data_size_in: packet.len() as u32,
+            data_size_out: data_out.len() as u32,
+            data_in: packet.as_ptr() as u64,
+            data_out: data_out.as_mut_ptr() as u64,
+            repeat: 1,
+            ..Default::default()
+        };
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_bpf,
+                BPF_PROG_TEST_RUN,
+                &mut attr as *mut TestRunAttr,
+                mem::size_of::<TestRunAttr>() as u32,
+            )
+        };
+        if ret < 0 {
+            return Err(anyhow!(
+                "failed to run {}: {}",
+                name,
+                std::io::Error::last_os_error()
+            ));
+        }
+        data_out.truncate(attr.data_size_out as usize);
+        Ok(TestRun {
+            action: attr.retval,
+            packet: data_out,
+            duration: Duration::from_nanos(attr.duration.into()),
+        })
+    }
+
+    /// Replaces the settings of the programs.
+    pub fn set_config(&mut self, config: Config) -> Result<(), Error> {
+        let mut config_map: Array<_, Config> = Array::try_from(self.map_mut("CONFIG")?)?;
+        config_map.set(0, config, 0)?;
+        Ok(())
+    }
+
+    /// Programs a Gateway balancing its backends with round robin, as the API
+    /// server would.
+    pub fn add_gateway(&mut self, vip: SocketAddr, backends: &[Backend]) -> Result<(), Error> {
+        if backends.len() > BACKENDS_ARRAY_CAPACITY {
+            return Err(anyhow!("too many backends"));
+        }
+        let key = backend_key(vip);
+        let mut backend_list = BackendList {
+            backends: [Backend::default(); BACKENDS_ARRAY_CAPACITY],
+            backends_len: backends.len() as u16,
+            algorithm: BalancingAlgorithm::RoundRobin,
+            affinity_timeout: 0,
+            slot: 0,
+            quic_cid_len: 0,
+            stateless: false,
+            split_weights: [0; MAX_SPLIT_GROUPS],
+            udp_idle_timeout: 0,
+        };
+        backend_list.backends[..backends.len()].copy_from_slice(backends);
+
+        let mut gateway_indexes: HashMap<_, GatewaySlotKey, GatewayIndex> =
+            HashMap::try_from(self.map_mut("GATEWAY_INDEXES")?)?;
+        gateway_indexes.insert(GatewaySlotKey { key, slot: 0 }, GatewayIndex::default(), 0)?;
+        let mut backends_map: HashMap<_, BackendKey, BackendList> =
+            HashMap::try_from(self.map_mut("BACKENDS")?)?;
+        backends_map.insert(key, backend_list, 0)?;
+        Ok(())
+    }
+
+    /// Returns the tracked TCP connection of a client, if any.
+    pub fn tcp_connection(
+        &mut self,
+        client: SocketAddr,
+    ) -> Result<Option<LoadBalancerMapping>, Error> {
+        self.lookup("LB_CONNECTIONS", &client_key(client))
+    }
+
+    /// Returns the tracked UDP flow of a client, if any.
+    pub fn udp_flow(
+        &mut self,
+        client: SocketAddr,
+    ) -> Result<Option<UdpLoadBalancerMapping>, Error> {
+        self.lookup("UDP_CONNECTIONS", &client_key(client))
+    }
+
+    /// Returns the count of an entry of a per-CPU array of counters, such as
+    /// TCP_DROPS, summed over the CPUs.
+    pub fn counter(&mut self, name: &str, index: u32) -> Result<u64, Error> {
+        let counters: PerCpuArray<_, u64> = PerCpuArray::try_from(self.map_mut(name)?)?;
+        Ok(counters.get(&index, 0)?.iter().sum())
+    }
+
+    fn lookup<K: Pod, V: Pod>(&mut self, name: &str, key: &K) -> Result<Option<V>, Error> {
+        let map: HashMap<_, K, V> = HashMap::try_from(self.map_mut(name)?)?;
+        match map.get(key, 0) {
+            Ok(value) => Ok(Some(value)),
+            Err(MapError::KeyNotFound) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn map_mut(&mut self, name: &str) -> Result<&mut Map, Error> {
+        self.bpf
+            .map_mut(name)
+            .ok_or_else(|| anyhow!("no map {}", name))
+    }
+}
+
+impl Drop for Datapath {
+    fn drop(&mut self) {
+        // The pinned maps outlive the programs otherwise.
+        let _ = fs::remove_dir_all(&self.pin_path);
+    }
+}
+
+/// Returns a backend of a Gateway forwarding with NAT, of weight 1.
+pub fn backend(addr: SocketAddr) -> Backend {
+    Backend {
+        daddr: ip_to_words(addr.ip()),
+        dport: addr.port().into(),
+        weight: 1,
+        ..Default::default()
+    }
+}
+
+pub fn backend_key(addr: SocketAddr) -> BackendKey {
+    BackendKey {
+        ip: ip_to_words(addr.ip()),
+        port: addr.port().into(),
+    }
+}
+
+pub fn client_key(addr: SocketAddr) -> ClientKey {
+    ClientKey {
+        ip: ip_to_words(addr.ip()),
+        port: addr.port().into(),
+    }
+}
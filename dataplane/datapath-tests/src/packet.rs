@@ -0,0 +1,251 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use anyhow::{anyhow, Error};
+
+/// The flags of the TCP header.
+pub const FIN: u8 = 0x01;
+pub const SYN: u8 = 0x02;
+pub const RST: u8 = 0x04;
+pub const PSH: u8 = 0x08;
+pub const ACK: u8 = 0x10;
+
+const ETH_HLEN: usize = 14;
+const IPV4_HLEN: usize = 20;
+const TCP_HLEN: usize = 20;
+const UDP_HLEN: usize = 8;
+const ETH_P_IP: u16 = 0x0800;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const CLIENT_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+const HOST_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+
+/// The fields of a TCP packet the tests check.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TcpPacket {
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+    pub ttl: u8,
+    pub flags: u8,
+    pub seq: u32,
+    pub ack_seq: u32,
+    pub payload: Vec<u8>,
+}
+
+/// The fields of a UDP packet the tests check.
+#[derive(Debug, PartialEq, Eq)]
+pub struct UdpPacket {
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+    pub ttl: u8,
+    /// The checksum, 0 when the sender didn't compute one.
+    pub checksum: u16,
+    pub payload: Vec<u8>,
+}
+
+/// Returns an IPv4 TCP packet from its Ethernet header on, with valid
+/// checksums and a TTL of 64.
+pub fn tcp_packet(src: SocketAddrV4, dst: SocketAddrV4, flags: u8, seq: u32) -> Vec<u8> {
+    tcp_packet_with_payload(src, dst, flags, seq, 0, &[])
+}
+
+/// Same as tcp_packet, with an acknowledged sequence number and data.
+pub fn tcp_packet_with_payload(
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    flags: u8,
+    seq: u32,
+    ack_seq: u32,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut packet = ipv4_packet(src, dst, IPPROTO_TCP, TCP_HLEN + payload.len());
+
+    let tcp_start = packet.len();
+    packet.extend_from_slice(&src.port().to_be_bytes());
+    packet.extend_from_slice(&dst.port().to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(&ack_seq.to_be_bytes());
+    packet.push((TCP_HLEN as u8 / 4) << 4);
+    packet.push(flags);
+    packet.extend_from_slice(&u16::MAX.to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0, 0]);
+    packet.extend_from_slice(payload);
+    let tcp_csum = checksum(
+        &packet[tcp_start..],
+        pseudo_header_sum(src.ip(), dst.ip(), IPPROTO_TCP, packet.len() - tcp_start),
+    );
+    packet[tcp_start + 16..tcp_start + 18].copy_from_slice(&tcp_csum.to_be_bytes());
+
+    packet
+}
+
+/// Returns an IPv4 UDP packet from its Ethernet header on, with a TTL of 64,
+/// and a valid UDP checksum unless `with_checksum` is false, which leaves it
+/// to 0.
+pub fn udp_packet(
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    payload: &[u8],
+    with_checksum: bool,
+) -> Vec<u8> {
+    let len = UDP_HLEN + payload.len();
+    let mut packet = ipv4_packet(src, dst, IPPROTO_UDP, len);
+
+    let udp_start = packet.len();
+    packet.extend_from_slice(&src.port().to_be_bytes());
+    packet.extend_from_slice(&dst.port().to_be_bytes());
+    packet.extend_from_slice(&(len as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(payload);
+    if with_checksum {
+        let udp_csum = match checksum(
+            &packet[udp_start..],
+            pseudo_header_sum(src.ip(), dst.ip(), IPPROTO_UDP, len),
+        ) {
+            // A computed checksum of 0 is sent as all ones, 0 meaning none.
+            0 => u16::MAX,
+            udp_csum => udp_csum,
+        };
+        packet[udp_start + 6..udp_start + 8].copy_from_slice(&udp_csum.to_be_bytes());
+    }
+
+    packet
+}
+
+// Returns the Ethernet and IPv4 headers of a packet whose L4 header and data
+// are `l4_len` bytes long.
+fn ipv4_packet(src: SocketAddrV4, dst: SocketAddrV4, proto: u8, l4_len: usize) -> Vec<u8> {
+    let tot_len = IPV4_HLEN + l4_len;
+    let mut packet = Vec::with_capacity(ETH_HLEN + tot_len);
+
+    packet.extend_from_slice(&HOST_MAC);
+    packet.extend_from_slice(&CLIENT_MAC);
+    packet.extend_from_slice(&ETH_P_IP.to_be_bytes());
+
+    let ip_start = packet.len();
+    packet.push(0x45);
+    packet.push(0);
+    packet.extend_from_slice(&(tot_len as u16).to_be_bytes());
+    // The identification, and the don't fragment flag.
+    packet.extend_from_slice(&[0, 1, 0x40, 0]);
+    packet.push(64);
+    packet.push(proto);
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&src.ip().octets());
+    packet.extend_from_slice(&dst.ip().octets());
+    let ip_csum = checksum(&packet[ip_start..], 0);
+    packet[ip_start + 10..ip_start + 12].copy_from_slice(&ip_csum.to_be_bytes());
+
+    packet
+}
+
+/// Parses an IPv4 TCP packet from its Ethernet header on, failing unless its
+/// checksums are valid.
+pub fn parse_tcp(packet: &[u8]) -> Result<TcpPacket, Error> {
+    let ip = parse_ipv4(packet, IPPROTO_TCP, TCP_HLEN)?;
+    let (src, dst, tcp) = (ip.src, ip.dst, ip.l4);
+    if checksum(tcp, pseudo_header_sum(&src, &dst, IPPROTO_TCP, tcp.len())) != 0 {
+        return Err(anyhow!("invalid TCP checksum"));
+    }
+    let doff = (tcp[12] >> 4) as usize * 4;
+    Ok(TcpPacket {
+        src: SocketAddrV4::new(src, u16::from_be_bytes([tcp[0], tcp[1]])),
+        dst: SocketAddrV4::new(dst, u16::from_be_bytes([tcp[2], tcp[3]])),
+        ttl: ip.ttl,
+        flags: tcp[13],
+        seq: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+        ack_seq: u32::from_be_bytes([tcp[8], tcp[9], tcp[10], tcp[11]]),
+        payload: tcp[doff..].to_vec(),
+    })
+}
+
+/// Parses an IPv4 UDP packet from its Ethernet header on, failing unless its
+/// checksums are valid. A UDP checksum of 0, i.e. none, is valid.
+pub fn parse_udp(packet: &[u8]) -> Result<UdpPacket, Error> {
+    let ip = parse_ipv4(packet, IPPROTO_UDP, UDP_HLEN)?;
+    let (src, dst, udp) = (ip.src, ip.dst, ip.l4);
+    if u16::from_be_bytes([udp[4], udp[5]]) as usize != udp.len() {
+        return Err(anyhow!("invalid UDP length"));
+    }
+    let udp_csum = u16::from_be_bytes([udp[6], udp[7]]);
+    if udp_csum != 0 && checksum(udp, pseudo_header_sum(&src, &dst, IPPROTO_UDP, udp.len())) != 0 {
+        return Err(anyhow!("invalid UDP checksum"));
+    }
+    Ok(UdpPacket {
+        src: SocketAddrV4::new(src, u16::from_be_bytes([udp[0], udp[1]])),
+        dst: SocketAddrV4::new(dst, u16::from_be_bytes([udp[2], udp[3]])),
+        ttl: ip.ttl,
+        checksum: udp_csum,
+        payload: udp[UDP_HLEN..].to_vec(),
+    })
+}
+
+// The fields of an IPv4 header the parsers need, and the L4 header and data.
+struct Ipv4Packet<'a> {
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    ttl: u8,
+    l4: &'a [u8],
+}
+
+// Parses the Ethernet and IPv4 headers of a packet, the L4 header and data
+// must be at least `l4_hlen` bytes long.
+fn parse_ipv4(packet: &[u8], proto: u8, l4_hlen: usize) -> Result<Ipv4Packet<'_>, Error> {
+    if packet.len() < ETH_HLEN + IPV4_HLEN {
+        return Err(anyhow!("packet too short"));
+    }
+    if u16::from_be_bytes([packet[12], packet[13]]) != ETH_P_IP {
+        return Err(anyhow!("not an IPv4 packet"));
+    }
+    let ip = &packet[ETH_HLEN..];
+    let ihl = (ip[0] & 0x0f) as usize * 4;
+    let tot_len = u16::from_be_bytes([ip[2], ip[3]]) as usize;
+    if ip[9] != proto {
+        return Err(anyhow!("unexpected protocol {}", ip[9]));
+    }
+    if tot_len > ip.len() || tot_len < ihl + l4_hlen {
+        return Err(anyhow!("invalid total length {}", tot_len));
+    }
+    if checksum(&ip[..ihl], 0) != 0 {
+        return Err(anyhow!("invalid IP checksum"));
+    }
+    Ok(Ipv4Packet {
+        src: Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]),
+        dst: Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]),
+        ttl: ip[8],
+        l4: &ip[ihl..tot_len],
+    })
+}
+
+// Returns the sum of the pseudo-header the TCP and UDP checksums cover.
+fn pseudo_header_sum(src: &Ipv4Addr, dst: &Ipv4Addr, proto: u8, len: usize) -> u32 {
+    let words = |ip: &Ipv4Addr| {
+        let octets = ip.octets();
+        u16::from_be_bytes([octets[0], octets[1]]) as u32
+            + u16::from_be_bytes([octets[2], octets[3]]) as u32
+    };
+    words(src) + words(dst) + proto as u32 + len as u32
+}
+
+// Returns the Internet checksum of the data, starting from a partial sum.
+// Data holding a valid checksum sums to 0.
+fn checksum(data: &[u8], initial: u32) -> u16 {
+    let mut sum = initial;
+    for chunk in data.chunks(2) {
+        let word = match chunk {
+            [hi, lo] => u16::from_be_bytes([*hi, *lo]),
+            [hi] => u16::from_be_bytes([*hi, 0]),
+            _ => unreachable!(),
+        };
+        sum += word as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
@@ -0,0 +1,166 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::net::{SocketAddr, SocketAddrV4};
+
+use common::{Config, PassReason, TCPState, UntrackedTCPAction};
+use datapath_tests::packet::{parse_tcp, tcp_packet, ACK, RST, SYN};
+use datapath_tests::{backend, Datapath, TC_ACT_OK, TC_ACT_REDIRECT};
+
+fn addr(addr: &str) -> SocketAddrV4 {
+    addr.parse().unwrap()
+}
+
+// Returns a datapath with the Gateway 198.51.100.1:80 balancing to 10.0.0.2:8080.
+fn datapath() -> Datapath {
+    let mut datapath = Datapath::load().unwrap();
+    datapath
+        .add_gateway(
+            SocketAddr::V4(addr("198.51.100.1:80")),
+            &[backend(SocketAddr::V4(addr("10.0.0.2:8080")))],
+        )
+        .unwrap();
+    datapath
+}
+
+#[test]
+#[ignore = "needs root, see cargo xtask test-datapath"]
+fn syn_to_gateway_is_forwarded_to_backend() {
+    let mut datapath = datapath();
+    let client = addr("192.0.2.10:40000");
+
+    let run = datapath
+        .ingress(&tcp_packet(client, addr("198.51.100.1:80"), SYN, 1000))
+        .unwrap();
+
+    assert_eq!(run.action, TC_ACT_REDIRECT);
+    let packet = parse_tcp(&run.packet).unwrap();
+    assert_eq!(packet.src, client);
+    assert_eq!(packet.dst, addr("10.0.0.2:8080"));
+    assert_eq!(packet.ttl, 63);
+    assert_eq!(packet.flags, SYN);
+    assert_eq!(packet.seq, 1000);
+
+    let connection = datapath
+        .tcp_connection(SocketAddr::V4(client))
+        .unwrap()
+        .expect("the connection is tracked");
+    assert_eq!(connection.backend.dport, 8080);
+    assert!(matches!(connection.tcp_state, Some(TCPState::SynSent)));
+}
+
+#[test]
+#[ignore = "needs root, see cargo xtask test-datapath"]
+fn reply_of_backend_comes_from_gateway() {
+    let mut datapath = datapath();
+    let client = addr("192.0.2.10:40000");
+    datapath
+        .ingress(&tcp_packet(client, addr("198.51.100.1:80"), SYN, 1000))
+        .unwrap();
+
+    let run = datapath
+        .egress(&tcp_packet(addr("10.0.0.2:8080"), client, SYN | ACK, 5000))
+        .unwrap();
+
+    assert_eq!(run.action, TC_ACT_OK);
+    let packet = parse_tcp(&run.packet).unwrap();
+    assert_eq!(packet.src, addr("198.51.100.1:80"));
+    assert_eq!(packet.dst, client);
+    assert_eq!(packet.flags, SYN | ACK);
+}
+
+#[test]
+#[ignore = "needs root, see cargo xtask test-datapath"]
+fn reset_removes_connection() {
+    let mut datapath = datapath();
+    let client = addr("192.0.2.10:40000");
+    datapath
+        .ingress(&tcp_packet(client, addr("198.51.100.1:80"), SYN, 1000))
+        .unwrap();
+
+    let run = datapath
+        .ingress(&tcp_packet(client, addr("198.51.100.1:80"), RST, 1001))
+        .unwrap();
+
+    assert_eq!(run.action, TC_ACT_REDIRECT);
+    assert_eq!(parse_tcp(&run.packet).unwrap().dst, addr("10.0.0.2:8080"));
+    assert!(datapath
+        .tcp_connection(SocketAddr::V4(client))
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+#[ignore = "needs root, see cargo xtask test-datapath"]
+fn untracked_packets_are_passed() {
+    let mut datapath = datapath();
+    datapath
+        .set_config(Config {
+            untracked_tcp: UntrackedTCPAction::Pass,
+            ..Default::default()
+        })
+        .unwrap();
+    let client = addr("192.0.2.10:40000");
+    let packet = tcp_packet(client, addr("198.51.100.1:80"), ACK, 1000);
+
+    let run = datapath.ingress(&packet).unwrap();
+
+    assert_eq!(run.action, TC_ACT_OK);
+    assert_eq!(run.packet, packet);
+    assert!(datapath
+        .tcp_connection(SocketAddr::V4(client))
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+#[ignore = "needs root, see cargo xtask test-datapath"]
+fn syn_to_gateway_without_backends_is_passed() {
+    let mut datapath = Datapath::load().unwrap();
+    datapath
+        .add_gateway(SocketAddr::V4(addr("198.51.100.1:80")), &[])
+        .unwrap();
+
+    let run = datapath
+        .ingress(&tcp_packet(
+            addr("192.0.2.10:40000"),
+            addr("198.51.100.1:80"),
+            SYN,
+            1000,
+        ))
+        .unwrap();
+
+    assert_eq!(run.action, TC_ACT_OK);
+    assert_eq!(
+        datapath
+            .counter("TCP_PASSES", PassReason::NoBackend as u32)
+            .unwrap(),
+        1
+    );
+}
+
+#[test]
+#[ignore = "needs root, see cargo xtask test-datapath"]
+fn syn_to_another_address_is_passed() {
+    let mut datapath = datapath();
+
+    let run = datapath
+        .ingress(&tcp_packet(
+            addr("192.0.2.10:40000"),
+            addr("198.51.100.2:80"),
+            SYN,
+            1000,
+        ))
+        .unwrap();
+
+    assert_eq!(run.action, TC_ACT_OK);
+    assert_eq!(
+        datapath
+            .counter("TCP_PASSES", PassReason::NotGateway as u32)
+            .unwrap(),
+        1
+    );
+}
@@ -0,0 +1,112 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::net::{SocketAddr, SocketAddrV4};
+
+use datapath_tests::packet::{parse_udp, udp_packet};
+use datapath_tests::{backend, Datapath, TC_ACT_OK, TC_ACT_REDIRECT};
+
+fn addr(addr: &str) -> SocketAddrV4 {
+    addr.parse().unwrap()
+}
+
+// Returns a datapath with the Gateway 198.51.100.1:53 balancing to 10.0.0.2:5353, and a flow of
+// the client 192.0.2.10:40000 to it.
+fn datapath() -> Datapath {
+    let mut datapath = Datapath::load().unwrap();
+    datapath
+        .add_gateway(
+            SocketAddr::V4(addr("198.51.100.1:53")),
+            &[backend(SocketAddr::V4(addr("10.0.0.2:5353")))],
+        )
+        .unwrap();
+    let run = datapath
+        .ingress(&udp_packet(
+            addr("192.0.2.10:40000"),
+            addr("198.51.100.1:53"),
+            b"query",
+            true,
+        ))
+        .unwrap();
+    assert_eq!(run.action, TC_ACT_REDIRECT);
+    datapath
+}
+
+#[test]
+#[ignore = "needs root, see cargo xtask test-datapath"]
+fn packet_to_gateway_is_forwarded_to_backend() {
+    let mut datapath = Datapath::load().unwrap();
+    datapath
+        .add_gateway(
+            SocketAddr::V4(addr("198.51.100.1:53")),
+            &[backend(SocketAddr::V4(addr("10.0.0.2:5353")))],
+        )
+        .unwrap();
+    let client = addr("192.0.2.10:40000");
+
+    let run = datapath
+        .ingress(&udp_packet(client, addr("198.51.100.1:53"), b"query", true))
+        .unwrap();
+
+    assert_eq!(run.action, TC_ACT_REDIRECT);
+    let packet = parse_udp(&run.packet).unwrap();
+    assert_eq!(packet.src, client);
+    assert_eq!(packet.dst, addr("10.0.0.2:5353"));
+    assert_eq!(packet.ttl, 63);
+    assert_eq!(packet.payload, b"query");
+    assert!(datapath.udp_flow(SocketAddr::V4(client)).unwrap().is_some());
+}
+
+#[test]
+#[ignore = "needs root, see cargo xtask test-datapath"]
+fn reply_of_backend_comes_from_gateway() {
+    let mut datapath = datapath();
+    let client = addr("192.0.2.10:40000");
+
+    let run = datapath
+        .egress(&udp_packet(addr("10.0.0.2:5353"), client, b"answer", true))
+        .unwrap();
+
+    assert_eq!(run.action, TC_ACT_OK);
+    let packet = parse_udp(&run.packet).unwrap();
+    assert_eq!(packet.src, addr("198.51.100.1:53"));
+    assert_eq!(packet.dst, client);
+    assert_ne!(packet.checksum, 0);
+    assert_eq!(packet.payload, b"answer");
+}
+
+#[test]
+#[ignore = "needs root, see cargo xtask test-datapath"]
+fn reply_without_checksum_is_left_without_one() {
+    let mut datapath = datapath();
+    let client = addr("192.0.2.10:40000");
+
+    let run = datapath
+        .egress(&udp_packet(addr("10.0.0.2:5353"), client, b"answer", false))
+        .unwrap();
+
+    assert_eq!(run.action, TC_ACT_OK);
+    let packet = parse_udp(&run.packet).unwrap();
+    assert_eq!(packet.src, addr("198.51.100.1:53"));
+    assert_eq!(packet.checksum, 0);
+}
+
+#[test]
+#[ignore = "needs root, see cargo xtask test-datapath"]
+fn packet_of_other_source_is_left_alone() {
+    let mut datapath = datapath();
+    let packet = udp_packet(
+        addr("10.0.0.3:5353"),
+        addr("192.0.2.10:40000"),
+        b"answer",
+        true,
+    );
+
+    let run = datapath.egress(&packet).unwrap();
+
+    assert_eq!(run.action, TC_ACT_OK);
+    assert_eq!(run.packet, packet);
+}
@@ -4,84 +4,235 @@ Copyright 2023 The Kubernetes Authors.
 SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
 */
 
-use core::mem;
-
-use aya_ebpf::{bindings::TC_ACT_PIPE, helpers::bpf_csum_diff, programs::TcContext};
-use aya_log_ebpf::info;
-use common::ClientKey;
-use network_types::{eth::EthHdr, icmp::IcmpHdr, ip::Ipv4Hdr};
+use aya_ebpf::{bindings::TC_ACT_PIPE, programs::TcContext};
+use common::{Backend, BackendKey, ClientKey};
+use memoffset::offset_of;
+use network_types::{
+    icmp::IcmpHdr,
+    ip::{IpProto, Ipv4Hdr, Ipv6Hdr},
+};
 
 use crate::{
-    utils::{csum_fold_helper, ptr_at},
-    LB_CONNECTIONS,
+    utils::{echo_id, ip_octets, l4_csum_replace_addr, l4_csum_replace_port, ptr_at, IpHdr},
+    LB_CONNECTIONS, PINGS, UDP_CONNECTIONS,
 };
 
-const ICMP_PROTO_TYPE_UNREACH: u8 = 3;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMPV6_ECHO_REPLY: u8 = 129;
 
-pub fn handle_icmp_egress(ctx: TcContext) -> Result<i32, i64> {
-    let ip_hdr: *mut Ipv4Hdr = unsafe { ptr_at(&ctx, EthHdr::LEN)? };
+const ICMP_DEST_UNREACH: u8 = 3;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+const ICMP_PARAMETER_PROBLEM: u8 = 12;
 
-    let icmp_header_offset = EthHdr::LEN + Ipv4Hdr::LEN;
+const ICMPV6_DEST_UNREACH: u8 = 1;
+const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+const ICMPV6_TIME_EXCEEDED: u8 = 3;
+const ICMPV6_PARAMETER_PROBLEM: u8 = 4;
+
+// The source and destination ports, which start the TCP and UDP headers alike.
+#[repr(C)]
+pub struct L4Ports {
+    pub source: u16,
+    pub dest: u16,
+}
 
+// Translates the ICMP errors about a packet a client sent to a backend (e.g. port unreachable or
+// fragmentation needed), which the backend or a router on the way sends back to the client. The
+// packet quoted by the error still has the backend as its destination, so the client wouldn't
+// match it with its own flow: the quoted destination is restored to the Gateway, and so is the
+// source of the error if it comes from the backend.
+// The checksum of the quoted L4 header is not updated, the quote is usually too short to hold it
+// anyway and clients don't verify it.
+pub fn handle_icmp_egress(ctx: TcContext, ip_hdr: IpHdr) -> Result<i32, i64> {
+    let icmp_header_offset = ip_hdr.l4_offset();
     let icmp_hdr: *mut IcmpHdr = unsafe { ptr_at(&ctx, icmp_header_offset)? };
 
-    // We only care about redirecting port unreachable messages currently so a
-    // UDP client can tell when the server is shutdown
-    if unsafe { (*icmp_hdr).type_ } != ICMP_PROTO_TYPE_UNREACH {
+    let echo_reply = match ip_hdr {
+        IpHdr::V4(..) => ICMP_ECHO_REPLY,
+        IpHdr::V6(..) => ICMPV6_ECHO_REPLY,
+    };
+    if unsafe { (*icmp_hdr).type_ } == echo_reply {
+        return reverse_echo_reply(&ctx, ip_hdr);
+    }
+
+    if !is_icmp_error(ip_hdr, unsafe { (*icmp_hdr).type_ }) {
         return Ok(TC_ACT_PIPE);
     }
 
-    let dest_addr = unsafe { (*ip_hdr).dst_addr };
-    let client_key = &ClientKey {
-        ip: dest_addr.to_be(),
-        port: 0,
+    // The packet quoted by the error, which the client sent.
+    let inner_offset = icmp_header_offset + IcmpHdr::LEN;
+    let (inner_ip_hdr, inner_proto) = quoted_ip_hdr(&ctx, ip_hdr, inner_offset)?;
+    let inner_ports: *mut L4Ports = unsafe { ptr_at(&ctx, inner_ip_hdr.l4_offset())? };
+
+    let client_key = ClientKey {
+        ip: inner_ip_hdr.src_addr(),
+        port: u16::from_be(unsafe { (*inner_ports).source }) as u32,
+    };
+    // The addresses of the backend and of the Gateway, with the ports of the flow.
+    let (backend, backend_port, backend_key): (Backend, u16, BackendKey) = match inner_proto {
+        IpProto::Tcp => {
+            let lb_mapping = unsafe { LB_CONNECTIONS.get(&client_key) }.ok_or(TC_ACT_PIPE)?;
+            (
+                lb_mapping.backend,
+                lb_mapping.backend_port(),
+                BackendKey {
+                    ip: lb_mapping.backend_key.ip,
+                    port: lb_mapping.gateway_port() as u32,
+                },
+            )
+        }
+        IpProto::Udp => {
+            let udp_mapping = unsafe { UDP_CONNECTIONS.get(&client_key) }.ok_or(TC_ACT_PIPE)?;
+            (
+                udp_mapping.backend,
+                udp_mapping.backend_port(),
+                BackendKey {
+                    ip: udp_mapping.backend_key.ip,
+                    port: udp_mapping.gateway_port() as u32,
+                },
+            )
+        }
+        _ => return Ok(TC_ACT_PIPE),
     };
-    let lb_mapping = unsafe { LB_CONNECTIONS.get(client_key) }.ok_or(TC_ACT_PIPE)?;
+
+    let inner_daddr = inner_ip_hdr.dst_addr();
+    let inner_dport = unsafe { (*inner_ports).dest };
+    if inner_daddr != backend.daddr || inner_dport != backend_port.to_be() {
+        return Ok(TC_ACT_PIPE);
+    }
 
     info!(
         &ctx,
-        "Received a ICMP Unreachable packet destined for svc ip: {:i} ",
-        u32::from_be(dest_addr)
+        "Received an ICMP error for tracked IP {:i}:{}, restoring VIP {:i}:{}",
+        ip_octets(&client_key.ip),
+        client_key.port,
+        ip_octets(&backend_key.ip),
+        backend_key.port,
     );
 
-    // redirect icmp unreachable message back to client
-    unsafe {
-        (*ip_hdr).src_addr = lb_mapping.backend_key.ip.to_be();
-        (*ip_hdr).check = 0;
+    let original_saddr = ip_hdr.src_addr();
+    let from_backend = original_saddr == backend.daddr;
+    if from_backend {
+        ip_hdr.set_src_addr(&backend_key.ip);
+        ip_hdr.update_csum(&ctx)?;
+    }
+    inner_ip_hdr.set_dst_addr(&backend_key.ip);
+    unsafe { (*inner_ports).dest = (backend_key.port as u16).to_be() };
+
+    let icmp_check_offset = icmp_header_offset + offset_of!(IcmpHdr, checksum);
+    match ip_hdr {
+        IpHdr::V4(..) => {
+            // A valid IPv4 header always sums up to the same value, so fixing the quoted header's
+            // checksum makes up for the address in the ICMP checksum as well.
+            let inner_check_offset = inner_offset + offset_of!(Ipv4Hdr, check);
+            ctx.l3_csum_replace(
+                inner_check_offset,
+                inner_daddr[3].to_be() as u64,
+                backend_key.ip[3].to_be() as u64,
+                4,
+            )?;
+        }
+        IpHdr::V6(..) => {
+            // The ICMPv6 checksum covers the IPv6 pseudo-header, and the quoted addresses, which
+            // have no checksum of their own.
+            if from_backend {
+                l4_csum_replace_addr(&ctx, icmp_check_offset, &original_saddr, &backend_key.ip)?;
+            }
+            for i in 0..4 {
+                if inner_daddr[i] != backend_key.ip[i] {
+                    ctx.l4_csum_replace(
+                        icmp_check_offset,
+                        inner_daddr[i].to_be() as u64,
+                        backend_key.ip[i].to_be() as u64,
+                        4,
+                    )?;
+                }
+            }
+        }
+    }
+    l4_csum_replace_port(
+        &ctx,
+        icmp_check_offset,
+        inner_dport,
+        (backend_key.port as u16).to_be(),
+    )?;
+
+    Ok(TC_ACT_PIPE)
+}
+
+// Returns true if the ICMP or ICMPv6 message is an error, which quotes the start of the packet it
+// is about.
+#[inline(always)]
+pub fn is_icmp_error(ip_hdr: IpHdr, icmp_type: u8) -> bool {
+    match ip_hdr {
+        IpHdr::V4(..) => matches!(
+            icmp_type,
+            ICMP_DEST_UNREACH | ICMP_TIME_EXCEEDED | ICMP_PARAMETER_PROBLEM
+        ),
+        IpHdr::V6(..) => matches!(
+            icmp_type,
+            ICMPV6_DEST_UNREACH
+                | ICMPV6_PACKET_TOO_BIG
+                | ICMPV6_TIME_EXCEEDED
+                | ICMPV6_PARAMETER_PROBLEM
+        ),
+    }
+}
+
+// Returns the IP header of the packet quoted by an ICMP error at `inner_offset`, which is of the
+// family of the error, and its protocol.
+#[inline(always)]
+pub fn quoted_ip_hdr(
+    ctx: &TcContext,
+    ip_hdr: IpHdr,
+    inner_offset: usize,
+) -> Result<(IpHdr, IpProto), i64> {
+    match ip_hdr {
+        IpHdr::V4(..) => {
+            let hdr: *mut Ipv4Hdr = unsafe { ptr_at(ctx, inner_offset)? };
+            let proto = unsafe { (*hdr).proto };
+            Ok((IpHdr::V4(hdr, inner_offset), proto))
+        }
+        IpHdr::V6(..) => {
+            let hdr: *mut Ipv6Hdr = unsafe { ptr_at(ctx, inner_offset)? };
+            let proto = unsafe { (*hdr).next_hdr };
+            Ok((IpHdr::V6(hdr, inner_offset), proto))
+        }
     }
+}
 
-    let full_cksum = unsafe {
-        bpf_csum_diff(
-            mem::MaybeUninit::zeroed().assume_init(),
-            0,
-            ip_hdr as *mut u32,
-            Ipv4Hdr::LEN as u32,
-            0,
-        )
-    } as u64;
-    unsafe { (*ip_hdr).check = csum_fold_helper(full_cksum) };
-
-    // Get inner ipheader since we need to update that as well
-    let icmp_inner_ip_hdr: *mut Ipv4Hdr =
-        unsafe { ptr_at(&ctx, icmp_header_offset + IcmpHdr::LEN) }?;
-
-    unsafe {
-        (*icmp_inner_ip_hdr).dst_addr = lb_mapping.backend_key.ip.to_be();
-        (*icmp_inner_ip_hdr).check = 0;
+// Translates the echo reply of a backend to a ping which was forwarded to it back into the reply of
+// the Gateway the ping was sent to, see forward_echo_request. Other echo replies are left alone.
+fn reverse_echo_reply(ctx: &TcContext, ip_hdr: IpHdr) -> Result<i32, i64> {
+    let icmp_header_offset = ip_hdr.l4_offset();
+    let client_key = ClientKey {
+        ip: ip_hdr.dst_addr(),
+        port: echo_id(ctx, icmp_header_offset)? as u32,
+    };
+    let ping = match unsafe { PINGS.get(&client_key) } {
+        Some(ping) => *ping,
+        None => return Ok(TC_ACT_PIPE),
+    };
+    let original_saddr = ip_hdr.src_addr();
+    if original_saddr != ping.backend {
+        return Ok(TC_ACT_PIPE);
     }
 
-    let full_cksum = unsafe {
-        bpf_csum_diff(
-            mem::MaybeUninit::zeroed().assume_init(),
-            0,
-            icmp_inner_ip_hdr as *mut u32,
-            Ipv4Hdr::LEN as u32,
-            0,
-        )
-    } as u64;
-    unsafe { (*icmp_inner_ip_hdr).check = csum_fold_helper(full_cksum) };
+    info!(
+        ctx,
+        "Received an echo reply for tracked IP {:i}, setting source IP to VIP {:i}",
+        ip_octets(&client_key.ip),
+        ip_octets(&ping.gateway),
+    );
 
-    unsafe { LB_CONNECTIONS.remove(client_key)? };
+    // SNAT the ip address
+    ip_hdr.set_src_addr(&ping.gateway);
+    ip_hdr.update_csum(ctx)?;
+    // The ICMPv6 checksum covers the source address, which is part of the pseudo-header.
+    if let IpHdr::V6(..) = ip_hdr {
+        let icmp_check_offset = icmp_header_offset + offset_of!(IcmpHdr, checksum);
+        l4_csum_replace_addr(ctx, icmp_check_offset, &original_saddr, &ping.gateway)?;
+    }
 
-    return Ok(TC_ACT_PIPE);
+    Ok(TC_ACT_PIPE)
 }
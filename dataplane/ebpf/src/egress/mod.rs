@@ -5,4 +5,9 @@ SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
 */
 
 pub mod icmp;
+pub mod proxy;
+pub mod sctp;
+pub mod stateless;
+pub mod syncookie;
 pub mod tcp;
+pub mod udp;
@@ -0,0 +1,62 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use aya_ebpf::programs::TcContext;
+use memoffset::offset_of;
+use network_types::tcp::TcpHdr;
+
+use crate::{
+    ingress::proxy::PROXY_V2_MAX_LEN,
+    utils::{ptr_at, seq_after, update_mss},
+};
+use common::LoadBalancerMapping;
+
+// Undoes the shift of the client's sequence numbers caused by the PROXY protocol header in the
+// acknowledgements sent by the backend, so that the client never sees the header being
+// acknowledged. The backend's MSS is also lowered so that the first segment of the client still
+// fits in the path once the header is injected.
+// SACK blocks are left untouched, the client ignores the ones it can't make sense of.
+pub fn proxy_protocol_egress(
+    ctx: &TcContext,
+    tcp_header_offset: usize,
+    lb_mapping: &LoadBalancerMapping,
+) -> Result<(), i64> {
+    let tcp_check_offset = tcp_header_offset + offset_of!(TcpHdr, check);
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(ctx, tcp_header_offset)? };
+    let tcp_hdr_ref = unsafe { &mut *tcp_hdr };
+
+    if tcp_hdr_ref.syn() == 1 {
+        return update_mss(ctx, tcp_header_offset, |mss| {
+            mss.saturating_sub(PROXY_V2_MAX_LEN)
+        });
+    }
+
+    if lb_mapping.proxy_len == 0 || tcp_hdr_ref.ack() == 0 {
+        return Ok(());
+    }
+
+    let ack_seq = u32::from_be(tcp_hdr_ref.ack_seq);
+    let header_end = lb_mapping
+        .proxy_seq
+        .wrapping_add(lb_mapping.proxy_len as u32);
+    let client_ack_seq = if !seq_after(header_end, ack_seq) {
+        ack_seq.wrapping_sub(lb_mapping.proxy_len as u32)
+    } else if seq_after(ack_seq, lb_mapping.proxy_seq) {
+        // Only part of the header was acknowledged, none of the client's data was.
+        lb_mapping.proxy_seq
+    } else {
+        return Ok(());
+    };
+
+    let original_ack_seq = tcp_hdr_ref.ack_seq;
+    tcp_hdr_ref.ack_seq = client_ack_seq.to_be();
+    ctx.l4_csum_replace(
+        tcp_check_offset,
+        original_ack_seq as u64,
+        client_ack_seq.to_be() as u64,
+        4,
+    )
+}
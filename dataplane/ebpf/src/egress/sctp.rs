@@ -0,0 +1,73 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use aya_ebpf::{bindings::TC_ACT_PIPE, helpers::bpf_ktime_get_ns, programs::TcContext};
+use common::ClientKey;
+use memoffset::offset_of;
+
+use crate::{
+    utils::{count_reply, ip_octets, ptr_at, sctp_csum_replace_port, IpHdr, SctpHdr},
+    SCTP_CONNECTIONS,
+};
+
+pub fn handle_sctp_egress(ctx: TcContext, ip_hdr: IpHdr) -> Result<i32, i64> {
+    let sctp_header_offset = ip_hdr.l4_offset();
+
+    let sctp_hdr: *mut SctpHdr = unsafe { ptr_at(&ctx, sctp_header_offset)? };
+
+    let client_addr = ip_hdr.dst_addr();
+    let dest_port = unsafe { (*sctp_hdr).dest };
+    let original_saddr = ip_hdr.src_addr();
+    let original_sport = unsafe { (*sctp_hdr).source };
+    let client_key = ClientKey {
+        ip: client_addr,
+        port: u16::from_be(dest_port) as u32,
+    };
+    let sctp_mapping = unsafe {
+        &mut *SCTP_CONNECTIONS
+            .get_ptr_mut(&client_key)
+            .ok_or(TC_ACT_PIPE)?
+    };
+
+    // Only the replies of the backend are translated, other traffic to the client (e.g. from the
+    // host itself) is left alone.
+    if original_saddr != sctp_mapping.backend.daddr
+        || original_sport != sctp_mapping.backend_port().to_be()
+    {
+        return Ok(TC_ACT_PIPE);
+    }
+    // Replies keep the association alive too.
+    sctp_mapping.last_seen = unsafe { bpf_ktime_get_ns() };
+    let gateway_port = sctp_mapping.gateway_port();
+    count_reply(&sctp_mapping.backend, ctx.len())?;
+    sctp_mapping.stats.count_reply(ctx.len());
+
+    info!(
+        &ctx,
+        "Received SCTP packet destined for tracked IP {:i}:{} setting source IP to VIP {:i}:{}",
+        ip_octets(&client_addr),
+        u16::from_be(dest_port),
+        ip_octets(&sctp_mapping.backend_key.ip),
+        gateway_port,
+    );
+
+    let sctp_len = ip_hdr.l4_len();
+    // SNAT the ip address, which the SCTP checksum doesn't cover
+    ip_hdr.set_src_addr(&sctp_mapping.backend_key.ip);
+    ip_hdr.update_csum(&ctx)?;
+    // SNAT the port
+    unsafe { (*sctp_hdr).source = gateway_port.to_be() };
+    sctp_csum_replace_port(
+        &ctx,
+        sctp_header_offset,
+        offset_of!(SctpHdr, source),
+        sctp_len,
+        original_sport,
+        gateway_port.to_be(),
+    )?;
+
+    Ok(TC_ACT_PIPE)
+}
@@ -0,0 +1,67 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use aya_ebpf::{bindings::TC_ACT_PIPE, programs::TcContext};
+use common::BackendKey;
+use memoffset::offset_of;
+use network_types::{ip::IpProto, tcp::TcpHdr, udp::UdpHdr};
+
+use crate::{
+    utils::{
+        ip_octets, l4_csum_replace_addr, l4_csum_replace_port, ptr_at, udp_csum_replace_addr,
+        udp_csum_replace_port, IpHdr,
+    },
+    STATELESS_TARGETS,
+};
+
+// Translates a packet from a backend of a stateless Gateway, whose connections aren't tracked,
+// back into a reply of the Gateway. The Gateway is found from the backend's address and port,
+// which belong to a single stateless Gateway. Anything else is left alone.
+pub fn reverse_stateless(ctx: &TcContext, ip_hdr: IpHdr, proto: IpProto) -> Result<i32, i64> {
+    // The ports are at the same offset in both headers.
+    let l4_offset = ip_hdr.l4_offset();
+    let sport: *mut u16 = unsafe { ptr_at(ctx, l4_offset + offset_of!(TcpHdr, source))? };
+    let original_sport = unsafe { *sport };
+    let original_saddr = ip_hdr.src_addr();
+
+    let target_key = BackendKey {
+        ip: original_saddr,
+        port: u16::from_be(original_sport) as u32,
+    };
+    let gateway_key = match unsafe { STATELESS_TARGETS.get(&target_key) } {
+        Some(gateway_key) => *gateway_key,
+        None => return Ok(TC_ACT_PIPE),
+    };
+    let gateway_port = gateway_key.port as u16;
+
+    info!(
+        ctx,
+        "Received packet from stateless target {:i}:{} setting source IP to VIP {:i}:{}",
+        ip_octets(&original_saddr),
+        u16::from_be(original_sport),
+        ip_octets(&gateway_key.ip),
+        gateway_port,
+    );
+
+    // SNAT the ip address
+    ip_hdr.set_src_addr(&gateway_key.ip);
+    // SNAT the port
+    unsafe { *sport = gateway_port.to_be() };
+    ip_hdr.update_csum(ctx)?;
+
+    // Calculate l4 cksum, the source address is part of the pseudo-header
+    if proto == IpProto::Udp {
+        let check_offset = l4_offset + offset_of!(UdpHdr, check);
+        udp_csum_replace_addr(ctx, check_offset, &original_saddr, &gateway_key.ip)?;
+        udp_csum_replace_port(ctx, check_offset, original_sport, gateway_port.to_be())?;
+    } else {
+        let check_offset = l4_offset + offset_of!(TcpHdr, check);
+        l4_csum_replace_addr(ctx, check_offset, &original_saddr, &gateway_key.ip)?;
+        l4_csum_replace_port(ctx, check_offset, original_sport, gateway_port.to_be())?;
+    }
+
+    Ok(TC_ACT_PIPE)
+}
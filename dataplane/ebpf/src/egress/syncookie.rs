@@ -0,0 +1,100 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use aya_ebpf::programs::TcContext;
+use memoffset::offset_of;
+use network_types::tcp::TcpHdr;
+
+use crate::{
+    ingress::{fib::redirect_to_backend, reply::REPLY_TTL, snat::snat_addr},
+    utils::{ip_octets, ptr_at, truncate_tcp_segment, IpHdr},
+};
+use common::{LoadBalancerMapping, SynCookieState};
+
+// Answers the SYN-ACK the backend sent back for the SYN replayed on behalf of a client which opened
+// its connection with a SYN cookie, with the ACK that completes the backend's handshake. The client
+// is done with its own, so the SYN-ACK goes no further. The offset between the backend's initial
+// sequence number and the cookie is recorded in the connection, to be applied from now on.
+pub fn ack_backend_syn(
+    ctx: &TcContext,
+    ip_hdr: IpHdr,
+    lb_mapping: &mut LoadBalancerMapping,
+) -> Result<i32, i64> {
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(ctx, ip_hdr.l4_offset())? };
+    let tcp_hdr_ref = unsafe { &mut *tcp_hdr };
+
+    let backend_seq = u32::from_be(tcp_hdr_ref.seq);
+    lb_mapping.seq_offset = backend_seq.wrapping_sub(lb_mapping.cookie_seq);
+    lb_mapping.syn_cookie = SynCookieState::Established;
+
+    let backend_addr = ip_hdr.src_addr();
+    // The backend knows the client by the address and port it was source NATed to, if it was.
+    let (client_addr, client_port) = match snat_addr(ip_hdr) {
+        Some(snat_addr) if lb_mapping.snat_port != 0 => (snat_addr, lb_mapping.snat_port.to_be()),
+        _ => (ip_hdr.dst_addr(), tcp_hdr_ref.dest),
+    };
+
+    info!(
+        ctx,
+        "Completing the handshake of backend {:i} for SYN cookie client port {}",
+        ip_octets(&backend_addr),
+        u16::from_be(client_port)
+    );
+
+    // The backend acknowledged the client's SYN, which is where the client's data starts. The
+    // window is the backend's own until the client's next packet announces its actual window.
+    tcp_hdr_ref.dest = tcp_hdr_ref.source;
+    tcp_hdr_ref.source = client_port;
+    tcp_hdr_ref.seq = tcp_hdr_ref.ack_seq;
+    tcp_hdr_ref.ack_seq = backend_seq.wrapping_add(1).to_be();
+    tcp_hdr_ref.set_fin(0);
+    tcp_hdr_ref.set_syn(0);
+    tcp_hdr_ref.set_rst(0);
+    tcp_hdr_ref.set_psh(0);
+    tcp_hdr_ref.set_ack(1);
+    tcp_hdr_ref.set_urg(0);
+    tcp_hdr_ref.set_ece(0);
+    tcp_hdr_ref.set_cwr(0);
+    tcp_hdr_ref.urg_ptr = 0;
+
+    ip_hdr.set_src_addr(&client_addr);
+    ip_hdr.set_dst_addr(&backend_addr);
+    ip_hdr.set_ttl(REPLY_TTL);
+    ip_hdr.clear_ecn();
+
+    truncate_tcp_segment(ctx, ip_hdr, None)?;
+
+    redirect_to_backend(ctx, ip_hdr, &lb_mapping.backend)
+}
+
+// Shifts the sequence number of a packet of the backend of a connection opened with a SYN cookie,
+// from the backend's sequence space to the cookie's, which the client knows.
+pub fn shift_backend_seq(
+    ctx: &TcContext,
+    tcp_header_offset: usize,
+    lb_mapping: &LoadBalancerMapping,
+) -> Result<(), i64> {
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(ctx, tcp_header_offset)? };
+    let tcp_hdr_ref = unsafe { &mut *tcp_hdr };
+
+    let seq = match lb_mapping.syn_cookie {
+        SynCookieState::Off => return Ok(()),
+        // The backend refused the replayed SYN, its RST is made to fit in the client's window.
+        SynCookieState::Replayed => lb_mapping.cookie_seq.wrapping_add(1),
+        SynCookieState::Established => {
+            u32::from_be(tcp_hdr_ref.seq).wrapping_sub(lb_mapping.seq_offset)
+        }
+    };
+
+    let original_seq = tcp_hdr_ref.seq;
+    tcp_hdr_ref.seq = seq.to_be();
+    ctx.l4_csum_replace(
+        tcp_header_offset + offset_of!(TcpHdr, check),
+        original_seq as u64,
+        seq.to_be() as u64,
+        4,
+    )
+}
@@ -4,87 +4,139 @@ Copyright 2023 The Kubernetes Authors.
 SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
 */
 
-use core::mem;
-
 use aya_ebpf::{
-    bindings::{TC_ACT_OK, TC_ACT_PIPE},
-    helpers::bpf_csum_diff,
+    bindings::{TC_ACT_OK, TC_ACT_PIPE, TC_ACT_SHOT},
+    helpers::bpf_ktime_get_ns,
     programs::TcContext,
 };
-use aya_log_ebpf::info;
-use common::ClientKey;
-use network_types::{eth::EthHdr, ip::Ipv4Hdr, tcp::TcpHdr};
+use common::{ClientKey, CloseReason, SynCookieState, TCPSide};
+use memoffset::offset_of;
+use network_types::{ip::IpProto, tcp::TcpHdr};
 
 use crate::{
-    utils::{csum_fold_helper, ptr_at, update_tcp_conns},
+    egress::{
+        proxy::proxy_protocol_egress,
+        stateless::reverse_stateless,
+        syncookie::{ack_backend_syn, shift_backend_seq},
+    },
+    utils::{
+        clamp_mss, count_reply, ip_octets, l4_csum_replace_addr, l4_csum_replace_port, max_mss,
+        ptr_at, record_backend_failure, record_backend_success, record_syn_latency,
+        remove_tcp_conn, update_tcp_conns, IpHdr,
+    },
     LB_CONNECTIONS,
 };
 
-pub fn handle_tcp_egress(ctx: TcContext) -> Result<i32, i64> {
+pub fn handle_tcp_egress(ctx: TcContext, ip_hdr: IpHdr) -> Result<i32, i64> {
     // gather the TCP header
-    let ip_hdr: *mut Ipv4Hdr = unsafe { ptr_at(&ctx, EthHdr::LEN)? };
-
-    let tcp_header_offset = EthHdr::LEN + Ipv4Hdr::LEN;
+    let tcp_header_offset = ip_hdr.l4_offset();
 
     let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(&ctx, tcp_header_offset)? };
 
     // capture some IP and port information
-    let client_addr = unsafe { (*ip_hdr).dst_addr };
+    let client_addr = ip_hdr.dst_addr();
     let dest_port = unsafe { (*tcp_hdr).dest };
+    let original_saddr = ip_hdr.src_addr();
+    let original_sport = unsafe { (*tcp_hdr).source };
     // The source identifier
     let client_key = ClientKey {
-        ip: u32::from_be(client_addr),
+        ip: client_addr,
         port: u16::from_be(dest_port) as u32,
     };
-    let lb_mapping = unsafe { LB_CONNECTIONS.get(&client_key) }.ok_or(TC_ACT_PIPE)?;
+    let lb_mapping = match unsafe { LB_CONNECTIONS.get_ptr_mut(&client_key) } {
+        Some(lb_mapping) => unsafe { &mut *lb_mapping },
+        None => return reverse_stateless(&ctx, ip_hdr, IpProto::Tcp),
+    };
+
+    // Only the replies of the backend are translated, other traffic to the client (e.g. from the
+    // host itself) is left alone. Replies which already come from the Gateway (e.g. in DSR mode)
+    // are still tracked.
+    let gateway_port = lb_mapping.gateway_port();
+    let from_backend = original_saddr == lb_mapping.backend.daddr
+        && original_sport == lb_mapping.backend_port().to_be();
+    let from_gateway =
+        original_saddr == lb_mapping.backend_key.ip && original_sport == gateway_port.to_be();
+    if !from_backend && !from_gateway {
+        return Ok(TC_ACT_PIPE);
+    }
+
+    let now = unsafe { bpf_ktime_get_ns() };
+    lb_mapping.last_seen = now;
+
+    // The client of a connection opened with a SYN cookie is done with its handshake, only the
+    // backend's is left to complete. Nothing but its answer to the replayed SYN is expected.
+    if lb_mapping.syn_cookie == SynCookieState::Replayed {
+        let tcp_hdr_ref = unsafe { tcp_hdr.as_ref().ok_or(TC_ACT_OK)? };
+        if tcp_hdr_ref.syn() == 1 && tcp_hdr_ref.ack() == 1 {
+            record_backend_success(&lb_mapping.backend);
+            record_syn_latency(lb_mapping, now)?;
+            return ack_backend_syn(&ctx, ip_hdr, lb_mapping);
+        }
+        if tcp_hdr_ref.rst() == 0 {
+            return Ok(TC_ACT_SHOT);
+        }
+    }
+
+    count_reply(&lb_mapping.backend, ctx.len())?;
+    lb_mapping.stats.count_reply(ctx.len());
 
     info!(
         &ctx,
         "Received TCP packet destined for tracked IP {:i}:{} setting source IP to VIP {:i}:{}",
-        u32::from_be(client_addr),
+        ip_octets(&client_addr),
         u16::from_be(dest_port),
-        lb_mapping.backend_key.ip,
-        lb_mapping.backend_key.port,
+        ip_octets(&lb_mapping.backend_key.ip),
+        gateway_port,
     );
 
-    // TODO: connection tracking cleanup https://github.com/kubernetes-sigs/blixt/issues/85
     // SNAT the ip address
-    unsafe {
-        (*ip_hdr).src_addr = lb_mapping.backend_key.ip.to_be();
-    };
+    ip_hdr.set_src_addr(&lb_mapping.backend_key.ip);
     // SNAT the port
-    unsafe { (*tcp_hdr).source = u16::from_be(lb_mapping.backend_key.port as u16) };
+    unsafe { (*tcp_hdr).source = gateway_port.to_be() };
 
-    if (ctx.data() + EthHdr::LEN + Ipv4Hdr::LEN) > ctx.data_end() {
-        info!(&ctx, "Iphdr is out of bounds");
-        return Ok(TC_ACT_OK);
-    }
+    ip_hdr.update_csum(&ctx)?;
 
-    unsafe { (*ip_hdr).check = 0 };
-    let full_cksum = unsafe {
-        bpf_csum_diff(
-            mem::MaybeUninit::zeroed().assume_init(),
-            0,
-            ip_hdr as *mut u32,
-            Ipv4Hdr::LEN as u32,
-            0,
-        )
-    } as u64;
-    unsafe { (*ip_hdr).check = csum_fold_helper(full_cksum) };
-    unsafe { (*tcp_hdr).check = 0 };
+    // Calculate l4 cksum, the source address is part of the pseudo-header
+    let tcp_check_offset = tcp_header_offset + offset_of!(TcpHdr, check);
+    l4_csum_replace_addr(
+        &ctx,
+        tcp_check_offset,
+        &original_saddr,
+        &lb_mapping.backend_key.ip,
+    )?;
+    l4_csum_replace_port(&ctx, tcp_check_offset, original_sport, gateway_port.to_be())?;
+
+    shift_backend_seq(&ctx, tcp_header_offset, lb_mapping)?;
+
+    clamp_mss(&ctx, tcp_header_offset, max_mss(ip_hdr))?;
 
+    if lb_mapping.backend.proxy_protocol {
+        proxy_protocol_egress(&ctx, tcp_header_offset, lb_mapping)?;
+    }
+
+    // Replacing the checksum invalidated our packet pointers, so grab the TCP header again.
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(&ctx, tcp_header_offset)? };
     let tcp_hdr_ref = unsafe { tcp_hdr.as_ref().ok_or(TC_ACT_OK)? };
 
+    if tcp_hdr_ref.syn() == 1 && tcp_hdr_ref.ack() == 1 {
+        record_backend_success(&lb_mapping.backend);
+        record_syn_latency(lb_mapping, now)?;
+    }
+
+    let mut mapping = *lb_mapping;
+
     // If the packet has the RST flag set, it means the connection is being terminated, so remove it
     // from our map.
     if tcp_hdr_ref.rst() == 1 {
-        unsafe {
-            LB_CONNECTIONS.remove(&client_key)?;
+        // A RST acknowledging the client's SYN means the backend refused the connection.
+        if tcp_hdr_ref.ack() == 1 && u32::from_be(tcp_hdr_ref.ack_seq) == mapping.proxy_seq {
+            record_backend_failure(&mapping.backend);
         }
+        remove_tcp_conn(&client_key, &mapping, CloseReason::Rst)?;
+        return Ok(TC_ACT_PIPE);
     }
 
-    let mut mapping = *lb_mapping;
-    update_tcp_conns(tcp_hdr_ref, &client_key, &mut mapping)?;
+    update_tcp_conns(tcp_hdr_ref, TCPSide::Backend, &client_key, &mut mapping)?;
 
     Ok(TC_ACT_PIPE)
 }
@@ -0,0 +1,89 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use aya_ebpf::{bindings::TC_ACT_PIPE, helpers::bpf_ktime_get_ns, programs::TcContext};
+use common::ClientKey;
+use memoffset::offset_of;
+use network_types::{ip::IpProto, udp::UdpHdr};
+
+use crate::{
+    egress::stateless::reverse_stateless,
+    ingress::{gateway::find_gateway, quic::record_quic_cid},
+    utils::{count_reply, ip_octets, ptr_at, udp_csum_replace_addr, udp_csum_replace_port, IpHdr},
+    UDP_CONNECTIONS,
+};
+
+pub fn handle_udp_egress(ctx: TcContext, ip_hdr: IpHdr) -> Result<i32, i64> {
+    let udp_header_offset = ip_hdr.l4_offset();
+
+    let udp_hdr: *mut UdpHdr = unsafe { ptr_at(&ctx, udp_header_offset)? };
+
+    let client_addr = ip_hdr.dst_addr();
+    let dest_port = unsafe { (*udp_hdr).dest };
+    let original_saddr = ip_hdr.src_addr();
+    let original_sport = unsafe { (*udp_hdr).source };
+    // The source identifier
+    let client_key = ClientKey {
+        ip: client_addr,
+        port: u16::from_be(dest_port) as u32,
+    };
+    let udp_mapping = match unsafe { UDP_CONNECTIONS.get_ptr_mut(&client_key) } {
+        Some(udp_mapping) => unsafe { &mut *udp_mapping },
+        None => return reverse_stateless(&ctx, ip_hdr, IpProto::Udp),
+    };
+
+    // Only the replies of the backend are translated, other traffic to the client (e.g. from the
+    // host itself) is left alone.
+    if original_saddr != udp_mapping.backend.daddr
+        || original_sport != udp_mapping.backend_port().to_be()
+    {
+        return Ok(TC_ACT_PIPE);
+    }
+    // Replies keep the flow alive too.
+    udp_mapping.last_seen = unsafe { bpf_ktime_get_ns() };
+    let gateway_port = udp_mapping.gateway_port();
+    count_reply(&udp_mapping.backend, ctx.len())?;
+    udp_mapping.stats.count_reply(ctx.len());
+
+    // The backends of QUIC Gateways tell their connection IDs in the handshake of their replies.
+    if let Some(gateway) = find_gateway(udp_mapping.backend_key.ip, gateway_port) {
+        record_quic_cid(
+            &ctx,
+            udp_header_offset + UdpHdr::LEN,
+            &gateway,
+            &udp_mapping.backend,
+        );
+    }
+
+    info!(
+        &ctx,
+        "Received UDP packet destined for tracked IP {:i}:{} setting source IP to VIP {:i}:{}",
+        ip_octets(&client_addr),
+        u16::from_be(dest_port),
+        ip_octets(&udp_mapping.backend_key.ip),
+        gateway_port,
+    );
+
+    // SNAT the ip address
+    ip_hdr.set_src_addr(&udp_mapping.backend_key.ip);
+    // SNAT the port
+    unsafe { (*udp_hdr).source = gateway_port.to_be() };
+
+    ip_hdr.update_csum(&ctx)?;
+
+    // Calculate l4 cksum, the source address is part of the pseudo-header. Replies the backend sent
+    // without a checksum are left without one.
+    let udp_check_offset = udp_header_offset + offset_of!(UdpHdr, check);
+    udp_csum_replace_addr(
+        &ctx,
+        udp_check_offset,
+        &original_saddr,
+        &udp_mapping.backend_key.ip,
+    )?;
+    udp_csum_replace_port(&ctx, udp_check_offset, original_sport, gateway_port.to_be())?;
+
+    Ok(TC_ACT_PIPE)
+}
@@ -0,0 +1,24 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use aya_ebpf::maps::lpm_trie::Key;
+
+use crate::ACLS;
+use common::{AclAction, AclKey, ACL_VIP_PREFIX_LEN};
+
+// Returns whether the ACL of the address of the Gateways denies the client. The longest prefix
+// matching the client decides, clients matching none are allowed.
+#[inline(always)]
+pub fn is_denied(vip_ip: &[u32; 4], client_ip: &[u32; 4]) -> bool {
+    let key = Key::new(
+        ACL_VIP_PREFIX_LEN + 128,
+        AclKey {
+            vip_ip: *vip_ip,
+            src_ip: client_ip.map(u32::to_be),
+        },
+    );
+    matches!(unsafe { ACLS.get(&key) }, Some(AclAction::Deny))
+}
@@ -0,0 +1,500 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use core::{ffi::c_void, ptr::addr_of_mut};
+
+use aya_ebpf::{
+    helpers::{bpf_get_prandom_u32, bpf_ktime_get_ns, bpf_map_lookup_percpu_elem},
+    programs::TcContext,
+};
+use network_types::ip::IpProto;
+
+use crate::{
+    ingress::gateway::Gateway,
+    utils::{config, is_backend_ejected},
+    AFFINITIES, BACKEND_CONNECTIONS, GATEWAY_INDEXES, MAGLEV_TABLES, RELEASED_CONNECTIONS,
+};
+use common::{
+    is_ipv4_mapped, Affinity, AffinityKey, Backend, BackendConnections, BackendKey, BackendList,
+    BalancingAlgorithm, ClientKey, GatewaySlotKey, MaglevTable, BACKENDS_ARRAY_CAPACITY,
+    MAGLEV_TABLE_SIZE, MAX_CPUS,
+};
+
+// How many entries of the Maglev table following the flow's are tried when the backend of its
+// entry is ejected for failing new connections or full.
+const MAGLEV_PROBES: usize = 8;
+
+// Mixed into the flow hash of the client to draw the second choice of power_of_two.
+const P2C_SEED: u32 = 0x9e3779b9;
+
+// Selects the backend for a new connection from the client to the Gateway, using the Gateway's
+// balancing algorithm unless the client has a session affinity. Returns None if the Gateway has no
+// backend to offer. The Gateways of both IP families of a dual-stack group share the balancing
+// state, the backend is returned with the address of the client's family.
+pub fn select_backend(
+    ctx: &TcContext,
+    backend_key: &BackendKey,
+    backend_list: &BackendList,
+    client_key: &ClientKey,
+) -> Option<Backend> {
+    let backend = select_group_backend(ctx, backend_key, backend_list, client_key)?;
+    Some(backend.for_family(is_ipv4_mapped(&client_key.ip)))
+}
+
+fn select_group_backend(
+    ctx: &TcContext,
+    backend_key: &BackendKey,
+    backend_list: &BackendList,
+    client_key: &ClientKey,
+) -> Option<Backend> {
+    if backend_list.affinity_timeout == 0 {
+        return select_backend_with_algorithm(ctx, backend_key, backend_list, client_key);
+    }
+
+    let affinity_key = AffinityKey {
+        backend_key: *backend_key,
+        client_ip: client_key.ip,
+    };
+    let now = unsafe { bpf_ktime_get_ns() };
+
+    if let Some(affinity) = unsafe { AFFINITIES.get_ptr_mut(&affinity_key) } {
+        let affinity = unsafe { &mut *affinity };
+        if now.saturating_sub(affinity.last_seen) < backend_list.affinity_timeout {
+            if let Some(backend) = find_backend(backend_list, &affinity.backend) {
+                debug!(
+                    ctx,
+                    "Found session affinity for client port {}", client_key.port
+                );
+                affinity.last_seen = now;
+                return Some(backend);
+            }
+        }
+    }
+
+    let backend = select_backend_with_algorithm(ctx, backend_key, backend_list, client_key)?;
+    let affinity = Affinity {
+        backend,
+        last_seen: now,
+    };
+    // Failing to record the affinity only means the client's next connection may land elsewhere.
+    let _ = unsafe { AFFINITIES.insert(&affinity_key, &affinity, 0_u64) };
+    Some(backend)
+}
+
+// Selects the backend of a packet to a stateless Gateway by hashing its 5-tuple through the
+// Gateway's Maglev table, ignoring the session affinity and the split groups. The backend is
+// returned with the address of the client's family.
+pub fn select_stateless_backend(
+    ctx: &TcContext,
+    gateway: &Gateway,
+    proto: IpProto,
+    client_key: &ClientKey,
+) -> Option<Backend> {
+    let slot_key = slot_key(&gateway.group_key, gateway.backend_list, None);
+    let table = unsafe { MAGLEV_TABLES.get(&slot_key) }?;
+
+    let mut hash = flow_hash(client_key);
+    for word in gateway.key.ip {
+        hash = fmix32(hash ^ word);
+    }
+    let port = gateway.key.port + gateway.port_offset as u32;
+    hash = fmix32(hash ^ (port << 8 | proto as u32));
+
+    let backend = maglev_lookup(ctx, gateway.backend_list, table, hash)?;
+    Some(backend.for_family(is_ipv4_mapped(&client_key.ip)))
+}
+
+// Returns the Gateway's current version of the backend, as long as it still accepts new
+// connections and isn't ejected for failing them.
+#[inline(always)]
+fn find_backend(backend_list: &BackendList, backend: &Backend) -> Option<Backend> {
+    let candidate = find_listed_backend(backend_list, backend)?;
+    if !is_selectable(&candidate) {
+        return None;
+    }
+    Some(candidate)
+}
+
+// Returns the Gateway's current version of the backend, if the Gateway still has it, whether or not
+// it accepts new connections.
+#[inline(always)]
+pub fn find_listed_backend(backend_list: &BackendList, backend: &Backend) -> Option<Backend> {
+    let backends_len = backend_list.backends_len as usize;
+    // The loop bound has to be a constant for the verifier to accept it.
+    for index in 0..BACKENDS_ARRAY_CAPACITY {
+        if index >= backends_len {
+            break;
+        }
+        if let Some(candidate) = backend_list.backends.get(index) {
+            if candidate.key() == backend.key() {
+                return Some(*candidate);
+            }
+        }
+    }
+    None
+}
+
+// Selects the backend with the Gateway's balancing algorithm. A Gateway splitting its traffic first
+// picks the group of backends the connection goes to, a group without a backend to offer leaves
+// its share to the whole list.
+fn select_backend_with_algorithm(
+    ctx: &TcContext,
+    backend_key: &BackendKey,
+    backend_list: &BackendList,
+    client_key: &ClientKey,
+) -> Option<Backend> {
+    if let Some(split_group) = pick_split_group(backend_list, client_key) {
+        let backend = select_split_backend(
+            ctx,
+            backend_key,
+            backend_list,
+            client_key,
+            Some(split_group),
+        );
+        if backend.is_some() {
+            return backend;
+        }
+    }
+    select_split_backend(ctx, backend_key, backend_list, client_key, None)
+}
+
+fn select_split_backend(
+    ctx: &TcContext,
+    backend_key: &BackendKey,
+    backend_list: &BackendList,
+    client_key: &ClientKey,
+    split_group: Option<u8>,
+) -> Option<Backend> {
+    match backend_list.algorithm {
+        BalancingAlgorithm::RoundRobin => round_robin(ctx, backend_key, backend_list, split_group),
+        BalancingAlgorithm::Maglev => {
+            maglev(ctx, backend_key, backend_list, client_key, split_group)
+        }
+        BalancingAlgorithm::LeastConn => least_conn(ctx, backend_list, split_group),
+        BalancingAlgorithm::PowerOfTwo => {
+            power_of_two(ctx, backend_key, backend_list, client_key, split_group)
+        }
+        BalancingAlgorithm::Random => random(ctx, backend_list, split_group),
+    }
+}
+
+// Picks the split group of the new connection of the client in proportion to the groups' weights,
+// or None if the Gateway doesn't split its traffic. The client's flow hash picks it, so that every
+// load balancer instance sends a flow to the same group.
+#[inline(always)]
+fn pick_split_group(backend_list: &BackendList, client_key: &ClientKey) -> Option<u8> {
+    // Remixed, for the group not to be correlated with the Maglev entry of the flow.
+    let point = fmix32(!flow_hash(client_key)) % 100;
+    let mut cumulative = 0;
+    for (split_group, weight) in backend_list.split_weights.iter().enumerate() {
+        cumulative += *weight as u32;
+        if point < cumulative {
+            return Some(split_group as u8);
+        }
+    }
+    None
+}
+
+// Returns the key of the balancing state of the split group, or of the whole list if None.
+#[inline(always)]
+fn slot_key(
+    backend_key: &BackendKey,
+    backend_list: &BackendList,
+    split_group: Option<u8>,
+) -> GatewaySlotKey {
+    match split_group {
+        Some(split_group) => GatewaySlotKey::split(*backend_key, backend_list.slot, split_group),
+        None => GatewaySlotKey {
+            key: *backend_key,
+            slot: backend_list.slot,
+        },
+    }
+}
+
+// Returns whether the backend is in the split group, any backend being in None.
+#[inline(always)]
+fn in_split_group(backend: &Backend, split_group: Option<u8>) -> bool {
+    split_group.map_or(true, |split_group| backend.split_group == split_group)
+}
+
+// Weighted round robin: each backend is assigned as many consecutive new connections as its weight
+// before moving on to the next one. Backends with a weight of 0, being drained, full or ejected by
+// the health checks or the passive failure detection are skipped, as are those of other split
+// groups.
+fn round_robin(
+    ctx: &TcContext,
+    backend_key: &BackendKey,
+    backend_list: &BackendList,
+    split_group: Option<u8>,
+) -> Option<Backend> {
+    let slot_key = slot_key(backend_key, backend_list, split_group);
+    let gateway_index = unsafe { GATEWAY_INDEXES.get_ptr_mut(&slot_key) }?;
+
+    let backends_len = backend_list.backends_len as usize;
+    if backends_len == 0 {
+        return None;
+    }
+
+    let mut index = unsafe { (*gateway_index).index } as usize;
+    let mut assigned = unsafe { (*gateway_index).assigned };
+
+    debug!(ctx, "Destination backend index: {}", index);
+    debug!(ctx, "Backends length: {}", backends_len);
+
+    // Visiting every backend once is enough to find one which accepts new connections, if any. The
+    // loop bound has to be a constant for the verifier to accept it.
+    for _ in 0..=BACKENDS_ARRAY_CAPACITY {
+        // this check asserts that we don't use a "zero-value" Backend
+        if index >= backends_len {
+            index = 0;
+            assigned = 0;
+        }
+        // the bpf verifier is aware of variables that are used as an index for
+        // an array and requires that we check the array boundaries against
+        // the index to ensure our access is in-bounds.
+        let backend = match backend_list.backends.get(index) {
+            Some(backend) => backend,
+            None => return None,
+        };
+
+        if is_selectable(backend)
+            && in_split_group(backend, split_group)
+            && assigned < backend.weight
+        {
+            unsafe {
+                (*gateway_index).index = index as u16;
+                (*gateway_index).assigned = assigned + 1;
+            }
+            return Some(*backend);
+        }
+
+        // this backend has had its share of connections, move on to the next one in our list
+        index += 1;
+        assigned = 0;
+    }
+
+    None
+}
+
+// Maglev consistent hashing: the flow hash of the client's address and port picks an entry of the
+// Gateway's lookup table, which holds the index of the backend to use. The connections of a client
+// thus spread over the backends, each sticking to its own. Backends with a weight of 0 or being
+// drained have no entries. If the backend is ejected for failing new connections or is full, the
+// following entries are tried, which spreads its flows over the other backends. Each split group
+// has a table of its own.
+fn maglev(
+    ctx: &TcContext,
+    backend_key: &BackendKey,
+    backend_list: &BackendList,
+    client_key: &ClientKey,
+    split_group: Option<u8>,
+) -> Option<Backend> {
+    let slot_key = slot_key(backend_key, backend_list, split_group);
+    let table = unsafe { MAGLEV_TABLES.get(&slot_key) }?;
+
+    maglev_lookup(ctx, backend_list, table, flow_hash(client_key))
+}
+
+// Returns the backend of the table's entry for the hash, or of the first of the following entries
+// whose backend is neither ejected nor full.
+#[inline(always)]
+fn maglev_lookup(
+    ctx: &TcContext,
+    backend_list: &BackendList,
+    table: &MaglevTable,
+    hash: u32,
+) -> Option<Backend> {
+    for probe in 0..MAGLEV_PROBES {
+        let entry = (hash as usize).wrapping_add(probe) % MAGLEV_TABLE_SIZE;
+        let index = *table.entries.get(entry)? as usize;
+
+        debug!(ctx, "Maglev table entry for flow hash {}: {}", hash, index);
+
+        // this check asserts that we don't use a "zero-value" Backend.
+        if index >= backend_list.backends_len as usize {
+            return None;
+        }
+        let backend = backend_list.backends.get(index)?;
+        if !is_backend_ejected(backend) && !is_backend_full(backend) {
+            return Some(*backend);
+        }
+    }
+    None
+}
+
+// Power of two choices: the client's flow hash and a remix of it pick two backends through the
+// Gateway's Maglev table, and the one with the fewer live connections gets the new connection, the
+// first winning ties. Most of a client's connections thus stay on the same two backends when the
+// backends change, while the load is steered away from the busier one. A pick landing on the
+// other's backend is compared with itself.
+fn power_of_two(
+    ctx: &TcContext,
+    backend_key: &BackendKey,
+    backend_list: &BackendList,
+    client_key: &ClientKey,
+    split_group: Option<u8>,
+) -> Option<Backend> {
+    let slot_key = slot_key(backend_key, backend_list, split_group);
+    let table = unsafe { MAGLEV_TABLES.get(&slot_key) }?;
+
+    let hash = flow_hash(client_key);
+    let first = maglev_lookup(ctx, backend_list, table, hash);
+    // Remixed differently than for the split group, for the picks not to be correlated.
+    let second = maglev_lookup(ctx, backend_list, table, fmix32(hash ^ P2C_SEED));
+    match (first, second) {
+        (Some(first), Some(second)) => {
+            if live_connections(&second.key()) < live_connections(&first.key()) {
+                Some(second)
+            } else {
+                Some(first)
+            }
+        }
+        (first, second) => first.or(second),
+    }
+}
+
+// Least connections: the backend with the fewest live connections gets the new one, the first in
+// the list winning ties. Backends with a weight of 0, being drained, full or ejected by the health
+// checks or the passive failure detection are skipped, as are those of other split groups.
+fn least_conn(
+    ctx: &TcContext,
+    backend_list: &BackendList,
+    split_group: Option<u8>,
+) -> Option<Backend> {
+    let backends_len = backend_list.backends_len as usize;
+
+    let mut selected: Option<Backend> = None;
+    let mut fewest = u64::MAX;
+    // The loop bound has to be a constant for the verifier to accept it.
+    for index in 0..BACKENDS_ARRAY_CAPACITY {
+        if index >= backends_len {
+            break;
+        }
+        let backend = backend_list.backends.get(index)?;
+        if !backend.accepts_new_connections()
+            || is_backend_ejected(backend)
+            || !in_split_group(backend, split_group)
+        {
+            continue;
+        }
+
+        // Counted once for both the backend's max_conns and the comparison, rather than through
+        // is_selectable.
+        let live = live_connections(&backend.key());
+        if backend.max_conns != 0 && live >= backend.max_conns as u64 {
+            continue;
+        }
+        if live < fewest {
+            fewest = live;
+            selected = Some(*backend);
+        }
+    }
+
+    debug!(ctx, "Fewest live connections of a backend: {}", fewest);
+    selected
+}
+
+// Random: a backend is drawn at random for the new connection, which unlike round robin writes no
+// state shared by every CPU. Draws landing on a backend with a weight of 0, being drained, full or
+// ejected by the health checks or the passive failure detection, or of another split group, move
+// on to the next backend in the list. The other weights aren't taken into account.
+fn random(ctx: &TcContext, backend_list: &BackendList, split_group: Option<u8>) -> Option<Backend> {
+    let backends_len = backend_list.backends_len as usize;
+    if backends_len == 0 {
+        return None;
+    }
+
+    let mut index = unsafe { bpf_get_prandom_u32() } as usize % backends_len;
+    debug!(ctx, "Random backend index: {}", index);
+
+    // The loop bound has to be a constant for the verifier to accept it.
+    for _ in 0..BACKENDS_ARRAY_CAPACITY {
+        if index >= backends_len {
+            index = 0;
+        }
+        let backend = backend_list.backends.get(index)?;
+        if is_selectable(backend) && in_split_group(backend, split_group) {
+            return Some(*backend);
+        }
+        index += 1;
+    }
+    None
+}
+
+// Returns whether the backend can be assigned a new connection. The backends which have their
+// maximum of live connections are skipped by every algorithm, for the new connections to spill over
+// to the next candidate rather than overload them.
+#[inline(always)]
+fn is_selectable(backend: &Backend) -> bool {
+    backend.accepts_new_connections() && !is_backend_ejected(backend) && !is_backend_full(backend)
+}
+
+// Returns whether the backend has as many live connections as its max_conns.
+#[inline(always)]
+fn is_backend_full(backend: &Backend) -> bool {
+    backend.max_conns != 0 && live_connections(&backend.key()) >= backend.max_conns as u64
+}
+
+// Returns the number of live connections of a backend, summing its counters over the CPUs of the
+// node, see Config.nr_cpus.
+#[inline(always)]
+pub fn live_connections(key: &BackendKey) -> u64 {
+    let nr_cpus = match config().nr_cpus {
+        0 => MAX_CPUS,
+        nr_cpus => nr_cpus.min(MAX_CPUS),
+    };
+    let mut opened: u64 = 0;
+    let mut closed: u64 = 0;
+    // The loop bound has to be a constant for the verifier to accept it.
+    for cpu in 0..MAX_CPUS {
+        if cpu >= nr_cpus {
+            break;
+        }
+        let counters = unsafe {
+            bpf_map_lookup_percpu_elem(
+                addr_of_mut!(BACKEND_CONNECTIONS) as *mut c_void,
+                key as *const BackendKey as *const c_void,
+                cpu,
+            )
+        } as *const BackendConnections;
+        // Looking up a CPU past the last possible one fails, as does looking up a backend
+        // which hasn't been assigned any connection yet.
+        if counters.is_null() {
+            break;
+        }
+        unsafe {
+            opened += (*counters).opened;
+            closed += (*counters).closed;
+        }
+    }
+    let released = unsafe { RELEASED_CONNECTIONS.get(key) }
+        .copied()
+        .unwrap_or(0);
+
+    opened.saturating_sub(closed).saturating_sub(released)
+}
+
+// Hashes the client's address and port. This has to be deterministic, so that every load balancer
+// instance picks the same backend for a flow.
+#[inline(always)]
+fn flow_hash(client_key: &ClientKey) -> u32 {
+    let mut hash = 0;
+    for word in client_key.ip {
+        hash = fmix32(hash ^ word);
+    }
+    fmix32(hash ^ client_key.port)
+}
+
+// The MurmurHash3 finalizer, which mixes all bits of its input into the output.
+#[inline(always)]
+fn fmix32(mut hash: u32) -> u32 {
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85ebca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2ae35);
+    hash ^= hash >> 16;
+    hash
+}
@@ -0,0 +1,33 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use aya_ebpf::{helpers::bpf_ktime_get_ns, programs::TcContext};
+
+use crate::{CAPTURED_PACKETS, CAPTURES};
+use common::{BackendKey, CapturedPacket};
+
+// Copies the packet sent to the Gateway to userspace while the Gateway is in CAPTURES, before it
+// is rewritten for its backend. Userspace bounds the capture, and stops it by removing the Gateway
+// from CAPTURES. Packets are lost while the ring buffer is full, capturing never fails the packet.
+#[inline(always)]
+pub fn capture_packet(ctx: &TcContext, backend_key: &BackendKey) {
+    if unsafe { CAPTURES.get(backend_key) }.is_none() {
+        return;
+    }
+    let mut entry = match unsafe { CAPTURED_PACKETS.reserve::<CapturedPacket>(0) } {
+        Some(entry) => entry,
+        None => return,
+    };
+    let packet = entry.as_mut_ptr();
+    unsafe {
+        (*packet).timestamp = bpf_ktime_get_ns();
+        (*packet).backend_key = *backend_key;
+        (*packet).len = ctx.len();
+        // Packets longer than the buffer are cut short, like with a snap length.
+        (*packet).captured_len = ctx.load_bytes(0, &mut (*packet).data).unwrap_or(0) as u32;
+    }
+    entry.submit(0);
+}
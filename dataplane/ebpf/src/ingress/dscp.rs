@@ -0,0 +1,27 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use aya_ebpf::programs::TcContext;
+
+use crate::{
+    utils::{IpHdr, ECN_MASK},
+    DSCP_MARKS,
+};
+use common::BackendKey;
+
+// Marks the packet forwarded from the Gateway with the Gateway's DSCP, if it has one, so that the
+// underlay can prioritize the Gateway's traffic. The ECN field is left as it is.
+#[inline(always)]
+pub fn mark_dscp(ctx: &TcContext, ip_hdr: IpHdr, gateway_key: &BackendKey) -> Result<(), i64> {
+    let dscp = match unsafe { DSCP_MARKS.get(gateway_key) } {
+        Some(dscp) => *dscp,
+        None => return Ok(()),
+    };
+    // The packet may have been rewritten since the IP header was grabbed.
+    let ip_hdr = ip_hdr.reload(ctx)?;
+    ip_hdr.set_tos(dscp << 2 | ip_hdr.tos() & ECN_MASK);
+    ip_hdr.update_csum(ctx)
+}
@@ -0,0 +1,35 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use aya_ebpf::{bindings::TC_ACT_SHOT, programs::TcContext};
+use network_types::eth::EthHdr;
+
+use crate::utils::{count_forwarded, count_redirect_error, ptr_at};
+use common::Backend;
+
+// Sends the packet to the backend without touching its L3 and L4 headers, so that the backend,
+// which holds the Gateway's address, replies directly to the client (Direct Server Return). Only
+// the MAC addresses are rewritten, the backend has to be reachable on the same L2 segment.
+pub fn redirect_dsr(ctx: &TcContext, backend: &Backend) -> Result<i32, i64> {
+    let eth_hdr: *mut EthHdr = unsafe { ptr_at(ctx, 0)? };
+    unsafe {
+        // The packet was sent to this node, so its destination is our own MAC address.
+        (*eth_hdr).src_addr = (*eth_hdr).dst_addr;
+        (*eth_hdr).dst_addr = backend.mac;
+    }
+
+    count_forwarded(backend, ctx.len())?;
+
+    // The ingress program lets the packets it doesn't drop through to the host, so send a copy of
+    // the packet to the backend and drop the original one.
+    if let Err(err) = ctx.clone_redirect(backend.ifindex as u32, 0) {
+        count_redirect_error();
+        return Err(err);
+    }
+
+    info!(ctx, "DSR redirect to ifindex: {}", backend.ifindex);
+    Ok(TC_ACT_SHOT)
+}
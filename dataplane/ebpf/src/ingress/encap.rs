@@ -0,0 +1,183 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use core::mem;
+
+use aya_ebpf::{
+    bindings::{__sk_buff, BPF_ADJ_ROOM_MAC},
+    helpers::bpf_get_hash_recalc,
+    programs::TcContext,
+    EbpfContext,
+};
+use network_types::{
+    ip::{IpProto, Ipv4Hdr, Ipv6Hdr},
+    udp::UdpHdr,
+};
+
+use crate::{
+    utils::{config, ptr_at, IpHdr, ETH_P_IP, ETH_P_IPV6},
+    TUNNELS,
+};
+use common::{Backend, Encapsulation};
+
+// Ref: https://elixir.bootlin.com/linux/v6.6/source/include/uapi/linux/bpf.h#L5953
+const BPF_F_ADJ_ROOM_ENCAP_L3_IPV4: u64 = 1 << 1;
+const BPF_F_ADJ_ROOM_ENCAP_L3_IPV6: u64 = 1 << 2;
+const BPF_F_ADJ_ROOM_ENCAP_L4_UDP: u64 = 1 << 4;
+
+// The IP protocols the GUE header tells its IPv4 and IPv6 payloads apart with.
+const IPPROTO_IPIP: u8 = 4;
+const IPPROTO_IPV6: u8 = 41;
+
+// The TTL of the encapsulated packets, whose inner packets keep their own.
+const TUNNEL_TTL: u8 = 64;
+
+// The source ports of the encapsulated packets are drawn from the flow hash of the inner packet in
+// the dynamic port range, for the flows to be spread over the paths and receive queues to the node.
+const TUNNEL_SPORT_MIN: u16 = 49152;
+const TUNNEL_SPORT_RANGE: u32 = 16384;
+
+// A Geneve header without options, whose protocol is the EtherType of the payload.
+// Ref: https://www.rfc-editor.org/rfc/rfc8926#section-3.4
+#[repr(C)]
+struct GeneveHdr {
+    ver_opt_len: u8,
+    flags: u8,
+    protocol: u16,
+    vni_reserved: u32,
+}
+
+// A variant 0 GUE header without optional fields, whose protocol is the IP protocol of the
+// payload.
+// Ref: https://datatracker.ietf.org/doc/html/draft-ietf-intarea-gue#section-3.1
+#[repr(C)]
+struct GueHdr {
+    ver_hlen: u8,
+    proto: u8,
+    flags: u16,
+}
+
+// Encapsulates the packet, whose destination was rewritten to the backend, to the backend's node
+// if the backend has one in TUNNELS, and returns the outer IP header to forward the packet with.
+// The outer header is of the family of the inner one, the packets of a family without a tunnel
+// source address in the Config are left as they are.
+pub fn encapsulate(ctx: &TcContext, ip_hdr: IpHdr, backend: &Backend) -> Result<IpHdr, i64> {
+    let tunnel = match unsafe { TUNNELS.get(&backend.daddr) } {
+        Some(tunnel) => *tunnel,
+        None => return Ok(ip_hdr),
+    };
+    let config = config();
+    let saddr = match ip_hdr {
+        IpHdr::V4(..) => config.tunnel_src_ipv4,
+        IpHdr::V6(..) => config.tunnel_src_ipv6,
+    };
+    if saddr == [0; 4] {
+        return Ok(ip_hdr);
+    }
+
+    let l3_offset = ip_hdr.l3_offset();
+    // The outer header copies the TOS of the inner one, ECN field included, for the congestion
+    // experienced along the tunnel to be carried over to the inner packet by the node.
+    // Ref: https://www.rfc-editor.org/rfc/rfc6040#section-4.1
+    let (outer_ip_len, inner_len, tos, l3_flag) = match ip_hdr {
+        IpHdr::V4(hdr, _) => unsafe {
+            (
+                Ipv4Hdr::LEN,
+                u16::from_be((*hdr).tot_len),
+                (*hdr).tos,
+                BPF_F_ADJ_ROOM_ENCAP_L3_IPV4,
+            )
+        },
+        IpHdr::V6(hdr, _) => unsafe {
+            (
+                Ipv6Hdr::LEN,
+                Ipv6Hdr::LEN as u16 + u16::from_be((*hdr).payload_len),
+                (*hdr).priority() << 4 | (*hdr).flow_label[0] >> 4,
+                BPF_F_ADJ_ROOM_ENCAP_L3_IPV6,
+            )
+        },
+    };
+    let tunnel_hdr_len = match tunnel.encapsulation {
+        Encapsulation::Geneve => mem::size_of::<GeneveHdr>(),
+        Encapsulation::Gue => mem::size_of::<GueHdr>(),
+    };
+    let udp_len = (UdpHdr::LEN + tunnel_hdr_len) as u16 + inner_len;
+    let hash = unsafe { bpf_get_hash_recalc(ctx.as_ptr() as *mut __sk_buff) };
+
+    ctx.adjust_room(
+        (outer_ip_len + UdpHdr::LEN + tunnel_hdr_len) as i32,
+        BPF_ADJ_ROOM_MAC,
+        l3_flag | BPF_F_ADJ_ROOM_ENCAP_L4_UDP,
+    )?;
+
+    // Resizing the packet invalidated our packet pointers, the outer IP header now sits where the
+    // inner one was.
+    let outer_ip_hdr = match ip_hdr {
+        IpHdr::V4(..) => {
+            let hdr: *mut Ipv4Hdr = unsafe { ptr_at(ctx, l3_offset)? };
+            unsafe {
+                (*hdr).set_version(4);
+                (*hdr).set_ihl((Ipv4Hdr::LEN / 4) as u8);
+                (*hdr).tos = tos;
+                (*hdr).tot_len = (Ipv4Hdr::LEN as u16 + udp_len).to_be();
+                (*hdr).id = 0;
+                (*hdr).frag_off = 0;
+                (*hdr).ttl = TUNNEL_TTL;
+                (*hdr).proto = IpProto::Udp;
+            }
+            IpHdr::V4(hdr, l3_offset)
+        }
+        IpHdr::V6(..) => {
+            let hdr: *mut Ipv6Hdr = unsafe { ptr_at(ctx, l3_offset)? };
+            unsafe {
+                (*hdr).set_version(6);
+                (*hdr).set_priority(tos >> 4);
+                (*hdr).flow_label = [tos << 4, 0, 0];
+                (*hdr).payload_len = udp_len.to_be();
+                (*hdr).next_hdr = IpProto::Udp;
+                (*hdr).hop_limit = TUNNEL_TTL;
+            }
+            IpHdr::V6(hdr, l3_offset)
+        }
+    };
+    outer_ip_hdr.set_src_addr(&saddr);
+    outer_ip_hdr.set_dst_addr(&tunnel.node_addr);
+    outer_ip_hdr.update_csum(ctx)?;
+
+    // The UDP checksum is left out, which IPv6 allows for tunnels (RFC 6935) as long as the
+    // node accepts it, the inner packet has its own.
+    let udp_hdr: *mut UdpHdr = unsafe { ptr_at(ctx, l3_offset + outer_ip_len)? };
+    unsafe {
+        (*udp_hdr).source = (TUNNEL_SPORT_MIN + (hash % TUNNEL_SPORT_RANGE) as u16).to_be();
+        (*udp_hdr).dest = tunnel.port.to_be();
+        (*udp_hdr).len = udp_len.to_be();
+        (*udp_hdr).check = 0;
+    }
+
+    let tunnel_hdr_offset = l3_offset + outer_ip_len + UdpHdr::LEN;
+    let is_ipv4 = matches!(ip_hdr, IpHdr::V4(..));
+    match tunnel.encapsulation {
+        Encapsulation::Geneve => {
+            let geneve_hdr = GeneveHdr {
+                ver_opt_len: 0,
+                flags: 0,
+                protocol: if is_ipv4 { ETH_P_IP } else { ETH_P_IPV6 }.to_be(),
+                vni_reserved: 0,
+            };
+            ctx.store(tunnel_hdr_offset, &geneve_hdr, 0)?;
+        }
+        Encapsulation::Gue => {
+            let gue_hdr = GueHdr {
+                ver_hlen: 0,
+                proto: if is_ipv4 { IPPROTO_IPIP } else { IPPROTO_IPV6 },
+                flags: 0,
+            };
+            ctx.store(tunnel_hdr_offset, &gue_hdr, 0)?;
+        }
+    }
+
+    Ok(outer_ip_hdr)
+}
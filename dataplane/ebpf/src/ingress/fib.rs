@@ -0,0 +1,118 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use core::mem;
+
+use aya_ebpf::{
+    bindings::{__sk_buff, bpf_fib_lookup as FibLookup, TC_ACT_SHOT},
+    helpers::{bpf_fib_lookup, bpf_redirect, bpf_redirect_neigh, bpf_redirect_peer},
+    programs::TcContext,
+    EbpfContext,
+};
+use network_types::{eth::EthHdr, ip::Ipv6Hdr};
+
+use crate::{
+    ingress::encap::encapsulate,
+    utils::{count_forwarded, count_redirect_error, ptr_at, IpHdr},
+};
+use common::Backend;
+
+const AF_INET: u8 = 2;
+const AF_INET6: u8 = 10;
+
+// Ref: https://elixir.bootlin.com/linux/v6.6/source/include/uapi/linux/bpf.h#L7060
+const BPF_FIB_LKUP_RET_SUCCESS: i64 = 0;
+const BPF_FIB_LKUP_RET_NO_NEIGH: i64 = 7;
+
+// Redirects the packet, whose destination was rewritten to the backend, to the next hop towards the
+// backend according to the FIB, so that the packets follow the routes as they change. The interface
+// the control plane programmed for the backend is only used when the FIB has no answer. Packets
+// to the backends on other nodes with a tunnel are encapsulated first, and go to the next hop
+// towards the node. The TTL of the packet is decremented like a router would, the packets whose
+// TTL runs out are answered before they're rewritten, see reply_icmp_time_exceeded.
+pub fn redirect_to_backend(ctx: &TcContext, ip_hdr: IpHdr, backend: &Backend) -> Result<i32, i64> {
+    // The packet may have been rewritten since the IP header was grabbed.
+    let ip_hdr = ip_hdr.reload(ctx)?;
+    ip_hdr.set_ttl(ip_hdr.ttl().saturating_sub(1));
+    ip_hdr.update_csum(ctx)?;
+    let ip_hdr = encapsulate(ctx, ip_hdr, backend)?;
+
+    let mut params: FibLookup = unsafe { mem::zeroed() };
+    params.ifindex = unsafe { (*(ctx.as_ptr() as *mut __sk_buff)).ifindex };
+    match ip_hdr {
+        IpHdr::V4(hdr, _) => unsafe {
+            params.family = AF_INET;
+            params.l4_protocol = (*hdr).proto as u8;
+            params.__bindgen_anon_1.tot_len = u16::from_be((*hdr).tot_len);
+            params.__bindgen_anon_2.tos = (*hdr).tos;
+            params.__bindgen_anon_3.ipv4_src = (*hdr).src_addr;
+            params.__bindgen_anon_4.ipv4_dst = (*hdr).dst_addr;
+        },
+        IpHdr::V6(hdr, _) => unsafe {
+            params.family = AF_INET6;
+            params.l4_protocol = (*hdr).next_hdr as u8;
+            params.__bindgen_anon_1.tot_len =
+                Ipv6Hdr::LEN as u16 + u16::from_be((*hdr).payload_len);
+            params.__bindgen_anon_3.ipv6_src = (*hdr).src_addr.in6_u.u6_addr32;
+            params.__bindgen_anon_4.ipv6_dst = (*hdr).dst_addr.in6_u.u6_addr32;
+        },
+    }
+
+    count_forwarded(backend, ctx.len())?;
+
+    let ret = unsafe {
+        bpf_fib_lookup(
+            ctx.as_ptr(),
+            &mut params as *mut FibLookup,
+            mem::size_of::<FibLookup>() as i32,
+            0,
+        )
+    };
+    let action = match ret {
+        BPF_FIB_LKUP_RET_SUCCESS => {
+            // The FIB resolved the neighbor as well, so the packet can go as is.
+            let eth_hdr: *mut EthHdr = unsafe { ptr_at(ctx, 0)? };
+            unsafe {
+                (*eth_hdr).src_addr = params.smac;
+                (*eth_hdr).dst_addr = params.dmac;
+            }
+            // Pods on this node get the packet straight into their network namespace, without
+            // going through the host side of their veth.
+            if backend.local && params.ifindex == backend.ifindex as u32 {
+                unsafe { bpf_redirect_peer(params.ifindex, 0) }
+            } else {
+                unsafe { bpf_redirect(params.ifindex, 0) }
+            }
+        }
+        // The neighbor subsystem resolves the next hop itself.
+        BPF_FIB_LKUP_RET_NO_NEIGH => unsafe {
+            bpf_redirect_neigh(
+                params.ifindex,
+                mem::MaybeUninit::zeroed().assume_init(),
+                0,
+                0,
+            )
+        },
+        _ => {
+            debug!(
+                ctx,
+                "FIB lookup failed with {}, falling back to ifindex {}", ret, backend.ifindex
+            );
+            unsafe {
+                bpf_redirect_neigh(
+                    backend.ifindex as u32,
+                    mem::MaybeUninit::zeroed().assume_init(),
+                    0,
+                    0,
+                )
+            }
+        }
+    };
+    if action == TC_ACT_SHOT as i64 {
+        count_redirect_error();
+    }
+    Ok(action as i32)
+}
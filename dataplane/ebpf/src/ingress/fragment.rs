@@ -0,0 +1,102 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use aya_ebpf::{bindings::TC_ACT_PIPE, programs::TcContext};
+
+use crate::{
+    ingress::{
+        dsr::redirect_dsr, fib::redirect_to_backend, reply::reply_icmp_time_exceeded,
+        snat::snat_addr,
+    },
+    utils::{ip_octets, IpHdr},
+    FRAGMENTS,
+};
+use common::{Backend, ForwardingMode, FragmentKey};
+
+// The More Fragments flag and the fragment offset, which share the frag_off field.
+const IP_MF: u16 = 0x2000;
+const IP_OFFSET: u16 = 0x1FFF;
+
+#[inline(always)]
+fn frag_off(ip_hdr: IpHdr) -> u16 {
+    match ip_hdr {
+        IpHdr::V4(hdr, _) => u16::from_be(unsafe { (*hdr).frag_off }),
+        // IPv6 fragments come with an extension header, which isn't parsed.
+        IpHdr::V6(..) => 0,
+    }
+}
+
+#[inline(always)]
+fn fragment_key(ip_hdr: IpHdr) -> FragmentKey {
+    let id = match ip_hdr {
+        IpHdr::V4(hdr, _) => u16::from_be(unsafe { (*hdr).id }) as u32,
+        IpHdr::V6(..) => 0,
+    };
+    FragmentKey {
+        src_addr: ip_hdr.src_addr(),
+        dst_addr: ip_hdr.dst_addr(),
+        id,
+    }
+}
+
+// Returns whether the packet is a fragment other than the first one, which has no L4 header.
+#[inline(always)]
+pub fn is_later_fragment(ip_hdr: IpHdr) -> bool {
+    frag_off(ip_hdr) & IP_OFFSET != 0
+}
+
+// Returns whether the packet is a fragment of a fragmented packet, the first one included.
+#[inline(always)]
+pub fn is_fragmented(ip_hdr: IpHdr) -> bool {
+    frag_off(ip_hdr) & (IP_MF | IP_OFFSET) != 0
+}
+
+// Records the backend the packet is forwarded to if it is the first fragment of a fragmented
+// packet, so that the fragments that follow go to the same backend. It has to be called before the
+// destination of the packet is rewritten.
+#[inline(always)]
+pub fn record_first_fragment(ip_hdr: IpHdr, backend: &Backend) -> Result<(), i64> {
+    let frag_off = frag_off(ip_hdr);
+    if frag_off & IP_MF == 0 || frag_off & IP_OFFSET != 0 {
+        return Ok(());
+    }
+    unsafe { FRAGMENTS.insert(&fragment_key(ip_hdr), backend, 0) }
+}
+
+// Forwards a fragment that follows the first one to the backend the first one was forwarded to.
+// Fragments can't be told apart past their IP header, so only the addresses are translated. The
+// fragments whose first fragment wasn't seen (yet) are left to the host.
+pub fn handle_fragment_ingress(ctx: TcContext, ip_hdr: IpHdr) -> Result<i32, i64> {
+    let backend = match unsafe { FRAGMENTS.get(&fragment_key(ip_hdr)) } {
+        Some(backend) => *backend,
+        None => return Ok(TC_ACT_PIPE),
+    };
+
+    info!(
+        &ctx,
+        "Received a fragment destined for svc ip: {:i}, forwarding it to {:i}",
+        ip_octets(&ip_hdr.dst_addr()),
+        ip_octets(&backend.daddr)
+    );
+
+    if backend.forwarding == ForwardingMode::Dsr {
+        return redirect_dsr(&ctx, &backend);
+    }
+    // The client has to recognize its packet in the reply, so it's answered before being rewritten.
+    if ip_hdr.ttl() <= 1 {
+        return reply_icmp_time_exceeded(&ctx, ip_hdr);
+    }
+
+    ip_hdr.set_dst_addr(&backend.daddr);
+    if backend.forwarding == ForwardingMode::Snat {
+        if let Some(snat_addr) = snat_addr(ip_hdr) {
+            ip_hdr.set_src_addr(&snat_addr);
+        }
+    }
+    ip_hdr.update_csum(&ctx)?;
+
+    redirect_to_backend(&ctx, ip_hdr, &backend)
+}
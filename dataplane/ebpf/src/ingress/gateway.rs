@@ -0,0 +1,83 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use common::{BackendKey, BackendList, PortRangeList, PORT_RANGES_CAPACITY};
+
+use crate::{BACKENDS, GATEWAY_ALIASES, PORT_RANGES};
+
+// Gateway is the Gateway a packet is destined for.
+pub struct Gateway {
+    // The key of the Gateway, whose port is the first of its port range if it has one.
+    pub key: BackendKey,
+    // The key of the Gateway whose backends and balancing state are used, which is another
+    // Gateway's if this one is an alias of it.
+    pub group_key: BackendKey,
+    pub backend_list: &'static BackendList,
+    // The offset of the port in the Gateway's port range.
+    pub port_offset: u16,
+}
+
+// Returns the Gateway listening on the port of the address. A Gateway listening on the single port
+// takes precedence over the port ranges which cover it, which take precedence over a Gateway
+// listening on any port. The latter has port 0, so that the offset is the port itself.
+#[inline(always)]
+pub fn find_gateway(ip: [u32; 4], port: u16) -> Option<Gateway> {
+    let key = BackendKey {
+        ip,
+        port: port as u32,
+    };
+    if let Some(gateway) = gateway_at(key, 0) {
+        return Some(gateway);
+    }
+
+    if let Some(range_list) = unsafe { PORT_RANGES.get(&ip) } {
+        if let Some(gateway) = find_port_range(ip, port, range_list) {
+            return Some(gateway);
+        }
+    }
+
+    gateway_at(BackendKey { ip, port: 0 }, port)
+}
+
+#[inline(always)]
+fn find_port_range(ip: [u32; 4], port: u16, range_list: &PortRangeList) -> Option<Gateway> {
+    for index in 0..PORT_RANGES_CAPACITY {
+        if index >= range_list.ranges_len as usize {
+            break;
+        }
+        let range = range_list.ranges.get(index)?;
+        if range.contains(port) {
+            let key = BackendKey {
+                ip,
+                port: range.start as u32,
+            };
+            return gateway_at(key, port - range.start);
+        }
+    }
+    None
+}
+
+// Returns the Gateway of the key, following aliases to the Gateway they share the backends of.
+#[inline(always)]
+fn gateway_at(key: BackendKey, port_offset: u16) -> Option<Gateway> {
+    if let Some(backend_list) = unsafe { BACKENDS.get(&key) } {
+        return Some(Gateway {
+            key,
+            group_key: key,
+            backend_list,
+            port_offset,
+        });
+    }
+
+    let group_key = *unsafe { GATEWAY_ALIASES.get(&key) }?;
+    let backend_list = unsafe { BACKENDS.get(&group_key) }?;
+    Some(Gateway {
+        key,
+        group_key,
+        backend_list,
+        port_offset,
+    })
+}
@@ -0,0 +1,257 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use aya_ebpf::{
+    bindings::{TC_ACT_PIPE, TC_ACT_SHOT},
+    programs::TcContext,
+};
+use common::{ClientKey, ForwardingMode, PingMapping};
+use memoffset::offset_of;
+use network_types::{
+    icmp::IcmpHdr,
+    ip::{IpProto, Ipv4Hdr},
+};
+
+use crate::{
+    egress::icmp::{is_icmp_error, quoted_ip_hdr, L4Ports},
+    ingress::{
+        acl::is_denied,
+        balancing::select_backend,
+        dsr::redirect_dsr,
+        fib::redirect_to_backend,
+        fragment::is_fragmented,
+        gateway::find_gateway,
+        nat64::is_nat64,
+        reply::{reply_icmp_echo, reply_icmp_time_exceeded},
+    },
+    utils::{echo_id, ip_octets, l4_csum_replace_addr, l4_csum_replace_port, ptr_at, IpHdr},
+    LB_CONNECTIONS, PINGS, PING_GATEWAYS, UDP_CONNECTIONS, VIP_ADDRESSES,
+};
+
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+
+// Answers the pings to the addresses of the Gateways, so that they can be monitored without
+// reaching a backend, unless the address forwards them to the backends of one of its Gateways.
+// The errors about the packets the backends sent through the Gateways are forwarded to them, see
+// translate_icmp_error. Other ICMP messages, and the pings to other addresses, are left to the
+// host.
+pub fn handle_icmp_ingress(ctx: TcContext, ip_hdr: IpHdr) -> Result<i32, i64> {
+    let icmp_hdr: *mut IcmpHdr = unsafe { ptr_at(&ctx, ip_hdr.l4_offset())? };
+    let icmp_type = unsafe { (*icmp_hdr).type_ };
+    if is_icmp_error(ip_hdr, icmp_type) {
+        return translate_icmp_error(&ctx, ip_hdr);
+    }
+    let echo_request = match ip_hdr {
+        IpHdr::V4(..) => ICMP_ECHO_REQUEST,
+        IpHdr::V6(..) => ICMPV6_ECHO_REQUEST,
+    };
+    if icmp_type != echo_request {
+        return Ok(TC_ACT_PIPE);
+    }
+
+    let gateway_addr = ip_hdr.dst_addr();
+    if unsafe { VIP_ADDRESSES.get(&gateway_addr) }.is_none() {
+        return Ok(TC_ACT_PIPE);
+    }
+    // The reply would only echo the first fragment, so fragmented pings are left to the host.
+    if is_fragmented(ip_hdr) {
+        return Ok(TC_ACT_PIPE);
+    }
+    if is_denied(&gateway_addr, &ip_hdr.src_addr()) {
+        info!(&ctx, "Client is denied by the ACL, dropping the packet");
+        return Ok(TC_ACT_SHOT);
+    }
+
+    if let Some(action) = forward_echo_request(&ctx, ip_hdr, gateway_addr)? {
+        return Ok(action);
+    }
+
+    info!(
+        &ctx,
+        "Received a ping for svc ip: {:i}, replying",
+        ip_octets(&gateway_addr)
+    );
+    reply_icmp_echo(&ctx, ip_hdr)
+}
+
+// Forwards an echo request to a backend of the Gateway which the pings to its address are forwarded
+// to, if any, picked like the backends of the Gateway's connections. The ping is tracked by the
+// client and the identifier of its echo requests, so that the backend's echo replies are translated
+// back on egress. Returns None when the ping is to be answered by the Gateway.
+fn forward_echo_request(
+    ctx: &TcContext,
+    ip_hdr: IpHdr,
+    gateway_addr: [u32; 4],
+) -> Result<Option<i32>, i64> {
+    let gateway_key = match unsafe { PING_GATEWAYS.get(&gateway_addr) } {
+        Some(gateway_key) => *gateway_key,
+        None => return Ok(None),
+    };
+    let gateway = match find_gateway(gateway_key.ip, gateway_key.port as u16) {
+        Some(gateway) => gateway,
+        None => return Ok(None),
+    };
+
+    let icmp_header_offset = ip_hdr.l4_offset();
+    let client_key = ClientKey {
+        ip: ip_hdr.src_addr(),
+        port: echo_id(ctx, icmp_header_offset)? as u32,
+    };
+    // The Gateway keeps answering its pings while it has no backend to forward them to.
+    let backend = match select_backend(ctx, &gateway.group_key, gateway.backend_list, &client_key) {
+        Some(backend) => backend,
+        None => return Ok(None),
+    };
+    // Only the TCP connections of IPv6 clients are translated for IPv4 backends.
+    if is_nat64(ip_hdr, &backend) {
+        return Ok(None);
+    }
+    // The backends reached with direct server return answer from the Gateway's address already.
+    if backend.forwarding == ForwardingMode::Dsr {
+        return redirect_dsr(ctx, &backend).map(Some);
+    }
+    if ip_hdr.ttl() <= 1 {
+        return reply_icmp_time_exceeded(ctx, ip_hdr).map(Some);
+    }
+
+    let ping = PingMapping {
+        backend: backend.daddr,
+        gateway: gateway_addr,
+    };
+    unsafe { PINGS.insert(&client_key, &ping, 0)? };
+
+    info!(
+        ctx,
+        "Forwarding a ping for svc ip: {:i} to backend {:i}",
+        ip_octets(&gateway_addr),
+        ip_octets(&backend.daddr),
+    );
+
+    // DNAT the ip address
+    ip_hdr.set_dst_addr(&backend.daddr);
+    ip_hdr.update_csum(ctx)?;
+    // The ICMPv6 checksum covers the destination address, which is part of the pseudo-header.
+    if let IpHdr::V6(..) = ip_hdr {
+        let icmp_check_offset = icmp_header_offset + offset_of!(IcmpHdr, checksum);
+        l4_csum_replace_addr(ctx, icmp_check_offset, &gateway_addr, &backend.daddr)?;
+    }
+
+    redirect_to_backend(ctx, ip_hdr, &backend).map(Some)
+}
+
+// Translates the ICMP errors about a packet a backend sent to a client through a Gateway (e.g.
+// fragmentation needed from a router on the way, or port unreachable from the client), which are
+// sent to the Gateway, and forwards them to the backend. The packet quoted by the error comes from
+// the Gateway, so the backend wouldn't match it with its own connection: the quoted source and the
+// destination of the error are restored to the backend. This is the counterpart of
+// handle_icmp_egress, the checksum of the quoted L4 header isn't updated either.
+fn translate_icmp_error(ctx: &TcContext, ip_hdr: IpHdr) -> Result<i32, i64> {
+    let icmp_header_offset = ip_hdr.l4_offset();
+
+    // The packet quoted by the error, which the Gateway sent on behalf of the backend.
+    let inner_offset = icmp_header_offset + IcmpHdr::LEN;
+    let (inner_ip_hdr, inner_proto) = quoted_ip_hdr(ctx, ip_hdr, inner_offset)?;
+    let inner_ports: *mut L4Ports = unsafe { ptr_at(ctx, inner_ip_hdr.l4_offset())? };
+
+    let client_key = ClientKey {
+        ip: inner_ip_hdr.dst_addr(),
+        port: u16::from_be(unsafe { (*inner_ports).dest }) as u32,
+    };
+    // The backend of the flow, with the addresses and ports of the backend and of the Gateway.
+    let (backend, backend_port, gateway_addr, gateway_port) = match inner_proto {
+        IpProto::Tcp => {
+            let lb_mapping = unsafe { LB_CONNECTIONS.get(&client_key) }.ok_or(TC_ACT_PIPE)?;
+            (
+                lb_mapping.backend,
+                lb_mapping.backend_port(),
+                lb_mapping.backend_key.ip,
+                lb_mapping.gateway_port(),
+            )
+        }
+        IpProto::Udp => {
+            let udp_mapping = unsafe { UDP_CONNECTIONS.get(&client_key) }.ok_or(TC_ACT_PIPE)?;
+            (
+                udp_mapping.backend,
+                udp_mapping.backend_port(),
+                udp_mapping.backend_key.ip,
+                udp_mapping.gateway_port(),
+            )
+        }
+        _ => return Ok(TC_ACT_PIPE),
+    };
+
+    let inner_saddr = inner_ip_hdr.src_addr();
+    let inner_sport = unsafe { (*inner_ports).source };
+    if ip_hdr.dst_addr() != gateway_addr
+        || inner_saddr != gateway_addr
+        || inner_sport != gateway_port.to_be()
+    {
+        return Ok(TC_ACT_PIPE);
+    }
+    // The errors of IPv6 clients would have to be translated to ICMP for IPv4 backends.
+    if is_nat64(ip_hdr, &backend) {
+        return Ok(TC_ACT_PIPE);
+    }
+
+    info!(
+        ctx,
+        "Received an ICMP error for tracked IP {:i}:{}, forwarding it to backend {:i}:{}",
+        ip_octets(&client_key.ip),
+        client_key.port,
+        ip_octets(&backend.daddr),
+        backend_port,
+    );
+
+    // The backends reached with direct server return sent the quoted packet from the Gateway's
+    // address themselves.
+    if backend.forwarding == ForwardingMode::Dsr {
+        return redirect_dsr(ctx, &backend);
+    }
+    // No ICMP error is sent about an ICMP error, the ones whose TTL runs out are dropped.
+    if ip_hdr.ttl() <= 1 {
+        return Ok(TC_ACT_SHOT);
+    }
+
+    // DNAT the ip address
+    ip_hdr.set_dst_addr(&backend.daddr);
+    ip_hdr.update_csum(ctx)?;
+    inner_ip_hdr.set_src_addr(&backend.daddr);
+    unsafe { (*inner_ports).source = backend_port.to_be() };
+
+    let icmp_check_offset = icmp_header_offset + offset_of!(IcmpHdr, checksum);
+    match ip_hdr {
+        IpHdr::V4(..) => {
+            // A valid IPv4 header always sums up to the same value, so fixing the quoted header's
+            // checksum makes up for the address in the ICMP checksum as well.
+            let inner_check_offset = inner_offset + offset_of!(Ipv4Hdr, check);
+            ctx.l3_csum_replace(
+                inner_check_offset,
+                gateway_addr[3].to_be() as u64,
+                backend.daddr[3].to_be() as u64,
+                4,
+            )?;
+        }
+        IpHdr::V6(..) => {
+            // The ICMPv6 checksum covers the IPv6 pseudo-header, and the quoted addresses, which
+            // have no checksum of their own.
+            l4_csum_replace_addr(ctx, icmp_check_offset, &gateway_addr, &backend.daddr)?;
+            for i in 0..4 {
+                if gateway_addr[i] != backend.daddr[i] {
+                    ctx.l4_csum_replace(
+                        icmp_check_offset,
+                        gateway_addr[i].to_be() as u64,
+                        backend.daddr[i].to_be() as u64,
+                        4,
+                    )?;
+                }
+            }
+        }
+    }
+    l4_csum_replace_port(ctx, icmp_check_offset, inner_sport, backend_port.to_be())?;
+
+    redirect_to_backend(ctx, ip_hdr, &backend)
+}
@@ -0,0 +1,50 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use aya_ebpf::programs::TcContext;
+use network_types::eth::EthHdr;
+
+use crate::{
+    utils::{count_redirect_error, ptr_at, IpHdr},
+    MIRRORS,
+};
+use common::BackendKey;
+
+// Sends a copy of the packet to the Gateway's mirror, if it has one, before the packet is
+// rewritten for its backend. Like with direct server return only the MAC addresses of the copy
+// are rewritten, the mirror's replies are its own to discard. The packet itself is left as it
+// came in, failing to mirror it doesn't keep it from its backend. The IP header is returned
+// grabbed again, since cloning the packet invalidates the packet pointers.
+#[inline(always)]
+pub fn mirror_packet(
+    ctx: &TcContext,
+    ip_hdr: IpHdr,
+    gateway_key: &BackendKey,
+) -> Result<IpHdr, i64> {
+    let mirror = match unsafe { MIRRORS.get(gateway_key) } {
+        Some(mirror) => *mirror,
+        None => return Ok(ip_hdr),
+    };
+
+    let eth_hdr: *mut EthHdr = unsafe { ptr_at(ctx, 0)? };
+    let (src_addr, dst_addr) = unsafe { ((*eth_hdr).src_addr, (*eth_hdr).dst_addr) };
+    unsafe {
+        // The packet was sent to this node, so its destination is our own MAC address.
+        (*eth_hdr).src_addr = dst_addr;
+        (*eth_hdr).dst_addr = mirror.mac;
+    }
+
+    if ctx.clone_redirect(mirror.ifindex as u32, 0).is_err() {
+        count_redirect_error();
+    }
+
+    let eth_hdr: *mut EthHdr = unsafe { ptr_at(ctx, 0)? };
+    unsafe {
+        (*eth_hdr).src_addr = src_addr;
+        (*eth_hdr).dst_addr = dst_addr;
+    }
+    ip_hdr.reload(ctx)
+}
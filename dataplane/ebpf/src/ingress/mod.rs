@@ -4,5 +4,28 @@ Copyright 2023 The Kubernetes Authors.
 SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
 */
 
+pub mod acl;
+pub mod balancing;
+pub mod capture;
+pub mod dscp;
+pub mod dsr;
+pub mod encap;
+pub mod fib;
+pub mod fragment;
+pub mod gateway;
+pub mod icmp;
+pub mod mirror;
+pub mod nat64;
+pub mod proxy;
+pub mod quic;
+pub mod ratelimit;
+pub mod reply;
+pub mod sample;
+pub mod sctp;
+pub mod snat;
+pub mod stateless;
+pub mod syncookie;
 pub mod tcp;
+pub mod toa;
+pub mod tproxy;
 pub mod udp;
@@ -0,0 +1,196 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use aya_ebpf::{
+    bindings::{__sk_buff, TC_ACT_SHOT},
+    helpers::bpf_skb_change_proto,
+    programs::TcContext,
+};
+use memoffset::offset_of;
+use network_types::{
+    ip::{IpProto, Ipv4Hdr, Ipv6Hdr},
+    tcp::TcpHdr,
+};
+
+use crate::utils::{
+    config, l4_csum_replace_addr, l4_csum_replace_port, ptr_at, IpHdr, ETH_P_IP, ETH_P_IPV6,
+};
+use common::{is_ipv4_mapped, Backend};
+
+// The Don't Fragment flag, which the translated IPv4 packets carry since IPv6 routers don't
+// fragment either.
+const IP_DF: u16 = 0x4000;
+// The More Fragments flag and the fragment offset, which share the frag_off field.
+const IP_FRAGMENT: u16 = 0x3FFF;
+
+// Returns whether the packet is translated from IPv6 to IPv4 (NAT64) to be forwarded to the
+// backend, which is the case of the IPv4 targets of IPv6 Gateways.
+#[inline(always)]
+pub fn is_nat64(ip_hdr: IpHdr, backend: &Backend) -> bool {
+    matches!(ip_hdr, IpHdr::V6(..)) && is_ipv4_mapped(&backend.daddr)
+}
+
+// Returns the address the translated packets come from, which is the IPv4 source NAT address of
+// this node, if one is configured.
+#[inline(always)]
+pub fn nat64_addr() -> Option<[u32; 4]> {
+    let addr = config().snat_ipv4;
+    if addr == [0; 4] {
+        return None;
+    }
+    Some(addr)
+}
+
+// Returns the address the way it sums up in the L4 pseudo-header, see IpHdr::pseudo_hdr_csum.
+#[inline(always)]
+fn pseudo_hdr_addr(addr: &[u32; 4]) -> [u32; 4] {
+    match is_ipv4_mapped(addr) {
+        true => [0, 0, 0, addr[3]],
+        false => *addr,
+    }
+}
+
+// Rewrites the addresses and ports of the TCP packet ahead of its translation to the other IP
+// family, updating the TCP checksum for them and for the pseudo-header of the new family.
+#[inline(always)]
+fn translate_tcp_hdr(
+    ctx: &TcContext,
+    ip_hdr: IpHdr,
+    saddr: &[u32; 4],
+    sport: u16,
+    daddr: &[u32; 4],
+    dport: u16,
+) -> Result<(), i64> {
+    let tcp_header_offset = ip_hdr.l4_offset();
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(ctx, tcp_header_offset)? };
+    let original_saddr = pseudo_hdr_addr(&ip_hdr.src_addr());
+    let original_daddr = pseudo_hdr_addr(&ip_hdr.dst_addr());
+    let original_sport = unsafe { (*tcp_hdr).source };
+    let original_dport = unsafe { (*tcp_hdr).dest };
+    unsafe {
+        (*tcp_hdr).source = sport.to_be();
+        (*tcp_hdr).dest = dport.to_be();
+    }
+
+    // The length and protocol sum up to the same value in both pseudo-headers, only the addresses
+    // differ.
+    let tcp_check_offset = tcp_header_offset + offset_of!(TcpHdr, check);
+    l4_csum_replace_addr(
+        ctx,
+        tcp_check_offset,
+        &original_saddr,
+        &pseudo_hdr_addr(saddr),
+    )?;
+    l4_csum_replace_addr(
+        ctx,
+        tcp_check_offset,
+        &original_daddr,
+        &pseudo_hdr_addr(daddr),
+    )?;
+    l4_csum_replace_port(ctx, tcp_check_offset, original_sport, sport.to_be())?;
+    l4_csum_replace_port(ctx, tcp_check_offset, original_dport, dport.to_be())
+}
+
+// Switches the packet to the protocol of the given EtherType, resizing its IP header, and rewrites
+// the EtherType of its frame (or VLAN tag), which sits right in front of the IP header.
+#[inline(always)]
+fn change_proto(ctx: &TcContext, l3_offset: usize, ether_type: u16) -> Result<(), i64> {
+    let ret =
+        unsafe { bpf_skb_change_proto(ctx.as_ptr() as *mut __sk_buff, ether_type.to_be(), 0) };
+    if ret != 0 {
+        return Err(ret);
+    }
+    ctx.store(l3_offset - 2, &ether_type.to_be(), 0)?;
+    Ok(())
+}
+
+// Translates the IPv6 TCP packet of a client into an IPv4 packet from `saddr`:`sport` to
+// `daddr`:`dport`, all IPv4-mapped, and returns its IP header. The traffic class and hop limit
+// carry over, the IPv4 header is otherwise built from scratch.
+// Ref: https://www.rfc-editor.org/rfc/rfc7915#section-5.1
+pub fn translate_tcp_6to4(
+    ctx: &TcContext,
+    ip_hdr: IpHdr,
+    saddr: &[u32; 4],
+    sport: u16,
+    daddr: &[u32; 4],
+    dport: u16,
+) -> Result<IpHdr, i64> {
+    let (hdr, l3_offset) = match ip_hdr {
+        IpHdr::V6(hdr, l3_offset) => (hdr, l3_offset),
+        IpHdr::V4(..) => return Ok(ip_hdr),
+    };
+    let (tos, payload_len, hop_limit) = unsafe {
+        (
+            (*hdr).priority() << 4 | (*hdr).flow_label[0] >> 4,
+            u16::from_be((*hdr).payload_len),
+            (*hdr).hop_limit,
+        )
+    };
+
+    translate_tcp_hdr(ctx, ip_hdr, saddr, sport, daddr, dport)?;
+    change_proto(ctx, l3_offset, ETH_P_IP)?;
+
+    // Resizing the packet invalidated our packet pointers, so grab the IP header again.
+    let hdr: *mut Ipv4Hdr = unsafe { ptr_at(ctx, l3_offset)? };
+    unsafe {
+        (*hdr).set_version(4);
+        (*hdr).set_ihl((Ipv4Hdr::LEN / 4) as u8);
+        (*hdr).tos = tos;
+        (*hdr).tot_len = (Ipv4Hdr::LEN as u16 + payload_len).to_be();
+        (*hdr).id = 0;
+        (*hdr).frag_off = IP_DF.to_be();
+        (*hdr).ttl = hop_limit;
+        (*hdr).proto = IpProto::Tcp;
+    }
+    let ip_hdr = IpHdr::V4(hdr, l3_offset);
+    ip_hdr.set_src_addr(saddr);
+    ip_hdr.set_dst_addr(daddr);
+    ip_hdr.update_csum(ctx)?;
+    Ok(ip_hdr)
+}
+
+// Translates the IPv4 TCP packet of a backend into an IPv6 packet from `saddr`:`sport` to
+// `daddr`:`dport`, and returns its IP header. The TOS and TTL carry over, the IPv4 options and
+// fragments can't be translated and are dropped.
+// Ref: https://www.rfc-editor.org/rfc/rfc7915#section-4.1
+pub fn translate_tcp_4to6(
+    ctx: &TcContext,
+    ip_hdr: IpHdr,
+    saddr: &[u32; 4],
+    sport: u16,
+    daddr: &[u32; 4],
+    dport: u16,
+) -> Result<IpHdr, i64> {
+    let (hdr, l3_offset) = match ip_hdr {
+        IpHdr::V4(hdr, l3_offset) => (hdr, l3_offset),
+        IpHdr::V6(..) => return Ok(ip_hdr),
+    };
+    if ip_hdr.header_len() != Ipv4Hdr::LEN
+        || u16::from_be(unsafe { (*hdr).frag_off }) & IP_FRAGMENT != 0
+    {
+        return Err(TC_ACT_SHOT.into());
+    }
+    let (tos, payload_len, ttl) = unsafe { ((*hdr).tos, ip_hdr.l4_len(), (*hdr).ttl) };
+
+    translate_tcp_hdr(ctx, ip_hdr, saddr, sport, daddr, dport)?;
+    change_proto(ctx, l3_offset, ETH_P_IPV6)?;
+
+    // Resizing the packet invalidated our packet pointers, so grab the IP header again.
+    let hdr: *mut Ipv6Hdr = unsafe { ptr_at(ctx, l3_offset)? };
+    unsafe {
+        (*hdr).set_version(6);
+        (*hdr).set_priority(tos >> 4);
+        (*hdr).flow_label = [tos << 4, 0, 0];
+        (*hdr).payload_len = payload_len.to_be();
+        (*hdr).next_hdr = IpProto::Tcp;
+        (*hdr).hop_limit = ttl;
+    }
+    let ip_hdr = IpHdr::V6(hdr, l3_offset);
+    ip_hdr.set_src_addr(saddr);
+    ip_hdr.set_dst_addr(daddr);
+    Ok(ip_hdr)
+}
@@ -0,0 +1,127 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use core::mem;
+
+use aya_ebpf::programs::TcContext;
+use memoffset::offset_of;
+use network_types::tcp::TcpHdr;
+
+use crate::utils::{insert_after_tcp_header, ptr_at, seq_after, IpHdr};
+use common::{is_ipv4_mapped, BackendKey, ClientKey};
+
+// Ref: https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+const PROXY_V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+// Version 2, PROXY command.
+const PROXY_V2_VERSION_COMMAND: u8 = 0x21;
+const PROXY_V2_TCP_OVER_IPV4: u8 = 0x11;
+const PROXY_V2_TCP_OVER_IPV6: u8 = 0x21;
+
+#[repr(C)]
+struct ProxyV2Ipv4Hdr {
+    signature: [u8; 12],
+    version_command: u8,
+    family: u8,
+    // Length of the addresses that follow, in network byte order.
+    len: u16,
+    src_addr: u32,
+    dst_addr: u32,
+    src_port: u16,
+    dst_port: u16,
+}
+
+#[repr(C)]
+struct ProxyV2Ipv6Hdr {
+    signature: [u8; 12],
+    version_command: u8,
+    family: u8,
+    // Length of the addresses that follow, in network byte order.
+    len: u16,
+    src_addr: [u32; 4],
+    dst_addr: [u32; 4],
+    src_port: u16,
+    dst_port: u16,
+}
+
+// The longest PROXY protocol header that can be injected in a connection, which the backends'
+// MSS has to leave room for.
+pub const PROXY_V2_MAX_LEN: u16 = mem::size_of::<ProxyV2Ipv6Hdr>() as u16;
+
+// Injects a PROXY protocol v2 header in front of the first byte of data sent by the client, whose
+// sequence number is `proxy_seq`, and shifts the sequence numbers of the data that follows by the
+// length of the header so that the backend sees a contiguous stream. Retransmissions of the first
+// segment get the header injected again. `proxy_len` is the length of the header if it was already
+// injected, or 0. It returns the length of the injected header, or 0 if it wasn't injected yet.
+pub fn proxy_protocol_ingress(
+    ctx: &TcContext,
+    ip_hdr: IpHdr,
+    client_key: &ClientKey,
+    backend_key: &BackendKey,
+    proxy_seq: u32,
+    proxy_len: u16,
+) -> Result<u16, i64> {
+    let tcp_header_offset = ip_hdr.l4_offset();
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(ctx, tcp_header_offset)? };
+    let tcp_hdr_ref = unsafe { &mut *tcp_hdr };
+
+    let seq = u32::from_be(tcp_hdr_ref.seq);
+    let header_len = tcp_hdr_ref.doff() * 4;
+    let payload_len = ip_hdr.l4_len().saturating_sub(header_len);
+
+    if seq == proxy_seq && payload_len > 0 && tcp_hdr_ref.syn() == 0 {
+        info!(
+            ctx,
+            "Injecting a PROXY protocol header for client port {}", client_key.port
+        );
+        // The header carries the addresses of the client's IP family, which isn't the packet's
+        // when it was translated from IPv6 to IPv4 (NAT64).
+        return match is_ipv4_mapped(&client_key.ip) {
+            true => {
+                let header = ProxyV2Ipv4Hdr {
+                    signature: PROXY_V2_SIGNATURE,
+                    version_command: PROXY_V2_VERSION_COMMAND,
+                    family: PROXY_V2_TCP_OVER_IPV4,
+                    len: ((mem::size_of::<ProxyV2Ipv4Hdr>() - 16) as u16).to_be(),
+                    // IPv4 addresses are stored IPv4-mapped, the address is the last word.
+                    src_addr: client_key.ip[3].to_be(),
+                    dst_addr: backend_key.ip[3].to_be(),
+                    src_port: (client_key.port as u16).to_be(),
+                    dst_port: (backend_key.port as u16).to_be(),
+                };
+                insert_after_tcp_header(ctx, ip_hdr, &header)
+            }
+            false => {
+                let header = ProxyV2Ipv6Hdr {
+                    signature: PROXY_V2_SIGNATURE,
+                    version_command: PROXY_V2_VERSION_COMMAND,
+                    family: PROXY_V2_TCP_OVER_IPV6,
+                    len: ((mem::size_of::<ProxyV2Ipv6Hdr>() - 16) as u16).to_be(),
+                    src_addr: client_key.ip.map(u32::to_be),
+                    dst_addr: backend_key.ip.map(u32::to_be),
+                    src_port: (client_key.port as u16).to_be(),
+                    dst_port: (backend_key.port as u16).to_be(),
+                };
+                insert_after_tcp_header(ctx, ip_hdr, &header)
+            }
+        };
+    }
+
+    if proxy_len != 0 && seq_after(seq, proxy_seq) {
+        let shifted_seq = seq.wrapping_add(proxy_len as u32).to_be();
+        let original_seq = tcp_hdr_ref.seq;
+        tcp_hdr_ref.seq = shifted_seq;
+        ctx.l4_csum_replace(
+            tcp_header_offset + offset_of!(TcpHdr, check),
+            original_seq as u64,
+            shifted_seq as u64,
+            4,
+        )?;
+    }
+
+    Ok(proxy_len)
+}
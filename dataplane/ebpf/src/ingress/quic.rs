@@ -0,0 +1,128 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use aya_ebpf::programs::TcContext;
+
+use crate::{
+    ingress::{balancing::find_listed_backend, gateway::Gateway},
+    utils::ptr_at,
+    QUIC_CONNECTIONS,
+};
+use common::{is_ipv4_mapped, Backend, ClientKey, QuicCidKey, QUIC_MAX_CID_LEN};
+
+// The Header Form bit of the first byte, set in long headers, and the Fixed Bit which QUIC
+// version 1 packets always set.
+// Ref: https://www.rfc-editor.org/rfc/rfc9000#section-17.2
+const QUIC_LONG_HEADER: u8 = 0x80;
+const QUIC_FIXED_BIT: u8 = 0x40;
+// Long headers carry the length of the destination connection ID after the first byte and the
+// version, followed by the connection ID itself.
+const QUIC_LONG_DCID_LEN_OFFSET: usize = 5;
+
+// Reads the connection ID of `len` bytes at `offset`, zero-padded to the longest connection ID.
+// QUIC packets are always long enough to load the padding from, see RFC 9001 section 5.4.2.
+#[inline(always)]
+fn read_cid(ctx: &TcContext, offset: usize, len: u8) -> Option<[u8; QUIC_MAX_CID_LEN]> {
+    let mut cid = [0_u8; QUIC_MAX_CID_LEN];
+    ctx.load_bytes(offset, &mut cid).ok()?;
+    for (index, byte) in cid.iter_mut().enumerate() {
+        if index >= len as usize {
+            *byte = 0;
+        }
+    }
+    Some(cid)
+}
+
+// Returns the backend of the QUIC connection which the client's packet at `payload_offset` belongs
+// to, so that clients whose address changed keep their connection's backend. It's the backend
+// which chose the packet's destination connection ID, as long as the Gateway still has it.
+// Short headers don't carry the length of their connection IDs, it's the length the Gateway's
+// backends choose them with. The backend is returned with the address of the client's family.
+pub fn find_quic_backend(
+    ctx: &TcContext,
+    payload_offset: usize,
+    gateway: &Gateway,
+    client_key: &ClientKey,
+) -> Option<Backend> {
+    let cid_len = gateway.backend_list.quic_cid_len;
+    if cid_len == 0 {
+        return None;
+    }
+    let first_byte: *const u8 = unsafe { ptr_at(ctx, payload_offset) }.ok()?;
+    let first_byte = unsafe { *first_byte };
+    if first_byte & QUIC_FIXED_BIT == 0 {
+        return None;
+    }
+    let cid_offset = if first_byte & QUIC_LONG_HEADER != 0 {
+        let dcid_len: *const u8 =
+            unsafe { ptr_at(ctx, payload_offset + QUIC_LONG_DCID_LEN_OFFSET) }.ok()?;
+        if unsafe { *dcid_len } != cid_len {
+            return None;
+        }
+        payload_offset + QUIC_LONG_DCID_LEN_OFFSET + 1
+    } else {
+        payload_offset + 1
+    };
+
+    let quic_key = QuicCidKey {
+        backend_key: gateway.group_key,
+        cid: read_cid(ctx, cid_offset, cid_len)?,
+    };
+    let backend = unsafe { QUIC_CONNECTIONS.get(&quic_key) }?;
+    let backend = find_listed_backend(gateway.backend_list, backend)?;
+    Some(backend.for_family(is_ipv4_mapped(&client_key.ip)))
+}
+
+// Records the connection ID that the backend chose for the QUIC connection of its reply at
+// `payload_offset`, so that the client's packets to it keep going to the backend.
+pub fn record_quic_cid(
+    ctx: &TcContext,
+    payload_offset: usize,
+    gateway: &Gateway,
+    backend: &Backend,
+) {
+    let cid_len = gateway.backend_list.quic_cid_len;
+    if cid_len == 0 {
+        return;
+    }
+    if let Some(cid) = read_reply_scid(ctx, payload_offset, cid_len) {
+        let quic_key = QuicCidKey {
+            backend_key: gateway.group_key,
+            cid,
+        };
+        // Failing to record the connection ID only means the client can't change its address.
+        let _ = unsafe { QUIC_CONNECTIONS.insert(&quic_key, backend, 0_u64) };
+    }
+}
+
+// Returns the source connection ID of the reply at `payload_offset`, which the long headers of
+// the backend's handshake carry. The connection IDs of other lengths than the Gateway's are
+// skipped, since the client's short headers couldn't be matched to them.
+#[inline(always)]
+fn read_reply_scid(
+    ctx: &TcContext,
+    payload_offset: usize,
+    cid_len: u8,
+) -> Option<[u8; QUIC_MAX_CID_LEN]> {
+    let first_byte: *const u8 = unsafe { ptr_at(ctx, payload_offset) }.ok()?;
+    if unsafe { *first_byte } & (QUIC_LONG_HEADER | QUIC_FIXED_BIT)
+        != QUIC_LONG_HEADER | QUIC_FIXED_BIT
+    {
+        return None;
+    }
+    let dcid_len: *const u8 =
+        unsafe { ptr_at(ctx, payload_offset + QUIC_LONG_DCID_LEN_OFFSET) }.ok()?;
+    let dcid_len = unsafe { *dcid_len } as usize;
+    if dcid_len > QUIC_MAX_CID_LEN {
+        return None;
+    }
+    let scid_len_offset = payload_offset + QUIC_LONG_DCID_LEN_OFFSET + 1 + dcid_len;
+    let scid_len: *const u8 = unsafe { ptr_at(ctx, scid_len_offset) }.ok()?;
+    if unsafe { *scid_len } != cid_len {
+        return None;
+    }
+    read_cid(ctx, scid_len_offset + 1, cid_len)
+}
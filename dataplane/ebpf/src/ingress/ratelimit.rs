@@ -0,0 +1,86 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use aya_ebpf::helpers::bpf_ktime_get_ns;
+
+use crate::{
+    ingress::{balancing::live_connections, gateway::Gateway},
+    utils::config,
+    CONNECTION_LIMITS, LIMITED_CONNECTIONS, SYN_RATE_LIMITS,
+};
+use common::{LimitAction, TokenBucket, BACKENDS_ARRAY_CAPACITY, TOKEN_COST};
+
+// Takes a token from the client's bucket for a new connection, and returns false if there is none
+// left, i.e. if the client opens new connections faster than the configured rate. Buckets refill
+// continuously at the rate, up to the configured burst, and start full.
+#[inline(always)]
+pub fn allow_new_connection(client_ip: &[u32; 4]) -> bool {
+    let config = config();
+    if config.syn_rate == 0 {
+        return true;
+    }
+
+    let capacity = config.syn_burst as u64 * TOKEN_COST;
+    let now = unsafe { bpf_ktime_get_ns() };
+
+    let bucket = match unsafe { SYN_RATE_LIMITS.get_ptr_mut(client_ip) } {
+        Some(bucket) => unsafe { &mut *bucket },
+        None => {
+            let bucket = TokenBucket {
+                tokens: capacity.saturating_sub(TOKEN_COST),
+                last_refill: now,
+            };
+            // Failing to record the client only means its next connection gets a full bucket.
+            let _ = unsafe { SYN_RATE_LIMITS.insert(client_ip, &bucket, 0_u64) };
+            return capacity >= TOKEN_COST;
+        }
+    };
+
+    // Tokens are counted in billionths, so that a token accrues every 1/rate seconds.
+    let elapsed = now.saturating_sub(bucket.last_refill);
+    let tokens = bucket
+        .tokens
+        .saturating_add(elapsed.saturating_mul(config.syn_rate as u64))
+        .min(capacity);
+    bucket.last_refill = now;
+    if tokens < TOKEN_COST {
+        bucket.tokens = tokens;
+        return false;
+    }
+    bucket.tokens = tokens - TOKEN_COST;
+    true
+}
+
+// Returns what to do with a new connection to the Gateway if its backends already have as many live
+// connections as its limit allows, and counts the connection as rejected. Aliases share the limit
+// of the Gateway they share the backends of.
+#[inline(always)]
+pub fn over_connection_limit(gateway: &Gateway) -> Option<LimitAction> {
+    let limit = unsafe { CONNECTION_LIMITS.get(&gateway.group_key) }?;
+
+    let backend_list = gateway.backend_list;
+    let mut live: u64 = 0;
+    // The loop bound has to be a constant for the verifier to accept it.
+    for index in 0..BACKENDS_ARRAY_CAPACITY {
+        if index >= backend_list.backends_len as usize {
+            break;
+        }
+        let backend = backend_list.backends.get(index)?;
+        live += live_connections(&backend.key());
+    }
+    if live < limit.max_connections {
+        return None;
+    }
+
+    match unsafe { LIMITED_CONNECTIONS.get_ptr_mut(&gateway.group_key) } {
+        Some(limited) => unsafe { *limited += 1 },
+        None => {
+            // Inserting from eBPF only sets the value of this CPU.
+            let _ = unsafe { LIMITED_CONNECTIONS.insert(&gateway.group_key, &1, 0_u64) };
+        }
+    }
+    Some(limit.action)
+}
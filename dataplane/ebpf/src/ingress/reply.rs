@@ -0,0 +1,384 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use core::{ffi::c_void, mem};
+
+use aya_ebpf::{
+    bindings::{__sk_buff, TC_ACT_OK, TC_ACT_SHOT},
+    helpers::{bpf_csum_diff, bpf_skb_change_tail, bpf_skb_store_bytes},
+    programs::TcContext,
+    EbpfContext,
+};
+use memoffset::offset_of;
+use network_types::{
+    eth::EthHdr,
+    icmp::IcmpHdr,
+    ip::{IpProto, Ipv4Hdr},
+    tcp::TcpHdr,
+    udp::UdpHdr,
+};
+
+use crate::utils::{
+    count_redirect_error, csum_fold_helper, ip_octets, ptr_at, truncate_tcp_segment, IpHdr,
+    IPV4_MAX_OPTIONS_LEN,
+};
+
+// TTL of the packets generated by the datapath.
+pub const REPLY_TTL: u8 = 64;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_DEST_UNREACH: u8 = 3;
+const ICMP_PORT_UNREACH: u8 = 3;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+const ICMP_EXC_TTL: u8 = 0;
+const ICMPV6_DEST_UNREACH: u8 = 1;
+const ICMPV6_PORT_UNREACH: u8 = 4;
+const ICMPV6_TIME_EXCEED: u8 = 3;
+const ICMPV6_EXC_HOPLIMIT: u8 = 0;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+// ICMP errors quote the IP header, options included, and the first 8 bytes of the offending
+// datagram.
+const ICMP_MAX_QUOTE_LEN: usize = Ipv4Hdr::LEN + IPV4_MAX_OPTIONS_LEN + UdpHdr::LEN;
+
+// Turns a TCP packet sent to a Gateway into a RST sent back to the client, so that it fails fast
+// instead of waiting for a timeout. The reply is sent out of the interface the packet came in, and
+// the original packet is dropped.
+// Ref: https://www.rfc-editor.org/rfc/rfc9293#section-3.10.7.1
+pub fn reply_tcp_reset(ctx: &TcContext, ip_hdr: IpHdr) -> Result<i32, i64> {
+    let tcp_header_offset = ip_hdr.l4_offset();
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(ctx, tcp_header_offset)? };
+    let tcp_hdr_ref = unsafe { &mut *tcp_hdr };
+
+    // Never answer a RST with a RST.
+    if tcp_hdr_ref.rst() == 1 {
+        return Ok(TC_ACT_SHOT);
+    }
+
+    // If the packet acknowledges something the RST takes its sequence number from there, otherwise
+    // the RST acknowledges everything the packet occupied in the sequence space.
+    let (seq, ack_seq, ack) = if tcp_hdr_ref.ack() == 1 {
+        (u32::from_be(tcp_hdr_ref.ack_seq), 0, 0)
+    } else {
+        let header_len = tcp_hdr_ref.doff() * 4;
+        let payload_len = ip_hdr.l4_len().saturating_sub(header_len) as u32;
+        let seq_len = payload_len + tcp_hdr_ref.syn() as u32 + tcp_hdr_ref.fin() as u32;
+        (0, u32::from_be(tcp_hdr_ref.seq).wrapping_add(seq_len), 1)
+    };
+
+    let client_addr = ip_hdr.src_addr();
+    let gateway_addr = ip_hdr.dst_addr();
+
+    info!(
+        ctx,
+        "No backend available for svc ip: {:i} at Port: {}, resetting the connection",
+        ip_octets(&gateway_addr),
+        u16::from_be(tcp_hdr_ref.dest)
+    );
+
+    let client_port = tcp_hdr_ref.source;
+    tcp_hdr_ref.source = tcp_hdr_ref.dest;
+    tcp_hdr_ref.dest = client_port;
+    tcp_hdr_ref.seq = seq.to_be();
+    tcp_hdr_ref.ack_seq = ack_seq.to_be();
+    // Options are dropped along with the payload, only the RST and ACK flags are left.
+    tcp_hdr_ref.set_doff((TcpHdr::LEN / 4) as u16);
+    tcp_hdr_ref.set_fin(0);
+    tcp_hdr_ref.set_syn(0);
+    tcp_hdr_ref.set_rst(1);
+    tcp_hdr_ref.set_psh(0);
+    tcp_hdr_ref.set_ack(ack);
+    tcp_hdr_ref.set_urg(0);
+    tcp_hdr_ref.set_ece(0);
+    tcp_hdr_ref.set_cwr(0);
+    tcp_hdr_ref.window = 0;
+    tcp_hdr_ref.urg_ptr = 0;
+
+    ip_hdr.set_src_addr(&gateway_addr);
+    ip_hdr.set_dst_addr(&client_addr);
+    ip_hdr.set_l4_len(TcpHdr::LEN as u16);
+    ip_hdr.set_ttl(REPLY_TTL);
+    ip_hdr.clear_ecn();
+    ip_hdr.update_csum(ctx)?;
+
+    swap_eth_addrs(ctx)?;
+
+    // Drop whatever followed the TCP header.
+    let ret = unsafe {
+        bpf_skb_change_tail(
+            ctx.as_ptr() as *mut __sk_buff,
+            (tcp_header_offset + TcpHdr::LEN) as u32,
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(ret);
+    }
+
+    // Resizing the packet invalidated our packet pointers, so grab the headers again.
+    let ip_hdr = ip_hdr.reload(ctx)?;
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(ctx, tcp_header_offset)? };
+    unsafe { (*tcp_hdr).check = 0 };
+    let full_cksum = unsafe {
+        bpf_csum_diff(
+            mem::MaybeUninit::zeroed().assume_init(),
+            0,
+            tcp_hdr as *mut u32,
+            TcpHdr::LEN as u32,
+            ip_hdr.pseudo_hdr_csum(IpProto::Tcp as u8, TcpHdr::LEN as u16) as u32,
+        )
+    } as u64;
+    unsafe { (*tcp_hdr).check = csum_fold_helper(full_cksum) };
+
+    send_back(ctx)
+}
+
+// Answers the SYN of a client to a Gateway with a SYN-ACK whose sequence number is the SYN cookie
+// `cookie`, announcing `mss` as its only option, without keeping any state about the connection.
+// The reply is sent out of the interface the packet came in, and the original packet is dropped.
+// Ref: https://www.rfc-editor.org/rfc/rfc4987#section-3.6
+pub fn reply_syn_cookie(ctx: &TcContext, ip_hdr: IpHdr, cookie: u32, mss: u16) -> Result<i32, i64> {
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(ctx, ip_hdr.l4_offset())? };
+    let tcp_hdr_ref = unsafe { &mut *tcp_hdr };
+
+    let client_addr = ip_hdr.src_addr();
+    let gateway_addr = ip_hdr.dst_addr();
+
+    info!(
+        ctx,
+        "Answering a SYN for svc ip: {:i} at Port: {} with a SYN cookie",
+        ip_octets(&gateway_addr),
+        u16::from_be(tcp_hdr_ref.dest)
+    );
+
+    let client_port = tcp_hdr_ref.source;
+    tcp_hdr_ref.source = tcp_hdr_ref.dest;
+    tcp_hdr_ref.dest = client_port;
+    tcp_hdr_ref.ack_seq = u32::from_be(tcp_hdr_ref.seq).wrapping_add(1).to_be();
+    tcp_hdr_ref.seq = cookie.to_be();
+    tcp_hdr_ref.set_fin(0);
+    tcp_hdr_ref.set_syn(1);
+    tcp_hdr_ref.set_rst(0);
+    tcp_hdr_ref.set_psh(0);
+    tcp_hdr_ref.set_ack(1);
+    tcp_hdr_ref.set_urg(0);
+    tcp_hdr_ref.set_ece(0);
+    tcp_hdr_ref.set_cwr(0);
+    // Without window scaling, the largest window there is.
+    tcp_hdr_ref.window = u16::MAX.to_be();
+    tcp_hdr_ref.urg_ptr = 0;
+
+    ip_hdr.set_src_addr(&gateway_addr);
+    ip_hdr.set_dst_addr(&client_addr);
+    ip_hdr.set_ttl(REPLY_TTL);
+    ip_hdr.clear_ecn();
+
+    swap_eth_addrs(ctx)?;
+
+    truncate_tcp_segment(ctx, ip_hdr, Some(mss))?;
+
+    send_back(ctx)
+}
+
+// Answers a UDP packet sent to a Gateway with an ICMP port unreachable message, like kube-proxy
+// does for Services without endpoints, so that the client can tell why it gets no response. The
+// reply is sent out of the interface the packet came in, and the original packet is dropped.
+// Ref: https://www.rfc-editor.org/rfc/rfc792
+// Ref: https://www.rfc-editor.org/rfc/rfc4443#section-3.1
+pub fn reply_icmp_port_unreachable(ctx: &TcContext, ip_hdr: IpHdr) -> Result<i32, i64> {
+    info!(
+        ctx,
+        "No backend available for svc ip: {:i}, replying port unreachable",
+        ip_octets(&ip_hdr.dst_addr())
+    );
+    reply_icmp_error(
+        ctx,
+        ip_hdr,
+        (ICMP_DEST_UNREACH, ICMP_PORT_UNREACH),
+        (ICMPV6_DEST_UNREACH, ICMPV6_PORT_UNREACH),
+    )
+}
+
+// Answers a packet sent to a Gateway whose TTL (or hop limit) runs out here with an ICMP time
+// exceeded message from the Gateway, like a router would, so that traceroute shows the Gateway as
+// a hop. The reply is sent out of the interface the packet came in, and the original packet is
+// dropped.
+// Ref: https://www.rfc-editor.org/rfc/rfc792
+// Ref: https://www.rfc-editor.org/rfc/rfc4443#section-3.3
+pub fn reply_icmp_time_exceeded(ctx: &TcContext, ip_hdr: IpHdr) -> Result<i32, i64> {
+    info!(
+        ctx,
+        "TTL exceeded for svc ip: {:i}, replying time exceeded",
+        ip_octets(&ip_hdr.dst_addr())
+    );
+    reply_icmp_error(
+        ctx,
+        ip_hdr,
+        (ICMP_TIME_EXCEEDED, ICMP_EXC_TTL),
+        (ICMPV6_TIME_EXCEED, ICMPV6_EXC_HOPLIMIT),
+    )
+}
+
+// Turns an ICMP echo request sent to a Gateway into the echo reply of the Gateway, which keeps the
+// identifier, sequence number and data of the request. The reply is sent out of the interface the
+// packet came in, and the original packet is dropped.
+// Ref: https://www.rfc-editor.org/rfc/rfc792
+// Ref: https://www.rfc-editor.org/rfc/rfc4443#section-4.2
+pub fn reply_icmp_echo(ctx: &TcContext, ip_hdr: IpHdr) -> Result<i32, i64> {
+    let client_addr = ip_hdr.src_addr();
+    let gateway_addr = ip_hdr.dst_addr();
+
+    ip_hdr.set_src_addr(&gateway_addr);
+    ip_hdr.set_dst_addr(&client_addr);
+    ip_hdr.set_ttl(REPLY_TTL);
+    ip_hdr.clear_ecn();
+    ip_hdr.update_csum(ctx)?;
+
+    // Only the type changes, swapping the addresses leaves the ICMPv6 pseudo-header sum as it is.
+    let icmp_header_offset = ip_hdr.l4_offset();
+    let icmp_hdr: *mut IcmpHdr = unsafe { ptr_at(ctx, icmp_header_offset)? };
+    let echo_reply = match ip_hdr {
+        IpHdr::V4(..) => ICMP_ECHO_REPLY,
+        IpHdr::V6(..) => ICMPV6_ECHO_REPLY,
+    };
+    let (echo_request, code) = unsafe { ((*icmp_hdr).type_, (*icmp_hdr).code) };
+    unsafe { (*icmp_hdr).type_ = echo_reply };
+    ctx.l4_csum_replace(
+        icmp_header_offset + offset_of!(IcmpHdr, checksum),
+        u16::from_ne_bytes([echo_request, code]) as u64,
+        u16::from_ne_bytes([echo_reply, code]) as u64,
+        2,
+    )?;
+
+    swap_eth_addrs(ctx)?;
+
+    send_back(ctx)
+}
+
+// Turns the packet sent to a Gateway into an ICMP error from the Gateway back to the client, of
+// the given type and code for IPv4 and IPv6, quoting the packet's IP header and the first 8 bytes
+// of its payload.
+fn reply_icmp_error(
+    ctx: &TcContext,
+    ip_hdr: IpHdr,
+    icmp_error: (u8, u8),
+    icmpv6_error: (u8, u8),
+) -> Result<i32, i64> {
+    let quote_len = ip_hdr.header_len() + UdpHdr::LEN;
+    let mut quoted = [0_u8; ICMP_MAX_QUOTE_LEN];
+    let quoted = quoted.get_mut(..quote_len).ok_or(TC_ACT_OK)?;
+    ctx.load_bytes(ip_hdr.l3_offset(), quoted)?;
+
+    let client_addr = ip_hdr.src_addr();
+    let gateway_addr = ip_hdr.dst_addr();
+
+    ip_hdr.set_src_addr(&gateway_addr);
+    ip_hdr.set_dst_addr(&client_addr);
+    ip_hdr.set_l4_len((IcmpHdr::LEN + quote_len) as u16);
+    ip_hdr.set_ttl(REPLY_TTL);
+    ip_hdr.clear_ecn();
+    // ICMPv6 messages are covered by the checksum of a pseudo-header, like TCP and UDP.
+    let (icmp_type, icmp_code, pseudo_hdr_csum) = match ip_hdr {
+        IpHdr::V4(hdr, _) => {
+            unsafe {
+                (*hdr).proto = IpProto::Icmp;
+                (*hdr).frag_off = 0;
+            }
+            (icmp_error.0, icmp_error.1, 0)
+        }
+        IpHdr::V6(hdr, _) => {
+            unsafe { (*hdr).next_hdr = IpProto::Ipv6Icmp };
+            let csum =
+                ip_hdr.pseudo_hdr_csum(IpProto::Ipv6Icmp as u8, (IcmpHdr::LEN + quote_len) as u16);
+            (icmpv6_error.0, icmpv6_error.1, csum)
+        }
+    };
+    ip_hdr.update_csum(ctx)?;
+
+    swap_eth_addrs(ctx)?;
+
+    let icmp_header_offset = ip_hdr.l4_offset();
+    let ret = unsafe {
+        bpf_skb_change_tail(
+            ctx.as_ptr() as *mut __sk_buff,
+            (icmp_header_offset + IcmpHdr::LEN + quote_len) as u32,
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(ret);
+    }
+    let ret = unsafe {
+        bpf_skb_store_bytes(
+            ctx.as_ptr() as *mut __sk_buff,
+            (icmp_header_offset + IcmpHdr::LEN) as u32,
+            quoted.as_ptr() as *const c_void,
+            quote_len as u32,
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(ret);
+    }
+
+    // Resizing the packet invalidated our packet pointers, so grab the ICMP header again.
+    let icmp_hdr: *mut IcmpHdr = unsafe { ptr_at(ctx, icmp_header_offset)? };
+    unsafe {
+        (*icmp_hdr).type_ = icmp_type;
+        (*icmp_hdr).code = icmp_code;
+        (*icmp_hdr).un = mem::zeroed();
+        (*icmp_hdr).checksum = 0;
+    }
+
+    // The quote is summed from our copy, whose length the verifier can tell is bounded.
+    let quote_cksum = unsafe {
+        bpf_csum_diff(
+            mem::MaybeUninit::zeroed().assume_init(),
+            0,
+            quoted.as_ptr() as *mut u32,
+            quote_len as u32,
+            0,
+        )
+    };
+    if quote_cksum < 0 {
+        return Err(quote_cksum);
+    }
+    let full_cksum = unsafe {
+        bpf_csum_diff(
+            mem::MaybeUninit::zeroed().assume_init(),
+            0,
+            icmp_hdr as *mut u32,
+            IcmpHdr::LEN as u32,
+            quote_cksum as u32,
+        )
+    } as u64;
+    unsafe { (*icmp_hdr).checksum = csum_fold_helper(full_cksum + pseudo_hdr_csum) };
+
+    send_back(ctx)
+}
+
+// Swaps the source and destination MAC addresses, so that the packet goes back to where it came
+// from.
+#[inline(always)]
+fn swap_eth_addrs(ctx: &TcContext) -> Result<(), i64> {
+    let eth_hdr: *mut EthHdr = unsafe { ptr_at(ctx, 0)? };
+    unsafe {
+        let src_addr = (*eth_hdr).src_addr;
+        (*eth_hdr).src_addr = (*eth_hdr).dst_addr;
+        (*eth_hdr).dst_addr = src_addr;
+    }
+    Ok(())
+}
+
+// Sends a copy of the packet out of the interface it came in, and drops the original one.
+#[inline(always)]
+fn send_back(ctx: &TcContext) -> Result<i32, i64> {
+    let ifindex = unsafe { (*(ctx.as_ptr() as *mut __sk_buff)).ifindex };
+    if let Err(err) = ctx.clone_redirect(ifindex, 0) {
+        count_redirect_error();
+        return Err(err);
+    }
+    Ok(TC_ACT_SHOT)
+}
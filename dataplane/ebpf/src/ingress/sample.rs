@@ -0,0 +1,59 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use aya_ebpf::{helpers::bpf_ktime_get_ns, programs::TcContext};
+use network_types::ip::IpProto;
+
+use crate::{utils::config, PACKET_SAMPLES, SAMPLE_COUNTERS};
+use common::{Backend, BackendKey, PacketSample};
+
+// Copies the headers of one in Config.sample_every of the packets forwarded to the backends to
+// userspace, along with the backend they were given, before they are rewritten for it. The packets
+// are counted per CPU, so the sampled ones are spread evenly over the traffic of each CPU rather
+// than exactly one in N overall. Samples are lost while the ring buffer is full, sampling never
+// fails the packet.
+#[inline(always)]
+pub fn sample_packet(
+    ctx: &TcContext,
+    proto: IpProto,
+    backend_key: &BackendKey,
+    backend: &Backend,
+    new_conn: bool,
+) {
+    let sample_every = config().sample_every;
+    if sample_every == 0 {
+        return;
+    }
+    let counter = match unsafe { SAMPLE_COUNTERS.get_ptr_mut(0) } {
+        Some(counter) => counter,
+        None => return,
+    };
+    unsafe {
+        *counter += 1;
+        if *counter < sample_every {
+            return;
+        }
+        *counter = 0;
+    }
+
+    let mut entry = match unsafe { PACKET_SAMPLES.reserve::<PacketSample>(0) } {
+        Some(entry) => entry,
+        None => return,
+    };
+    let sample = entry.as_mut_ptr();
+    unsafe {
+        (*sample).timestamp = bpf_ktime_get_ns();
+        (*sample).backend_key = *backend_key;
+        (*sample).backend = backend.key();
+        (*sample).len = ctx.len();
+        (*sample).proto = proto as u8;
+        (*sample).new_conn = new_conn;
+        (*sample).forwarding = backend.forwarding;
+        // Packets shorter than the buffer are copied whole.
+        (*sample).headers_len = ctx.load_bytes(0, &mut (*sample).headers).unwrap_or(0) as u32;
+    }
+    entry.submit(0);
+}
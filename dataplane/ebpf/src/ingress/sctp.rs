@@ -0,0 +1,177 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use aya_ebpf::{
+    bindings::{TC_ACT_PIPE, TC_ACT_SHOT},
+    helpers::bpf_ktime_get_ns,
+    programs::TcContext,
+};
+use memoffset::offset_of;
+use network_types::ip::IpProto;
+
+use crate::{
+    ingress::{
+        acl::is_denied,
+        balancing::select_backend,
+        capture::capture_packet,
+        dscp::mark_dscp,
+        dsr::redirect_dsr,
+        fib::redirect_to_backend,
+        fragment::record_first_fragment,
+        gateway::find_gateway,
+        mirror::mirror_packet,
+        nat64::is_nat64,
+        reply::{reply_icmp_port_unreachable, reply_icmp_time_exceeded},
+        sample::sample_packet,
+    },
+    utils::{
+        count_connection_closed, count_connection_opened, ip_octets, ptr_at,
+        report_connection_closed, report_connection_opened, sctp_csum_replace_port, IpHdr, SctpHdr,
+    },
+    SCTP_CONNECTIONS,
+};
+use common::{ClientKey, CloseReason, FlowStats, ForwardingMode, UdpLoadBalancerMapping};
+
+// Associations are tracked like UDP flows, by the client's address and port, until they've been
+// idle for long enough. Their multi-homing isn't supported, each path of an association is
+// balanced on its own.
+pub fn handle_sctp_ingress(ctx: TcContext, ip_hdr: IpHdr) -> Result<i32, i64> {
+    let sctp_header_offset = ip_hdr.l4_offset();
+
+    let sctp_hdr: *mut SctpHdr = unsafe { ptr_at(&ctx, sctp_header_offset) }?;
+
+    let original_daddr = ip_hdr.dst_addr();
+    let original_dport = unsafe { (*sctp_hdr).dest };
+
+    let gateway = find_gateway(original_daddr, u16::from_be(original_dport)).ok_or(TC_ACT_PIPE)?;
+    let backend_key = gateway.key;
+    let port_offset = gateway.port_offset;
+
+    info!(
+        &ctx,
+        "Received an SCTP packet destined for svc ip: {:i} at Port: {} ",
+        ip_octets(&original_daddr),
+        u16::from_be(original_dport),
+    );
+
+    let client_key = ClientKey {
+        ip: ip_hdr.src_addr(),
+        port: (u16::from_be(unsafe { (*sctp_hdr).source })) as u32,
+    };
+    if is_denied(&backend_key.ip, &client_key.ip) {
+        info!(&ctx, "Client is denied by the ACL, dropping the packet");
+        return Ok(TC_ACT_SHOT);
+    }
+    let now = unsafe { bpf_ktime_get_ns() };
+
+    // Packets of an association we're already tracking keep going to the same backend, as long as
+    // the association is still destined for the same Gateway.
+    let tracked_backend = match unsafe { SCTP_CONNECTIONS.get_ptr_mut(&client_key) } {
+        Some(sctp_mapping) => unsafe {
+            if (*sctp_mapping).backend_key.ip == backend_key.ip
+                && (*sctp_mapping).backend_key.port == backend_key.port
+                && (*sctp_mapping).port_offset == port_offset
+            {
+                (*sctp_mapping).last_seen = now;
+                (*sctp_mapping).stats.count(ctx.len());
+                Some((*sctp_mapping).backend)
+            } else {
+                // The association is replaced below by one to the new Gateway.
+                report_connection_closed(
+                    IpProto::Sctp,
+                    &client_key,
+                    &(*sctp_mapping).backend_key,
+                    &(*sctp_mapping).backend,
+                    CloseReason::Replaced,
+                    &(*sctp_mapping).stats,
+                );
+                count_connection_closed(&(*sctp_mapping).backend)?;
+                None
+            }
+        },
+        None => None,
+    };
+
+    let new_association = tracked_backend.is_none();
+    let backend = match tracked_backend {
+        Some(backend) => backend,
+        None => {
+            let backend =
+                match select_backend(&ctx, &gateway.group_key, gateway.backend_list, &client_key) {
+                    Some(backend) => backend,
+                    None => return reply_icmp_port_unreachable(&ctx, ip_hdr),
+                };
+            // Only the TCP connections of IPv6 clients are translated for IPv4 backends.
+            if is_nat64(ip_hdr, &backend) {
+                info!(
+                    &ctx,
+                    "SCTP associations aren't translated to IPv4 backends, dropping the packet"
+                );
+                return Ok(TC_ACT_SHOT);
+            }
+
+            let sctp_mapping = UdpLoadBalancerMapping {
+                backend,
+                backend_key,
+                last_seen: now,
+                idle_timeout: 0,
+                port_offset,
+                stats: FlowStats::new(now, ctx.len()),
+            };
+            unsafe {
+                SCTP_CONNECTIONS.insert(&client_key, &sctp_mapping, 0_u64)?;
+            }
+            count_connection_opened(&backend)?;
+            report_connection_opened(
+                IpProto::Sctp,
+                &client_key,
+                &backend_key,
+                &backend,
+                &sctp_mapping.stats,
+            );
+
+            backend
+        }
+    };
+
+    let ip_hdr = mirror_packet(&ctx, ip_hdr, &backend_key)?;
+    let sctp_hdr: *mut SctpHdr = unsafe { ptr_at(&ctx, sctp_header_offset) }?;
+    record_first_fragment(ip_hdr, &backend)?;
+    sample_packet(&ctx, IpProto::Sctp, &backend_key, &backend, new_association);
+    capture_packet(&ctx, &backend_key);
+
+    if backend.forwarding == ForwardingMode::Dsr {
+        return redirect_dsr(&ctx, &backend);
+    }
+
+    // The client has to recognize its packet in the reply, so it's answered before being rewritten.
+    if ip_hdr.ttl() <= 1 {
+        return reply_icmp_time_exceeded(&ctx, ip_hdr);
+    }
+
+    let backend_port = (backend.dport as u16).wrapping_add(port_offset);
+    let sctp_len = ip_hdr.l4_len();
+    // DNAT the ip address, which the SCTP checksum doesn't cover
+    ip_hdr.set_dst_addr(&backend.daddr);
+    ip_hdr.update_csum(&ctx)?;
+    // DNAT the port
+    unsafe { (*sctp_hdr).dest = backend_port.to_be() };
+    sctp_csum_replace_port(
+        &ctx,
+        sctp_header_offset,
+        offset_of!(SctpHdr, dest),
+        sctp_len,
+        original_dport,
+        backend_port.to_be(),
+    )?;
+
+    mark_dscp(&ctx, ip_hdr, &backend_key)?;
+    let action = redirect_to_backend(&ctx, ip_hdr, &backend)?;
+
+    info!(&ctx, "redirect action: {}", action);
+
+    Ok(action)
+}
@@ -0,0 +1,163 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use aya_ebpf::{bindings::BPF_NOEXIST, helpers::bpf_get_prandom_u32, programs::TcContext};
+use memoffset::offset_of;
+use network_types::tcp::TcpHdr;
+
+use crate::{
+    ingress::nat64::translate_tcp_4to6,
+    utils::{config, ip_octets, l4_csum_replace_addr, l4_csum_replace_port, ptr_at, IpHdr},
+    LB_CONNECTIONS, SNAT_CONNECTIONS, SNAT_PORT_CURSORS,
+};
+use common::{is_ipv4_mapped, Backend, ClientKey, SnatKey};
+
+// How many ports are tried before giving up on source NATing a new connection.
+const SNAT_PORT_ATTEMPTS: u32 = 16;
+
+// Returns the address the source of the packet is rewritten to, if one is configured for its IP
+// family.
+#[inline(always)]
+pub fn snat_addr(ip_hdr: IpHdr) -> Option<[u32; 4]> {
+    let config = config();
+    let addr = match ip_hdr {
+        IpHdr::V4(..) => config.snat_ipv4,
+        IpHdr::V6(..) => config.snat_ipv6,
+    };
+    if addr == [0; 4] {
+        return None;
+    }
+    Some(addr)
+}
+
+// Allocates a source port to the connection of the client to the backend, whose port is shifted by
+// port_offset, and records it in SNAT_CONNECTIONS. Ports are taken in turn from this CPU's position
+// in the configured range, and the ports already allocated to connections to the same backend are
+// skipped. It returns None if no free port was found in a few attempts.
+pub fn allocate_snat_port(
+    backend: &Backend,
+    port_offset: u16,
+    client_key: &ClientKey,
+) -> Option<u16> {
+    let config = config();
+    let range = (config.snat_port_max as u32 + 1).saturating_sub(config.snat_port_min as u32);
+    if range == 0 {
+        return None;
+    }
+
+    let cursor = unsafe { SNAT_PORT_CURSORS.get_ptr_mut(0) }?;
+    // Start each CPU at a random position, so that they don't collide from the start.
+    if unsafe { *cursor } == 0 {
+        unsafe { *cursor = bpf_get_prandom_u32() };
+    }
+    let start = unsafe { *cursor };
+
+    for attempt in 0..SNAT_PORT_ATTEMPTS {
+        let snat_port = config.snat_port_min as u32 + start.wrapping_add(attempt) % range;
+        let snat_key = SnatKey {
+            ip: backend.daddr,
+            port: (backend.dport as u16).wrapping_add(port_offset) as u32,
+            snat_port,
+        };
+        // The port may have been taken by another CPU since, which the insertion tells us.
+        if unsafe { SNAT_CONNECTIONS.insert(&snat_key, client_key, BPF_NOEXIST as u64) }.is_ok() {
+            unsafe { *cursor = start.wrapping_add(attempt + 1) };
+            return Some(snat_port as u16);
+        }
+    }
+
+    unsafe { *cursor = start.wrapping_add(SNAT_PORT_ATTEMPTS) };
+    None
+}
+
+// Rewrites the source of the TCP packet to `snat_addr` and `snat_port`. The IP header checksum is
+// left for the caller to update.
+#[inline(always)]
+pub fn snat_tcp(
+    ctx: &TcContext,
+    ip_hdr: IpHdr,
+    snat_addr: &[u32; 4],
+    snat_port: u16,
+) -> Result<(), i64> {
+    let tcp_header_offset = ip_hdr.l4_offset();
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(ctx, tcp_header_offset)? };
+
+    let original_saddr = ip_hdr.src_addr();
+    let original_sport = unsafe { (*tcp_hdr).source };
+    ip_hdr.set_src_addr(snat_addr);
+    unsafe { (*tcp_hdr).source = snat_port.to_be() };
+
+    // The source address is part of the pseudo-header.
+    let tcp_check_offset = tcp_header_offset + offset_of!(TcpHdr, check);
+    l4_csum_replace_addr(ctx, tcp_check_offset, &original_saddr, snat_addr)?;
+    l4_csum_replace_port(ctx, tcp_check_offset, original_sport, snat_port.to_be())
+}
+
+// Rewrites the destination of a TCP packet sent by a backend to a source NATed connection back to
+// the client, and returns true. The reply then makes its way to the client through the egress
+// program like any other reply, which restores the Gateway as its source. Other packets are left
+// untouched. The replies to the connections of IPv6 clients are translated back to IPv6 (NAT64),
+// with the Gateway as their source already.
+pub fn reverse_snat_tcp(ctx: &TcContext, ip_hdr: IpHdr) -> Result<bool, i64> {
+    let daddr = ip_hdr.dst_addr();
+    if snat_addr(ip_hdr) != Some(daddr) {
+        return Ok(false);
+    }
+
+    let tcp_header_offset = ip_hdr.l4_offset();
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(ctx, tcp_header_offset)? };
+    let original_dport = unsafe { (*tcp_hdr).dest };
+    let snat_key = SnatKey {
+        ip: ip_hdr.src_addr(),
+        port: u16::from_be(unsafe { (*tcp_hdr).source }) as u32,
+        snat_port: u16::from_be(original_dport) as u32,
+    };
+    let client_key = match unsafe { SNAT_CONNECTIONS.get(&snat_key) } {
+        Some(client_key) => *client_key,
+        None => return Ok(false),
+    };
+    // Make sure the connection this port was allocated to is still around.
+    let lb_mapping = match unsafe { LB_CONNECTIONS.get(&client_key) } {
+        Some(lb_mapping) if lb_mapping.snat_port as u32 == snat_key.snat_port => lb_mapping,
+        _ => return Ok(false),
+    };
+
+    info!(
+        ctx,
+        "Received a TCP reply for source NATed port {}, setting destination to {:i}:{}",
+        snat_key.snat_port,
+        ip_octets(&client_key.ip),
+        client_key.port
+    );
+
+    if !is_ipv4_mapped(&client_key.ip) {
+        translate_tcp_4to6(
+            ctx,
+            ip_hdr,
+            &lb_mapping.backend_key.ip,
+            lb_mapping.gateway_port(),
+            &client_key.ip,
+            client_key.port as u16,
+        )?;
+        return Ok(true);
+    }
+
+    ip_hdr.set_dst_addr(&client_key.ip);
+    unsafe { (*tcp_hdr).dest = (client_key.port as u16).to_be() };
+    ip_hdr.update_csum(ctx)?;
+
+    // The destination address is part of the pseudo-header.
+    let tcp_check_offset = tcp_header_offset + offset_of!(TcpHdr, check);
+    l4_csum_replace_addr(ctx, tcp_check_offset, &daddr, &client_key.ip)?;
+    l4_csum_replace_port(
+        ctx,
+        tcp_check_offset,
+        original_dport,
+        (client_key.port as u16).to_be(),
+    )?;
+
+    Ok(true)
+}
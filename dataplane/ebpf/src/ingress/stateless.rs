@@ -0,0 +1,90 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use aya_ebpf::{bindings::TC_ACT_SHOT, programs::TcContext};
+use memoffset::offset_of;
+use network_types::{ip::IpProto, tcp::TcpHdr, udp::UdpHdr};
+
+use crate::{
+    ingress::{
+        balancing::select_stateless_backend,
+        dscp::mark_dscp,
+        dsr::redirect_dsr,
+        fib::redirect_to_backend,
+        gateway::Gateway,
+        mirror::mirror_packet,
+        nat64::is_nat64,
+        reply::{reply_icmp_port_unreachable, reply_icmp_time_exceeded},
+    },
+    utils::{
+        l4_csum_replace_addr, l4_csum_replace_port, ptr_at, udp_csum_replace_addr,
+        udp_csum_replace_port, IpHdr,
+    },
+};
+use common::{ClientKey, ForwardingMode};
+
+// Forwards the TCP or UDP packet of a client to a stateless Gateway (see BackendList.stateless)
+// to the backend picked by hashing its 5-tuple, without tracking its connection. Every packet of
+// the connection hashes to the same backend as long as the Gateway's backends don't change, and
+// the backend's replies are translated back by the egress program from the backend's address and
+// port alone. Nothing is written to the maps, the Gateway's connection limit, the SYN cookies and
+// the rate limits of the clients don't apply.
+pub fn forward_stateless(
+    ctx: &TcContext,
+    ip_hdr: IpHdr,
+    gateway: &Gateway,
+    proto: IpProto,
+    client_key: &ClientKey,
+) -> Result<i32, i64> {
+    let backend = match select_stateless_backend(ctx, gateway, proto, client_key) {
+        Some(backend) => backend,
+        None if proto == IpProto::Udp => return reply_icmp_port_unreachable(ctx, ip_hdr),
+        None => return Ok(TC_ACT_SHOT),
+    };
+    // Translating to IPv4 takes a source port of the node's, which has to be tracked.
+    if is_nat64(ip_hdr, &backend) {
+        return Ok(TC_ACT_SHOT);
+    }
+
+    let ip_hdr = mirror_packet(ctx, ip_hdr, &gateway.key)?;
+    if backend.forwarding == ForwardingMode::Dsr {
+        return redirect_dsr(ctx, &backend);
+    }
+
+    // The client has to recognize its packet in the reply, so it's answered before being rewritten.
+    if ip_hdr.ttl() <= 1 {
+        return reply_icmp_time_exceeded(ctx, ip_hdr);
+    }
+
+    // The ports are at the same offset in both headers.
+    let l4_offset = ip_hdr.l4_offset();
+    let dport: *mut u16 = unsafe { ptr_at(ctx, l4_offset + offset_of!(TcpHdr, dest))? };
+    let original_dport = unsafe { *dport };
+    let original_daddr = ip_hdr.dst_addr();
+    let backend_port = (backend.dport as u16).wrapping_add(gateway.port_offset);
+
+    // DNAT the ip address
+    ip_hdr.set_dst_addr(&backend.daddr);
+    // DNAT the port
+    unsafe { *dport = backend_port.to_be() };
+    ip_hdr.update_csum(ctx)?;
+
+    // Calculate l4 cksum, the destination address is part of the pseudo-header
+    if proto == IpProto::Udp {
+        let check_offset = l4_offset + offset_of!(UdpHdr, check);
+        udp_csum_replace_addr(ctx, check_offset, &original_daddr, &backend.daddr)?;
+        udp_csum_replace_port(ctx, check_offset, original_dport, backend_port.to_be())?;
+    } else {
+        let check_offset = l4_offset + offset_of!(TcpHdr, check);
+        l4_csum_replace_addr(ctx, check_offset, &original_daddr, &backend.daddr)?;
+        l4_csum_replace_port(ctx, check_offset, original_dport, backend_port.to_be())?;
+    }
+
+    // Replacing the checksum invalidated our packet pointers, so grab the IP header again.
+    let ip_hdr = ip_hdr.reload(ctx)?;
+    mark_dscp(ctx, ip_hdr, &gateway.key)?;
+    redirect_to_backend(ctx, ip_hdr, &backend)
+}
@@ -0,0 +1,172 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use aya_ebpf::{helpers::bpf_ktime_get_ns, programs::TcContext};
+use memoffset::offset_of;
+use network_types::tcp::TcpHdr;
+
+use crate::{
+    utils::{config, ptr_at, truncate_tcp_segment, IpHdr},
+    SYN_COOKIES,
+};
+use common::{BackendKey, ClientKey};
+
+// The MSS values a SYN cookie can carry, the client's MSS is rounded down to one of them. The
+// index of the value is kept in the low bits of the cookie. The other options of the SYN have
+// nowhere to be kept, so the connections go without window scaling, SACK and timestamps.
+// Ref: https://www.rfc-editor.org/rfc/rfc4987#section-3.6
+const COOKIE_MSS: [u16; 4] = [536, 1220, 1440, 1460];
+const COOKIE_MSS_MASK: u32 = 0b11;
+
+// The cookies are accepted during the period they were issued in and the next one, i.e. for one
+// to two periods of this many nanoseconds.
+const COOKIE_PERIOD: u64 = 64_000_000_000;
+
+// Returns whether the Gateway answers the SYNs of new connections with SYN cookies, so that a flood
+// of SYNs doesn't take up any room in LB_CONNECTIONS. Aliases share the setting of the Gateway they
+// share the backends of.
+#[inline(always)]
+pub fn syn_cookies_enabled(gateway_key: &BackendKey) -> bool {
+    unsafe { SYN_COOKIES.get(gateway_key) }.is_some()
+}
+
+// Returns the SYN cookie of the connection of the client to `vip_key`, the address and port it
+// connected to, whose SYN has the sequence number `client_seq` and announces `mss`. The cookie is a
+// keyed hash of the connection and of the current period, with the index of the MSS in its low
+// bits.
+#[inline(always)]
+pub fn syn_cookie(client_key: &ClientKey, vip_key: &BackendKey, client_seq: u32, mss: u16) -> u32 {
+    let mut mss_index = 0;
+    for (index, cookie_mss) in COOKIE_MSS.iter().enumerate() {
+        if *cookie_mss <= mss {
+            mss_index = index as u32;
+        }
+    }
+    let hash = cookie_hash(client_key, vip_key, client_seq, current_period());
+    (hash & !COOKIE_MSS_MASK) | mss_index
+}
+
+// Returns whether the SYN cookie the client acknowledged was issued for its connection during the
+// current or the previous period.
+#[inline(always)]
+pub fn is_valid_syn_cookie(
+    client_key: &ClientKey,
+    vip_key: &BackendKey,
+    client_seq: u32,
+    cookie: u32,
+) -> bool {
+    let period = current_period();
+    for age in 0..2 {
+        let hash = cookie_hash(client_key, vip_key, client_seq, period.wrapping_sub(age));
+        if (hash ^ cookie) & !COOKIE_MSS_MASK == 0 {
+            return true;
+        }
+    }
+    false
+}
+
+// Returns the MSS carried by the SYN cookie.
+#[inline(always)]
+pub fn cookie_mss(cookie: u32) -> u16 {
+    match COOKIE_MSS.get((cookie & COOKIE_MSS_MASK) as usize) {
+        Some(mss) => *mss,
+        None => COOKIE_MSS[0],
+    }
+}
+
+#[inline(always)]
+fn current_period() -> u32 {
+    (unsafe { bpf_ktime_get_ns() } / COOKIE_PERIOD) as u32
+}
+
+// Hashes the connection, the client's initial sequence number and the period with the configured
+// secret, mixing each word in with the finalizer of MurmurHash3. It isn't a cryptographic hash, but
+// the secret keeps clients which never received a cookie from computing it.
+#[inline(always)]
+fn cookie_hash(client_key: &ClientKey, vip_key: &BackendKey, client_seq: u32, period: u32) -> u32 {
+    let secret = config().syn_cookie_secret;
+    let words = [
+        client_key.ip[0],
+        client_key.ip[1],
+        client_key.ip[2],
+        client_key.ip[3],
+        vip_key.ip[0],
+        vip_key.ip[1],
+        vip_key.ip[2],
+        vip_key.ip[3],
+        client_key.port << 16 | vip_key.port,
+        client_seq,
+        period,
+    ];
+    let mut hash = 0;
+    for (index, word) in words.iter().enumerate() {
+        hash = fmix32(hash ^ word ^ secret[index % secret.len()]);
+    }
+    hash
+}
+
+// Ref: https://github.com/aappleby/smhasher/blob/master/src/MurmurHash3.cpp
+#[inline(always)]
+fn fmix32(mut hash: u32) -> u32 {
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^= hash >> 16;
+    hash
+}
+
+// Turns the packet of the client into the SYN it opened the connection with, whose sequence number
+// is `client_seq` and whose MSS is the one `cookie` carries, so that the backend goes through the
+// handshake the client went through with the cookie. The packet pointers are invalidated.
+pub fn replay_syn(ctx: &TcContext, ip_hdr: IpHdr, client_seq: u32, cookie: u32) -> Result<(), i64> {
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(ctx, ip_hdr.l4_offset())? };
+    let tcp_hdr_ref = unsafe { &mut *tcp_hdr };
+
+    tcp_hdr_ref.seq = client_seq.to_be();
+    tcp_hdr_ref.ack_seq = 0;
+    tcp_hdr_ref.set_fin(0);
+    tcp_hdr_ref.set_syn(1);
+    tcp_hdr_ref.set_rst(0);
+    tcp_hdr_ref.set_psh(0);
+    tcp_hdr_ref.set_ack(0);
+    tcp_hdr_ref.set_urg(0);
+    tcp_hdr_ref.set_ece(0);
+    tcp_hdr_ref.set_cwr(0);
+    tcp_hdr_ref.urg_ptr = 0;
+    // The cookie can't carry the ECN negotiation, neither the SYN-ACK with the cookie nor the
+    // replayed SYN set up ECN, and SYNs aren't ECN-capable themselves.
+    // Ref: https://www.rfc-editor.org/rfc/rfc3168#section-6.1.1
+    ip_hdr.clear_ecn();
+
+    truncate_tcp_segment(ctx, ip_hdr, Some(cookie_mss(cookie)))
+}
+
+// Shifts the acknowledgement number of a packet of the client by `seq_offset`, from the sequence
+// space of the SYN cookie to the backend's.
+pub fn shift_client_ack(
+    ctx: &TcContext,
+    tcp_header_offset: usize,
+    seq_offset: u32,
+) -> Result<(), i64> {
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(ctx, tcp_header_offset)? };
+    let tcp_hdr_ref = unsafe { &mut *tcp_hdr };
+    if tcp_hdr_ref.ack() == 0 || seq_offset == 0 {
+        return Ok(());
+    }
+
+    let original_ack_seq = tcp_hdr_ref.ack_seq;
+    let shifted_ack_seq = u32::from_be(original_ack_seq)
+        .wrapping_add(seq_offset)
+        .to_be();
+    tcp_hdr_ref.ack_seq = shifted_ack_seq;
+    ctx.l4_csum_replace(
+        tcp_header_offset + offset_of!(TcpHdr, check),
+        original_ack_seq as u64,
+        shifted_ack_seq as u64,
+        4,
+    )
+}
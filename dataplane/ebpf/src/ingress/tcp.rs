@@ -4,172 +4,519 @@ Copyright 2023 The Kubernetes Authors.
 SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
 */
 
-use core::mem;
-
 use aya_ebpf::{
-    bindings::TC_ACT_OK,
-    helpers::{bpf_csum_diff, bpf_redirect_neigh},
+    bindings::{TC_ACT_OK, TC_ACT_PIPE, TC_ACT_SHOT},
+    helpers::bpf_ktime_get_ns,
     programs::TcContext,
 };
-use aya_log_ebpf::{debug, info};
-use network_types::{eth::EthHdr, ip::Ipv4Hdr, tcp::TcpHdr};
+use memoffset::offset_of;
+use network_types::{ip::IpProto, tcp::TcpHdr};
 
 use crate::{
-    utils::{csum_fold_helper, ptr_at, update_tcp_conns},
-    BACKENDS, GATEWAY_INDEXES, LB_CONNECTIONS,
+    ingress::{
+        acl::is_denied,
+        balancing::select_backend,
+        capture::capture_packet,
+        dscp::mark_dscp,
+        dsr::redirect_dsr,
+        fib::redirect_to_backend,
+        fragment::record_first_fragment,
+        gateway::find_gateway,
+        mirror::mirror_packet,
+        nat64::{is_nat64, nat64_addr, translate_tcp_6to4},
+        proxy::proxy_protocol_ingress,
+        ratelimit::{allow_new_connection, over_connection_limit},
+        reply::{reply_icmp_time_exceeded, reply_syn_cookie, reply_tcp_reset},
+        sample::sample_packet,
+        snat::{allocate_snat_port, reverse_snat_tcp, snat_addr, snat_tcp},
+        stateless::forward_stateless,
+        syncookie::{
+            cookie_mss, is_valid_syn_cookie, replay_syn, shift_client_ack, syn_cookie,
+            syn_cookies_enabled,
+        },
+        toa::insert_toa,
+        tproxy::steer_to_tproxy,
+    },
+    utils::{
+        clamp_mss, client_under_cap, config, count_client_connection_opened,
+        count_connection_opened, count_tcp_drop, count_tcp_pass, ip_octets, is_tcp_conn_expired,
+        l4_csum_replace_addr, l4_csum_replace_port, max_mss, ptr_at, read_mss,
+        record_backend_failure, remove_tcp_conn, report_connection_opened, track_tcp_conn,
+        update_tcp_conns, IpHdr,
+    },
+    LB_CONNECTIONS,
 };
 use common::{
-    Backend, BackendKey, ClientKey, LoadBalancerMapping, TCPState, BACKENDS_ARRAY_CAPACITY,
+    Backend, BackendKey, ClientKey, CloseReason, DropReason, FlowStats, ForwardingMode,
+    LimitAction, LoadBalancerMapping, PassReason, SynCookieState, TCPSide, TCPState,
+    UntrackedTCPAction,
 };
 
-pub fn handle_tcp_ingress(ctx: TcContext) -> Result<i32, i64> {
-    let ip_hdr: *mut Ipv4Hdr = unsafe { ptr_at(&ctx, EthHdr::LEN)? };
+// Every TCP packet dropped or let through to the host is counted by its reason in TCP_DROPS and
+// TCP_PASSES, those failing on an error included, which tc_ingress lets through.
+pub fn handle_tcp_ingress(ctx: TcContext, ip_hdr: IpHdr) -> Result<i32, i64> {
+    let result = try_handle_tcp_ingress(ctx, ip_hdr);
+    if let Err(err) = result {
+        // Failed bounds checks return TC_ACT_OK, the maps and helpers return negative errnos.
+        if err == TC_ACT_OK as i64 {
+            count_tcp_pass(PassReason::OutOfBounds);
+        } else {
+            count_tcp_pass(PassReason::Error);
+        }
+    }
+    result
+}
 
-    let tcp_header_offset = EthHdr::LEN + Ipv4Hdr::LEN;
+fn try_handle_tcp_ingress(ctx: TcContext, ip_hdr: IpHdr) -> Result<i32, i64> {
+    // Replies of the backends to source NATed connections are sent to this node, turn them back
+    // into replies to the clients and let the host route them.
+    if reverse_snat_tcp(&ctx, ip_hdr)? {
+        count_tcp_pass(PassReason::SnatReply);
+        return Ok(TC_ACT_OK);
+    }
+
+    let mut tcp_header_offset = ip_hdr.l4_offset();
 
     let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(&ctx, tcp_header_offset) }?;
 
-    let original_daddr = unsafe { (*ip_hdr).dst_addr };
+    let original_daddr = ip_hdr.dst_addr();
+    let original_dport = unsafe { (*tcp_hdr).dest };
 
     // The source identifier
     let client_key = ClientKey {
-        ip: u32::from_be(unsafe { (*ip_hdr).src_addr }),
+        ip: ip_hdr.src_addr(),
         port: (u16::from_be(unsafe { (*tcp_hdr).source })) as u32,
     };
+
+    // The ACL applies to the connections to the address' Gateways only, leave anything else to the
+    // host.
+    if is_denied(&original_daddr, &client_key.ip)
+        && find_gateway(original_daddr, u16::from_be(original_dport)).is_some()
+    {
+        info!(
+            &ctx,
+            "Client {:i} is denied by the ACL of svc ip: {:i}, dropping the packet",
+            ip_octets(&client_key.ip),
+            ip_octets(&original_daddr)
+        );
+        count_tcp_drop(DropReason::Denied);
+        return Ok(TC_ACT_SHOT);
+    }
     // The backend that is responsible for handling this TCP connection.
-    let mut backend: Backend;
+    let backend: Backend;
     // The Gateway that the TCP connections is forwarded from.
     let backend_key: BackendKey;
     // Flag to check whether this is a new connection.
     let mut new_conn = false;
     // The state of this TCP connection.
     let mut tcp_state = Some(TCPState::default());
+    // The side that started closing this TCP connection, if any.
+    let mut tcp_closer = TCPSide::default();
+    // The client's sequence number in front of which the PROXY protocol header goes, and the
+    // length of the header once injected.
+    let mut proxy_seq = 0;
+    let mut proxy_len = 0;
+    // The source port allocated to this TCP connection if it is source NATed.
+    let mut snat_port = 0;
+    // Where the handshake with the backend stands if this TCP connection was opened with a SYN
+    // cookie, the cookie, and the offset of the backend's sequence numbers from it.
+    let mut syn_cookie = SynCookieState::default();
+    let mut cookie_seq = 0;
+    let mut seq_offset = 0;
+    // When the SYN opening this TCP connection was forwarded, until the backend answers it.
+    let mut syn_sent = 0;
+    // The packets and bytes of this TCP connection, counting this packet.
+    let stats: FlowStats;
+    // The offset of the port in the Gateway's port range.
+    let port_offset: u16;
+    let now = unsafe { bpf_ktime_get_ns() };
+
+    // Connections idle for longer than the timeout of their state are gone, even if userspace
+    // hasn't expired them yet, so the packet starts a new one.
+    let tracked = match unsafe { LB_CONNECTIONS.get_ptr_mut(&client_key) } {
+        Some(val) if is_tcp_conn_expired(unsafe { &*val }, now) => {
+            let lb_mapping = unsafe { *val };
+            remove_tcp_conn(&client_key, &lb_mapping, CloseReason::Timeout)?;
+            None
+        }
+        tracked => tracked,
+    };
 
     // Try to find the backend previously used for this connection. If not found, it means that
     // this is a new connection, so assign it the next backend in line.
-    if let Some(val) = unsafe { LB_CONNECTIONS.get(&client_key) } {
-        backend = val.backend;
-        backend_key = val.backend_key;
-        tcp_state = val.tcp_state;
+    if let Some(val) = tracked {
+        unsafe {
+            (*val).last_seen = now;
+            backend = (*val).backend;
+            backend_key = (*val).backend_key;
+            tcp_state = (*val).tcp_state;
+            tcp_closer = (*val).tcp_closer;
+            proxy_seq = (*val).proxy_seq;
+            proxy_len = (*val).proxy_len;
+            snat_port = (*val).snat_port;
+            port_offset = (*val).port_offset;
+            syn_cookie = (*val).syn_cookie;
+            cookie_seq = (*val).cookie_seq;
+            seq_offset = (*val).seq_offset;
+            syn_sent = (*val).syn_sent;
+            (*val).stats.count(ctx.len());
+            stats = (*val).stats;
+        }
     } else {
         new_conn = true;
+        stats = FlowStats::new(now, ctx.len());
 
-        backend_key = BackendKey {
-            ip: u32::from_be(original_daddr),
-            port: (u16::from_be(unsafe { (*tcp_hdr).dest })) as u32,
+        let gateway = match find_gateway(original_daddr, u16::from_be(original_dport)) {
+            Some(gateway) => gateway,
+            None => {
+                count_tcp_pass(PassReason::NotGateway);
+                return Ok(TC_ACT_OK);
+            }
         };
-        let backend_list = unsafe { BACKENDS.get(&backend_key) }.ok_or(TC_ACT_OK)?;
-        let backend_index = unsafe { GATEWAY_INDEXES.get(&backend_key) }.ok_or(TC_ACT_OK)?;
+        backend_key = gateway.key;
+        port_offset = gateway.port_offset;
 
-        debug!(&ctx, "Destination backend index: {}", *backend_index);
-        debug!(&ctx, "Backends length: {}", backend_list.backends_len);
-
-        // this check asserts that we don't use a "zero-value" Backend
-        if backend_list.backends_len <= *backend_index {
-            return Ok(TC_ACT_OK);
+        // The connections handed to a local proxy are tracked by the proxy's sockets.
+        if let Some(action) = steer_to_tproxy(
+            &ctx,
+            ip_hdr,
+            &backend_key,
+            IpProto::Tcp,
+            unsafe { (*tcp_hdr).source },
+            original_dport,
+        )? {
+            return Ok(action);
         }
-        // the bpf verifier is aware of variables that are used as an index for
-        // an array and requires that we check the array boundaries against
-        // the index to ensure our access is in-bounds.
-        if *backend_index as usize >= BACKENDS_ARRAY_CAPACITY {
-            return Ok(TC_ACT_OK);
+        // The connections of stateless Gateways are never tracked.
+        if gateway.backend_list.stateless {
+            return forward_stateless(&ctx, ip_hdr, &gateway, IpProto::Tcp, &client_key);
         }
 
-        backend = backend_list.backends[0];
-        if let Some(val) = backend_list.backends.get(*backend_index as usize) {
-            backend = *val;
-        } else {
-            debug!(
+        let tcp_hdr_ref = unsafe { tcp_hdr.as_ref().ok_or(TC_ACT_OK)? };
+        // Under SYN cookies the SYNs are answered without keeping any state, the connection is
+        // only tracked once the client acknowledges a valid cookie.
+        if syn_cookies_enabled(&gateway.group_key) && tcp_hdr_ref.rst() == 0 {
+            let vip_key = BackendKey {
+                ip: original_daddr,
+                port: u16::from_be(original_dport) as u32,
+            };
+            let client_seq = u32::from_be(tcp_hdr_ref.seq);
+            if tcp_hdr_ref.syn() == 1 && tcp_hdr_ref.ack() == 0 {
+                // Clients which don't announce an MSS get the smallest, which is the default one.
+                let mss = read_mss(&ctx, tcp_header_offset).unwrap_or_default();
+                let cookie = syn_cookie(&client_key, &vip_key, client_seq, mss);
+                return reply_syn_cookie(&ctx, ip_hdr, cookie, cookie_mss(cookie));
+            }
+            if tcp_hdr_ref.syn() == 0 && tcp_hdr_ref.ack() == 1 {
+                // The ACK comes right after the SYN in both directions.
+                cookie_seq = u32::from_be(tcp_hdr_ref.ack_seq).wrapping_sub(1);
+                if !is_valid_syn_cookie(
+                    &client_key,
+                    &vip_key,
+                    client_seq.wrapping_sub(1),
+                    cookie_seq,
+                ) {
+                    info!(
+                        &ctx,
+                        "Client {:i} acknowledged an invalid SYN cookie, dropping the packet",
+                        ip_octets(&client_key.ip)
+                    );
+                    count_tcp_drop(DropReason::InvalidSynCookie);
+                    return Ok(TC_ACT_SHOT);
+                }
+                syn_cookie = SynCookieState::Replayed;
+                syn_sent = now;
+            }
+        }
+        // Only a SYN (or the ACK of a SYN cookie) starts a new connection, anything else belongs
+        // to a connection we don't know about (e.g. one that was evicted or started before the
+        // Gateway existed).
+        if (tcp_hdr_ref.syn() == 0 || tcp_hdr_ref.ack() == 1) && syn_cookie == SynCookieState::Off {
+            match config().untracked_tcp {
+                UntrackedTCPAction::Track => {}
+                UntrackedTCPAction::Pass => {
+                    count_tcp_pass(PassReason::Untracked);
+                    return Ok(TC_ACT_PIPE);
+                }
+                UntrackedTCPAction::Drop => {
+                    count_tcp_drop(DropReason::Untracked);
+                    return Ok(TC_ACT_SHOT);
+                }
+            }
+        }
+        // Keep a client opening connections too fast from filling up LB_CONNECTIONS.
+        if !allow_new_connection(&client_key.ip) {
+            info!(
+                &ctx,
+                "Client {:i} is over its new connection rate, dropping the packet",
+                ip_octets(&client_key.ip)
+            );
+            count_tcp_drop(DropReason::RateLimited);
+            return Ok(TC_ACT_SHOT);
+        }
+        if !client_under_cap(&client_key.ip) {
+            info!(
                 &ctx,
-                "Failed to find backend in backends_list at index {}, falling back to 0th index; backends_len: {} ",
-                *backend_index,
-                backend_list.backends_len
-            )
+                "Client {:i} is over its connection cap, dropping the packet",
+                ip_octets(&client_key.ip)
+            );
+            count_tcp_drop(DropReason::ClientCap);
+            return Ok(TC_ACT_SHOT);
+        }
+        if let Some(action) = over_connection_limit(&gateway) {
+            info!(
+                &ctx,
+                "svc ip: {:i} at Port: {} is over its connection limit, rejecting the connection",
+                ip_octets(&original_daddr),
+                u16::from_be(original_dport)
+            );
+            match action {
+                LimitAction::Drop => {
+                    count_tcp_drop(DropReason::ConnectionLimit);
+                    return Ok(TC_ACT_SHOT);
+                }
+                LimitAction::Reset => return reply_tcp_reset(&ctx, ip_hdr),
+            }
+        }
+        // The client's data starts right after its SYN, which is where the ACK of a SYN cookie
+        // is. Connections tracked from the middle of the stream never get a PROXY protocol header.
+        if tcp_hdr_ref.syn() == 1 {
+            proxy_seq = u32::from_be(tcp_hdr_ref.seq).wrapping_add(1);
+        } else if syn_cookie == SynCookieState::Replayed {
+            proxy_seq = u32::from_be(tcp_hdr_ref.seq);
+        }
+        if tcp_hdr_ref.syn() == 1 && tcp_hdr_ref.ack() == 0 {
+            tcp_state = Some(TCPState::SynSent);
+            syn_sent = now;
         }
 
-        // move the index to the next backend in our list
-        let mut next = *backend_index + 1;
-        if next >= backend_list.backends_len {
-            next = 0;
+        backend = match select_backend(&ctx, &gateway.group_key, gateway.backend_list, &client_key)
+        {
+            Some(backend) => backend,
+            None if config().reset_without_backend => return reply_tcp_reset(&ctx, ip_hdr),
+            None => {
+                count_tcp_pass(PassReason::NoBackend);
+                return Ok(TC_ACT_OK);
+            }
+        };
+        // The replies of the backends in DSR mode skip the egress program, which could neither
+        // complete their handshake nor shift their sequence numbers.
+        if syn_cookie == SynCookieState::Replayed && backend.forwarding == ForwardingMode::Dsr {
+            return reply_tcp_reset(&ctx, ip_hdr);
         }
-        unsafe {
-            GATEWAY_INDEXES.insert(&backend_key, &next, 0_u64)?;
+
+        // A backend connecting to its own Gateway would get its own packets back with its own
+        // address as the source, and answer them without going through us. Source NAT these
+        // connections, so that the replies come back to us to be translated.
+        let hairpin = backend.forwarding == ForwardingMode::Nat && backend.daddr == client_key.ip;
+        // The connections of IPv6 clients to IPv4 backends are translated to connections from the
+        // IPv4 address of this node, which need a source port of their own too.
+        let nat64 = is_nat64(ip_hdr, &backend);
+        if nat64 && nat64_addr().is_none() {
+            info!(
+                &ctx,
+                "No IPv4 address to translate the new connection from, dropping it"
+            );
+            count_tcp_drop(DropReason::NoNat64Address);
+            return Ok(TC_ACT_SHOT);
+        }
+        if nat64
+            || ((backend.forwarding == ForwardingMode::Snat || hairpin)
+                && snat_addr(ip_hdr).is_some())
+        {
+            snat_port = match allocate_snat_port(&backend, port_offset, &client_key) {
+                Some(snat_port) => snat_port,
+                None => {
+                    info!(
+                        &ctx,
+                        "No source port left for the new connection, dropping it"
+                    );
+                    count_tcp_drop(DropReason::NoSnatPort);
+                    return Ok(TC_ACT_SHOT);
+                }
+            };
+        }
+    }
+
+    // The client retransmitting its SYN means the backend didn't answer the previous one.
+    if !new_conn {
+        let tcp_hdr_ref = unsafe { tcp_hdr.as_ref().ok_or(TC_ACT_OK)? };
+        if tcp_hdr_ref.syn() == 1
+            && tcp_hdr_ref.ack() == 0
+            && u32::from_be(tcp_hdr_ref.seq).wrapping_add(1) == proxy_seq
+        {
+            record_backend_failure(&backend);
         }
     }
 
+    // Until the backend answers, each packet of a connection opened with a SYN cookie is turned
+    // into the SYN the client opened it with, so that a lost SYN is replayed with the client's
+    // retransmissions. A RST goes through as is, for the backend to know the client gave up.
+    let tcp_hdr_ref = unsafe { tcp_hdr.as_ref().ok_or(TC_ACT_OK)? };
+    if syn_cookie == SynCookieState::Replayed && tcp_hdr_ref.rst() == 0 {
+        replay_syn(&ctx, ip_hdr, proxy_seq.wrapping_sub(1), cookie_seq)?;
+    }
+    // Replaying the SYN resized the packet, which invalidated our packet pointers.
+    let ip_hdr = ip_hdr.reload(&ctx)?;
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(&ctx, tcp_header_offset) }?;
+    if syn_cookie == SynCookieState::Established {
+        shift_client_ack(&ctx, tcp_header_offset, seq_offset)?;
+    }
+
     info!(
         &ctx,
         "Received a TCP packet destined for svc ip: {:i} at Port: {} ",
-        u32::from_be(original_daddr),
-        u16::from_be(unsafe { (*tcp_hdr).dest })
+        ip_octets(&original_daddr),
+        u16::from_be(original_dport)
     );
 
-    // DNAT the ip address
-    unsafe {
-        (*ip_hdr).dst_addr = backend.daddr.to_be();
-    }
-    // DNAT the port
-    unsafe { (*tcp_hdr).dest = (backend.dport as u16).to_be() };
+    let ip_hdr = mirror_packet(&ctx, ip_hdr, &backend_key)?;
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(&ctx, tcp_header_offset) }?;
+    record_first_fragment(ip_hdr, &backend)?;
+    sample_packet(&ctx, IpProto::Tcp, &backend_key, &backend, new_conn);
+    capture_packet(&ctx, &backend_key);
 
-    if (ctx.data() + EthHdr::LEN + Ipv4Hdr::LEN) > ctx.data_end() {
-        info!(&ctx, "Iphdr is out of bounds");
-        return Ok(TC_ACT_OK);
-    }
+    // Ports of a Gateway's port range map to the same offset in the backend's.
+    let backend_port = (backend.dport as u16).wrapping_add(port_offset);
+
+    let mut record_proxy = false;
+    let action = match backend.forwarding {
+        ForwardingMode::Nat | ForwardingMode::Snat => {
+            // The client has to recognize its packet in the reply, so it's answered before being
+            // rewritten, and new connections aren't tracked.
+            if ip_hdr.ttl() <= 1 {
+                return reply_icmp_time_exceeded(&ctx, ip_hdr);
+            }
+            let ip_hdr = if is_nat64(ip_hdr, &backend) {
+                // The backend's segments grow by the difference between the IP headers once
+                // translated, so the MSS is clamped on the client's side.
+                clamp_mss(&ctx, tcp_header_offset, max_mss(ip_hdr))?;
+                let ip_hdr = ip_hdr.reload(&ctx)?;
+
+                let nat64_addr = nat64_addr().ok_or(TC_ACT_SHOT)?;
+                let ip_hdr = translate_tcp_6to4(
+                    &ctx,
+                    ip_hdr,
+                    &nat64_addr,
+                    snat_port,
+                    &backend.daddr,
+                    backend_port,
+                )?;
+                tcp_header_offset = ip_hdr.l4_offset();
+                ip_hdr
+            } else {
+                // DNAT the ip address
+                ip_hdr.set_dst_addr(&backend.daddr);
+                // DNAT the port
+                unsafe { (*tcp_hdr).dest = backend_port.to_be() };
+
+                ip_hdr.update_csum(&ctx)?;
+
+                // Calculate l4 cksum, the destination address is part of the pseudo-header
+                let tcp_check_offset = tcp_header_offset + offset_of!(TcpHdr, check);
+                l4_csum_replace_addr(&ctx, tcp_check_offset, &original_daddr, &backend.daddr)?;
+                l4_csum_replace_port(&ctx, tcp_check_offset, original_dport, backend_port.to_be())?;
+
+                // Replacing the checksum invalidated our packet pointers, so grab the IP header
+                // again.
+                let ip_hdr = ip_hdr.reload(&ctx)?;
+
+                clamp_mss(&ctx, tcp_header_offset, max_mss(ip_hdr))?;
+
+                if snat_port != 0 {
+                    if let Some(snat_addr) = snat_addr(ip_hdr) {
+                        snat_tcp(&ctx, ip_hdr, &snat_addr, snat_port)?;
+                        let ip_hdr = ip_hdr.reload(&ctx)?;
+                        ip_hdr.update_csum(&ctx)?;
+                    }
+                }
 
-    // Calculate l3 cksum
-    // TODO(astoycos) use l3_cksum_replace instead
-    unsafe { (*ip_hdr).check = 0 };
-    let full_cksum = unsafe {
-        bpf_csum_diff(
-            mem::MaybeUninit::zeroed().assume_init(),
-            0,
-            ip_hdr as *mut u32,
-            Ipv4Hdr::LEN as u32,
-            0,
-        )
-    } as u64;
-    unsafe { (*ip_hdr).check = csum_fold_helper(full_cksum) };
-    // FIXME
-    unsafe { (*tcp_hdr).check = 0 };
-
-    let action = unsafe {
-        bpf_redirect_neigh(
-            backend.ifindex as u32,
-            mem::MaybeUninit::zeroed().assume_init(),
-            0,
-            0,
-        )
+                if backend.toa {
+                    insert_toa(&ctx, ip_hdr, &client_key)?;
+                }
+                ip_hdr
+            };
+
+            if backend.proxy_protocol && !new_conn {
+                let injected_len = proxy_protocol_ingress(
+                    &ctx,
+                    ip_hdr,
+                    &client_key,
+                    &BackendKey {
+                        ip: original_daddr,
+                        port: u16::from_be(original_dport) as u32,
+                    },
+                    proxy_seq,
+                    proxy_len,
+                )?;
+                // Record the header as soon as it is first injected, since it shifts the sequence
+                // numbers of everything that follows.
+                if injected_len != proxy_len {
+                    proxy_len = injected_len;
+                    record_proxy = true;
+                }
+            }
+
+            mark_dscp(&ctx, ip_hdr, &backend_key)?;
+            redirect_to_backend(&ctx, ip_hdr, &backend)?
+        }
+        ForwardingMode::Dsr => {
+            clamp_mss(&ctx, tcp_header_offset, max_mss(ip_hdr))?;
+            redirect_dsr(&ctx, &backend)?
+        }
     };
 
     let mut lb_mapping = LoadBalancerMapping {
         backend,
         backend_key,
         tcp_state,
+        tcp_closer,
+        last_seen: now,
+        proxy_seq,
+        proxy_len,
+        snat_port,
+        port_offset,
+        syn_cookie,
+        cookie_seq,
+        seq_offset,
+        syn_sent,
+        stats,
     };
-
-    // If the connection is new, then record it in our map for future tracking.
-    if new_conn {
+    if record_proxy {
         unsafe {
             LB_CONNECTIONS.insert(&client_key, &lb_mapping, 0_u64)?;
         }
+    }
+
+    // If the connection is new, then record it in our map for future tracking.
+    if new_conn {
+        track_tcp_conn(&client_key, &lb_mapping)?;
+        count_connection_opened(&backend)?;
+        count_client_connection_opened(&client_key.ip);
+        report_connection_opened(IpProto::Tcp, &client_key, &backend_key, &backend, &stats);
 
         // since this is a new connection, there is nothing else to do, so exit early
         info!(&ctx, "redirect action: {}", action);
-        return Ok(action as i32);
+        return Ok(action);
     }
 
+    // Replacing the checksum invalidated our packet pointers, so grab the TCP header again.
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(&ctx, tcp_header_offset) }?;
     let tcp_hdr_ref = unsafe { tcp_hdr.as_ref().ok_or(TC_ACT_OK)? };
 
     // If the packet has the RST flag set, it means the connection is being terminated, so remove it
     // from our map.
     if tcp_hdr_ref.rst() == 1 {
-        unsafe {
-            LB_CONNECTIONS.remove(&client_key)?;
-        }
+        remove_tcp_conn(&client_key, &lb_mapping, CloseReason::Rst)?;
+
+        info!(&ctx, "redirect action: {}", action);
+        return Ok(action);
     }
 
-    update_tcp_conns(tcp_hdr_ref, &client_key, &mut lb_mapping)?;
+    // In DSR mode the backend's side of the connection doesn't go through here, so its termination
+    // can't be tracked and is left for userspace to expire.
+    update_tcp_conns(tcp_hdr_ref, TCPSide::Client, &client_key, &mut lb_mapping)?;
 
     info!(&ctx, "redirect action: {}", action);
-    Ok(action as i32)
+    Ok(action)
 }
@@ -0,0 +1,94 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use core::mem;
+
+use aya_ebpf::programs::TcContext;
+use memoffset::offset_of;
+use network_types::tcp::TcpHdr;
+
+use crate::utils::{insert_after_tcp_header, ptr_at, IpHdr, TCP_MAX_HEADER_LEN};
+use common::ClientKey;
+
+// The option kind used by the TOA kernel modules.
+const TCP_OPTION_TOA: u8 = 254;
+
+#[repr(C)]
+struct ToaIpv4Option {
+    kind: u8,
+    len: u8,
+    port: u16,
+    addr: u32,
+}
+
+#[repr(C)]
+struct ToaIpv6Option {
+    kind: u8,
+    len: u8,
+    port: u16,
+    addr: [u32; 4],
+}
+
+// Appends a TOA (TCP Option Address) option carrying the client's address and port to the options
+// of a SYN, so that backends running a TOA kernel module see the client's address instead of the
+// one of the Gateway. Other packets, and SYNs without room left for the option, are left as is.
+pub fn insert_toa(ctx: &TcContext, ip_hdr: IpHdr, client_key: &ClientKey) -> Result<(), i64> {
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(ctx, ip_hdr.l4_offset())? };
+    if unsafe { (*tcp_hdr).syn() == 0 || (*tcp_hdr).ack() == 1 } {
+        return Ok(());
+    }
+
+    match ip_hdr {
+        IpHdr::V4(..) => {
+            let option = ToaIpv4Option {
+                kind: TCP_OPTION_TOA,
+                len: mem::size_of::<ToaIpv4Option>() as u8,
+                port: (client_key.port as u16).to_be(),
+                // IPv4 addresses are stored IPv4-mapped, the address is the last word.
+                addr: client_key.ip[3].to_be(),
+            };
+            insert_tcp_option(ctx, ip_hdr, &option)
+        }
+        IpHdr::V6(..) => {
+            let option = ToaIpv6Option {
+                kind: TCP_OPTION_TOA,
+                len: mem::size_of::<ToaIpv6Option>() as u8,
+                port: (client_key.port as u16).to_be(),
+                addr: client_key.ip.map(u32::to_be),
+            };
+            insert_tcp_option(ctx, ip_hdr, &option)
+        }
+    }
+}
+
+// Appends `option`, whose length must be a multiple of 4, to the options of the TCP header.
+#[inline(always)]
+fn insert_tcp_option<T>(ctx: &TcContext, ip_hdr: IpHdr, option: &T) -> Result<(), i64> {
+    let tcp_header_offset = ip_hdr.l4_offset();
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(ctx, tcp_header_offset)? };
+    let doff = unsafe { (*tcp_hdr).doff() };
+    if doff as usize * 4 + mem::size_of::<T>() > TCP_MAX_HEADER_LEN {
+        info!(ctx, "No room left in the TCP header for the TOA option");
+        return Ok(());
+    }
+
+    let len = insert_after_tcp_header(ctx, ip_hdr, option)?;
+
+    // The option is now the start of the payload, grow the header over it. The data offset shares
+    // its 16-bit word with the flags, which is what the checksum is updated with.
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(ctx, tcp_header_offset)? };
+    let doff_word =
+        unsafe { (tcp_hdr as *mut u8).add(offset_of!(TcpHdr, ack_seq) + 4) as *mut u16 };
+    let original_word = unsafe { *doff_word };
+    unsafe { (*tcp_hdr).set_doff(doff + len / 4) };
+    let updated_word = unsafe { *doff_word };
+    ctx.l4_csum_replace(
+        tcp_header_offset + offset_of!(TcpHdr, check),
+        original_word as u64,
+        updated_word as u64,
+        2,
+    )
+}
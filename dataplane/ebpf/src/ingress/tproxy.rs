@@ -0,0 +1,150 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use core::{ffi::c_void, mem};
+
+use aya_ebpf::{
+    bindings::{__sk_buff, bpf_sock, bpf_sock_tuple, TC_ACT_OK, TC_ACT_SHOT},
+    helpers::{bpf_sk_assign, bpf_sk_lookup_udp, bpf_sk_release, bpf_skc_lookup_tcp},
+    programs::TcContext,
+    EbpfContext,
+};
+use network_types::ip::IpProto;
+
+use crate::{
+    utils::{config, ip_octets, IpHdr},
+    TPROXY_PORTS,
+};
+use common::BackendKey;
+
+// The state of the listening TCP sockets.
+const BPF_TCP_LISTEN: u32 = 10;
+// Looks the sockets up in the network namespace of the packet.
+const BPF_F_CURRENT_NETNS: u64 = -1_i64 as u64;
+
+// The tuples the sockets are looked up with, per IP family, as in struct bpf_sock_tuple. The
+// addresses and ports are in network byte order.
+#[repr(C)]
+struct SockTupleV4 {
+    saddr: u32,
+    daddr: u32,
+    sport: u16,
+    dport: u16,
+}
+
+#[repr(C)]
+struct SockTupleV6 {
+    saddr: [u32; 4],
+    daddr: [u32; 4],
+    sport: u16,
+    dport: u16,
+}
+
+// Hands the packet to the local transparent proxy of the Gateway, if it has one, instead of
+// forwarding it to a backend: the packets of the connections the proxy accepted go to their own
+// socket, and the others to the proxy's listening socket, which must have IP_TRANSPARENT set for
+// the connections to keep the Gateway's address. The packets are marked with the Config's
+// tproxy_mark for the host to route them locally. Returns the action to take on the packet, or
+// None when the Gateway has no proxy.
+// Ref: https://docs.kernel.org/networking/tproxy.html
+#[inline(always)]
+pub fn steer_to_tproxy(
+    ctx: &TcContext,
+    ip_hdr: IpHdr,
+    gateway_key: &BackendKey,
+    proto: IpProto,
+    sport: u16,
+    dport: u16,
+) -> Result<Option<i32>, i64> {
+    let proxy_port = match unsafe { TPROXY_PORTS.get(gateway_key) } {
+        Some(proxy_port) => *proxy_port,
+        None => return Ok(None),
+    };
+
+    let mut sk = lookup_socket(ctx, ip_hdr, proto, sport, dport);
+    if !sk.is_null() && proto == IpProto::Tcp && unsafe { (*sk).state } == BPF_TCP_LISTEN {
+        // Someone else listens on the Gateway's port, which isn't the proxy.
+        unsafe { bpf_sk_release(sk as *mut c_void) };
+        sk = core::ptr::null_mut();
+    }
+    if sk.is_null() {
+        sk = lookup_socket(ctx, ip_hdr, proto, sport, proxy_port.to_be());
+    }
+    if sk.is_null() {
+        info!(
+            ctx,
+            "No proxy listening on port {} for svc ip: {:i}, dropping the packet",
+            proxy_port,
+            ip_octets(&gateway_key.ip)
+        );
+        return Ok(Some(TC_ACT_SHOT));
+    }
+
+    let ret = unsafe { bpf_sk_assign(ctx.as_ptr(), sk as *mut c_void, 0) };
+    unsafe { bpf_sk_release(sk as *mut c_void) };
+    if ret != 0 {
+        return Err(ret);
+    }
+    let mark = config().tproxy_mark;
+    if mark != 0 {
+        unsafe { (*(ctx.as_ptr() as *mut __sk_buff)).mark = mark };
+    }
+    Ok(Some(TC_ACT_OK))
+}
+
+// Looks up the socket of the packet's protocol bound to its destination address and `dport`, and
+// connected to its source, if any. The returned socket has to be released.
+#[inline(always)]
+fn lookup_socket(
+    ctx: &TcContext,
+    ip_hdr: IpHdr,
+    proto: IpProto,
+    sport: u16,
+    dport: u16,
+) -> *mut bpf_sock {
+    let saddr = ip_hdr.src_addr();
+    let daddr = ip_hdr.dst_addr();
+    let mut tuple_v4 = SockTupleV4 {
+        saddr: saddr[3].to_be(),
+        daddr: daddr[3].to_be(),
+        sport,
+        dport,
+    };
+    let mut tuple_v6 = SockTupleV6 {
+        saddr: saddr.map(u32::to_be),
+        daddr: daddr.map(u32::to_be),
+        sport,
+        dport,
+    };
+    let (tuple, tuple_len) = match ip_hdr {
+        IpHdr::V4(..) => (
+            &mut tuple_v4 as *mut SockTupleV4 as *mut bpf_sock_tuple,
+            mem::size_of::<SockTupleV4>(),
+        ),
+        IpHdr::V6(..) => (
+            &mut tuple_v6 as *mut SockTupleV6 as *mut bpf_sock_tuple,
+            mem::size_of::<SockTupleV6>(),
+        ),
+    };
+    unsafe {
+        match proto {
+            IpProto::Tcp => bpf_skc_lookup_tcp(
+                ctx.as_ptr(),
+                tuple,
+                tuple_len as u32,
+                BPF_F_CURRENT_NETNS,
+                0,
+            ),
+            _ => bpf_sk_lookup_udp(
+                ctx.as_ptr(),
+                tuple,
+                tuple_len as u32,
+                BPF_F_CURRENT_NETNS,
+                0,
+            ),
+        }
+    }
+}
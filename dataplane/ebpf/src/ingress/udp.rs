@@ -4,132 +4,217 @@ Copyright 2023 The Kubernetes Authors.
 SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
 */
 
-use core::mem;
-
 use aya_ebpf::{
-    bindings::TC_ACT_PIPE,
-    helpers::{bpf_csum_diff, bpf_redirect_neigh},
+    bindings::{TC_ACT_PIPE, TC_ACT_SHOT},
+    helpers::bpf_ktime_get_ns,
     programs::TcContext,
 };
-use aya_log_ebpf::{debug, info};
-use network_types::{eth::EthHdr, ip::Ipv4Hdr, udp::UdpHdr};
+use memoffset::offset_of;
+use network_types::{ip::IpProto, udp::UdpHdr};
 
 use crate::{
-    utils::{csum_fold_helper, ptr_at},
-    BACKENDS, GATEWAY_INDEXES, LB_CONNECTIONS,
+    ingress::{
+        acl::is_denied,
+        balancing::select_backend,
+        capture::capture_packet,
+        dscp::mark_dscp,
+        dsr::redirect_dsr,
+        fib::redirect_to_backend,
+        fragment::record_first_fragment,
+        gateway::find_gateway,
+        mirror::mirror_packet,
+        nat64::is_nat64,
+        quic::find_quic_backend,
+        reply::{reply_icmp_port_unreachable, reply_icmp_time_exceeded},
+        sample::sample_packet,
+        stateless::forward_stateless,
+        tproxy::steer_to_tproxy,
+    },
+    utils::{
+        count_connection_closed, count_connection_opened, ip_octets, ptr_at,
+        report_connection_closed, report_connection_opened, udp_csum_replace_addr,
+        udp_csum_replace_port, IpHdr,
+    },
+    UDP_CONNECTIONS,
 };
-use common::{BackendKey, ClientKey, LoadBalancerMapping, BACKENDS_ARRAY_CAPACITY};
-
-pub fn handle_udp_ingress(ctx: TcContext) -> Result<i32, i64> {
-    let ip_hdr: *mut Ipv4Hdr = unsafe { ptr_at(&ctx, EthHdr::LEN)? };
+use common::{ClientKey, CloseReason, FlowStats, ForwardingMode, UdpLoadBalancerMapping};
 
-    let udp_header_offset = EthHdr::LEN + Ipv4Hdr::LEN;
+pub fn handle_udp_ingress(ctx: TcContext, ip_hdr: IpHdr) -> Result<i32, i64> {
+    let udp_header_offset = ip_hdr.l4_offset();
 
     let udp_hdr: *mut UdpHdr = unsafe { ptr_at(&ctx, udp_header_offset) }?;
 
-    let original_daddr = unsafe { (*ip_hdr).dst_addr };
+    let original_daddr = ip_hdr.dst_addr();
     let original_dport = unsafe { (*udp_hdr).dest };
 
-    let backend_key = BackendKey {
-        ip: u32::from_be(original_daddr),
-        port: (u16::from_be(original_dport)) as u32,
-    };
-    let backend_list = unsafe { BACKENDS.get(&backend_key) }.ok_or(TC_ACT_PIPE)?;
-    let backend_index = unsafe { GATEWAY_INDEXES.get(&backend_key) }.ok_or(TC_ACT_PIPE)?;
+    let gateway = find_gateway(original_daddr, u16::from_be(original_dport)).ok_or(TC_ACT_PIPE)?;
+    let backend_key = gateway.key;
+    let port_offset = gateway.port_offset;
 
     info!(
         &ctx,
         "Received a UDP packet destined for svc ip: {:i} at Port: {} ",
-        backend_key.ip,
-        backend_key.port as u16,
+        ip_octets(&original_daddr),
+        u16::from_be(original_dport),
     );
-    debug!(&ctx, "Destination backend index: {}", *backend_index);
-    debug!(&ctx, "Backends length: {}", backend_list.backends_len);
 
-    // this check asserts that we don't use a "zero-value" Backend
-    if backend_list.backends_len <= *backend_index {
-        return Ok(TC_ACT_PIPE);
+    let client_key = ClientKey {
+        ip: ip_hdr.src_addr(),
+        port: (u16::from_be(unsafe { (*udp_hdr).source })) as u32,
+    };
+    if is_denied(&backend_key.ip, &client_key.ip) {
+        info!(&ctx, "Client is denied by the ACL, dropping the packet");
+        return Ok(TC_ACT_SHOT);
     }
-    // this check is to make the verifier happy
-    if *backend_index as usize >= BACKENDS_ARRAY_CAPACITY {
-        return Ok(TC_ACT_PIPE);
+    if gateway.backend_list.stateless {
+        return forward_stateless(&ctx, ip_hdr, &gateway, IpProto::Udp, &client_key);
     }
+    let now = unsafe { bpf_ktime_get_ns() };
+
+    let idle_timeout = gateway.backend_list.udp_idle_timeout;
+
+    // Packets of a flow we're already tracking keep going to the same backend, as long as the
+    // flow is still destined for the same Gateway and hasn't been idle for longer than the
+    // Gateway's timeout, which userspace may not have caught up with yet.
+    let tracked_backend = match unsafe { UDP_CONNECTIONS.get_ptr_mut(&client_key) } {
+        Some(udp_mapping) => unsafe {
+            let same_gateway = (*udp_mapping).backend_key.ip == backend_key.ip
+                && (*udp_mapping).backend_key.port == backend_key.port
+                && (*udp_mapping).port_offset == port_offset;
+            let expired =
+                idle_timeout != 0 && now.saturating_sub((*udp_mapping).last_seen) > idle_timeout;
+            if same_gateway && !expired {
+                (*udp_mapping).last_seen = now;
+                (*udp_mapping).idle_timeout = idle_timeout;
+                (*udp_mapping).stats.count(ctx.len());
+                Some((*udp_mapping).backend)
+            } else {
+                // The flow is replaced below by a new one.
+                let reason = if same_gateway {
+                    CloseReason::Timeout
+                } else {
+                    CloseReason::Replaced
+                };
+                report_connection_closed(
+                    IpProto::Udp,
+                    &client_key,
+                    &(*udp_mapping).backend_key,
+                    &(*udp_mapping).backend,
+                    reason,
+                    &(*udp_mapping).stats,
+                );
+                count_connection_closed(&(*udp_mapping).backend)?;
+                None
+            }
+        },
+        None => None,
+    };
 
-    let mut backend = backend_list.backends[0];
-    match backend_list.backends.get(*backend_index as usize) {
-        Some(bk) => backend = *bk,
-        None => {
+    let new_flow = tracked_backend.is_none();
+    let backend = match tracked_backend {
+        Some(backend) => {
             debug!(
                 &ctx,
-                "Failed to find backend in backends_list at index {}, falling back to 0th index; backends_len: {} ",
-                *backend_index,
-                backend_list.backends_len
-            )
+                "Found tracked UDP flow for client port {}", client_key.port
+            );
+            backend
+        }
+        None => {
+            // The flows handed to a local proxy are tracked by the proxy's sockets.
+            if let Some(action) = steer_to_tproxy(
+                &ctx,
+                ip_hdr,
+                &backend_key,
+                IpProto::Udp,
+                unsafe { (*udp_hdr).source },
+                original_dport,
+            )? {
+                return Ok(action);
+            }
+            // QUIC clients whose address changed keep the backend of their connection, which
+            // their packets identify.
+            let quic_backend =
+                find_quic_backend(&ctx, udp_header_offset + UdpHdr::LEN, &gateway, &client_key);
+            let backend = match quic_backend.or_else(|| {
+                select_backend(&ctx, &gateway.group_key, gateway.backend_list, &client_key)
+            }) {
+                Some(backend) => backend,
+                None => return reply_icmp_port_unreachable(&ctx, ip_hdr),
+            };
+            // Only the TCP connections of IPv6 clients are translated for IPv4 backends.
+            if is_nat64(ip_hdr, &backend) {
+                info!(
+                    &ctx,
+                    "UDP flows aren't translated to IPv4 backends, dropping the packet"
+                );
+                return Ok(TC_ACT_SHOT);
+            }
+
+            let udp_mapping = UdpLoadBalancerMapping {
+                backend,
+                backend_key,
+                last_seen: now,
+                idle_timeout,
+                port_offset,
+                stats: FlowStats::new(now, ctx.len()),
+            };
+            unsafe {
+                UDP_CONNECTIONS.insert(&client_key, &udp_mapping, 0_u64)?;
+            }
+            count_connection_opened(&backend)?;
+            report_connection_opened(
+                IpProto::Udp,
+                &client_key,
+                &backend_key,
+                &backend,
+                &udp_mapping.stats,
+            );
+
+            backend
         }
-    }
-
-    unsafe {
-        // DNAT the ip address
-        (*ip_hdr).dst_addr = backend.daddr.to_be();
-        // DNAT the port
-        (*udp_hdr).dest = (backend.dport as u16).to_be();
-
-        // Record the packet's source and destination in our connection tracking map.
-        let client_key = ClientKey {
-            ip: u32::from_be((*ip_hdr).src_addr),
-            // The only reason we're tracking UDP packets is to be able to allow ICMP egress
-            // traffic. Since ICMP is a L3 protocol, an ICMP packet's header does not have access to
-            // the UDP port and operates solely based on the IP address.
-            port: 0,
-        };
-        let lb_mapping = LoadBalancerMapping {
-            backend,
-            backend_key,
-            tcp_state: None,
-        };
-        LB_CONNECTIONS.insert(&client_key, &lb_mapping, 0_u64)?;
     };
 
-    if (ctx.data() + EthHdr::LEN + Ipv4Hdr::LEN) > ctx.data_end() {
-        info!(&ctx, "Iphdr is out of bounds");
-        return Ok(TC_ACT_PIPE);
-    }
+    let ip_hdr = mirror_packet(&ctx, ip_hdr, &backend_key)?;
+    let udp_hdr: *mut UdpHdr = unsafe { ptr_at(&ctx, udp_header_offset) }?;
+    record_first_fragment(ip_hdr, &backend)?;
+    sample_packet(&ctx, IpProto::Udp, &backend_key, &backend, new_flow);
+    capture_packet(&ctx, &backend_key);
 
-    // Calculate l3 cksum
-    // TODO(astoycos) use l3_cksum_replace instead
-    unsafe { (*ip_hdr).check = 0 };
-    let full_cksum = unsafe {
-        bpf_csum_diff(
-            mem::MaybeUninit::zeroed().assume_init(),
-            0,
-            ip_hdr as *mut u32,
-            Ipv4Hdr::LEN as u32,
-            0,
-        )
-    } as u64;
-    unsafe { (*ip_hdr).check = csum_fold_helper(full_cksum) };
-    // Kernel allows UDP packet with unset checksums
-    unsafe { (*udp_hdr).check = 0 };
-
-    let action = unsafe {
-        bpf_redirect_neigh(
-            backend.ifindex as u32,
-            mem::MaybeUninit::zeroed().assume_init(),
-            0,
-            0,
-        )
-    };
+    if backend.forwarding == ForwardingMode::Dsr {
+        return redirect_dsr(&ctx, &backend);
+    }
 
-    // move the index to the next backend in our list
-    let mut next = *backend_index + 1;
-    if next >= backend_list.backends_len {
-        next = 0;
+    // The client has to recognize its packet in the reply, so it's answered before being rewritten.
+    if ip_hdr.ttl() <= 1 {
+        return reply_icmp_time_exceeded(&ctx, ip_hdr);
     }
-    unsafe {
-        GATEWAY_INDEXES.insert(&backend_key, &next, 0 as u64)?;
+
+    let backend_port = (backend.dport as u16).wrapping_add(port_offset);
+    // DNAT the ip address
+    ip_hdr.set_dst_addr(&backend.daddr);
+    // DNAT the port
+    unsafe { (*udp_hdr).dest = backend_port.to_be() };
+
+    // Calculate l3 cksum
+    ip_hdr.update_csum(&ctx)?;
+    match ip_hdr {
+        // Kernel allows UDP packet with unset checksums
+        IpHdr::V4(..) => unsafe { (*udp_hdr).check = 0 },
+        // but the checksum is mandatory over IPv6, calculate the l4 cksum, the destination address
+        // is part of the pseudo-header.
+        IpHdr::V6(..) => {
+            let udp_check_offset = udp_header_offset + offset_of!(UdpHdr, check);
+            udp_csum_replace_addr(&ctx, udp_check_offset, &original_daddr, &backend.daddr)?;
+            udp_csum_replace_port(&ctx, udp_check_offset, original_dport, backend_port.to_be())?;
+        }
     }
 
+    // Replacing the checksum invalidated our packet pointers, so grab the IP header again.
+    let ip_hdr = ip_hdr.reload(&ctx)?;
+    mark_dscp(&ctx, ip_hdr, &backend_key)?;
+    let action = redirect_to_backend(&ctx, ip_hdr, &backend)?;
+
     info!(&ctx, "redirect action: {}", action);
 
-    Ok(action as i32)
+    Ok(action)
 }
@@ -0,0 +1,59 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+// The logging macros of aya_log_ebpf, which only log while the level is enabled in LOG_LEVEL, so
+// that the per-packet logs cost a map lookup in production and can be turned on while debugging.
+// They are declared before the modules of the programs, which use them instead of aya_log_ebpf's.
+// Without the logging feature nothing is ever logged, and the compiler drops the logs entirely.
+
+use common::LogLevel;
+
+use crate::LOG_LEVEL;
+
+#[inline(always)]
+pub fn log_enabled(level: LogLevel) -> bool {
+    if !cfg!(feature = "logging") {
+        return false;
+    }
+    match unsafe { LOG_LEVEL.get(0) } {
+        Some(max_level) => level <= *max_level,
+        None => false,
+    }
+}
+
+#[allow(unused_macros)]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        if $crate::logging::log_enabled(common::LogLevel::Error) {
+            aya_log_ebpf::error!($($arg)*);
+        }
+    };
+}
+
+#[allow(unused_macros)]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        if $crate::logging::log_enabled(common::LogLevel::Warn) {
+            aya_log_ebpf::warn!($($arg)*);
+        }
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::logging::log_enabled(common::LogLevel::Info) {
+            aya_log_ebpf::info!($($arg)*);
+        }
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::logging::log_enabled(common::LogLevel::Debug) {
+            aya_log_ebpf::debug!($($arg)*);
+        }
+    };
+}
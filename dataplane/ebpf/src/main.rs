@@ -7,46 +7,317 @@ SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
 #![no_std]
 #![no_main]
 
+#[macro_use]
+mod logging;
+
 #[allow(non_upper_case_globals)]
 #[allow(non_snake_case)]
 #[allow(non_camel_case_types)]
 #[allow(dead_code)]
 mod egress;
 mod ingress;
+mod sockmap;
 mod utils;
+mod xdp;
 
 use aya_ebpf::{
-    bindings::{TC_ACT_OK, TC_ACT_PIPE, TC_ACT_SHOT},
-    macros::{classifier, map},
-    maps::HashMap,
-    programs::TcContext,
+    bindings::{
+        sk_action::SK_PASS, xdp_action::XDP_PASS, BPF_F_NO_PREALLOC, TC_ACT_PIPE, TC_ACT_REDIRECT,
+        TC_ACT_SHOT,
+    },
+    macros::{classifier, map, sk_msg, sock_ops, xdp},
+    maps::{Array, HashMap, LpmTrie, LruHashMap, PerCpuArray, PerCpuHashMap, RingBuf, SockHash},
+    programs::{SkMsgContext, SockOpsContext, TcContext, XdpContext},
 };
 
-use common::{BackendKey, BackendList, ClientKey, LoadBalancerMapping, BPF_MAPS_CAPACITY};
-use egress::{icmp::handle_icmp_egress, tcp::handle_tcp_egress};
-use ingress::{tcp::handle_tcp_ingress, udp::handle_udp_ingress};
-
-use network_types::{
-    eth::{EthHdr, EtherType},
-    ip::{IpProto, Ipv4Hdr},
+use common::{
+    AclAction, AclKey, Affinity, AffinityKey, Backend, BackendConnections, BackendFailures,
+    BackendKey, BackendList, BackendTraffic, ClientKey, Config, ConnectionLimit, FlowTableStats,
+    FragmentKey, GatewayIndex, GatewaySlotKey, LoadBalancerMapping, LogLevel, MaglevTable, Mirror,
+    PingMapping, PortRangeList, QuicCidKey, SnatKey, SockKey, SynLatency, TcpTimeouts, TokenBucket,
+    Tunnel, UdpLoadBalancerMapping, ACL_RULES_CAPACITY, BACKENDS_ARRAY_CAPACITY, BPF_MAPS_CAPACITY,
+    DROP_REASONS, GATEWAY_SLOTS, LB_CONNECTIONS_CAPACITY, PASS_REASONS, PINGS_CAPACITY,
+};
+use egress::{
+    icmp::handle_icmp_egress, sctp::handle_sctp_egress, tcp::handle_tcp_egress,
+    udp::handle_udp_egress,
 };
-use utils::ptr_at;
+use ingress::{
+    fragment::{handle_fragment_ingress, is_later_fragment},
+    icmp::handle_icmp_ingress,
+    sctp::handle_sctp_ingress,
+    tcp::handle_tcp_ingress,
+    udp::handle_udp_ingress,
+};
+
+use network_types::ip::{IpProto, Ipv4Hdr, Ipv6Hdr};
+use sockmap::{handle_sk_msg, handle_sock_ops};
+use utils::{parse_eth_hdr, pass_action, ptr_at, IpHdr, ETH_P_IP, ETH_P_IPV6};
+use xdp::handle_xdp_ingress;
 
 // -----------------------------------------------------------------------------
 // Maps
 // -----------------------------------------------------------------------------
 
+#[map(name = "CONFIG")]
+static mut CONFIG: Array<Config> = Array::<Config>::with_max_entries(1, 0);
+
+// The verbosity of the logs of the programs, in the only entry. It is kept apart from CONFIG for
+// userspace to change it without rewriting the other settings.
+#[map(name = "LOG_LEVEL")]
+static mut LOG_LEVEL: Array<LogLevel> = Array::<LogLevel>::with_max_entries(1, 0);
+
+// The timeouts of the states of the TCP connections, in the only entry, see TcpTimeouts.
+#[map(name = "TCP_TIMEOUTS")]
+static mut TCP_TIMEOUTS: Array<TcpTimeouts> = Array::<TcpTimeouts>::with_max_entries(1, 0);
+
+// The maps pinned by name are picked up by the next programs when the dataplane restarts, so that
+// the tracked connections and the Gateways they go to survive upgrades.
 #[map(name = "BACKENDS")]
 static mut BACKENDS: HashMap<BackendKey, BackendList> =
-    HashMap::<BackendKey, BackendList>::with_max_entries(BPF_MAPS_CAPACITY, 0);
+    HashMap::<BackendKey, BackendList>::pinned(BPF_MAPS_CAPACITY, 0);
+
+// The port ranges of the Gateways listening on a range of ports, by address.
+#[map(name = "PORT_RANGES")]
+static mut PORT_RANGES: HashMap<[u32; 4], PortRangeList> =
+    HashMap::<[u32; 4], PortRangeList>::pinned(BPF_MAPS_CAPACITY, 0);
 
+// The number of Gateways and aliases listening on each address, whose pings are answered.
+#[map(name = "VIP_ADDRESSES")]
+static mut VIP_ADDRESSES: HashMap<[u32; 4], u32> =
+    HashMap::<[u32; 4], u32>::pinned(BPF_MAPS_CAPACITY, 0);
+
+// The Gateways sharing the backends and balancing state of another Gateway, whose key they map to.
+#[map(name = "GATEWAY_ALIASES")]
+static mut GATEWAY_ALIASES: HashMap<BackendKey, BackendKey> =
+    HashMap::<BackendKey, BackendKey>::pinned(BPF_MAPS_CAPACITY, 0);
+
+// The balancing state of the Gateways, in the two slots each Gateway switches between when its
+// backends change, see BackendList.slot, and of their split groups.
 #[map(name = "GATEWAY_INDEXES")]
-static mut GATEWAY_INDEXES: HashMap<BackendKey, u16> =
+static mut GATEWAY_INDEXES: HashMap<GatewaySlotKey, GatewayIndex> =
+    HashMap::<GatewaySlotKey, GatewayIndex>::pinned(BPF_MAPS_CAPACITY * GATEWAY_SLOTS, 0);
+
+// Few Gateways split their traffic, so the tables aren't preallocated for all of their groups.
+#[map(name = "MAGLEV_TABLES")]
+static mut MAGLEV_TABLES: HashMap<GatewaySlotKey, MaglevTable> =
+    HashMap::<GatewaySlotKey, MaglevTable>::pinned(
+        BPF_MAPS_CAPACITY * GATEWAY_SLOTS,
+        BPF_F_NO_PREALLOC,
+    );
+
+// Expired affinities are only overwritten when the client comes back, so let the least recently
+// used ones go when the map is full.
+#[map(name = "AFFINITIES")]
+static mut AFFINITIES: LruHashMap<AffinityKey, Affinity> =
+    LruHashMap::<AffinityKey, Affinity>::with_max_entries(
+        BPF_MAPS_CAPACITY * BACKENDS_ARRAY_CAPACITY as u32,
+        0,
+    );
+
+// The backends of the QUIC connections, by the connection IDs the backends chose. Connections
+// which are gone are never removed, so let the least recently used ones go when the map is full.
+#[map(name = "QUIC_CONNECTIONS")]
+static mut QUIC_CONNECTIONS: LruHashMap<QuicCidKey, Backend> =
+    LruHashMap::<QuicCidKey, Backend>::with_max_entries(LB_CONNECTIONS_CAPACITY, 0);
+
+#[map(name = "BACKEND_CONNECTIONS")]
+static mut BACKEND_CONNECTIONS: PerCpuHashMap<BackendKey, BackendConnections> =
+    PerCpuHashMap::<BackendKey, BackendConnections>::pinned(
+        BPF_MAPS_CAPACITY * BACKENDS_ARRAY_CAPACITY as u32,
+        0,
+    );
+
+#[map(name = "BACKEND_TRAFFIC")]
+static mut BACKEND_TRAFFIC: PerCpuHashMap<BackendKey, BackendTraffic> =
+    PerCpuHashMap::<BackendKey, BackendTraffic>::with_max_entries(
+        BPF_MAPS_CAPACITY * BACKENDS_ARRAY_CAPACITY as u32,
+        0,
+    );
+
+// The times the backends took to answer the SYNs of their new connections.
+#[map(name = "SYN_LATENCIES")]
+static mut SYN_LATENCIES: PerCpuHashMap<BackendKey, SynLatency> =
+    PerCpuHashMap::<BackendKey, SynLatency>::with_max_entries(
+        BPF_MAPS_CAPACITY * BACKENDS_ARRAY_CAPACITY as u32,
+        0,
+    );
+
+// The failed new connections of the backends, which get them skipped by the selection of new
+// connections for a while once they reach the configured threshold.
+#[map(name = "BACKEND_FAILURES")]
+static mut BACKEND_FAILURES: LruHashMap<BackendKey, BackendFailures> =
+    LruHashMap::<BackendKey, BackendFailures>::with_max_entries(
+        BPF_MAPS_CAPACITY * BACKENDS_ARRAY_CAPACITY as u32,
+        0,
+    );
+
+// The source prefixes allowed or denied to connect to the Gateways of an address, see AclKey. Tries
+// can't be preallocated.
+#[map(name = "ACLS")]
+static mut ACLS: LpmTrie<AclKey, AclAction> =
+    LpmTrie::<AclKey, AclAction>::with_max_entries(ACL_RULES_CAPACITY, BPF_F_NO_PREALLOC);
+
+// The connection limits of the Gateways which have one.
+#[map(name = "CONNECTION_LIMITS")]
+static mut CONNECTION_LIMITS: HashMap<BackendKey, ConnectionLimit> =
+    HashMap::<BackendKey, ConnectionLimit>::with_max_entries(BPF_MAPS_CAPACITY, 0);
+
+// The Gateways which answer the SYNs of new connections with SYN cookies, the value is unused.
+#[map(name = "SYN_COOKIES")]
+static mut SYN_COOKIES: HashMap<BackendKey, u32> =
+    HashMap::<BackendKey, u32>::with_max_entries(BPF_MAPS_CAPACITY, 0);
+
+// The ports of the local transparent proxies the connections to the Gateways are handed to instead
+// of their backends, see steer_to_tproxy.
+#[map(name = "TPROXY_PORTS")]
+static mut TPROXY_PORTS: HashMap<BackendKey, u16> =
     HashMap::<BackendKey, u16>::with_max_entries(BPF_MAPS_CAPACITY, 0);
 
+// The Gateway whose backends the pings to an address are forwarded to, for the addresses which
+// don't answer their pings themselves.
+#[map(name = "PING_GATEWAYS")]
+static mut PING_GATEWAYS: HashMap<[u32; 4], BackendKey> =
+    HashMap::<[u32; 4], BackendKey>::with_max_entries(BPF_MAPS_CAPACITY, 0);
+
+// The pings forwarded to a backend, keyed by the client and the identifier of its echo requests.
+#[map(name = "PINGS")]
+static mut PINGS: LruHashMap<ClientKey, PingMapping> =
+    LruHashMap::<ClientKey, PingMapping>::with_max_entries(PINGS_CAPACITY, 0);
+
+// The DSCP the packets forwarded from the Gateways which have one are marked with.
+#[map(name = "DSCP_MARKS")]
+static mut DSCP_MARKS: HashMap<BackendKey, u8> =
+    HashMap::<BackendKey, u8>::with_max_entries(BPF_MAPS_CAPACITY, 0);
+
+// The mirrors the packets to the Gateways which have one are copied to.
+#[map(name = "MIRRORS")]
+static mut MIRRORS: HashMap<BackendKey, Mirror> =
+    HashMap::<BackendKey, Mirror>::with_max_entries(BPF_MAPS_CAPACITY, 0);
+
+// The new connections each Gateway rejected for being over its connection limit.
+#[map(name = "LIMITED_CONNECTIONS")]
+static mut LIMITED_CONNECTIONS: PerCpuHashMap<BackendKey, u64> =
+    PerCpuHashMap::<BackendKey, u64>::with_max_entries(BPF_MAPS_CAPACITY, 0);
+
+// The packets the programs failed to redirect, in the only entry.
+#[map(name = "REDIRECT_ERRORS")]
+static mut REDIRECT_ERRORS: PerCpuArray<u64> = PerCpuArray::<u64>::with_max_entries(1, 0);
+
+// The TCP packets the ingress program dropped, by DropReason.
+#[map(name = "TCP_DROPS")]
+static mut TCP_DROPS: PerCpuArray<u64> = PerCpuArray::<u64>::with_max_entries(DROP_REASONS, 0);
+
+// The TCP packets the ingress program let through to the host, by PassReason.
+#[map(name = "TCP_PASSES")]
+static mut TCP_PASSES: PerCpuArray<u64> = PerCpuArray::<u64>::with_max_entries(PASS_REASONS, 0);
+
+// The connections the programs inserted into and removed from LB_CONNECTIONS, in the only entry.
+#[map(name = "FLOW_TABLE_STATS")]
+static mut FLOW_TABLE_STATS: PerCpuArray<FlowTableStats> =
+    PerCpuArray::<FlowTableStats>::with_max_entries(1, 0);
+
+// Connections of each backend that were removed from the connection tracking maps by userspace,
+// which can't safely update the per-CPU counters of BACKEND_CONNECTIONS, or evicted from
+// LB_CONNECTIONS, UDP_CONNECTIONS or SCTP_CONNECTIONS. Userspace recomputes them from the
+// connections left in the maps periodically.
+#[map(name = "RELEASED_CONNECTIONS")]
+static mut RELEASED_CONNECTIONS: HashMap<BackendKey, u64> =
+    HashMap::<BackendKey, u64>::with_max_entries(
+        BPF_MAPS_CAPACITY * BACKENDS_ARRAY_CAPACITY as u32,
+        0,
+    );
+
+// Once full, the least recently used connections are evicted to make room for new ones, instead of
+// failing to track (and thus dropping) new connections.
 #[map(name = "LB_CONNECTIONS")]
-static mut LB_CONNECTIONS: HashMap<ClientKey, LoadBalancerMapping> =
-    HashMap::<ClientKey, LoadBalancerMapping>::with_max_entries(128, 0);
+static mut LB_CONNECTIONS: LruHashMap<ClientKey, LoadBalancerMapping> =
+    LruHashMap::<ClientKey, LoadBalancerMapping>::pinned(LB_CONNECTIONS_CAPACITY, 0);
+
+// The source NATed connections, by the backend's side of the connection. Entries follow those of
+// LB_CONNECTIONS, which can be evicted without notice, so these are evicted the same way.
+#[map(name = "SNAT_CONNECTIONS")]
+static mut SNAT_CONNECTIONS: LruHashMap<SnatKey, ClientKey> =
+    LruHashMap::<SnatKey, ClientKey>::pinned(LB_CONNECTIONS_CAPACITY, 0);
+
+// The new connections each client address may still open, see Config.syn_rate. Clients which
+// haven't opened a connection for a while are evicted, and start with a full bucket again.
+#[map(name = "SYN_RATE_LIMITS")]
+static mut SYN_RATE_LIMITS: LruHashMap<[u32; 4], TokenBucket> =
+    LruHashMap::<[u32; 4], TokenBucket>::with_max_entries(LB_CONNECTIONS_CAPACITY, 0);
+
+// The live TCP connections of each client address, counted while Config.max_client_connections is
+// set. Userspace decrements the counts of the connections it removes from LB_CONNECTIONS.
+#[map(name = "CLIENT_CONNECTIONS")]
+static mut CLIENT_CONNECTIONS: LruHashMap<[u32; 4], u32> =
+    LruHashMap::<[u32; 4], u32>::with_max_entries(LB_CONNECTIONS_CAPACITY, 0);
+
+// Where each CPU is at in the range of source ports, so that they don't all compete for the same
+// ports.
+#[map(name = "SNAT_PORT_CURSORS")]
+static mut SNAT_PORT_CURSORS: PerCpuArray<u32> = PerCpuArray::<u32>::with_max_entries(1, 0);
+
+// The UDP flows, by the client's side of the flow. Sized and evicted like LB_CONNECTIONS, for new
+// flows not to be dropped once it is full.
+#[map(name = "UDP_CONNECTIONS")]
+static mut UDP_CONNECTIONS: LruHashMap<ClientKey, UdpLoadBalancerMapping> =
+    LruHashMap::<ClientKey, UdpLoadBalancerMapping>::with_max_entries(LB_CONNECTIONS_CAPACITY, 0);
+
+// The SCTP associations, tracked, sized and evicted like the UDP flows.
+#[map(name = "SCTP_CONNECTIONS")]
+static mut SCTP_CONNECTIONS: LruHashMap<ClientKey, UdpLoadBalancerMapping> =
+    LruHashMap::<ClientKey, UdpLoadBalancerMapping>::with_max_entries(LB_CONNECTIONS_CAPACITY, 0);
+
+// The backends the first fragments of fragmented packets were forwarded to, for the fragments that
+// follow to be forwarded there as well. Fragments are reassembled or given up on within seconds, so
+// the least recently used entries are simply evicted.
+#[map(name = "FRAGMENTS")]
+static mut FRAGMENTS: LruHashMap<FragmentKey, Backend> =
+    LruHashMap::<FragmentKey, Backend>::with_max_entries(LB_CONNECTIONS_CAPACITY, 0);
+
+// The stateless Gateways by the addresses and ports of their backends, which the replies of the
+// backends are translated back to, see forward_stateless.
+#[map(name = "STATELESS_TARGETS")]
+static mut STATELESS_TARGETS: HashMap<BackendKey, BackendKey> =
+    HashMap::<BackendKey, BackendKey>::with_max_entries(BPF_MAPS_CAPACITY, 0);
+
+// The nodes of the backends whose packets are encapsulated to their node, by the address the
+// backends are forwarded to.
+#[map(name = "TUNNELS")]
+static mut TUNNELS: HashMap<[u32; 4], Tunnel> = HashMap::<[u32; 4], Tunnel>::with_max_entries(
+    BPF_MAPS_CAPACITY * BACKENDS_ARRAY_CAPACITY as u32,
+    0,
+);
+
+// The sockets of the node-local connections through the Gateways whose payload is spliced, see
+// handle_sock_ops.
+#[map(name = "SOCK_PAIRS")]
+static mut SOCK_PAIRS: SockHash<SockKey> =
+    SockHash::<SockKey>::with_max_entries(BPF_MAPS_CAPACITY, 0);
+
+// The connections opened and closed by the programs, for userspace to log and export. Events are
+// dropped while the buffer is full.
+#[map(name = "CONNECTION_EVENTS")]
+static mut CONNECTION_EVENTS: RingBuf = RingBuf::with_byte_size(256 * 1024, 0);
+
+// The packets sampled by the ingress program, for userspace to log. Samples are dropped while the
+// buffer is full.
+#[map(name = "PACKET_SAMPLES")]
+static mut PACKET_SAMPLES: RingBuf = RingBuf::with_byte_size(256 * 1024, 0);
+
+// The Gateways whose packets are being captured, for userspace to read from CAPTURED_PACKETS. The
+// values are unused.
+#[map(name = "CAPTURES")]
+static mut CAPTURES: HashMap<BackendKey, u8> =
+    HashMap::<BackendKey, u8>::with_max_entries(BPF_MAPS_CAPACITY, 0);
+
+// The packets captured by the ingress program. Packets are dropped while the buffer is full.
+#[map(name = "CAPTURED_PACKETS")]
+static mut CAPTURED_PACKETS: RingBuf = RingBuf::with_byte_size(1024 * 1024, 0);
+
+// The packets the ingress program forwarded since it last sampled one, in the only entry.
+#[map(name = "SAMPLE_COUNTERS")]
+static mut SAMPLE_COUNTERS: PerCpuArray<u32> = PerCpuArray::<u32>::with_max_entries(1, 0);
 
 // -----------------------------------------------------------------------------
 // Ingress
@@ -55,23 +326,48 @@ static mut LB_CONNECTIONS: HashMap<ClientKey, LoadBalancerMapping> =
 #[classifier]
 pub fn tc_ingress(ctx: TcContext) -> i32 {
     match try_tc_ingress(ctx) {
+        // Packets that were deliberately dropped stay dropped.
+        Ok(TC_ACT_SHOT) => return TC_ACT_SHOT,
+        // Packets redirected to the next hop towards their backend skip the host.
+        Ok(TC_ACT_REDIRECT) => return TC_ACT_REDIRECT,
         Ok(ret) => ret,
         Err(_) => TC_ACT_SHOT,
     };
 
     // TODO(https://github.com/Kong/blixt/issues/69) better Error reporting framework
-    return TC_ACT_OK;
+    return pass_action();
 }
 
 // Make sure ip_forwarding is enabled on the interface this it attached to
 fn try_tc_ingress(ctx: TcContext) -> Result<i32, i64> {
-    let eth_hdr: *const EthHdr = unsafe { ptr_at(&ctx, 0) }?;
-    match unsafe { *eth_hdr }.ether_type {
-        EtherType::Ipv4 => {
-            let ipv4hdr: *const Ipv4Hdr = unsafe { ptr_at(&ctx, EthHdr::LEN)? };
+    let (ether_type, l3_offset) = parse_eth_hdr(&ctx)?;
+    match ether_type {
+        ETH_P_IP => {
+            let ipv4hdr: *mut Ipv4Hdr = unsafe { ptr_at(&ctx, l3_offset)? };
+            // Headers shorter than the fixed header are malformed.
+            if unsafe { (*ipv4hdr).ihl() } < 5 {
+                return Ok(TC_ACT_PIPE);
+            }
+            let ip_hdr = IpHdr::V4(ipv4hdr, l3_offset);
+            // Fragments past the first one have no L4 header, they follow the first one.
+            if is_later_fragment(ip_hdr) {
+                return handle_fragment_ingress(ctx, ip_hdr);
+            }
             match unsafe { *ipv4hdr }.proto {
-                IpProto::Tcp => handle_tcp_ingress(ctx),
-                IpProto::Udp => handle_udp_ingress(ctx),
+                IpProto::Tcp => handle_tcp_ingress(ctx, ip_hdr),
+                IpProto::Udp => handle_udp_ingress(ctx, ip_hdr),
+                IpProto::Sctp => handle_sctp_ingress(ctx, ip_hdr),
+                IpProto::Icmp => handle_icmp_ingress(ctx, ip_hdr),
+                _ => Ok(TC_ACT_PIPE),
+            }
+        }
+        ETH_P_IPV6 => {
+            let ipv6hdr: *mut Ipv6Hdr = unsafe { ptr_at(&ctx, l3_offset)? };
+            match unsafe { *ipv6hdr }.next_hdr {
+                IpProto::Tcp => handle_tcp_ingress(ctx, IpHdr::V6(ipv6hdr, l3_offset)),
+                IpProto::Udp => handle_udp_ingress(ctx, IpHdr::V6(ipv6hdr, l3_offset)),
+                IpProto::Sctp => handle_sctp_ingress(ctx, IpHdr::V6(ipv6hdr, l3_offset)),
+                IpProto::Ipv6Icmp => handle_icmp_ingress(ctx, IpHdr::V6(ipv6hdr, l3_offset)),
                 _ => Ok(TC_ACT_PIPE),
             }
         }
@@ -79,6 +375,38 @@ fn try_tc_ingress(ctx: TcContext) -> Result<i32, i64> {
     }
 }
 
+// -----------------------------------------------------------------------------
+// XDP
+// -----------------------------------------------------------------------------
+
+// Optional fast path in front of tc_ingress, for the drivers which support XDP.
+#[xdp]
+pub fn xdp_ingress(ctx: XdpContext) -> u32 {
+    // Anything the fast path can't handle goes on to tc_ingress.
+    match handle_xdp_ingress(&ctx) {
+        Ok(action) => action,
+        Err(_) => XDP_PASS,
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Sockmap
+// -----------------------------------------------------------------------------
+
+// Optional splicing of the node-local connections, attached to a cgroup of the node's sockets.
+#[sock_ops]
+pub fn sock_ops_splice(ctx: SockOpsContext) -> u32 {
+    // Sockets which can't be spliced keep going through the stack.
+    let _ = handle_sock_ops(&ctx);
+    0
+}
+
+#[sk_msg]
+pub fn sk_msg_splice(ctx: SkMsgContext) -> u32 {
+    let _ = handle_sk_msg(&ctx);
+    SK_PASS
+}
+
 // -----------------------------------------------------------------------------
 // Egress
 // -----------------------------------------------------------------------------
@@ -86,22 +414,43 @@ fn try_tc_ingress(ctx: TcContext) -> Result<i32, i64> {
 #[classifier]
 pub fn tc_egress(ctx: TcContext) -> i32 {
     match try_tc_egress(ctx) {
+        // Packets that were deliberately dropped stay dropped, such as those of the backends still
+        // answering a replayed SYN cookie handshake.
+        Ok(TC_ACT_SHOT) => return TC_ACT_SHOT,
+        // The ACKs answering the backends' SYN-ACKs of replayed handshakes are sent back to them.
+        Ok(TC_ACT_REDIRECT) => return TC_ACT_REDIRECT,
         Ok(ret) => ret,
         Err(_) => TC_ACT_SHOT,
     };
 
     // TODO(https://github.com/Kong/blixt/issues/69) better Error reporting framework
-    return TC_ACT_OK;
+    return pass_action();
 }
 
 fn try_tc_egress(ctx: TcContext) -> Result<i32, i64> {
-    let eth_hdr: *const EthHdr = unsafe { ptr_at(&ctx, 0) }?;
-    match unsafe { *eth_hdr }.ether_type {
-        EtherType::Ipv4 => {
-            let ipv4hdr: *const Ipv4Hdr = unsafe { ptr_at(&ctx, EthHdr::LEN)? };
+    let (ether_type, l3_offset) = parse_eth_hdr(&ctx)?;
+    match ether_type {
+        ETH_P_IP => {
+            let ipv4hdr: *mut Ipv4Hdr = unsafe { ptr_at(&ctx, l3_offset)? };
+            // Headers shorter than the fixed header are malformed.
+            if unsafe { (*ipv4hdr).ihl() } < 5 {
+                return Ok(TC_ACT_PIPE);
+            }
             match unsafe { *ipv4hdr }.proto {
-                IpProto::Icmp => handle_icmp_egress(ctx),
-                IpProto::Tcp => handle_tcp_egress(ctx),
+                IpProto::Icmp => handle_icmp_egress(ctx, IpHdr::V4(ipv4hdr, l3_offset)),
+                IpProto::Tcp => handle_tcp_egress(ctx, IpHdr::V4(ipv4hdr, l3_offset)),
+                IpProto::Udp => handle_udp_egress(ctx, IpHdr::V4(ipv4hdr, l3_offset)),
+                IpProto::Sctp => handle_sctp_egress(ctx, IpHdr::V4(ipv4hdr, l3_offset)),
+                _ => Ok(TC_ACT_PIPE),
+            }
+        }
+        ETH_P_IPV6 => {
+            let ipv6hdr: *mut Ipv6Hdr = unsafe { ptr_at(&ctx, l3_offset)? };
+            match unsafe { *ipv6hdr }.next_hdr {
+                IpProto::Ipv6Icmp => handle_icmp_egress(ctx, IpHdr::V6(ipv6hdr, l3_offset)),
+                IpProto::Tcp => handle_tcp_egress(ctx, IpHdr::V6(ipv6hdr, l3_offset)),
+                IpProto::Udp => handle_udp_egress(ctx, IpHdr::V6(ipv6hdr, l3_offset)),
+                IpProto::Sctp => handle_sctp_egress(ctx, IpHdr::V6(ipv6hdr, l3_offset)),
                 _ => Ok(TC_ACT_PIPE),
             }
         }
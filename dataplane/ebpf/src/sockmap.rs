@@ -0,0 +1,161 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use aya_ebpf::{
+    helpers::bpf_ktime_get_ns,
+    programs::{SkMsgContext, SockOpsContext},
+};
+
+use crate::{utils::ipv6_from_be, LB_CONNECTIONS, SOCK_PAIRS};
+use common::{ipv4_mapped, ClientKey, ForwardingMode, LoadBalancerMapping, SockKey};
+
+// Ref: https://elixir.bootlin.com/linux/v6.6/source/include/uapi/linux/bpf.h#L6648
+const BPF_SOCK_OPS_ACTIVE_ESTABLISHED_CB: u32 = 4;
+const BPF_SOCK_OPS_PASSIVE_ESTABLISHED_CB: u32 = 5;
+// Ref: https://elixir.bootlin.com/linux/v6.6/source/include/uapi/linux/bpf.h#L5916
+const BPF_F_INGRESS: u64 = 1;
+
+const AF_INET: u32 = 2;
+const AF_INET6: u32 = 10;
+
+// The connections through the Gateways whose client and backend are both sockets of this node
+// (host clients, or clients in the pods of other backends) have the payload their sockets send
+// moved straight to the socket at the other end, without going through the TCP/IP stack and thus
+// the TC programs. Only the payload is spliced: the handshake and the termination of the
+// connections still go through the stack, which keeps tracking their state. The connections whose
+// packets are rewritten beyond their addresses and ports (source NAT, PROXY protocol) or which
+// don't reach the backend through this node (DSR) aren't spliced.
+//
+// Adds the sockets of the spliced connections to SOCK_PAIRS once they are established.
+pub fn handle_sock_ops(ctx: &SockOpsContext) -> Result<(), i64> {
+    match ctx.op() {
+        BPF_SOCK_OPS_ACTIVE_ESTABLISHED_CB | BPF_SOCK_OPS_PASSIVE_ESTABLISHED_CB => {}
+        _ => return Ok(()),
+    }
+    let ops = unsafe { &*ctx.ops };
+    let mut key = match sock_key(
+        ops.family,
+        ops.local_ip4,
+        ops.local_ip6,
+        ops.local_port,
+        ops.remote_ip4,
+        ops.remote_ip6,
+        ops.remote_port,
+    ) {
+        Some(key) => key,
+        None => return Ok(()),
+    };
+    if find_peer(&key).is_none() {
+        return Ok(());
+    }
+    // The sockets leave SOCK_PAIRS on their own when they are closed.
+    unsafe { SOCK_PAIRS.update(&mut key, &mut *ctx.ops, 0) }
+}
+
+// Redirects the payload sent by a socket of SOCK_PAIRS to the socket at the other end of its
+// connection, as if the peer had received it. The payload goes through the stack as usual until
+// the peer is in SOCK_PAIRS, and once its connection is no longer tracked.
+pub fn handle_sk_msg(ctx: &SkMsgContext) -> Result<(), i64> {
+    let msg = unsafe { &*ctx.msg };
+    let key = sock_key(
+        msg.family,
+        msg.local_ip4,
+        msg.local_ip6,
+        msg.local_port,
+        msg.remote_ip4,
+        msg.remote_ip6,
+        msg.remote_port,
+    )
+    .ok_or(0)?;
+    let (lb_mapping, mut peer_key) = find_peer(&key).ok_or(0)?;
+    // The spliced payload doesn't refresh the connection in the TC programs, it does here so that
+    // the connection doesn't look idle. It isn't counted in BACKEND_TRAFFIC.
+    unsafe { (*lb_mapping).last_seen = bpf_ktime_get_ns() };
+    unsafe { SOCK_PAIRS.redirect_msg(ctx, &mut peer_key, BPF_F_INGRESS) };
+    Ok(())
+}
+
+// Returns the key of a socket from the fields of its bpf_sock_ops or sk_msg_md, whose addresses
+// are in network byte order, local port in host byte order, and remote port a 32 bits word in
+// network byte order.
+#[inline(always)]
+fn sock_key(
+    family: u32,
+    local_ip4: u32,
+    local_ip6: [u32; 4],
+    local_port: u32,
+    remote_ip4: u32,
+    remote_ip6: [u32; 4],
+    remote_port: u32,
+) -> Option<SockKey> {
+    let (local_ip, remote_ip) = match family {
+        AF_INET => (
+            ipv4_mapped(u32::from_be(local_ip4)),
+            ipv4_mapped(u32::from_be(remote_ip4)),
+        ),
+        AF_INET6 => (ipv6_from_be(local_ip6), ipv6_from_be(remote_ip6)),
+        _ => return None,
+    };
+    Some(SockKey {
+        local_ip,
+        remote_ip,
+        local_port,
+        remote_port: u32::from_be(remote_port),
+    })
+}
+
+// Returns the tracked connection which the socket is the client or the backend of if it can be
+// spliced, along with the key of the socket at the other end of it. The client's socket is
+// connected to the Gateway, and the backend's to the client.
+#[inline(always)]
+fn find_peer(key: &SockKey) -> Option<(*mut LoadBalancerMapping, SockKey)> {
+    let client_key = ClientKey {
+        ip: key.local_ip,
+        port: key.local_port,
+    };
+    if let Some(lb_mapping) = unsafe { LB_CONNECTIONS.get_ptr_mut(&client_key) } {
+        let mapping = unsafe { &*lb_mapping };
+        if is_spliceable(mapping)
+            && mapping.backend_key.ip == key.remote_ip
+            && mapping.gateway_port() as u32 == key.remote_port
+        {
+            let peer_key = SockKey {
+                local_ip: mapping.backend.daddr,
+                remote_ip: key.local_ip,
+                local_port: mapping.backend_port() as u32,
+                remote_port: key.local_port,
+            };
+            return Some((lb_mapping, peer_key));
+        }
+    }
+
+    let client_key = ClientKey {
+        ip: key.remote_ip,
+        port: key.remote_port,
+    };
+    let lb_mapping = unsafe { LB_CONNECTIONS.get_ptr_mut(&client_key) }?;
+    let mapping = unsafe { &*lb_mapping };
+    if is_spliceable(mapping)
+        && mapping.backend.daddr == key.local_ip
+        && mapping.backend_port() as u32 == key.local_port
+    {
+        let peer_key = SockKey {
+            local_ip: key.remote_ip,
+            remote_ip: mapping.backend_key.ip,
+            local_port: key.remote_port,
+            remote_port: mapping.gateway_port() as u32,
+        };
+        return Some((lb_mapping, peer_key));
+    }
+    None
+}
+
+#[inline(always)]
+fn is_spliceable(mapping: &LoadBalancerMapping) -> bool {
+    mapping.backend.forwarding == ForwardingMode::Nat
+        && mapping.snat_port == 0
+        && !mapping.backend.proxy_protocol
+}
@@ -4,17 +4,347 @@ Copyright 2023 The Kubernetes Authors.
 SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
 */
 
-use aya_ebpf::{bindings::TC_ACT_OK, programs::TcContext};
-use core::mem;
-use network_types::tcp::TcpHdr;
+use aya_ebpf::{
+    bindings::{
+        __sk_buff, BPF_ADJ_ROOM_NET, BPF_F_MARK_MANGLED_0, BPF_F_PSEUDO_HDR, BPF_NOEXIST,
+        TC_ACT_OK, TC_ACT_UNSPEC,
+    },
+    helpers::{bpf_csum_diff, bpf_ktime_get_ns, bpf_skb_change_tail, bpf_skb_store_bytes},
+    programs::TcContext,
+    EbpfContext,
+};
+use core::{
+    ffi::c_void,
+    mem, ptr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use memoffset::offset_of;
+use network_types::{
+    eth::EthHdr,
+    ip::{IpProto, Ipv4Hdr, Ipv6Hdr},
+    tcp::TcpHdr,
+};
 
-use crate::LB_CONNECTIONS;
-use common::{ClientKey, LoadBalancerMapping, TCPState};
+use crate::{
+    BACKEND_CONNECTIONS, BACKEND_FAILURES, BACKEND_TRAFFIC, CLIENT_CONNECTIONS, CONFIG,
+    CONNECTION_EVENTS, FLOW_TABLE_STATS, LB_CONNECTIONS, REDIRECT_ERRORS, SNAT_CONNECTIONS,
+    SYN_LATENCIES, TCP_DROPS, TCP_PASSES, TCP_TIMEOUTS,
+};
+use common::{
+    ipv4_mapped, Backend, BackendConnections, BackendFailures, BackendKey, BackendTraffic,
+    ClientKey, CloseReason, Config, ConnectionEvent, ConnectionEventKind, DropReason, FlowStats,
+    FlowTableStats, LoadBalancerMapping, PassReason, SynLatency, TCPSide, TCPState,
+};
+
+// -----------------------------------------------------------------------------
+// Ethernet Headers
+// -----------------------------------------------------------------------------
+
+pub const ETH_P_IP: u16 = 0x0800;
+pub const ETH_P_IPV6: u16 = 0x86DD;
+const ETH_P_8021Q: u16 = 0x8100;
+const ETH_P_8021AD: u16 = 0x88A8;
+
+// An 802.1Q VLAN tag, which sits between the MAC addresses and the EtherType of the frame.
+#[repr(C)]
+struct VlanHdr {
+    tci: u16,
+    ether_type: u16,
+}
+
+// Returns the EtherType of the frame, and the offset of the L3 header that follows. A single VLAN
+// tag (802.1Q or 802.1ad) is skipped, so that tagged traffic is parsed like untagged traffic.
+#[inline(always)]
+pub fn parse_eth_hdr(ctx: &TcContext) -> Result<(u16, usize), i64> {
+    let ether_type: *const u16 = unsafe { ptr_at(ctx, offset_of!(EthHdr, ether_type))? };
+    match u16::from_be(unsafe { *ether_type }) {
+        ETH_P_8021Q | ETH_P_8021AD => {
+            let vlan_hdr: *const VlanHdr = unsafe { ptr_at(ctx, EthHdr::LEN)? };
+            Ok((
+                u16::from_be(unsafe { (*vlan_hdr).ether_type }),
+                EthHdr::LEN + mem::size_of::<VlanHdr>(),
+            ))
+        }
+        ether_type => Ok((ether_type, EthHdr::LEN)),
+    }
+}
+
+// -----------------------------------------------------------------------------
+// IP Headers
+// -----------------------------------------------------------------------------
+
+// The IHL of the IPv4 header is 4 bits long, counting 32-bit words, 5 of which are the fixed
+// header.
+pub const IPV4_MAX_OPTIONS_LEN: usize = 40;
+
+// The ECN field, which the lower 2 bits of the TOS (or traffic class) are.
+// Ref: https://www.rfc-editor.org/rfc/rfc3168#section-5
+pub const ECN_MASK: u8 = 0x03;
+
+// IpHdr is the IP header of the packet being processed, so that the L4 handlers can work with both
+// IPv4 and IPv6 packets. Addresses are read and written in the format of the maps shared with
+// userspace, see common::ipv4_mapped. Each header comes with its offset in the packet, which
+// depends on the L2 header in front of it.
+#[derive(Copy, Clone)]
+pub enum IpHdr {
+    V4(*mut Ipv4Hdr, usize),
+    V6(*mut Ipv6Hdr, usize),
+}
+
+impl IpHdr {
+    // Returns the IP header again, for when the packet pointers were invalidated (e.g. by resizing
+    // the packet).
+    #[inline(always)]
+    pub fn reload(&self, ctx: &TcContext) -> Result<IpHdr, i64> {
+        match self {
+            IpHdr::V4(_, offset) => Ok(IpHdr::V4(unsafe { ptr_at(ctx, *offset)? }, *offset)),
+            IpHdr::V6(_, offset) => Ok(IpHdr::V6(unsafe { ptr_at(ctx, *offset)? }, *offset)),
+        }
+    }
+
+    // Returns the length of this IP header, including the IPv4 options.
+    #[inline(always)]
+    pub fn header_len(&self) -> usize {
+        match *self {
+            // Masked so that the verifier knows the length is bounded.
+            IpHdr::V4(hdr, _) => (unsafe { (*hdr).ihl() } as usize & 0xF) * 4,
+            IpHdr::V6(..) => Ipv6Hdr::LEN,
+        }
+    }
+
+    // Returns the length of the L4 header and payload that follow this IP header, according to the
+    // IP header.
+    #[inline(always)]
+    pub fn l4_len(&self) -> u16 {
+        match *self {
+            IpHdr::V4(hdr, _) => {
+                u16::from_be(unsafe { (*hdr).tot_len }).saturating_sub(self.header_len() as u16)
+            }
+            IpHdr::V6(hdr, _) => u16::from_be(unsafe { (*hdr).payload_len }),
+        }
+    }
+
+    // Rewrites the length of the L4 header and payload that follow this IP header.
+    #[inline(always)]
+    pub fn set_l4_len(&self, len: u16) {
+        match *self {
+            IpHdr::V4(hdr, _) => unsafe {
+                (*hdr).tot_len = (self.header_len() as u16 + len).to_be()
+            },
+            IpHdr::V6(hdr, _) => unsafe { (*hdr).payload_len = len.to_be() },
+        }
+    }
+
+    // Returns the TOS, or the traffic class for IPv6, whose upper 6 bits are the DSCP and lower 2
+    // bits the ECN field.
+    #[inline(always)]
+    pub fn tos(&self) -> u8 {
+        match *self {
+            IpHdr::V4(hdr, _) => unsafe { (*hdr).tos },
+            IpHdr::V6(hdr, _) => unsafe { (*hdr).priority() << 4 | (*hdr).flow_label[0] >> 4 },
+        }
+    }
+
+    // Rewrites the TOS, or the traffic class for IPv6, which straddles the first bytes of the
+    // header with the flow label.
+    #[inline(always)]
+    pub fn set_tos(&self, tos: u8) {
+        match *self {
+            IpHdr::V4(hdr, _) => unsafe { (*hdr).tos = tos },
+            IpHdr::V6(hdr, _) => unsafe {
+                (*hdr).set_priority(tos >> 4);
+                (*hdr).flow_label[0] = tos << 4 | (*hdr).flow_label[0] & 0x0F;
+            },
+        }
+    }
+
+    // Marks the packet as not ECN-capable, keeping its DSCP. The packets the Gateway answers with
+    // are built from the client's, whose ECN field belongs to the client's own transport.
+    #[inline(always)]
+    pub fn clear_ecn(&self) {
+        self.set_tos(self.tos() & !ECN_MASK);
+    }
+
+    // Returns the TTL, or the hop limit for IPv6.
+    #[inline(always)]
+    pub fn ttl(&self) -> u8 {
+        match *self {
+            IpHdr::V4(hdr, _) => unsafe { (*hdr).ttl },
+            IpHdr::V6(hdr, _) => unsafe { (*hdr).hop_limit },
+        }
+    }
+
+    // Rewrites the TTL, or the hop limit for IPv6.
+    #[inline(always)]
+    pub fn set_ttl(&self, ttl: u8) {
+        match *self {
+            IpHdr::V4(hdr, _) => unsafe { (*hdr).ttl = ttl },
+            IpHdr::V6(hdr, _) => unsafe { (*hdr).hop_limit = ttl },
+        }
+    }
+
+    // Returns the unfolded checksum of the L4 pseudo-header for `len` bytes of the `proto`
+    // protocol, to be used as the seed of the L4 checksum computation.
+    #[inline(always)]
+    pub fn pseudo_hdr_csum(&self, proto: u8, len: u16) -> u64 {
+        // The IPv4 and IPv6 pseudo-headers sum up to the same value once the IPv4-mapped prefix
+        // is left out, so they can share this layout.
+        let mut pseudo_hdr = PseudoHdr {
+            src_addr: ipv6_to_be(&self.src_addr()),
+            dst_addr: ipv6_to_be(&self.dst_addr()),
+            len: (len as u32).to_be(),
+            proto: (proto as u32).to_be(),
+        };
+        if let IpHdr::V4(..) = self {
+            pseudo_hdr.src_addr[2] = 0;
+            pseudo_hdr.dst_addr[2] = 0;
+        }
+        let csum = unsafe {
+            bpf_csum_diff(
+                mem::MaybeUninit::zeroed().assume_init(),
+                0,
+                &mut pseudo_hdr as *mut PseudoHdr as *mut u32,
+                mem::size_of::<PseudoHdr>() as u32,
+                0,
+            )
+        };
+        csum as u64
+    }
+
+    // Returns the offset of this IP header.
+    #[inline(always)]
+    pub fn l3_offset(&self) -> usize {
+        match self {
+            IpHdr::V4(_, offset) | IpHdr::V6(_, offset) => *offset,
+        }
+    }
+
+    // Returns the offset of the L4 header that follows this IP header.
+    #[inline(always)]
+    pub fn l4_offset(&self) -> usize {
+        self.l3_offset() + self.header_len()
+    }
+
+    #[inline(always)]
+    pub fn src_addr(&self) -> [u32; 4] {
+        match *self {
+            IpHdr::V4(hdr, _) => ipv4_mapped(u32::from_be(unsafe { (*hdr).src_addr })),
+            IpHdr::V6(hdr, _) => ipv6_from_be(unsafe { (*hdr).src_addr.in6_u.u6_addr32 }),
+        }
+    }
+
+    #[inline(always)]
+    pub fn dst_addr(&self) -> [u32; 4] {
+        match *self {
+            IpHdr::V4(hdr, _) => ipv4_mapped(u32::from_be(unsafe { (*hdr).dst_addr })),
+            IpHdr::V6(hdr, _) => ipv6_from_be(unsafe { (*hdr).dst_addr.in6_u.u6_addr32 }),
+        }
+    }
+
+    // Rewrites the source address. For IPv4 packets the address must be IPv4-mapped, which is
+    // enforced by userspace when programming the maps.
+    #[inline(always)]
+    pub fn set_src_addr(&self, addr: &[u32; 4]) {
+        match *self {
+            IpHdr::V4(hdr, _) => unsafe { (*hdr).src_addr = addr[3].to_be() },
+            IpHdr::V6(hdr, _) => unsafe { (*hdr).src_addr.in6_u.u6_addr32 = ipv6_to_be(addr) },
+        }
+    }
+
+    // Rewrites the destination address, see set_src_addr.
+    #[inline(always)]
+    pub fn set_dst_addr(&self, addr: &[u32; 4]) {
+        match *self {
+            IpHdr::V4(hdr, _) => unsafe { (*hdr).dst_addr = addr[3].to_be() },
+            IpHdr::V6(hdr, _) => unsafe { (*hdr).dst_addr.in6_u.u6_addr32 = ipv6_to_be(addr) },
+        }
+    }
+
+    // Recalculates the l3 cksum after the header was modified. IPv6 headers don't have one.
+    // TODO(astoycos) use l3_cksum_replace instead
+    #[inline(always)]
+    pub fn update_csum(&self, ctx: &TcContext) -> Result<(), i64> {
+        if let IpHdr::V4(hdr, offset) = *self {
+            unsafe { (*hdr).check = 0 };
+            let mut full_cksum = unsafe {
+                bpf_csum_diff(
+                    mem::MaybeUninit::zeroed().assume_init(),
+                    0,
+                    hdr as *mut u32,
+                    Ipv4Hdr::LEN as u32,
+                    0,
+                )
+            } as u64;
+            // The options are covered by the checksum too, a word at a time.
+            let options_words = (self.header_len().saturating_sub(Ipv4Hdr::LEN)) / 4;
+            for i in 0..IPV4_MAX_OPTIONS_LEN / 4 {
+                if i >= options_words {
+                    break;
+                }
+                let word: *const u32 = unsafe { ptr_at(ctx, offset + Ipv4Hdr::LEN + i * 4)? };
+                full_cksum += unsafe { *word } as u64;
+            }
+            unsafe { (*hdr).check = csum_fold_helper(full_cksum) };
+        }
+        Ok(())
+    }
+}
+
+#[repr(C)]
+struct PseudoHdr {
+    src_addr: [u32; 4],
+    dst_addr: [u32; 4],
+    len: u32,
+    proto: u32,
+}
+
+// Returns the address in network byte order, which aya-log formats as an IPv6 address with `{:i}`
+// (IPv4-mapped addresses are displayed as ::ffff:a.b.c.d).
+#[inline(always)]
+pub fn ip_octets(addr: &[u32; 4]) -> [u8; 16] {
+    unsafe { mem::transmute(ipv6_to_be(addr)) }
+}
+
+#[inline(always)]
+pub fn ipv6_from_be(addr: [u32; 4]) -> [u32; 4] {
+    [
+        u32::from_be(addr[0]),
+        u32::from_be(addr[1]),
+        u32::from_be(addr[2]),
+        u32::from_be(addr[3]),
+    ]
+}
+
+#[inline(always)]
+fn ipv6_to_be(addr: &[u32; 4]) -> [u32; 4] {
+    [
+        addr[0].to_be(),
+        addr[1].to_be(),
+        addr[2].to_be(),
+        addr[3].to_be(),
+    ]
+}
 
 // -----------------------------------------------------------------------------
 // Helper Functions
 // -----------------------------------------------------------------------------
 
+// Returns the settings userspace configured, or the defaults if it hasn't yet.
+#[inline(always)]
+pub fn config() -> Config {
+    unsafe { CONFIG.get(0) }.copied().unwrap_or_default()
+}
+
+// Returns the action of the packets the programs let through, which are either accepted, or handed
+// to the filters after them on the hook when chaining with another datapath.
+#[inline(always)]
+pub fn pass_action() -> i32 {
+    match config().tc_chain {
+        true => TC_ACT_UNSPEC,
+        false => TC_ACT_OK,
+    }
+}
+
 // Gives us raw pointers to a specific offset in the packet
 #[inline(always)]
 pub unsafe fn ptr_at<T>(ctx: &TcContext, offset: usize) -> Result<*mut T, i64> {
@@ -28,6 +358,14 @@ pub unsafe fn ptr_at<T>(ctx: &TcContext, offset: usize) -> Result<*mut T, i64> {
     Ok((start + offset) as *mut T)
 }
 
+// Returns the identifier of an ICMP or ICMPv6 echo request or reply, which follows the type, code
+// and checksum of its header.
+#[inline(always)]
+pub fn echo_id(ctx: &TcContext, icmp_header_offset: usize) -> Result<u16, i64> {
+    let id: *const u16 = unsafe { ptr_at(ctx, icmp_header_offset + 4)? };
+    Ok(u16::from_be(unsafe { *id }))
+}
+
 // Converts a checksum into u16
 #[inline(always)]
 pub fn csum_fold_helper(mut csum: u64) -> u16 {
@@ -39,82 +377,561 @@ pub fn csum_fold_helper(mut csum: u64) -> u16 {
     return !(csum as u16);
 }
 
-// Updates the TCP connection's state based on the current phase and the incoming packet's header.
-// It returns true if the state transitioned to a different phase.
+// Incrementally updates the L4 checksum located at `csum_offset` after an address of the IP header,
+// which is part of the L4 pseudo-header, was rewritten from `from` to `to`. Both addresses are
+// expected in the format of IpHdr::src_addr, only the words that changed are replaced so this
+// works for IPv4 and IPv6 alike.
+#[inline(always)]
+pub fn l4_csum_replace_addr(
+    ctx: &TcContext,
+    csum_offset: usize,
+    from: &[u32; 4],
+    to: &[u32; 4],
+) -> Result<(), i64> {
+    csum_replace_addr(ctx, csum_offset, from, to, 0)
+}
+
+// Like l4_csum_replace_addr, for the checksum of a UDP header. A UDP checksum of 0 means that the
+// sender didn't compute one, which is left as is.
+#[inline(always)]
+pub fn udp_csum_replace_addr(
+    ctx: &TcContext,
+    csum_offset: usize,
+    from: &[u32; 4],
+    to: &[u32; 4],
+) -> Result<(), i64> {
+    csum_replace_addr(ctx, csum_offset, from, to, BPF_F_MARK_MANGLED_0)
+}
+
+#[inline(always)]
+fn csum_replace_addr(
+    ctx: &TcContext,
+    csum_offset: usize,
+    from: &[u32; 4],
+    to: &[u32; 4],
+    flags: u32,
+) -> Result<(), i64> {
+    for i in 0..4 {
+        if from[i] != to[i] {
+            ctx.l4_csum_replace(
+                csum_offset,
+                from[i].to_be() as u64,
+                to[i].to_be() as u64,
+                (BPF_F_PSEUDO_HDR | flags | 4) as u64,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// Incrementally updates the L4 checksum located at `csum_offset` after a 2-byte field of the L4
+// header (i.e. a port) was rewritten from `from` to `to`. Both values are expected in network
+// byte order.
+#[inline(always)]
+pub fn l4_csum_replace_port(
+    ctx: &TcContext,
+    csum_offset: usize,
+    from: u16,
+    to: u16,
+) -> Result<(), i64> {
+    ctx.l4_csum_replace(csum_offset, from as u64, to as u64, 2)
+}
+
+// Like l4_csum_replace_port, for the checksum of a UDP header. A UDP checksum of 0 means that the
+// sender didn't compute one, which is left as is.
+#[inline(always)]
+pub fn udp_csum_replace_port(
+    ctx: &TcContext,
+    csum_offset: usize,
+    from: u16,
+    to: u16,
+) -> Result<(), i64> {
+    ctx.l4_csum_replace(
+        csum_offset,
+        from as u64,
+        to as u64,
+        (BPF_F_MARK_MANGLED_0 | 2) as u64,
+    )
+}
+
+// -----------------------------------------------------------------------------
+// SCTP Headers
+// -----------------------------------------------------------------------------
+
+// The common header of SCTP packets, which the chunks follow.
+// Ref: https://www.rfc-editor.org/rfc/rfc9260#section-3.1
+#[repr(C)]
+pub struct SctpHdr {
+    pub source: u16,
+    pub dest: u16,
+    pub vtag: u32,
+    // The CRC32c of the whole SCTP packet, in little-endian byte order. The IP addresses aren't
+    // covered by it.
+    pub check: u32,
+}
+
+// The CRC32c polynomial, bit-reflected like the checksum.
+const CRC32C_POLY: u32 = 0x82F6_3B78;
+
+// Returns the raw CRC32c (without the initial value and final inversion) after `byte`.
+#[inline(always)]
+fn crc32c_update(mut crc: u32, byte: u8) -> u32 {
+    crc ^= byte as u32;
+    for _ in 0..8 {
+        crc = match crc & 1 {
+            1 => (crc >> 1) ^ CRC32C_POLY,
+            _ => crc >> 1,
+        };
+    }
+    crc
+}
+
+// Returns a * b modulo the CRC32c polynomial, both bit-reflected.
+#[inline(always)]
+fn crc32c_mult(a: u32, mut b: u32) -> u32 {
+    let mut product = 0;
+    for bit in 0..32 {
+        if a & (1 << (31 - bit)) != 0 {
+            product ^= b;
+        }
+        b = match b & 1 {
+            1 => (b >> 1) ^ CRC32C_POLY,
+            _ => b >> 1,
+        };
+    }
+    product
+}
+
+// Returns the raw CRC32c after `len` more zero bytes, i.e. crc * x^(8 * len) modulo the polynomial,
+// computed by squaring rather than a byte at a time.
+#[inline(always)]
+fn crc32c_shift(crc: u32, mut len: u16) -> u32 {
+    // x^0 and x^8, bit-reflected.
+    let mut shift = 1 << 31;
+    let mut power = 1 << 23;
+    for _ in 0..16 {
+        if len & 1 != 0 {
+            shift = crc32c_mult(power, shift);
+        }
+        power = crc32c_mult(power, power);
+        len >>= 1;
+    }
+    crc32c_mult(shift, crc)
+}
+
+// Incrementally updates the checksum of the SCTP packet of `len` bytes whose header is at
+// `sctp_header_offset`, after the port at `port_offset` in the header was rewritten from `from` to
+// `to`, both in network byte order. The CRC is linear, so the checksums of two packets of the same
+// length differ by the raw CRC of their difference, which is all zeroes past the port. Packets
+// whose checksum is left to the NIC can't be told apart and get a bogus checksum, so the offload
+// of SCTP checksums has to be disabled on the interfaces of the backends.
+#[inline(always)]
+pub fn sctp_csum_replace_port(
+    ctx: &TcContext,
+    sctp_header_offset: usize,
+    port_offset: usize,
+    len: u16,
+    from: u16,
+    to: u16,
+) -> Result<(), i64> {
+    let [high, low] = (from ^ to).to_ne_bytes();
+    let delta = crc32c_update(crc32c_update(0, high), low);
+    let delta = crc32c_shift(delta, len.saturating_sub(port_offset as u16 + 2));
+
+    let check: *mut u32 = unsafe { ptr_at(ctx, sctp_header_offset + offset_of!(SctpHdr, check))? };
+    unsafe { *check = (u32::from_le(*check) ^ delta).to_le() };
+    Ok(())
+}
+
+// The data offset of the TCP header is 4 bits long, counting 32-bit words.
+pub const TCP_MAX_HEADER_LEN: usize = 60;
+
+// Inserts `data` between the TCP header and the payload of the packet, and returns its length.
+// The checksums and the IP header's length are updated, the TCP header is left as is. There is no
+// helper to grow a packet after its L4 header, so room is made after the fixed IP header and the
+// IPv4 options and TCP header are moved to the front of it.
+#[inline(always)]
+pub fn insert_after_tcp_header<T>(ctx: &TcContext, ip_hdr: IpHdr, data: &T) -> Result<u16, i64> {
+    let len = mem::size_of::<T>();
+    let tcp_header_offset = ip_hdr.l4_offset();
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(ctx, tcp_header_offset)? };
+    let tcp_header_len = unsafe { (*tcp_hdr).doff() } as usize * 4;
+    if !(TcpHdr::LEN..=TCP_MAX_HEADER_LEN).contains(&tcp_header_len) {
+        return Err(TC_ACT_OK.into());
+    }
+    let l4_len = ip_hdr.l4_len();
+
+    // The room is made after the fixed IP header, whatever its options.
+    let moved_offset = match ip_hdr {
+        IpHdr::V4(_, offset) => offset + Ipv4Hdr::LEN,
+        IpHdr::V6(_, offset) => offset + Ipv6Hdr::LEN,
+    };
+    let moved_len = tcp_header_offset + tcp_header_len - moved_offset;
+    let mut moved = [0_u8; IPV4_MAX_OPTIONS_LEN + TCP_MAX_HEADER_LEN];
+    let moved = moved.get_mut(..moved_len).ok_or(TC_ACT_OK)?;
+    ctx.load_bytes(moved_offset, moved)?;
+
+    ctx.adjust_room(len as i32, BPF_ADJ_ROOM_NET, 0)?;
+
+    let ret = unsafe {
+        bpf_skb_store_bytes(
+            ctx.as_ptr() as *mut __sk_buff,
+            moved_offset as u32,
+            moved.as_ptr() as *const c_void,
+            moved_len as u32,
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(ret);
+    }
+    ctx.store(tcp_header_offset + tcp_header_len, data, 0)?;
+
+    // Resizing the packet invalidated our packet pointers, so grab the IP header again.
+    let ip_hdr = ip_hdr.reload(ctx)?;
+    ip_hdr.set_l4_len(l4_len + len as u16);
+    ip_hdr.update_csum(ctx)?;
+
+    // The length of the segment is part of the pseudo-header.
+    let tcp_check_offset = tcp_header_offset + offset_of!(TcpHdr, check);
+    ctx.l4_csum_replace(
+        tcp_check_offset,
+        l4_len.to_be() as u64,
+        (l4_len + len as u16).to_be() as u64,
+        (BPF_F_PSEUDO_HDR | 2) as u64,
+    )?;
+    // The TCP header length is a multiple of 4, so the payload keeps its 16-bit alignment and only
+    // the sum of the inserted data has to be added.
+    let data_csum = unsafe {
+        bpf_csum_diff(
+            ptr::null_mut(),
+            0,
+            data as *const T as *mut u32,
+            len as u32,
+            0,
+        )
+    };
+    if data_csum < 0 {
+        return Err(data_csum);
+    }
+    ctx.l4_csum_replace(tcp_check_offset, 0, data_csum as u64, 0)?;
+
+    Ok(len as u16)
+}
+
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+const TCP_OPTION_MSS_LEN: u8 = 4;
+
+// Returns the offset of the value of the MSS option of the TCP header at `tcp_header_offset`, if
+// it has one.
+#[inline(always)]
+fn mss_option_offset(ctx: &TcContext, tcp_header_offset: usize) -> Option<usize> {
+    let tcp_hdr: *const TcpHdr = unsafe { ptr_at(ctx, tcp_header_offset) }.ok()?;
+    let options_end = tcp_header_offset + unsafe { (*tcp_hdr).doff() } as usize * 4;
+    let mut offset = tcp_header_offset + TcpHdr::LEN;
+    // Options are at least one byte long, so there are no more of them than bytes of options.
+    for _ in 0..TCP_MAX_HEADER_LEN - TcpHdr::LEN {
+        if offset >= options_end {
+            return None;
+        }
+        let kind: *const u8 = unsafe { ptr_at(ctx, offset) }.ok()?;
+        match unsafe { *kind } {
+            TCP_OPTION_END => return None,
+            TCP_OPTION_NOP => {
+                offset += 1;
+                continue;
+            }
+            _ => {}
+        }
+        let len: *const u8 = unsafe { ptr_at(ctx, offset + 1) }.ok()?;
+        let len = unsafe { *len };
+        if unsafe { *kind } == TCP_OPTION_MSS {
+            if len != TCP_OPTION_MSS_LEN || offset + len as usize > options_end {
+                return None;
+            }
+            return Some(offset + 2);
+        }
+        if len < 2 {
+            return None;
+        }
+        offset += len as usize;
+    }
+    None
+}
+
+// Returns the MSS option of the TCP header at `tcp_header_offset`, if it has one.
+#[inline(always)]
+pub fn read_mss(ctx: &TcContext, tcp_header_offset: usize) -> Option<u16> {
+    let mss_offset = mss_option_offset(ctx, tcp_header_offset)?;
+    let mss: *const [u8; 2] = unsafe { ptr_at(ctx, mss_offset) }.ok()?;
+    Some(u16::from_be_bytes(unsafe { *mss }))
+}
+
+// Rewrites the MSS option of a SYN with what `update` returns for it. Other packets, and SYNs
+// without an MSS option, are left as is.
+#[inline(always)]
+pub fn update_mss(
+    ctx: &TcContext,
+    tcp_header_offset: usize,
+    update: impl Fn(u16) -> u16,
+) -> Result<(), i64> {
+    let tcp_hdr: *const TcpHdr = unsafe { ptr_at(ctx, tcp_header_offset)? };
+    if unsafe { (*tcp_hdr).syn() } == 0 {
+        return Ok(());
+    }
+    let mss_offset = match mss_option_offset(ctx, tcp_header_offset) {
+        Some(mss_offset) => mss_offset,
+        None => return Ok(()),
+    };
+
+    let mss: *mut [u8; 2] = unsafe { ptr_at(ctx, mss_offset)? };
+    let original_mss = u16::from_be_bytes(unsafe { *mss });
+    let updated_mss = update(original_mss);
+    if updated_mss == original_mss {
+        return Ok(());
+    }
+    unsafe { *mss = updated_mss.to_be_bytes() };
+
+    // The checksum sums up 16-bit words, options may leave the MSS straddling two of them in which
+    // case its bytes count the other way around.
+    let (from, to) = if (mss_offset - tcp_header_offset) % 2 == 0 {
+        (original_mss.to_be(), updated_mss.to_be())
+    } else {
+        (
+            original_mss.to_be().swap_bytes(),
+            updated_mss.to_be().swap_bytes(),
+        )
+    };
+    ctx.l4_csum_replace(
+        tcp_header_offset + offset_of!(TcpHdr, check),
+        from as u64,
+        to as u64,
+        2,
+    )
+}
+
+// Lowers the MSS option of a SYN to `max_mss` if it is greater, so that the segments of the other
+// side of the connection fit a path with a smaller MTU than the endpoints assume.
+#[inline(always)]
+pub fn clamp_mss(ctx: &TcContext, tcp_header_offset: usize, max_mss: u16) -> Result<(), i64> {
+    if max_mss == 0 {
+        return Ok(());
+    }
+    update_mss(ctx, tcp_header_offset, |mss| mss.min(max_mss))
+}
+
+// Returns the MSS that TCP packets with this IP header get clamped to, or 0 if they aren't.
+#[inline(always)]
+pub fn max_mss(ip_hdr: IpHdr) -> u16 {
+    let config = config();
+    match ip_hdr {
+        IpHdr::V4(..) => config.max_mss_ipv4,
+        IpHdr::V6(..) => config.max_mss_ipv6,
+    }
+}
+
+// The length of a TCP header carrying an MSS option and no other.
+const TCP_MSS_HEADER_LEN: usize = TcpHdr::LEN + TCP_OPTION_MSS_LEN as usize;
+
+// Cuts the TCP segment down to its fixed header followed by an MSS option of `mss`, if any,
+// dropping the other options and the payload. The data offset and the IP header are updated to
+// match, and the TCP checksum is computed from scratch.
+#[inline(always)]
+pub fn truncate_tcp_segment(ctx: &TcContext, ip_hdr: IpHdr, mss: Option<u16>) -> Result<(), i64> {
+    let tcp_header_offset = ip_hdr.l4_offset();
+    let tcp_len = match mss {
+        Some(_) => TCP_MSS_HEADER_LEN,
+        None => TcpHdr::LEN,
+    };
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(ctx, tcp_header_offset)? };
+    unsafe { (*tcp_hdr).set_doff((tcp_len / 4) as u16) };
+    ip_hdr.set_l4_len(tcp_len as u16);
+    ip_hdr.update_csum(ctx)?;
+
+    let ret = unsafe {
+        bpf_skb_change_tail(
+            ctx.as_ptr() as *mut __sk_buff,
+            (tcp_header_offset + tcp_len) as u32,
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(ret);
+    }
+    if let Some(mss) = mss {
+        let [high, low] = mss.to_be_bytes();
+        ctx.store(
+            tcp_header_offset + TcpHdr::LEN,
+            &[TCP_OPTION_MSS, TCP_OPTION_MSS_LEN, high, low],
+            0,
+        )?;
+    }
+
+    // Resizing the packet invalidated our packet pointers, so grab the headers again.
+    let ip_hdr = ip_hdr.reload(ctx)?;
+    let tcp_hdr: *mut TcpHdr = unsafe { ptr_at(ctx, tcp_header_offset)? };
+    unsafe { (*tcp_hdr).check = 0 };
+    let pseudo_hdr_csum = ip_hdr.pseudo_hdr_csum(IpProto::Tcp as u8, tcp_len as u16) as u32;
+    let full_cksum = match mss {
+        Some(_) => {
+            let segment: *mut [u8; TCP_MSS_HEADER_LEN] = unsafe { ptr_at(ctx, tcp_header_offset)? };
+            unsafe {
+                bpf_csum_diff(
+                    ptr::null_mut(),
+                    0,
+                    segment as *mut u32,
+                    TCP_MSS_HEADER_LEN as u32,
+                    pseudo_hdr_csum,
+                )
+            }
+        }
+        None => unsafe {
+            bpf_csum_diff(
+                ptr::null_mut(),
+                0,
+                tcp_hdr as *mut u32,
+                TcpHdr::LEN as u32,
+                pseudo_hdr_csum,
+            )
+        },
+    };
+    if full_cksum < 0 {
+        return Err(full_cksum);
+    }
+    unsafe { (*tcp_hdr).check = csum_fold_helper(full_cksum as u64) };
+    Ok(())
+}
+
+// Returns whether the TCP sequence number `a` comes after `b`, taking the wrap around of the
+// sequence space into account.
+// Ref: https://www.rfc-editor.org/rfc/rfc1982
+#[inline(always)]
+pub fn seq_after(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+// Updates the TCP connection's state based on the current phase and the header of a packet sent by
+// `sender`. Both directions of the connection are tracked so that the termination is only
+// considered complete once each side's FIN has been acknowledged by the other side.
+// It returns true if the state transitioned to a different phase. Only the FIN and ACK bits
+// matter, the ECE and CWR bits of ECN-enabled connections are left to their endpoints.
 // Ref: https://en.wikipedia.org/wiki/File:Tcp_state_diagram.png and
 // http://www.tcpipguide.com/free/t_TCPConnectionTermination-2.htm
 #[inline(always)]
-pub fn process_tcp_state_transition(hdr: &TcpHdr, state: &mut TCPState) -> bool {
+pub fn process_tcp_state_transition(
+    hdr: &TcpHdr,
+    sender: TCPSide,
+    state: &mut TCPState,
+    closer: &mut TCPSide,
+) -> bool {
     let fin = hdr.fin() == 1;
     let ack = hdr.ack() == 1;
+    let from_closer = sender == *closer;
     match state {
+        TCPState::SynSent => {
+            // The backend's SYN-ACK, or the client's ACK of it when the backend's side doesn't go
+            // through here, is the first packet with the ACK bit set.
+            if ack {
+                *state = TCPState::Established;
+                return true;
+            }
+        }
         TCPState::Established => {
-            // At the Established state, a FIN packet moves the state to FinWait1.
+            // At the Established state, a FIN packet moves the state to FinWait1, its sender being
+            // the one closing the connection.
             if fin {
                 *state = TCPState::FinWait1;
+                *closer = sender;
                 return true;
             }
         }
         TCPState::FinWait1 => {
-            // At the FinWait1 state, a packet with both the FIN and ACK bits set
-            // moves the state to TimeWait.
+            // Only the peer of the closer can move the termination forward from here.
+            if from_closer {
+                return false;
+            }
+            // At the FinWait1 state, a packet from the peer with both the FIN and ACK bits set
+            // acknowledges the closer's FIN and closes the other direction, which moves the state
+            // to LastAck.
             if fin && ack {
-                *state = TCPState::TimeWait;
+                *state = TCPState::LastAck;
                 return true;
             }
-            // At the FinWait1 state, a FIN packet moves the state to Closing.
+            // At the FinWait1 state, a FIN packet from the peer moves the state to Closing.
             if fin {
                 *state = TCPState::Closing;
                 return true;
             }
-            // At the FinWait1 state, an ACK packet moves the state to FinWait2.
+            // At the FinWait1 state, an ACK packet from the peer moves the state to FinWait2.
             if ack {
                 *state = TCPState::FinWait2;
                 return true;
             }
         }
         TCPState::FinWait2 => {
-            // At the FinWait2 state, an ACK packet moves the state to TimeWait.
-            if ack {
-                *state = TCPState::TimeWait;
+            // At the FinWait2 state, a FIN packet from the peer moves the state to LastAck.
+            if fin && !from_closer {
+                *state = TCPState::LastAck;
                 return true;
             }
         }
         TCPState::Closing => {
-            // At the Closing state, an ACK packet moves the state to TimeWait.
+            // At the Closing state, an ACK packet moves the state to LastAck, waiting for the
+            // acknowledgement of the other side.
             if ack {
-                *state = TCPState::TimeWait;
+                *state = TCPState::LastAck;
+                *closer = match sender {
+                    TCPSide::Client => TCPSide::Backend,
+                    TCPSide::Backend => TCPSide::Client,
+                };
                 return true;
             }
         }
-        TCPState::TimeWait => {
-            if ack {
-                *state = TCPState::Closed;
+        TCPState::LastAck => {
+            // At the LastAck state, an ACK packet from the closer moves the state to TimeWait.
+            if ack && from_closer {
+                *state = TCPState::TimeWait;
                 return true;
             }
         }
-        TCPState::Closed => {}
+        TCPState::TimeWait | TCPState::Closed => {}
     }
     return false;
 }
 
-// Modifies the map tracking TCP connections based on the current state
-// of the TCP connection and the incoming TCP packet's header.
+// Returns whether the tracked TCP connection has been idle for longer than the timeout of its state
+// at `now`, in which case it's gone even though userspace hasn't expired it yet.
+#[inline(always)]
+pub fn is_tcp_conn_expired(lb_mapping: &LoadBalancerMapping, now: u64) -> bool {
+    let state = match lb_mapping.tcp_state {
+        Some(state) => state,
+        None => return false,
+    };
+    let timeouts = unsafe { TCP_TIMEOUTS.get(0) }.copied().unwrap_or_default();
+    let timeout = timeouts.of(state);
+    timeout != 0 && now.saturating_sub(lb_mapping.last_seen) > timeout
+}
+
+// Modifies the map tracking TCP connections based on the current state of the TCP connection and
+// the header of a packet sent by `sender`. Connections are removed from the map once both
+// directions have been closed.
 #[inline(always)]
 pub fn update_tcp_conns(
     hdr: &TcpHdr,
+    sender: TCPSide,
     client_key: &ClientKey,
     lb_mapping: &mut LoadBalancerMapping,
 ) -> Result<(), i64> {
     if let Some(ref mut tcp_state) = lb_mapping.tcp_state {
-        let transitioned = process_tcp_state_transition(hdr, tcp_state);
-        if let TCPState::Closed = tcp_state {
-            unsafe {
-                return LB_CONNECTIONS.remove(&client_key);
-            }
+        let transitioned =
+            process_tcp_state_transition(hdr, sender, tcp_state, &mut lb_mapping.tcp_closer);
+        if let TCPState::TimeWait | TCPState::Closed = tcp_state {
+            return remove_tcp_conn(client_key, lb_mapping, CloseReason::Fin);
         }
-        // If the connection has not reached the Closed state yet, but it did transition to a new state,
-        // then record the new state.
+        // If the connection has not been closed yet, but it did transition to a new state, then
+        // record the new state.
         if transitioned {
             unsafe {
                 return LB_CONNECTIONS.insert(&client_key, &lb_mapping, 0_u64);
@@ -123,3 +940,349 @@ pub fn update_tcp_conns(
     }
     Ok(())
 }
+
+// Starts tracking a new TCP connection in the map tracking TCP connections, counting the insertion,
+// or its failure, in FLOW_TABLE_STATS.
+#[inline(always)]
+pub fn track_tcp_conn(client_key: &ClientKey, lb_mapping: &LoadBalancerMapping) -> Result<(), i64> {
+    let result = unsafe { LB_CONNECTIONS.insert(client_key, lb_mapping, 0_u64) };
+    update_flow_table_stats(|stats| match result {
+        Ok(()) => stats.inserts += 1,
+        Err(_) => stats.insert_failures += 1,
+    });
+    result
+}
+
+// Removes a TCP connection from the map tracking TCP connections, releases its source port if it
+// is source NATed, and counts it as closed for its backend.
+#[inline(always)]
+pub fn remove_tcp_conn(
+    client_key: &ClientKey,
+    lb_mapping: &LoadBalancerMapping,
+    reason: CloseReason,
+) -> Result<(), i64> {
+    unsafe { LB_CONNECTIONS.remove(client_key)? };
+    update_flow_table_stats(|stats| stats.removals += 1);
+    count_client_connection_closed(&client_key.ip);
+    if let Some(snat_key) = lb_mapping.snat_key() {
+        // The entry may already have been evicted.
+        let _ = unsafe { SNAT_CONNECTIONS.remove(&snat_key) };
+    }
+    report_connection_closed(
+        IpProto::Tcp,
+        client_key,
+        &lb_mapping.backend_key,
+        &lb_mapping.backend,
+        reason,
+        &lb_mapping.stats,
+    );
+    count_connection_closed(&lb_mapping.backend)
+}
+
+// -----------------------------------------------------------------------------
+// Connection Events
+// -----------------------------------------------------------------------------
+
+// Reports to userspace that the connection of the client to the Gateway was assigned the backend.
+#[inline(always)]
+pub fn report_connection_opened(
+    proto: IpProto,
+    client_key: &ClientKey,
+    backend_key: &BackendKey,
+    backend: &Backend,
+    stats: &FlowStats,
+) {
+    report_connection_event(
+        proto,
+        client_key,
+        backend_key,
+        backend,
+        ConnectionEventKind::Opened,
+        CloseReason::None,
+        stats,
+    )
+}
+
+// Reports to userspace that the connection of the client to the Gateway was closed.
+#[inline(always)]
+pub fn report_connection_closed(
+    proto: IpProto,
+    client_key: &ClientKey,
+    backend_key: &BackendKey,
+    backend: &Backend,
+    reason: CloseReason,
+    stats: &FlowStats,
+) {
+    report_connection_event(
+        proto,
+        client_key,
+        backend_key,
+        backend,
+        ConnectionEventKind::Closed,
+        reason,
+        stats,
+    )
+}
+
+#[inline(always)]
+fn report_connection_event(
+    proto: IpProto,
+    client_key: &ClientKey,
+    backend_key: &BackendKey,
+    backend: &Backend,
+    kind: ConnectionEventKind,
+    reason: CloseReason,
+    stats: &FlowStats,
+) {
+    let event = ConnectionEvent {
+        timestamp: unsafe { bpf_ktime_get_ns() },
+        client_key: *client_key,
+        backend_key: *backend_key,
+        backend: backend.key(),
+        proto: proto as u8,
+        kind,
+        reason,
+        stats: *stats,
+    };
+    // Losing an event while the buffer is full is no reason to fail the packet.
+    let _ = unsafe { CONNECTION_EVENTS.output(&event, 0) };
+}
+
+// -----------------------------------------------------------------------------
+// Backend Connection Counters
+// -----------------------------------------------------------------------------
+
+// Counts a new connection assigned to the backend on this CPU.
+#[inline(always)]
+pub fn count_connection_opened(backend: &Backend) -> Result<(), i64> {
+    let now = unsafe { bpf_ktime_get_ns() };
+    update_backend_connections(backend, |counters| {
+        counters.opened += 1;
+        counters.last_opened = now;
+    })
+}
+
+// Counts a connection of the backend that terminated on this CPU.
+#[inline(always)]
+pub fn count_connection_closed(backend: &Backend) -> Result<(), i64> {
+    update_backend_connections(backend, |counters| counters.closed += 1)
+}
+
+#[inline(always)]
+fn update_backend_connections(
+    backend: &Backend,
+    update: impl Fn(&mut BackendConnections),
+) -> Result<(), i64> {
+    let key = backend.key();
+    if let Some(counters) = unsafe { BACKEND_CONNECTIONS.get_ptr_mut(&key) } {
+        update(unsafe { &mut *counters });
+        return Ok(());
+    }
+
+    // Inserting from eBPF only sets the value of this CPU, so this doesn't clobber the counters
+    // of another CPU which may have created the entry in the meantime.
+    let mut counters = BackendConnections::default();
+    update(&mut counters);
+    unsafe { BACKEND_CONNECTIONS.insert(&key, &counters, 0_u64) }
+}
+
+// -----------------------------------------------------------------------------
+// Client Connection Counters
+// -----------------------------------------------------------------------------
+
+// Returns whether the client may open another TCP connection, i.e. if it has fewer live
+// connections than the configured cap.
+#[inline(always)]
+pub fn client_under_cap(client_ip: &[u32; 4]) -> bool {
+    let max_client_connections = config().max_client_connections;
+    if max_client_connections == 0 {
+        return true;
+    }
+    match unsafe { CLIENT_CONNECTIONS.get(client_ip) } {
+        Some(live) => *live < max_client_connections,
+        None => true,
+    }
+}
+
+// Counts a new TCP connection of the client. The counts are shared by all CPUs, so they are
+// updated atomically.
+#[inline(always)]
+pub fn count_client_connection_opened(client_ip: &[u32; 4]) {
+    if config().max_client_connections == 0 {
+        return;
+    }
+    if let Some(live) = unsafe { CLIENT_CONNECTIONS.get_ptr_mut(client_ip) } {
+        unsafe { AtomicU32::from_ptr(live) }.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    // Another CPU may have counted a connection of the client in the meantime.
+    if unsafe { CLIENT_CONNECTIONS.insert(client_ip, &1, BPF_NOEXIST as u64) }.is_err() {
+        if let Some(live) = unsafe { CLIENT_CONNECTIONS.get_ptr_mut(client_ip) } {
+            unsafe { AtomicU32::from_ptr(live) }.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Counts a TCP connection of the client that terminated.
+#[inline(always)]
+pub fn count_client_connection_closed(client_ip: &[u32; 4]) {
+    if config().max_client_connections == 0 {
+        return;
+    }
+    if let Some(live) = unsafe { CLIENT_CONNECTIONS.get_ptr_mut(client_ip) } {
+        let live = unsafe { AtomicU32::from_ptr(live) };
+        // The count starts over when the entry is evicted, don't let it wrap around.
+        if live.load(Ordering::Relaxed) > 0 {
+            live.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Backend Traffic Counters
+// -----------------------------------------------------------------------------
+
+// Counts a packet that couldn't be redirected on this CPU.
+#[inline(always)]
+pub fn count_redirect_error() {
+    if let Some(errors) = unsafe { REDIRECT_ERRORS.get_ptr_mut(0) } {
+        unsafe { *errors += 1 };
+    }
+}
+
+// Counts a TCP packet dropped by the ingress program for the reason on this CPU.
+#[inline(always)]
+pub fn count_tcp_drop(reason: DropReason) {
+    if let Some(drops) = unsafe { TCP_DROPS.get_ptr_mut(reason as u32) } {
+        unsafe { *drops += 1 };
+    }
+}
+
+// Counts a TCP packet let through to the host by the ingress program for the reason on this CPU.
+#[inline(always)]
+pub fn count_tcp_pass(reason: PassReason) {
+    if let Some(passes) = unsafe { TCP_PASSES.get_ptr_mut(reason as u32) } {
+        unsafe { *passes += 1 };
+    }
+}
+
+// Updates the counts of the changes made to LB_CONNECTIONS on this CPU.
+#[inline(always)]
+fn update_flow_table_stats(update: impl FnOnce(&mut FlowTableStats)) {
+    if let Some(stats) = unsafe { FLOW_TABLE_STATS.get_ptr_mut(0) } {
+        update(unsafe { &mut *stats });
+    }
+}
+
+// Counts a packet of `len` bytes forwarded to the backend on this CPU.
+#[inline(always)]
+pub fn count_forwarded(backend: &Backend, len: u32) -> Result<(), i64> {
+    update_backend_traffic(backend, |counters| {
+        counters.forwarded_packets += 1;
+        counters.forwarded_bytes += len as u64;
+    })
+}
+
+// Counts a reply of `len` bytes of the backend translated on this CPU.
+#[inline(always)]
+pub fn count_reply(backend: &Backend, len: u32) -> Result<(), i64> {
+    update_backend_traffic(backend, |counters| {
+        counters.reply_packets += 1;
+        counters.reply_bytes += len as u64;
+    })
+}
+
+#[inline(always)]
+fn update_backend_traffic(
+    backend: &Backend,
+    update: impl Fn(&mut BackendTraffic),
+) -> Result<(), i64> {
+    let key = backend.key();
+    if let Some(counters) = unsafe { BACKEND_TRAFFIC.get_ptr_mut(&key) } {
+        update(unsafe { &mut *counters });
+        return Ok(());
+    }
+
+    // See update_backend_connections.
+    let mut counters = BackendTraffic::default();
+    update(&mut counters);
+    unsafe { BACKEND_TRAFFIC.insert(&key, &counters, 0_u64) }
+}
+
+// -----------------------------------------------------------------------------
+// Passive Failure Detection
+// -----------------------------------------------------------------------------
+
+// Counts a new connection the backend failed, i.e. refused or left unanswered. The backend is
+// ejected once it fails as many in a row as the configured threshold, and the count starts over.
+#[inline(always)]
+pub fn record_backend_failure(backend: &Backend) {
+    let config = config();
+    if config.failure_threshold == 0 {
+        return;
+    }
+
+    let key = backend.key();
+    let mut failures = unsafe { BACKEND_FAILURES.get(&key) }
+        .copied()
+        .unwrap_or_default();
+    failures.failures += 1;
+    if failures.failures >= config.failure_threshold {
+        failures.failures = 0;
+        failures.ejected_until = unsafe { bpf_ktime_get_ns() } + config.failure_ejection_time;
+    }
+    // Failing to record the failure only delays the ejection of the backend.
+    let _ = unsafe { BACKEND_FAILURES.insert(&key, &failures, 0_u64) };
+}
+
+// Records the time the backend took to answer the SYN of the connection with the SYN-ACK it sent
+// at `now`, the first time it answers it.
+#[inline(always)]
+pub fn record_syn_latency(lb_mapping: &mut LoadBalancerMapping, now: u64) -> Result<(), i64> {
+    if lb_mapping.syn_sent == 0 {
+        return Ok(());
+    }
+    let latency = now.saturating_sub(lb_mapping.syn_sent);
+    lb_mapping.syn_sent = 0;
+
+    let key = lb_mapping.backend.key();
+    let bucket = SynLatency::bucket(latency);
+    if let Some(histogram) = unsafe { SYN_LATENCIES.get_ptr_mut(&key) } {
+        let histogram = unsafe { &mut *histogram };
+        if let Some(count) = histogram.buckets.get_mut(bucket) {
+            *count += 1;
+        }
+        histogram.sum_ns += latency;
+        return Ok(());
+    }
+
+    // See update_backend_connections.
+    let mut histogram = SynLatency::default();
+    if let Some(count) = histogram.buckets.get_mut(bucket) {
+        *count = 1;
+    }
+    histogram.sum_ns = latency;
+    unsafe { SYN_LATENCIES.insert(&key, &histogram, 0_u64) }
+}
+
+// Records that the backend accepted a new connection, which resets its count of failures.
+#[inline(always)]
+pub fn record_backend_success(backend: &Backend) {
+    let key = backend.key();
+    if let Some(failures) = unsafe { BACKEND_FAILURES.get_ptr_mut(&key) } {
+        unsafe { (*failures).failures = 0 };
+    }
+}
+
+// Returns whether the backend is ejected from the selection of new connections for failing too
+// many of them.
+#[inline(always)]
+pub fn is_backend_ejected(backend: &Backend) -> bool {
+    if config().failure_threshold == 0 {
+        return false;
+    }
+    match unsafe { BACKEND_FAILURES.get(&backend.key()) } {
+        Some(failures) => failures.ejected_until > unsafe { bpf_ktime_get_ns() },
+        None => false,
+    }
+}
@@ -0,0 +1,156 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use core::mem;
+
+use aya_ebpf::{
+    bindings::xdp_action::{XDP_PASS, XDP_REDIRECT, XDP_TX},
+    helpers::{bpf_ktime_get_ns, bpf_redirect},
+    programs::XdpContext,
+};
+use memoffset::offset_of;
+use network_types::{
+    eth::EthHdr,
+    ip::{IpProto, Ipv4Hdr, Ipv6Hdr},
+    tcp::TcpHdr,
+    udp::UdpHdr,
+};
+
+use crate::{
+    utils::{count_forwarded, count_redirect_error, IpHdr, ETH_P_IP, ETH_P_IPV6},
+    LB_CONNECTIONS, UDP_CONNECTIONS,
+};
+use common::{Backend, BackendKey, ClientKey, ForwardingMode, TCPState};
+
+// The XDP fast path forwards the packets of the flows the TC programs already assigned to a DSR
+// backend, before the kernel allocates a socket buffer for them. Whatever it can't handle on its
+// own (new flows, TCP packets changing the state of the connection, other forwarding modes, VLAN
+// tagged frames...) is passed on to the TC programs.
+pub fn handle_xdp_ingress(ctx: &XdpContext) -> Result<u32, ()> {
+    let eth_hdr: *mut EthHdr = unsafe { ptr_at(ctx, 0)? };
+    let ether_type: *const u16 = unsafe { ptr_at(ctx, offset_of!(EthHdr, ether_type))? };
+    let ip_hdr = match u16::from_be(unsafe { *ether_type }) {
+        ETH_P_IP => {
+            let hdr: *mut Ipv4Hdr = unsafe { ptr_at(ctx, EthHdr::LEN)? };
+            // Neither malformed headers nor fragments are worth the trouble here.
+            if unsafe { (*hdr).ihl() } < 5 || u16::from_be(unsafe { (*hdr).frag_off }) & 0x3FFF != 0
+            {
+                return Ok(XDP_PASS);
+            }
+            IpHdr::V4(hdr, EthHdr::LEN)
+        }
+        ETH_P_IPV6 => IpHdr::V6(unsafe { ptr_at(ctx, EthHdr::LEN)? }, EthHdr::LEN),
+        _ => return Ok(XDP_PASS),
+    };
+    let proto = match ip_hdr {
+        IpHdr::V4(hdr, _) => unsafe { (*hdr).proto },
+        IpHdr::V6(hdr, _) => unsafe { (*hdr).next_hdr },
+    };
+
+    let (backend, backend_key, dest) = match proto {
+        IpProto::Tcp => {
+            let tcp_hdr: *const TcpHdr = unsafe { ptr_at(ctx, ip_hdr.l4_offset())? };
+            let tcp_hdr = unsafe { &*tcp_hdr };
+            // Only the TC programs track the state of the connections.
+            if tcp_hdr.syn() != 0 || tcp_hdr.fin() != 0 || tcp_hdr.rst() != 0 {
+                return Ok(XDP_PASS);
+            }
+            let client_key = ClientKey {
+                ip: ip_hdr.src_addr(),
+                port: u16::from_be(tcp_hdr.source) as u32,
+            };
+            let lb_mapping = unsafe { LB_CONNECTIONS.get_ptr_mut(&client_key) }.ok_or(())?;
+            unsafe {
+                if !matches!((*lb_mapping).tcp_state, Some(TCPState::Established)) {
+                    return Ok(XDP_PASS);
+                }
+                (*lb_mapping).last_seen = bpf_ktime_get_ns();
+                (*lb_mapping)
+                    .stats
+                    .count((ctx.data_end() - ctx.data()) as u32);
+                (
+                    (*lb_mapping).backend,
+                    BackendKey {
+                        ip: (*lb_mapping).backend_key.ip,
+                        port: (*lb_mapping).gateway_port() as u32,
+                    },
+                    tcp_hdr.dest,
+                )
+            }
+        }
+        IpProto::Udp => {
+            // The TC programs only balance UDP over IPv4.
+            if let IpHdr::V6(..) = ip_hdr {
+                return Ok(XDP_PASS);
+            }
+            let udp_hdr: *const UdpHdr = unsafe { ptr_at(ctx, ip_hdr.l4_offset())? };
+            let client_key = ClientKey {
+                ip: ip_hdr.src_addr(),
+                port: u16::from_be(unsafe { (*udp_hdr).source }) as u32,
+            };
+            let udp_mapping = unsafe { UDP_CONNECTIONS.get_ptr_mut(&client_key) }.ok_or(())?;
+            unsafe {
+                (*udp_mapping).last_seen = bpf_ktime_get_ns();
+                (*udp_mapping)
+                    .stats
+                    .count((ctx.data_end() - ctx.data()) as u32);
+                (
+                    (*udp_mapping).backend,
+                    BackendKey {
+                        ip: (*udp_mapping).backend_key.ip,
+                        port: (*udp_mapping).gateway_port() as u32,
+                    },
+                    (*udp_hdr).dest,
+                )
+            }
+        }
+        _ => return Ok(XDP_PASS),
+    };
+
+    if !is_dsr_flow(ip_hdr, dest, &backend, &backend_key) {
+        return Ok(XDP_PASS);
+    }
+
+    unsafe {
+        // The packet was sent to this node, so its destination is our own MAC address.
+        (*eth_hdr).src_addr = (*eth_hdr).dst_addr;
+        (*eth_hdr).dst_addr = backend.mac;
+    }
+
+    count_forwarded(&backend, (ctx.data_end() - ctx.data()) as u32).map_err(|_| ())?;
+
+    let ingress_ifindex = unsafe { (*ctx.ctx).ingress_ifindex };
+    if backend.ifindex as u32 == ingress_ifindex {
+        return Ok(XDP_TX);
+    }
+    let action = unsafe { bpf_redirect(backend.ifindex as u32, 0) } as u32;
+    if action != XDP_REDIRECT {
+        count_redirect_error();
+    }
+    Ok(action)
+}
+
+// Returns whether the packet belongs to a flow forwarded to a DSR backend, which is still destined
+// for the Gateway it was assigned from.
+#[inline(always)]
+fn is_dsr_flow(ip_hdr: IpHdr, dest: u16, backend: &Backend, backend_key: &BackendKey) -> bool {
+    backend.forwarding == ForwardingMode::Dsr
+        && ip_hdr.dst_addr() == backend_key.ip
+        && u16::from_be(dest) as u32 == backend_key.port
+}
+
+// Gives us raw pointers to a specific offset in the packet, see utils::ptr_at.
+#[inline(always)]
+unsafe fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Result<*mut T, ()> {
+    let start = ctx.data();
+    let end = ctx.data_end();
+    let len = mem::size_of::<T>();
+
+    if start + offset + len > end {
+        return Err(());
+    }
+    Ok((start + offset) as *mut T)
+}
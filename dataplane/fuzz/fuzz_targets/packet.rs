@@ -0,0 +1,72 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+// Feeds arbitrary packets through the TC programs with BPF_PROG_TEST_RUN, to catch the header
+// offsets and lengths the parsing gets wrong: IHL and data offsets out of range, truncated headers,
+// total lengths disagreeing with the packet's. The programs run in the kernel, so this takes root
+// and the eBPF programs built with `cargo xtask build-ebpf`:
+//
+//   sudo -E cargo +nightly fuzz run packet
+
+#![no_main]
+
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+
+use datapath_tests::{backend, Datapath, TC_ACT_OK, TC_ACT_REDIRECT, TC_ACT_SHOT};
+use libfuzzer_sys::fuzz_target;
+
+const ETH_HLEN: usize = 14;
+const ETH_P_IP: [u8; 2] = [0x08, 0x00];
+const VIP: [u8; 4] = [198, 51, 100, 1];
+const VIP_PORT: u16 = 80;
+
+static DATAPATH: OnceLock<Mutex<Datapath>> = OnceLock::new();
+
+// Returns the datapath the packets run through, with a Gateway for VIP:VIP_PORT so that they make
+// it past the lookup of the Gateway.
+fn datapath() -> &'static Mutex<Datapath> {
+    DATAPATH.get_or_init(|| {
+        let mut datapath = Datapath::load().expect("failed to load the datapath");
+        datapath
+            .add_gateway(
+                SocketAddr::from((VIP, VIP_PORT)),
+                &[backend("10.0.0.2:8080".parse().unwrap())],
+            )
+            .expect("failed to add the gateway");
+        Mutex::new(datapath)
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut packet = data.to_vec();
+
+    // Random bytes would rarely be IPv4 packets to the Gateway. The header lengths, the protocol
+    // and everything else stay as the fuzzer wrote them.
+    if packet.len() >= ETH_HLEN + 20 {
+        packet[12..14].copy_from_slice(&ETH_P_IP);
+        packet[ETH_HLEN + 16..ETH_HLEN + 20].copy_from_slice(&VIP);
+        let ihl = (packet[ETH_HLEN] & 0x0f) as usize * 4;
+        if let Some(port) = packet.get_mut(ETH_HLEN + ihl + 2..ETH_HLEN + ihl + 4) {
+            port.copy_from_slice(&VIP_PORT.to_be_bytes());
+        }
+    }
+
+    let mut datapath = datapath().lock().unwrap();
+    for run in [datapath.ingress(&packet), datapath.egress(&packet)] {
+        // The kernel refuses to run packets shorter than an Ethernet header.
+        let run = match run {
+            Ok(run) => run,
+            Err(_) if packet.len() < ETH_HLEN => continue,
+            Err(err) => panic!("{:#}", err),
+        };
+        assert!(
+            [TC_ACT_OK, TC_ACT_SHOT, TC_ACT_REDIRECT].contains(&run.action),
+            "unexpected action {}",
+            run.action
+        );
+    }
+});
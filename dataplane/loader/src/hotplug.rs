@@ -0,0 +1,284 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use anyhow::Context;
+use aya::programs::tc::{SchedClassifierLinkId, TcOptions};
+use aya::programs::{tc, SchedClassifier, TcAttachType};
+use aya::Bpf;
+use regex::Regex;
+use tokio::io::unix::AsyncFd;
+use tracing::{info, warn};
+
+/// Length of the netlink message header.
+const NLMSG_HDR_LEN: usize = 16;
+/// Length of the ifinfomsg which follows the header of the link messages.
+const IFINFOMSG_LEN: usize = 16;
+/// Length of the header of the attributes of the link messages.
+const RTA_HDR_LEN: usize = 4;
+
+/// The links of the TC programs attached to an interface.
+struct Attachment {
+    ingress: SchedClassifierLinkId,
+    egress: SchedClassifierLinkId,
+}
+
+/// Keeps the TC programs attached to the interfaces whose name matches a
+/// pattern, as they come and go.
+pub struct Hotplug {
+    bpf: Bpf,
+    pattern: Regex,
+    /// The interfaces which are left alone: those the programs were attached
+    /// to at startup, which they stay attached to for as long as the loader
+    /// runs, and the excluded ones.
+    skipped: Vec<String>,
+    // The priority and the handle of the TC filters.
+    tc_priority: u16,
+    tc_handle: u32,
+    attached: HashMap<String, Attachment>,
+}
+
+impl Hotplug {
+    pub fn new(
+        bpf: Bpf,
+        pattern: Regex,
+        skipped: Vec<String>,
+        tc_priority: u16,
+        tc_handle: u32,
+    ) -> Hotplug {
+        Hotplug {
+            bpf,
+            pattern,
+            skipped,
+            tc_priority,
+            tc_handle,
+            attached: HashMap::new(),
+        }
+    }
+
+    /// Attaches the programs to the matching interfaces which already exist,
+    /// then to those created later on, as the kernel announces them. It only
+    /// returns if listening to the announcements fails.
+    pub async fn run(&mut self) -> Result<(), anyhow::Error> {
+        // Listen before listing the interfaces, so that none falls in between.
+        let socket = AsyncFd::new(link_socket().context("failed to open a netlink socket")?)?;
+
+        for entry in std::fs::read_dir("/sys/class/net")? {
+            if let Some(name) = entry?.file_name().to_str() {
+                self.link_added(name);
+            }
+        }
+
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let mut guard = socket.readable().await?;
+            let len = match guard.try_io(|socket| recv(socket.get_ref(), &mut buf)) {
+                Ok(result) => result?,
+                Err(_would_block) => continue,
+            };
+            for (kind, name) in link_messages(&buf[..len]) {
+                match kind {
+                    libc::RTM_NEWLINK => self.link_added(&name),
+                    libc::RTM_DELLINK => self.link_removed(&name),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn link_added(&mut self, name: &str) {
+        // The kernel announces the changes of the interfaces (e.g. going up) as
+        // new links too.
+        if self.skipped.iter().any(|skipped| skipped == name)
+            || self.attached.contains_key(name)
+            || !self.pattern.is_match(name)
+        {
+            return;
+        }
+        match self.attach(name) {
+            Ok(attachment) => {
+                info!(
+                    interface = name,
+                    "attached the tc programs to new interface"
+                );
+                self.attached.insert(name.to_string(), attachment);
+            }
+            Err(err) => warn!(
+                interface = name,
+                error = format!("{:#}", err),
+                "failed to attach the tc programs"
+            ),
+        }
+    }
+
+    fn link_removed(&mut self, name: &str) {
+        let attachment = match self.attached.remove(name) {
+            Some(attachment) => attachment,
+            None => return,
+        };
+        info!(
+            interface = name,
+            "detaching the tc programs from removed interface"
+        );
+        // The kernel removed the filters along with the interface, so detaching
+        // only lets go of the links.
+        if let Ok(program) = self.program_mut("tc_ingress") {
+            let _ = program.detach(attachment.ingress);
+        }
+        if let Ok(program) = self.program_mut("tc_egress") {
+            let _ = program.detach(attachment.egress);
+        }
+    }
+
+    fn attach(&mut self, name: &str) -> Result<Attachment, anyhow::Error> {
+        // The interface may have a clsact qdisc already.
+        let _ = tc::qdisc_add_clsact(name);
+        let (ingress_options, egress_options) = (self.tc_options(), self.tc_options());
+        let ingress = self
+            .program_mut("tc_ingress")?
+            .attach_with_options(name, TcAttachType::Ingress, ingress_options)
+            .context("failed to attach the ingress TC program")?;
+        let egress = match self.program_mut("tc_egress")?.attach_with_options(
+            name,
+            TcAttachType::Egress,
+            egress_options,
+        ) {
+            Ok(egress) => egress,
+            Err(err) => {
+                let _ = self.program_mut("tc_ingress")?.detach(ingress);
+                return Err(
+                    anyhow::Error::from(err).context("failed to attach the egress TC program")
+                );
+            }
+        };
+        Ok(Attachment { ingress, egress })
+    }
+
+    fn tc_options(&self) -> TcOptions {
+        TcOptions {
+            priority: self.tc_priority,
+            handle: self.tc_handle,
+        }
+    }
+
+    fn program_mut(&mut self, name: &str) -> Result<&mut SchedClassifier, anyhow::Error> {
+        let program = self
+            .bpf
+            .program_mut(name)
+            .with_context(|| format!("no programs named {}", name))?;
+        Ok(program.try_into()?)
+    }
+}
+
+/// Returns a non-blocking netlink socket which receives the announcements of
+/// the interfaces being created, changed and removed.
+fn link_socket() -> Result<OwnedFd, io::Error> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as u16;
+    addr.nl_groups = libc::RTMGRP_LINK as u32;
+    let ret = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as u32,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+fn recv(socket: &OwnedFd, buf: &mut [u8]) -> Result<usize, io::Error> {
+    let len = unsafe {
+        libc::recv(
+            socket.as_raw_fd(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            0,
+        )
+    };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(len as usize)
+}
+
+/// Returns the type and the interface name of the link messages in the
+/// buffer, skipping the other messages and those which are truncated.
+/// Ref: https://man7.org/linux/man-pages/man7/rtnetlink.7.html
+fn link_messages(buf: &[u8]) -> Vec<(u16, String)> {
+    let mut messages = Vec::new();
+    let mut offset = 0;
+    while let (Some(len), Some(kind)) = (read_u32(buf, offset), read_u16(buf, offset + 4)) {
+        let len = len as usize;
+        let message = match buf.get(offset..offset + len) {
+            Some(message) if len >= NLMSG_HDR_LEN => message,
+            _ => break,
+        };
+        if kind == libc::RTM_NEWLINK || kind == libc::RTM_DELLINK {
+            let name = message
+                .get(NLMSG_HDR_LEN + IFINFOMSG_LEN..)
+                .and_then(link_name);
+            if let Some(name) = name {
+                messages.push((kind, name));
+            }
+        }
+        offset += align(len);
+    }
+    messages
+}
+
+/// Returns the interface name among the attributes of a link message.
+fn link_name(attrs: &[u8]) -> Option<String> {
+    let mut offset = 0;
+    while let (Some(len), Some(kind)) = (read_u16(attrs, offset), read_u16(attrs, offset + 2)) {
+        let len = len as usize;
+        if len < RTA_HDR_LEN {
+            return None;
+        }
+        let value = attrs.get(offset + RTA_HDR_LEN..offset + len)?;
+        if kind == libc::IFLA_IFNAME {
+            let name = CStr::from_bytes_until_nul(value).ok()?;
+            return name.to_str().ok().map(str::to_string);
+        }
+        offset += align(len);
+    }
+    None
+}
+
+/// Returns the length rounded up to the 4-byte alignment of netlink messages
+/// and attributes.
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> Option<u16> {
+    let bytes = buf.get(offset..offset + 2)?;
+    Some(u16::from_ne_bytes(bytes.try_into().ok()?))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    let bytes = buf.get(offset..offset + 4)?;
+    Some(u32::from_ne_bytes(bytes.try_into().ok()?))
+}
@@ -4,101 +4,1048 @@ Copyright 2023 The Kubernetes Authors.
 SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
 */
 
-use std::{net::Ipv4Addr, path::Path};
+mod hotplug;
+mod shutdown;
+
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::Read,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::Context;
-use api_server::start as start_api_server;
-use aya::maps::{HashMap, Map, MapData};
-use aya::programs::{tc, SchedClassifier, TcAttachType};
-use aya::{include_bytes_aligned, Bpf};
+use api_server::{
+    auth::AuthConfig, netutils::ip_to_words, settings::DatapathSettings, start as start_api_server,
+    tls::TlsConfig, BpfMaps, ServerOptions,
+};
+use aya::maps::{
+    Array, HashMap, LpmTrie, Map, MapData, PerCpuArray, PerCpuHashMap, RingBuf, SockHash,
+};
+use aya::programs::{
+    tc, tc::TcOptions, SchedClassifier, SkMsg, SockOps, TcAttachType, Xdp, XdpFlags,
+};
+use aya::util::nr_cpus;
+use aya::{include_bytes_aligned, BpfLoader};
 use aya_log::BpfLogger;
-use clap::Parser;
-use common::{BackendKey, BackendList, ClientKey, LoadBalancerMapping};
-use log::{info, warn};
+use clap::{Parser, ValueEnum};
+use common::{
+    AclAction, AclKey, BackendConnections, BackendFailures, BackendKey, BackendList,
+    BackendTraffic, ClientKey, Config, ConnectionLimit, FlowTableStats, GatewayIndex,
+    GatewaySlotKey, LoadBalancerMapping, LogLevel, MaglevTable, Mirror, PortRangeList, SnatKey,
+    SockKey, SynLatency, TcpTimeouts, Tunnel, UdpLoadBalancerMapping, UntrackedTCPAction,
+    LB_CONNECTIONS_CAPACITY,
+};
+use regex::Regex;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+
+use hotplug::Hotplug;
+use shutdown::Shutdown;
 
 #[derive(Debug, Parser)]
+#[clap(args_override_self = true)]
 struct Opt {
-    #[clap(short, long, default_value = "lo")]
-    iface: String,
+    /// TOML file of options, named like the flags (e.g. `iface = ["eth0"]`,
+    /// `udp-idle-timeout = 60`, `xdp = true`), which those of the command line
+    /// take precedence over. The file is reloaded on SIGHUP and when it
+    /// changes, which applies the settings of the eBPF programs (the
+    /// untracked TCP, SNAT, MSS clamping, failure detection, SYN rate, tunnel,
+    /// TPROXY and sampling options, the datapath log level and the TCP
+    /// timeouts) and leaves the others to the next restart.
+    #[clap(long = "config")]
+    config_file: Option<PathBuf>,
+    /// Interfaces the TC programs are attached to, comma-separated or repeated.
+    #[clap(short, long, default_value = "lo", value_delimiter = ',')]
+    iface: Vec<String>,
+    /// Interfaces the ingress TC program is attached to, instead of those of
+    /// --iface.
+    #[clap(long, value_delimiter = ',')]
+    ingress_iface: Vec<String>,
+    /// Interfaces the egress TC program is attached to, instead of those of
+    /// --iface.
+    #[clap(long, value_delimiter = ',')]
+    egress_iface: Vec<String>,
+    /// Interfaces the programs are never attached to, even if they are listed
+    /// or match --iface-pattern.
+    #[clap(long, value_delimiter = ',')]
+    exclude_iface: Vec<String>,
+    /// Seconds after which an idle UDP flow is no longer pinned to its backend,
+    /// unless its VIP has a timeout of its own.
+    #[clap(long, default_value = "30", value_parser = clap::value_parser!(u64).range(1..))]
+    udp_idle_timeout: u64,
+    /// Seconds after which a TCP connection whose handshake the client
+    /// started is dropped if it goes no further, 0 never dropping it. Like the
+    /// other TCP timeouts, it can be changed at runtime through the API.
+    #[clap(long, default_value = "120")]
+    tcp_syn_sent_timeout: u64,
+    /// Seconds after which an idle established TCP connection is dropped, 0
+    /// never dropping it.
+    #[clap(long, default_value = "3600")]
+    tcp_established_timeout: u64,
+    /// Seconds after which a TCP connection which is being closed is dropped,
+    /// 0 never dropping it.
+    #[clap(long, default_value = "120")]
+    tcp_fin_wait_timeout: u64,
+    /// Seconds after which a closed TCP connection is dropped, 0 never
+    /// dropping it.
+    #[clap(long, default_value = "60")]
+    tcp_time_wait_timeout: u64,
+    /// Seconds after which an idle SCTP association is no longer pinned to its
+    /// backend. Associations send heartbeats every 30 seconds by default.
+    #[clap(long, default_value = "300", value_parser = clap::value_parser!(u64).range(1..))]
+    sctp_idle_timeout: u64,
+    /// What to do with TCP packets sent to a Gateway which neither start a new
+    /// connection nor belong to a tracked one.
+    #[clap(long, value_enum, default_value_t = UntrackedTcp::Track)]
+    untracked_tcp: UntrackedTcp,
+    /// Reset new TCP connections to a Gateway which has no backend, so that
+    /// clients fail fast instead of waiting for a timeout.
+    #[clap(long, action)]
+    reset_without_backend: bool,
+    /// Address of this node which the source of the connections to Gateways in
+    /// source NAT mode, and of the backends to their own Gateway, is rewritten
+    /// to, for IPv4 connections. IPv6 connections to IPv4 targets are
+    /// translated to IPv4 connections from this address too (NAT64).
+    #[clap(long)]
+    snat_ipv4: Option<Ipv4Addr>,
+    /// Address of this node which the source of the connections to Gateways in
+    /// source NAT mode, and of the backends to their own Gateway, is rewritten
+    /// to, for IPv6 connections.
+    #[clap(long)]
+    snat_ipv6: Option<Ipv6Addr>,
+    /// Address of this node which the IPv4 packets encapsulated to the nodes
+    /// of the targets with one come from. The packets are routed to the
+    /// targets without it.
+    #[clap(long)]
+    tunnel_source_ipv4: Option<Ipv4Addr>,
+    /// Address of this node which the IPv6 packets encapsulated to the nodes
+    /// of the targets with one come from. The packets are routed to the
+    /// targets without it.
+    #[clap(long)]
+    tunnel_source_ipv6: Option<Ipv6Addr>,
+    /// Mark of the packets handed to the local transparent proxies of the
+    /// Gateways, for a routing rule of the host to deliver them locally (e.g.
+    /// `ip rule add fwmark <mark> lookup 100` with
+    /// `ip route add local default dev lo table 100`). 0 leaves them unmarked.
+    #[clap(long, default_value = "0")]
+    tproxy_mark: u32,
+    /// Log the headers of one in N of the packets forwarded to the backends,
+    /// along with the backend they were forwarded to. 0 disables the sampling.
+    #[clap(long, default_value = "0")]
+    sample_every: u32,
+    /// Lowest source port allocated to source NATed connections. The range
+    /// should not overlap with the host's ephemeral ports.
+    #[clap(long, default_value = "61000", value_parser = clap::value_parser!(u16).range(1..))]
+    snat_port_min: u16,
+    /// Highest source port allocated to source NATed connections.
+    #[clap(long, default_value = "65535", value_parser = clap::value_parser!(u16).range(1..))]
+    snat_port_max: u16,
+    /// Clamp the MSS option of the TCP SYNs to and from the backends to this
+    /// value, for backends behind links with a smaller MTU (e.g. tunnels).
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..))]
+    clamp_mss: Option<u16>,
+    /// Clamp the MSS option of the TCP SYNs to and from the backends to what
+    /// fits in the MTU of the interface.
+    #[clap(long, action, conflicts_with = "clamp_mss")]
+    clamp_mss_to_mtu: bool,
+    /// Attach an XDP fast path for the packets of the connections to DSR
+    /// backends in front of the TC programs, if the driver of the interface
+    /// supports native XDP.
+    #[clap(long, action)]
+    xdp: bool,
+    /// Path of a cgroup v2 (e.g. /sys/fs/cgroup) whose sockets have the
+    /// payload of their connections through the Gateways spliced to the
+    /// backend's socket when the backend is on this node too, bypassing the
+    /// TCP/IP stack. Not supported with bpfd.
+    #[clap(long)]
+    sockmap_cgroup: Option<PathBuf>,
+    /// Number of new TCP connections in a row a target has to refuse or leave
+    /// unanswered to be skipped by the selection of new connections for a
+    /// while. 0 disables the passive failure detection.
+    #[clap(long, default_value = "0")]
+    failure_threshold: u32,
+    /// Seconds during which a target which reached the failure threshold is
+    /// skipped by the selection of new connections.
+    #[clap(long, default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    failure_ejection_time: u64,
+    /// Number of new TCP connections per second a client address may open,
+    /// past which its SYNs are dropped. 0 disables the rate limiting.
+    #[clap(long, default_value = "0")]
+    syn_rate: u32,
+    /// Number of new TCP connections a client address may open at once above
+    /// the rate, defaults to the rate.
+    #[clap(long)]
+    syn_burst: Option<u32>,
+    /// Number of live TCP connections a client address may have, past which
+    /// its new connections are dropped. 0 disables the cap.
+    #[clap(long, default_value = "0")]
+    max_client_connections: u32,
+    /// Serve Prometheus metrics on `/metrics` at this port.
+    #[clap(long)]
+    metrics_port: Option<u16>,
+    /// Serve the liveness of the dataplane on `/healthz` and its readiness on
+    /// `/readyz` at this port, for the probes of its pod. It is alive while
+    /// its eBPF programs are loaded, and ready once their maps can be read too.
+    #[clap(long)]
+    health_port: Option<u16>,
+    /// API addresses (`host:port`) of the dataplanes of the other nodes which
+    /// the tracked connections are replicated to as they are opened and
+    /// closed, comma-separated or repeated, for VIPs which are routed to
+    /// several nodes with ECMP or anycast. Each node lists the others.
+    #[clap(long, value_delimiter = ',')]
+    sync_peer: Vec<String>,
+    /// File holding the bearer token the connections are replicated to the
+    /// peers of --sync-peer with, when they require one (e.g. a projected
+    /// ServiceAccount token).
+    #[clap(long, requires = "sync_peer")]
+    sync_token_file: Option<PathBuf>,
+    /// Interface to answer the ARP requests for the IPv4 addresses of the
+    /// Gateways on, which are also announced with gratuitous ARP as they are
+    /// programmed, for L2 networks without BGP. The control plane programs
+    /// each VIP on a single node of the segment.
+    #[clap(long)]
+    announce_iface: Option<String>,
+    /// Directory the packet captures of the API write their pcap files in,
+    /// under the names the clients give them. Without it, the captures are
+    /// only streamed to the clients.
+    #[clap(long)]
+    capture_dir: Option<PathBuf>,
+    /// Address (`ip:port`) of an IPFIX collector to export the flows to over
+    /// UDP as they end, with the packets and bytes of each direction.
+    #[clap(long)]
+    ipfix_collector: Option<SocketAddr>,
+    /// OTLP/gRPC endpoint (e.g. `http://localhost:4317`) of an OpenTelemetry
+    /// collector to export the traces and metrics of the programming of the
+    /// Gateways to.
+    #[clap(long)]
+    otlp_endpoint: Option<String>,
+    /// PEM certificate the API is served with over mutual TLS, instead of
+    /// plaintext. The clients, and the peers of --sync-peer, have to present a
+    /// certificate signed by --tls-client-ca.
+    #[clap(long, requires_all = ["tls_key", "tls_client_ca"])]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of --tls-cert.
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// PEM certificate of the CA which signs the certificates of the clients
+    /// of the API and of the peer dataplanes.
+    #[clap(long, requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+    /// PEM public key (RSA or ECDSA) the bearer tokens of the writers of the
+    /// Gateways are signed with, e.g. the ServiceAccount signing key of the
+    /// cluster. The calls which change the state of the dataplane are then
+    /// rejected without a valid token issued for --auth-audience.
+    #[clap(long, requires = "auth_audience")]
+    auth_key: Option<PathBuf>,
+    /// Audience the bearer tokens have to be issued for.
+    #[clap(long, requires = "auth_key")]
+    auth_audience: Option<String>,
+    /// Issuer the bearer tokens have to come from, any if unset.
+    #[clap(long, requires = "auth_key")]
+    auth_issuer: Option<String>,
+    /// Subjects allowed to program the Gateways, comma-separated or repeated,
+    /// any if unset.
+    #[clap(long, value_delimiter = ',', requires = "auth_key")]
+    auth_subject: Vec<String>,
+    /// Verbosity of the logs of the eBPF programs, which log every packet at
+    /// info and debug. It can be changed at runtime through the API.
+    #[clap(long, value_enum, default_value_t = DatapathLogLevel::Off)]
+    datapath_log_level: DatapathLogLevel,
+    /// Format of the logs of the dataplane, whose verbosity is set by
+    /// RUST_LOG. The JSON logs carry one object per line, with the fields of
+    /// each event (e.g. interface, backend_key, generation) at the top level.
+    #[clap(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// Directory of the bpffs which the Gateways and the tracked TCP
+    /// connections are pinned in, so that a restarted dataplane picks them up
+    /// instead of dropping the live connections. The pinned maps have to be
+    /// removed when upgrading to a version which changed their layout, e.g.
+    /// with --flush-maps-on-exit.
+    #[clap(long, default_value = "/sys/fs/bpf/blixt")]
+    pin_path: PathBuf,
+    /// Unpin the Gateways and the tracked connections when the dataplane stops
+    /// on SIGTERM or SIGINT, so that the next one starts afresh instead of
+    /// picking them up. It doesn't apply to the maps of the programs bpfd
+    /// loaded.
+    #[clap(long, action)]
+    flush_maps_on_exit: bool,
+    /// Number of connections each of the connection tracking maps (the TCP,
+    /// source NAT, UDP and SCTP ones) holds, past which the least recently used
+    /// ones are evicted. It is read at startup only, and the maps already
+    /// pinned keep the size they were created with until they are removed.
+    /// Not supported with bpfd.
+    #[clap(
+        long,
+        default_value_t = LB_CONNECTIONS_CAPACITY,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    conntrack_capacity: u32,
+    /// Also attach both TC programs to the interfaces whose name matches this
+    /// regular expression, including those created while the loader runs
+    /// (e.g. new CNI attachments or bond members), and let go of them when
+    /// they are removed.
+    #[clap(long)]
+    iface_pattern: Option<String>,
+    /// Priority of the TC filters of the programs, lower values running first.
+    /// 0 lets the kernel pick one. The programs are always attached in
+    /// direct-action mode, which they rely on.
+    #[clap(long, default_value = "0")]
+    tc_priority: u16,
+    /// Handle of the TC filters of the programs, 0 lets the kernel pick one.
+    #[clap(long, default_value = "0")]
+    tc_handle: u32,
+    /// Hand the packets the programs let through to the TC filters of lower
+    /// priority on the same hook, e.g. another eBPF datapath's, instead of
+    /// accepting them.
+    #[clap(long, action)]
+    tc_chain: bool,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum UntrackedTcp {
+    /// Assign them a backend as if they started a new connection.
+    Track,
+    /// Let them through to the host unmodified.
+    Pass,
+    /// Drop them.
+    Drop,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum DatapathLogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+impl Opt {
+    /// Returns the interfaces the ingress TC program is attached to at startup.
+    fn ingress_ifaces(&self) -> Vec<&str> {
+        match self.ingress_iface.is_empty() {
+            true => self.ifaces(&self.iface),
+            false => self.ifaces(&self.ingress_iface),
+        }
+    }
+
+    /// Returns the interfaces the egress TC program is attached to at startup.
+    fn egress_ifaces(&self) -> Vec<&str> {
+        match self.egress_iface.is_empty() {
+            true => self.ifaces(&self.iface),
+            false => self.ifaces(&self.egress_iface),
+        }
+    }
+
+    /// Returns the interfaces either TC program is attached to at startup.
+    fn tc_ifaces(&self) -> Vec<&str> {
+        let mut ifaces = self.ingress_ifaces();
+        for iface in self.egress_ifaces() {
+            if !ifaces.contains(&iface) {
+                ifaces.push(iface);
+            }
+        }
+        ifaces
+    }
+
+    /// Returns the files the API is secured with, if any.
+    fn tls(&self) -> Option<TlsConfig> {
+        match (&self.tls_cert, &self.tls_key, &self.tls_client_ca) {
+            (Some(cert), Some(key), Some(client_ca)) => Some(TlsConfig {
+                cert: cert.clone(),
+                key: key.clone(),
+                client_ca: client_ca.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns how the bearer tokens of the writers are validated, if they are.
+    fn auth(&self) -> Option<AuthConfig> {
+        match (&self.auth_key, &self.auth_audience) {
+            (Some(key), Some(audience)) => Some(AuthConfig {
+                key: key.clone(),
+                audience: audience.clone(),
+                issuer: self.auth_issuer.clone(),
+                subjects: self.auth_subject.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns the settings of the API server, bpfd attaching the TC programs
+    /// only. The connection tracking maps hold `conntrack_capacity` entries.
+    fn server_options(&self, bpfd: bool, conntrack_capacity: u32) -> ServerOptions {
+        ServerOptions {
+            addr: Ipv4Addr::new(0, 0, 0, 0),
+            port: 9874,
+            udp_idle_timeout: Duration::from_secs(self.udp_idle_timeout),
+            sctp_idle_timeout: Duration::from_secs(self.sctp_idle_timeout),
+            metrics_port: self.metrics_port,
+            health_port: self.health_port,
+            programs: self.programs(bpfd),
+            conntrack_capacity,
+            sync_peers: self.sync_peer.clone(),
+            sync_token_file: self.sync_token_file.clone(),
+            capture_dir: self.capture_dir.clone(),
+            announce_iface: self.announce_iface.clone(),
+            ipfix_collector: self.ipfix_collector,
+            otlp_endpoint: self.otlp_endpoint.clone(),
+            tls: self.tls(),
+            auth: self.auth(),
+        }
+    }
+
+    /// Returns the names of the eBPF programs the dataplane attaches, bpfd
+    /// attaching only the TC ones.
+    fn programs(&self, bpfd: bool) -> Vec<String> {
+        let mut programs = vec!["tc_ingress".to_string(), "tc_egress".to_string()];
+        if bpfd {
+            return programs;
+        }
+        if self.xdp {
+            programs.push("xdp_ingress".to_string());
+        }
+        if self.sockmap_cgroup.is_some() {
+            programs.push("sk_msg_splice".to_string());
+            programs.push("sock_ops_splice".to_string());
+        }
+        programs
+    }
+
+    /// Returns the listed interfaces without the excluded and the repeated ones.
+    fn ifaces<'a>(&'a self, listed: &'a [String]) -> Vec<&'a str> {
+        let mut ifaces = Vec::new();
+        for iface in listed {
+            if !self.exclude_iface.contains(iface) && !ifaces.contains(&iface.as_str()) {
+                ifaces.push(iface.as_str());
+            }
+        }
+        ifaces
+    }
+
+    /// Returns the settings of the eBPF programs which are applied while they
+    /// run.
+    fn datapath_settings(&self) -> Result<DatapathSettings, anyhow::Error> {
+        Ok(DatapathSettings {
+            config: self.config()?,
+            log_level: self.log_level(),
+            tcp_timeouts: self.tcp_timeouts(),
+        })
+    }
+
+    /// Returns the settings of the eBPF programs, stored in the CONFIG map.
+    fn config(&self) -> Result<Config, anyhow::Error> {
+        let (max_mss_ipv4, max_mss_ipv6) = match self.clamp_mss {
+            Some(mss) => (mss, mss),
+            None if self.clamp_mss_to_mtu => {
+                // The backends may be reached through any of the interfaces.
+                let mut mtu = u32::MAX;
+                for iface in self.tc_ifaces() {
+                    mtu = mtu.min(iface_mtu(iface)?);
+                }
+                let mss =
+                    |headers_len: u32| mtu.saturating_sub(headers_len).min(u16::MAX as u32) as u16;
+                (mss(IPV4_TCP_HEADERS_LEN), mss(IPV6_TCP_HEADERS_LEN))
+            }
+            None => (0, 0),
+        };
+
+        Ok(Config {
+            untracked_tcp: match self.untracked_tcp {
+                UntrackedTcp::Track => UntrackedTCPAction::Track,
+                UntrackedTcp::Pass => UntrackedTCPAction::Pass,
+                UntrackedTcp::Drop => UntrackedTCPAction::Drop,
+            },
+            reset_without_backend: self.reset_without_backend,
+            snat_ipv4: self
+                .snat_ipv4
+                .map(|ip| ip_to_words(ip.into()))
+                .unwrap_or_default(),
+            snat_ipv6: self
+                .snat_ipv6
+                .map(|ip| ip_to_words(ip.into()))
+                .unwrap_or_default(),
+            snat_port_min: self.snat_port_min,
+            snat_port_max: self.snat_port_max,
+            max_mss_ipv4,
+            max_mss_ipv6,
+            failure_threshold: self.failure_threshold,
+            failure_ejection_time: Duration::from_secs(self.failure_ejection_time).as_nanos()
+                as u64,
+            syn_rate: self.syn_rate,
+            syn_burst: self.syn_burst.unwrap_or(self.syn_rate).max(1),
+            max_client_connections: self.max_client_connections,
+            syn_cookie_secret: random_words()?,
+            tc_chain: self.tc_chain,
+            tunnel_src_ipv4: self
+                .tunnel_source_ipv4
+                .map(|ip| ip_to_words(ip.into()))
+                .unwrap_or_default(),
+            tunnel_src_ipv6: self
+                .tunnel_source_ipv6
+                .map(|ip| ip_to_words(ip.into()))
+                .unwrap_or_default(),
+            tproxy_mark: self.tproxy_mark,
+            sample_every: self.sample_every,
+            nr_cpus: nr_cpus()? as u32,
+        })
+    }
+
+    /// Returns the options of the TC filters of the programs.
+    fn tc_options(&self) -> TcOptions {
+        TcOptions {
+            priority: self.tc_priority,
+            handle: self.tc_handle,
+        }
+    }
+
+    /// Returns the verbosity of the logs of the eBPF programs, stored in the
+    /// LOG_LEVEL map.
+    fn log_level(&self) -> LogLevel {
+        match self.datapath_log_level {
+            DatapathLogLevel::Off => LogLevel::Off,
+            DatapathLogLevel::Error => LogLevel::Error,
+            DatapathLogLevel::Warn => LogLevel::Warn,
+            DatapathLogLevel::Info => LogLevel::Info,
+            DatapathLogLevel::Debug => LogLevel::Debug,
+        }
+    }
+
+    /// Returns the timeouts of the TCP connection states, stored in the
+    /// TCP_TIMEOUTS map.
+    fn tcp_timeouts(&self) -> TcpTimeouts {
+        let nanos = |secs| Duration::from_secs(secs).as_nanos() as u64;
+        TcpTimeouts {
+            syn_sent: nanos(self.tcp_syn_sent_timeout),
+            established: nanos(self.tcp_established_timeout),
+            fin_wait: nanos(self.tcp_fin_wait_timeout),
+            time_wait: nanos(self.tcp_time_wait_timeout),
+        }
+    }
+}
+
+/// Length of the IP and TCP headers without options, which the MSS doesn't
+/// count, per IP family.
+const IPV4_TCP_HEADERS_LEN: u32 = 20 + 20;
+const IPV6_TCP_HEADERS_LEN: u32 = 40 + 20;
+
+/// Returns words drawn at random by the kernel.
+fn random_words() -> Result<[u32; 4], anyhow::Error> {
+    let mut bytes = [0; 16];
+    File::open("/dev/urandom")
+        .and_then(|mut file| file.read_exact(&mut bytes))
+        .context("failed to read /dev/urandom")?;
+    let mut words = [0; 4];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+        *word = u32::from_ne_bytes(chunk.try_into()?);
+    }
+    Ok(words)
+}
+
+/// How often the --config file is checked for changes.
+const CONFIG_FILE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The connection tracking maps sized by --conntrack-capacity.
+const CONNTRACK_MAPS: [&str; 4] = [
+    "LB_CONNECTIONS",
+    "SNAT_CONNECTIONS",
+    "UDP_CONNECTIONS",
+    "SCTP_CONNECTIONS",
+];
+
+/// Parses the options of the command line, on top of those of the --config
+/// file if any.
+fn parse_opt(args: &[OsString]) -> Result<Opt, anyhow::Error> {
+    let mut opt = Opt::try_parse_from(args)?;
+    if let Some(path) = &opt.config_file {
+        let mut file_args = vec![args[0].clone()];
+        file_args.extend(config_args(path)?);
+        file_args.extend(args[1..].iter().cloned());
+        opt = Opt::try_parse_from(file_args)?;
+    }
+    if opt.snat_port_min > opt.snat_port_max {
+        anyhow::bail!("--snat-port-min must not be greater than --snat-port-max");
+    }
+    Ok(opt)
+}
+
+/// Returns the flags of the options of a TOML file: the strings and numbers
+/// are the values of the flags named after their keys, the booleans set or
+/// leave out the flags, and the arrays repeat them.
+fn config_args(path: &Path) -> Result<Vec<OsString>, anyhow::Error> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let table: toml::Table = content
+        .parse()
+        .with_context(|| format!("invalid TOML in {}", path.display()))?;
+    let mut args = Vec::new();
+    for (key, value) in table {
+        let flag = format!("--{}", key.replace('_', "-"));
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Float(value) => value.to_string(),
+                toml::Value::Boolean(true) => {
+                    args.push(flag.clone().into());
+                    continue;
+                }
+                toml::Value::Boolean(false) => continue,
+                _ => anyhow::bail!("unsupported value for {} in {}", key, path.display()),
+            };
+            args.push(format!("{}={}", flag, value).into());
+        }
+    }
+    Ok(args)
+}
+
+/// Parses the options again whenever the dataplane receives SIGHUP or the
+/// --config file changes, and sends the settings of the eBPF programs to be
+/// applied. The options which fail to parse are left as they were.
+async fn reload_settings(
+    args: Vec<OsString>,
+    config_file: Option<PathBuf>,
+    settings: mpsc::Sender<DatapathSettings>,
+) -> Result<(), anyhow::Error> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    let mut interval = tokio::time::interval(CONFIG_FILE_POLL_INTERVAL);
+    let modified = |path: &Option<PathBuf>| {
+        path.as_ref().and_then(|path| {
+            std::fs::metadata(path)
+                .and_then(|meta| meta.modified())
+                .ok()
+        })
+    };
+    let mut last_modified = modified(&config_file);
+    loop {
+        tokio::select! {
+            _ = hangups.recv() => info!("reloading the options on SIGHUP"),
+            _ = interval.tick() => {
+                let modified = modified(&config_file);
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+                info!("reloading the options, the config file changed");
+            }
+        }
+        let datapath_settings = match parse_opt(&args).and_then(|opt| opt.datapath_settings()) {
+            Ok(datapath_settings) => datapath_settings,
+            Err(err) => {
+                warn!(error = format!("{:#}", err), "failed to reload the options");
+                continue;
+            }
+        };
+        if settings.send(datapath_settings).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Returns the MTU of the interface.
+fn iface_mtu(iface: &str) -> Result<u32, anyhow::Error> {
+    let path = Path::new("/sys/class/net").join(iface).join("mtu");
+    let mtu = std::fs::read_to_string(&path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    mtu.trim()
+        .parse()
+        .with_context(|| format!("invalid MTU {} for {}", mtu.trim(), iface))
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    let opt = Opt::parse();
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let opt = match parse_opt(&args) {
+        Ok(opt) => opt,
+        Err(err) => match err.downcast::<clap::Error>() {
+            Ok(err) => err.exit(),
+            Err(err) => return Err(err),
+        },
+    };
+    let iface_pattern = opt
+        .iface_pattern
+        .as_deref()
+        .map(Regex::new)
+        .transpose()
+        .context("invalid --iface-pattern")?;
 
     // TODO(astoycos) Let's determine a better way to let processes know bpfd is up and running,
     // Maybe if we're not running as a privileged deployment ALWAYS wait for bpfd?.
     std::thread::sleep(std::time::Duration::from_secs(5));
-    env_logger::init();
+    // The logs of the eBPF programs and of the libraries still on the log crate
+    // are bridged to the subscriber.
+    let subscriber = tracing_subscriber::fmt().with_env_filter(EnvFilter::from_default_env());
+    match opt.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().flatten_event(true).init(),
+    }
+    let shutdown = Shutdown::listen()?;
+
+    let (settings, settings_rx) = mpsc::channel(1);
+    let config_file = opt.config_file.clone();
+    tokio::spawn(async move {
+        if let Err(e) = reload_settings(args, config_file, settings).await {
+            warn!(error = %e, "stopped reloading the options");
+        }
+    });
 
     // If bpfd loaded the programs just load the maps.
     let bpfd_maps = Path::new("/run/bpfd/fs/maps");
 
     if bpfd_maps.exists() {
         info!("programs loaded via bpfd");
+        let mut config: Array<_, Config> =
+            Map::Array(MapData::from_pin(bpfd_maps.join("CONFIG")).expect("no maps named CONFIG"))
+                .try_into()?;
+        config.set(0, opt.config()?, 0)?;
+        let mut log_level: Array<_, LogLevel> = Map::Array(
+            MapData::from_pin(bpfd_maps.join("LOG_LEVEL")).expect("no maps named LOG_LEVEL"),
+        )
+        .try_into()?;
+        log_level.set(0, opt.log_level(), 0)?;
+        let mut tcp_timeouts: Array<_, TcpTimeouts> = Map::Array(
+            MapData::from_pin(bpfd_maps.join("TCP_TIMEOUTS")).expect("no maps named TCP_TIMEOUTS"),
+        )
+        .try_into()?;
+        tcp_timeouts.set(0, opt.tcp_timeouts(), 0)?;
+
         let backends: HashMap<_, BackendKey, BackendList> = Map::HashMap(
             MapData::from_pin(bpfd_maps.join("BACKENDS")).expect("no maps named BACKENDS"),
         )
         .try_into()?;
+        let port_ranges: HashMap<_, [u32; 4], PortRangeList> = Map::HashMap(
+            MapData::from_pin(bpfd_maps.join("PORT_RANGES")).expect("no maps named PORT_RANGES"),
+        )
+        .try_into()?;
+        let gateway_aliases: HashMap<_, BackendKey, BackendKey> = Map::HashMap(
+            MapData::from_pin(bpfd_maps.join("GATEWAY_ALIASES"))
+                .expect("no maps named GATEWAY_ALIASES"),
+        )
+        .try_into()?;
+        let vip_addresses: HashMap<_, [u32; 4], u32> = Map::HashMap(
+            MapData::from_pin(bpfd_maps.join("VIP_ADDRESSES"))
+                .expect("no maps named VIP_ADDRESSES"),
+        )
+        .try_into()?;
 
-        let gateway_indexes: HashMap<_, BackendKey, u16> = Map::HashMap(
+        let gateway_indexes: HashMap<_, GatewaySlotKey, GatewayIndex> = Map::HashMap(
             MapData::from_pin(bpfd_maps.join("GATEWAY_INDEXES"))
                 .expect("no maps named GATEWAY_INDEXES"),
         )
         .try_into()?;
-        let tcp_conns: HashMap<_, ClientKey, LoadBalancerMapping> = Map::HashMap(
+        let tcp_conns: HashMap<_, ClientKey, LoadBalancerMapping> = Map::LruHashMap(
             MapData::from_pin(bpfd_maps.join("LB_CONNECTIONS"))
                 .expect("no maps named LB_CONNECTIONS"),
         )
         .try_into()?;
+        let udp_conns: HashMap<_, ClientKey, UdpLoadBalancerMapping> = Map::LruHashMap(
+            MapData::from_pin(bpfd_maps.join("UDP_CONNECTIONS"))
+                .expect("no maps named UDP_CONNECTIONS"),
+        )
+        .try_into()?;
+        let sctp_conns: HashMap<_, ClientKey, UdpLoadBalancerMapping> = Map::LruHashMap(
+            MapData::from_pin(bpfd_maps.join("SCTP_CONNECTIONS"))
+                .expect("no maps named SCTP_CONNECTIONS"),
+        )
+        .try_into()?;
+        let maglev_tables: HashMap<_, GatewaySlotKey, MaglevTable> = Map::HashMap(
+            MapData::from_pin(bpfd_maps.join("MAGLEV_TABLES"))
+                .expect("no maps named MAGLEV_TABLES"),
+        )
+        .try_into()?;
+        let backend_conns: PerCpuHashMap<_, BackendKey, BackendConnections> = Map::PerCpuHashMap(
+            MapData::from_pin(bpfd_maps.join("BACKEND_CONNECTIONS"))
+                .expect("no maps named BACKEND_CONNECTIONS"),
+        )
+        .try_into()?;
+        let backend_traffic: PerCpuHashMap<_, BackendKey, BackendTraffic> = Map::PerCpuHashMap(
+            MapData::from_pin(bpfd_maps.join("BACKEND_TRAFFIC"))
+                .expect("no maps named BACKEND_TRAFFIC"),
+        )
+        .try_into()?;
+        let syn_latencies: PerCpuHashMap<_, BackendKey, SynLatency> = Map::PerCpuHashMap(
+            MapData::from_pin(bpfd_maps.join("SYN_LATENCIES"))
+                .expect("no maps named SYN_LATENCIES"),
+        )
+        .try_into()?;
+        let backend_failures: HashMap<_, BackendKey, BackendFailures> = Map::LruHashMap(
+            MapData::from_pin(bpfd_maps.join("BACKEND_FAILURES"))
+                .expect("no maps named BACKEND_FAILURES"),
+        )
+        .try_into()?;
+        let connection_limits: HashMap<_, BackendKey, ConnectionLimit> = Map::HashMap(
+            MapData::from_pin(bpfd_maps.join("CONNECTION_LIMITS"))
+                .expect("no maps named CONNECTION_LIMITS"),
+        )
+        .try_into()?;
+        let acls: LpmTrie<_, AclKey, AclAction> =
+            Map::LpmTrie(MapData::from_pin(bpfd_maps.join("ACLS")).expect("no maps named ACLS"))
+                .try_into()?;
+        let syn_cookies: HashMap<_, BackendKey, u32> = Map::HashMap(
+            MapData::from_pin(bpfd_maps.join("SYN_COOKIES")).expect("no maps named SYN_COOKIES"),
+        )
+        .try_into()?;
+        let dscp_marks: HashMap<_, BackendKey, u8> = Map::HashMap(
+            MapData::from_pin(bpfd_maps.join("DSCP_MARKS")).expect("no maps named DSCP_MARKS"),
+        )
+        .try_into()?;
+        let mirrors: HashMap<_, BackendKey, Mirror> = Map::HashMap(
+            MapData::from_pin(bpfd_maps.join("MIRRORS")).expect("no maps named MIRRORS"),
+        )
+        .try_into()?;
+        let tproxy_ports: HashMap<_, BackendKey, u16> = Map::HashMap(
+            MapData::from_pin(bpfd_maps.join("TPROXY_PORTS")).expect("no maps named TPROXY_PORTS"),
+        )
+        .try_into()?;
+        let ping_gateways: HashMap<_, [u32; 4], BackendKey> = Map::HashMap(
+            MapData::from_pin(bpfd_maps.join("PING_GATEWAYS"))
+                .expect("no maps named PING_GATEWAYS"),
+        )
+        .try_into()?;
+        let stateless_targets: HashMap<_, BackendKey, BackendKey> = Map::HashMap(
+            MapData::from_pin(bpfd_maps.join("STATELESS_TARGETS"))
+                .expect("no maps named STATELESS_TARGETS"),
+        )
+        .try_into()?;
+        let tunnels: HashMap<_, [u32; 4], Tunnel> = Map::HashMap(
+            MapData::from_pin(bpfd_maps.join("TUNNELS")).expect("no maps named TUNNELS"),
+        )
+        .try_into()?;
+        let limited_conns: PerCpuHashMap<_, BackendKey, u64> = Map::PerCpuHashMap(
+            MapData::from_pin(bpfd_maps.join("LIMITED_CONNECTIONS"))
+                .expect("no maps named LIMITED_CONNECTIONS"),
+        )
+        .try_into()?;
+        let released_conns: HashMap<_, BackendKey, u64> = Map::HashMap(
+            MapData::from_pin(bpfd_maps.join("RELEASED_CONNECTIONS"))
+                .expect("no maps named RELEASED_CONNECTIONS"),
+        )
+        .try_into()?;
+        let snat_conns: HashMap<_, SnatKey, ClientKey> = Map::LruHashMap(
+            MapData::from_pin(bpfd_maps.join("SNAT_CONNECTIONS"))
+                .expect("no maps named SNAT_CONNECTIONS"),
+        )
+        .try_into()?;
+        let client_conns: HashMap<_, [u32; 4], u32> = Map::LruHashMap(
+            MapData::from_pin(bpfd_maps.join("CLIENT_CONNECTIONS"))
+                .expect("no maps named CLIENT_CONNECTIONS"),
+        )
+        .try_into()?;
+        let redirect_errors: PerCpuArray<_, u64> = Map::PerCpuArray(
+            MapData::from_pin(bpfd_maps.join("REDIRECT_ERRORS"))
+                .expect("no maps named REDIRECT_ERRORS"),
+        )
+        .try_into()?;
+        let tcp_drops: PerCpuArray<_, u64> = Map::PerCpuArray(
+            MapData::from_pin(bpfd_maps.join("TCP_DROPS")).expect("no maps named TCP_DROPS"),
+        )
+        .try_into()?;
+        let tcp_passes: PerCpuArray<_, u64> = Map::PerCpuArray(
+            MapData::from_pin(bpfd_maps.join("TCP_PASSES")).expect("no maps named TCP_PASSES"),
+        )
+        .try_into()?;
+        let flow_table_stats: PerCpuArray<_, FlowTableStats> = Map::PerCpuArray(
+            MapData::from_pin(bpfd_maps.join("FLOW_TABLE_STATS"))
+                .expect("no maps named FLOW_TABLE_STATS"),
+        )
+        .try_into()?;
+        let connection_events: RingBuf<_> = Map::RingBuf(
+            MapData::from_pin(bpfd_maps.join("CONNECTION_EVENTS"))
+                .expect("no maps named CONNECTION_EVENTS"),
+        )
+        .try_into()?;
+        let packet_samples: RingBuf<_> = Map::RingBuf(
+            MapData::from_pin(bpfd_maps.join("PACKET_SAMPLES"))
+                .expect("no maps named PACKET_SAMPLES"),
+        )
+        .try_into()?;
+        let captures: HashMap<_, BackendKey, u8> = Map::HashMap(
+            MapData::from_pin(bpfd_maps.join("CAPTURES")).expect("no maps named CAPTURES"),
+        )
+        .try_into()?;
+        let captured_packets: RingBuf<_> = Map::RingBuf(
+            MapData::from_pin(bpfd_maps.join("CAPTURED_PACKETS"))
+                .expect("no maps named CAPTURED_PACKETS"),
+        )
+        .try_into()?;
 
         info!("starting api server");
-        start_api_server(
-            Ipv4Addr::new(0, 0, 0, 0),
-            9874,
-            backends,
-            gateway_indexes,
-            tcp_conns,
-        )
-        .await?;
+        let api_server = start_api_server(
+            opt.server_options(true, LB_CONNECTIONS_CAPACITY),
+            BpfMaps {
+                config,
+                backends,
+                port_ranges,
+                gateway_aliases,
+                vip_addresses,
+                gateway_indexes,
+                tcp_conns,
+                udp_conns,
+                sctp_conns,
+                maglev_tables,
+                backend_conns,
+                backend_traffic,
+                syn_latencies,
+                backend_failures,
+                connection_limits,
+                acls,
+                syn_cookies,
+                dscp_marks,
+                mirrors,
+                tproxy_ports,
+                ping_gateways,
+                stateless_targets,
+                tunnels,
+                log_level,
+                tcp_timeouts,
+                limited_conns,
+                released_conns,
+                snat_conns,
+                client_conns,
+                redirect_errors,
+                tcp_drops,
+                tcp_passes,
+                flow_table_stats,
+                connection_events,
+                packet_samples,
+                captures,
+                captured_packets,
+            },
+            settings_rx,
+            shutdown.clone().stopping(),
+        );
+        // bpfd keeps the programs attached, and their maps.
+        shutdown.serve(api_server).await?;
     } else {
         info!("loading ebpf programs");
 
+        // The maps declared as pinned are re-opened from the pin path if they are there, and
+        // pinned there otherwise.
+        std::fs::create_dir_all(&opt.pin_path)
+            .with_context(|| format!("failed to create {}", opt.pin_path.display()))?;
+        let mut loader = BpfLoader::new();
+        loader.map_pin_path(&opt.pin_path);
+        for name in CONNTRACK_MAPS {
+            loader.set_max_entries(name, opt.conntrack_capacity);
+        }
         #[cfg(debug_assertions)]
-        let mut bpf = Bpf::load(include_bytes_aligned!(
+        let mut bpf = loader.load(include_bytes_aligned!(
             "../../target/bpfel-unknown-none/debug/loader"
         ))?;
         #[cfg(not(debug_assertions))]
-        let mut bpf = Bpf::load(include_bytes_aligned!(
+        let mut bpf = loader.load(include_bytes_aligned!(
             "../../target/bpfel-unknown-none/release/loader"
         ))?;
         if let Err(e) = BpfLogger::init(&mut bpf) {
-            warn!("failed to initialize eBPF logger: {}", e);
+            warn!(error = %e, "failed to initialize eBPF logger");
         }
+        // A pinned LB_CONNECTIONS was re-opened with the size it was created with.
+        let conntrack_capacity = match bpf.map("LB_CONNECTIONS") {
+            Some(Map::LruHashMap(map)) => map.info()?.max_entries(),
+            _ => anyhow::bail!("no LRU maps named LB_CONNECTIONS"),
+        };
 
-        info!("attaching tc_ingress program to {}", &opt.iface);
+        let mut config: Array<_, Config> =
+            Array::try_from(bpf.take_map("CONFIG").expect("no maps named CONFIG"))?;
+        config.set(0, opt.config()?, 0)?;
+
+        // An existing clsact qdisc (e.g. another eBPF datapath's) is kept, along with its filters.
+        for iface in opt.tc_ifaces() {
+            let _ = tc::qdisc_add_clsact(iface);
+        }
 
-        let _ = tc::qdisc_add_clsact(&opt.iface);
         let ingress_program: &mut SchedClassifier =
             bpf.program_mut("tc_ingress").unwrap().try_into()?;
         ingress_program.load()?;
-        ingress_program
-            .attach(&opt.iface, TcAttachType::Ingress)
-            .context("failed to attach the ingress TC program")?;
-
-        info!("attaching tc_egress program to {}", &opt.iface);
+        for iface in opt.ingress_ifaces() {
+            info!(interface = iface, "attaching tc_ingress program");
+            ingress_program
+                .attach_with_options(iface, TcAttachType::Ingress, opt.tc_options())
+                .with_context(|| format!("failed to attach the ingress TC program to {}", iface))?;
+        }
 
         let egress_program: &mut SchedClassifier =
             bpf.program_mut("tc_egress").unwrap().try_into()?;
         egress_program.load()?;
-        egress_program
-            .attach(&opt.iface, TcAttachType::Egress)
-            .context("failed to attach the egress TC program")?;
+        for iface in opt.egress_ifaces() {
+            info!(interface = iface, "attaching tc_egress program");
+            egress_program
+                .attach_with_options(iface, TcAttachType::Egress, opt.tc_options())
+                .with_context(|| format!("failed to attach the egress TC program to {}", iface))?;
+        }
+
+        if opt.xdp {
+            let xdp_program: &mut Xdp = bpf.program_mut("xdp_ingress").unwrap().try_into()?;
+            xdp_program.load()?;
+            for iface in opt.ingress_ifaces() {
+                info!(interface = iface, "attaching xdp_ingress program");
+
+                // The TC programs handle everything on their own, so carry on without the fast
+                // path rather than with a generic XDP program which would only slow things down.
+                if let Err(e) = xdp_program.attach(iface, XdpFlags::DRV_MODE) {
+                    warn!(
+                        interface = iface,
+                        error = %e,
+                        "failed to attach the XDP program in native mode, falling back to TC only"
+                    );
+                }
+            }
+        }
+
+        // The sockets are added to SOCK_PAIRS by the sock_ops program as their connections are
+        // established, and the payload they send is redirected by the sk_msg program attached to
+        // it.
+        if let Some(cgroup) = &opt.sockmap_cgroup {
+            let sock_pairs: SockHash<_, SockKey> = SockHash::try_from(
+                bpf.take_map("SOCK_PAIRS")
+                    .expect("no maps named SOCK_PAIRS"),
+            )?;
+            let sk_msg_program: &mut SkMsg =
+                bpf.program_mut("sk_msg_splice").unwrap().try_into()?;
+            sk_msg_program.load()?;
+            sk_msg_program.attach(sock_pairs.fd())?;
+
+            info!(cgroup = %cgroup.display(), "attaching sock_ops_splice program");
+            let cgroup_file = File::open(cgroup)
+                .with_context(|| format!("failed to open the cgroup {}", cgroup.display()))?;
+            let sock_ops_program: &mut SockOps =
+                bpf.program_mut("sock_ops_splice").unwrap().try_into()?;
+            sock_ops_program.load()?;
+            sock_ops_program.attach(cgroup_file)?;
+        }
 
         info!("starting api server");
         let backends: HashMap<_, BackendKey, BackendList> =
             HashMap::try_from(bpf.take_map("BACKENDS").expect("no maps named BACKENDS"))?;
-        let gateway_indexes: HashMap<_, BackendKey, u16> = HashMap::try_from(
+        let port_ranges: HashMap<_, [u32; 4], PortRangeList> = HashMap::try_from(
+            bpf.take_map("PORT_RANGES")
+                .expect("no maps named PORT_RANGES"),
+        )?;
+        let gateway_aliases: HashMap<_, BackendKey, BackendKey> = HashMap::try_from(
+            bpf.take_map("GATEWAY_ALIASES")
+                .expect("no maps named GATEWAY_ALIASES"),
+        )?;
+        let vip_addresses: HashMap<_, [u32; 4], u32> = HashMap::try_from(
+            bpf.take_map("VIP_ADDRESSES")
+                .expect("no maps named VIP_ADDRESSES"),
+        )?;
+        let gateway_indexes: HashMap<_, GatewaySlotKey, GatewayIndex> = HashMap::try_from(
             bpf.take_map("GATEWAY_INDEXES")
                 .expect("no maps named GATEWAY_INDEXES"),
         )?;
@@ -106,18 +1053,327 @@ async fn main() -> Result<(), anyhow::Error> {
             bpf.take_map("LB_CONNECTIONS")
                 .expect("no maps named LB_CONNECTIONS"),
         )?;
+        let udp_conns: HashMap<_, ClientKey, UdpLoadBalancerMapping> = HashMap::try_from(
+            bpf.take_map("UDP_CONNECTIONS")
+                .expect("no maps named UDP_CONNECTIONS"),
+        )?;
+        let sctp_conns: HashMap<_, ClientKey, UdpLoadBalancerMapping> = HashMap::try_from(
+            bpf.take_map("SCTP_CONNECTIONS")
+                .expect("no maps named SCTP_CONNECTIONS"),
+        )?;
+        let maglev_tables: HashMap<_, GatewaySlotKey, MaglevTable> = HashMap::try_from(
+            bpf.take_map("MAGLEV_TABLES")
+                .expect("no maps named MAGLEV_TABLES"),
+        )?;
+        let backend_conns: PerCpuHashMap<_, BackendKey, BackendConnections> =
+            PerCpuHashMap::try_from(
+                bpf.take_map("BACKEND_CONNECTIONS")
+                    .expect("no maps named BACKEND_CONNECTIONS"),
+            )?;
+        let backend_traffic: PerCpuHashMap<_, BackendKey, BackendTraffic> =
+            PerCpuHashMap::try_from(
+                bpf.take_map("BACKEND_TRAFFIC")
+                    .expect("no maps named BACKEND_TRAFFIC"),
+            )?;
+        let syn_latencies: PerCpuHashMap<_, BackendKey, SynLatency> = PerCpuHashMap::try_from(
+            bpf.take_map("SYN_LATENCIES")
+                .expect("no maps named SYN_LATENCIES"),
+        )?;
+        let backend_failures: HashMap<_, BackendKey, BackendFailures> = HashMap::try_from(
+            bpf.take_map("BACKEND_FAILURES")
+                .expect("no maps named BACKEND_FAILURES"),
+        )?;
+        let connection_limits: HashMap<_, BackendKey, ConnectionLimit> = HashMap::try_from(
+            bpf.take_map("CONNECTION_LIMITS")
+                .expect("no maps named CONNECTION_LIMITS"),
+        )?;
+        let acls: LpmTrie<_, AclKey, AclAction> =
+            LpmTrie::try_from(bpf.take_map("ACLS").expect("no maps named ACLS"))?;
+        let syn_cookies: HashMap<_, BackendKey, u32> = HashMap::try_from(
+            bpf.take_map("SYN_COOKIES")
+                .expect("no maps named SYN_COOKIES"),
+        )?;
+        let dscp_marks: HashMap<_, BackendKey, u8> = HashMap::try_from(
+            bpf.take_map("DSCP_MARKS")
+                .expect("no maps named DSCP_MARKS"),
+        )?;
+        let mirrors: HashMap<_, BackendKey, Mirror> =
+            HashMap::try_from(bpf.take_map("MIRRORS").expect("no maps named MIRRORS"))?;
+        let tproxy_ports: HashMap<_, BackendKey, u16> = HashMap::try_from(
+            bpf.take_map("TPROXY_PORTS")
+                .expect("no maps named TPROXY_PORTS"),
+        )?;
+        let ping_gateways: HashMap<_, [u32; 4], BackendKey> = HashMap::try_from(
+            bpf.take_map("PING_GATEWAYS")
+                .expect("no maps named PING_GATEWAYS"),
+        )?;
+        let stateless_targets: HashMap<_, BackendKey, BackendKey> = HashMap::try_from(
+            bpf.take_map("STATELESS_TARGETS")
+                .expect("no maps named STATELESS_TARGETS"),
+        )?;
+        let tunnels: HashMap<_, [u32; 4], Tunnel> =
+            HashMap::try_from(bpf.take_map("TUNNELS").expect("no maps named TUNNELS"))?;
+        let mut log_level: Array<_, LogLevel> =
+            Array::try_from(bpf.take_map("LOG_LEVEL").expect("no maps named LOG_LEVEL"))?;
+        log_level.set(0, opt.log_level(), 0)?;
+        let mut tcp_timeouts: Array<_, TcpTimeouts> = Array::try_from(
+            bpf.take_map("TCP_TIMEOUTS")
+                .expect("no maps named TCP_TIMEOUTS"),
+        )?;
+        tcp_timeouts.set(0, opt.tcp_timeouts(), 0)?;
+        let limited_conns: PerCpuHashMap<_, BackendKey, u64> = PerCpuHashMap::try_from(
+            bpf.take_map("LIMITED_CONNECTIONS")
+                .expect("no maps named LIMITED_CONNECTIONS"),
+        )?;
+        let released_conns: HashMap<_, BackendKey, u64> = HashMap::try_from(
+            bpf.take_map("RELEASED_CONNECTIONS")
+                .expect("no maps named RELEASED_CONNECTIONS"),
+        )?;
+        let snat_conns: HashMap<_, SnatKey, ClientKey> = HashMap::try_from(
+            bpf.take_map("SNAT_CONNECTIONS")
+                .expect("no maps named SNAT_CONNECTIONS"),
+        )?;
+        let client_conns: HashMap<_, [u32; 4], u32> = HashMap::try_from(
+            bpf.take_map("CLIENT_CONNECTIONS")
+                .expect("no maps named CLIENT_CONNECTIONS"),
+        )?;
+        let redirect_errors: PerCpuArray<_, u64> = PerCpuArray::try_from(
+            bpf.take_map("REDIRECT_ERRORS")
+                .expect("no maps named REDIRECT_ERRORS"),
+        )?;
+        let tcp_drops: PerCpuArray<_, u64> =
+            PerCpuArray::try_from(bpf.take_map("TCP_DROPS").expect("no maps named TCP_DROPS"))?;
+        let tcp_passes: PerCpuArray<_, u64> = PerCpuArray::try_from(
+            bpf.take_map("TCP_PASSES")
+                .expect("no maps named TCP_PASSES"),
+        )?;
+        let flow_table_stats: PerCpuArray<_, FlowTableStats> = PerCpuArray::try_from(
+            bpf.take_map("FLOW_TABLE_STATS")
+                .expect("no maps named FLOW_TABLE_STATS"),
+        )?;
+        let connection_events: RingBuf<_> = RingBuf::try_from(
+            bpf.take_map("CONNECTION_EVENTS")
+                .expect("no maps named CONNECTION_EVENTS"),
+        )?;
+        let packet_samples: RingBuf<_> = RingBuf::try_from(
+            bpf.take_map("PACKET_SAMPLES")
+                .expect("no maps named PACKET_SAMPLES"),
+        )?;
+        let captures: HashMap<_, BackendKey, u8> =
+            HashMap::try_from(bpf.take_map("CAPTURES").expect("no maps named CAPTURES"))?;
+        let captured_packets: RingBuf<_> = RingBuf::try_from(
+            bpf.take_map("CAPTURED_PACKETS")
+                .expect("no maps named CAPTURED_PACKETS"),
+        )?;
 
-        start_api_server(
-            Ipv4Addr::new(0, 0, 0, 0),
-            9874,
-            backends,
-            gateway_indexes,
-            tcp_conns,
-        )
-        .await?;
+        // The watcher owns the programs from then on, and hands them back when the dataplane stops.
+        let (bpf, watcher) = match iface_pattern {
+            Some(pattern) => {
+                // The interfaces attached to at startup are left as they are.
+                let mut skipped: Vec<String> =
+                    opt.tc_ifaces().into_iter().map(String::from).collect();
+                skipped.extend(opt.exclude_iface.iter().cloned());
+                let mut hotplug =
+                    Hotplug::new(bpf, pattern, skipped, opt.tc_priority, opt.tc_handle);
+                let watcher_shutdown = shutdown.clone();
+                let watcher = tokio::spawn(async move {
+                    let result = tokio::select! {
+                        result = hotplug.run() => result,
+                        _ = watcher_shutdown.clone().stopping() => Ok(()),
+                    };
+                    if let Err(e) = result {
+                        warn!(
+                            error = format!("{:#}", e),
+                            "stopped watching for new interfaces"
+                        );
+                        // Dropping the programs would detach them from every interface.
+                        watcher_shutdown.stopping().await;
+                    }
+                    hotplug
+                });
+                (None, Some(watcher))
+            }
+            None => (Some(bpf), None),
+        };
+
+        let api_server = start_api_server(
+            opt.server_options(false, conntrack_capacity),
+            BpfMaps {
+                config,
+                backends,
+                port_ranges,
+                gateway_aliases,
+                vip_addresses,
+                gateway_indexes,
+                tcp_conns,
+                udp_conns,
+                sctp_conns,
+                maglev_tables,
+                backend_conns,
+                backend_traffic,
+                syn_latencies,
+                backend_failures,
+                connection_limits,
+                acls,
+                syn_cookies,
+                dscp_marks,
+                mirrors,
+                tproxy_ports,
+                ping_gateways,
+                stateless_targets,
+                tunnels,
+                log_level,
+                tcp_timeouts,
+                limited_conns,
+                released_conns,
+                snat_conns,
+                client_conns,
+                redirect_errors,
+                tcp_drops,
+                tcp_passes,
+                flow_table_stats,
+                connection_events,
+                packet_samples,
+                captures,
+                captured_packets,
+            },
+            settings_rx,
+            shutdown.clone().stopping(),
+        );
+        shutdown.serve(api_server).await?;
+
+        // Dropping the programs detaches them, which removes only their own TC filters: the clsact
+        // qdiscs are left in place, along with the filters of others.
+        info!("detaching the programs");
+        drop(bpf);
+        if let Some(watcher) = watcher {
+            drop(watcher.await?);
+        }
+        if opt.flush_maps_on_exit {
+            shutdown::flush_pinned_maps(&opt.pin_path);
+        }
     }
 
     info!("Exiting...");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes the config file of a test, named after it so that the tests
+    // running in parallel don't share their files.
+    fn config_file(name: &str, content: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("blixt-config-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        std::iter::once("loader")
+            .chain(args.iter().copied())
+            .map(OsString::from)
+            .collect()
+    }
+
+    #[test]
+    fn config_values_are_flags() {
+        let path = config_file(
+            "values",
+            r#"
+            iface = ["eth0", "eth1"]
+            udp_idle_timeout = 10
+            reset_without_backend = true
+            xdp = false
+            snat_ipv4 = "192.0.2.1"
+            "#,
+        );
+        let mut flags = config_args(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        flags.sort();
+        assert_eq!(
+            flags,
+            [
+                "--iface=eth0",
+                "--iface=eth1",
+                "--reset-without-backend",
+                "--snat-ipv4=192.0.2.1",
+                "--udp-idle-timeout=10",
+            ]
+        );
+    }
+
+    #[test]
+    fn config_tables_are_rejected() {
+        let path = config_file("tables", "[iface]\nname = \"eth0\"\n");
+        let result = config_args(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn missing_config_file_is_an_error() {
+        let path = std::env::temp_dir().join("blixt-config-missing.toml");
+        assert!(parse_opt(&args(&["--config", path.to_str().unwrap()])).is_err());
+    }
+
+    #[test]
+    fn command_line_overrides_config_file() {
+        let path = config_file(
+            "overrides",
+            "udp_idle_timeout = 10\ntcp_syn_sent_timeout = 20\n",
+        );
+        let opt = parse_opt(&args(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--udp-idle-timeout",
+            "15",
+        ]));
+        std::fs::remove_file(&path).unwrap();
+        let opt = opt.unwrap();
+        assert_eq!(opt.udp_idle_timeout, 15);
+        assert_eq!(opt.tcp_syn_sent_timeout, 20);
+    }
+
+    #[test]
+    fn config_values_are_validated() {
+        let path = config_file("invalid", "udp_idle_timeout = 0\n");
+        let result = parse_opt(&args(&["--config", path.to_str().unwrap()]));
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn conntrack_capacity_is_configurable() {
+        let opt = parse_opt(&args(&[])).unwrap();
+        assert_eq!(opt.conntrack_capacity, LB_CONNECTIONS_CAPACITY);
+
+        let path = config_file("capacity", "conntrack_capacity = 1048576\n");
+        let opt = parse_opt(&args(&["--config", path.to_str().unwrap()]));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(opt.unwrap().conntrack_capacity, 1048576);
+        assert!(parse_opt(&args(&["--conntrack-capacity", "0"])).is_err());
+    }
+
+    #[test]
+    fn snat_port_range_must_not_be_empty() {
+        assert!(parse_opt(&args(&[
+            "--snat-port-min",
+            "2000",
+            "--snat-port-max",
+            "1000"
+        ]))
+        .is_err());
+        let opt = parse_opt(&args(&[
+            "--snat-port-min",
+            "1000",
+            "--snat-port-max",
+            "2000",
+        ]))
+        .unwrap();
+        assert_eq!((opt.snat_port_min, opt.snat_port_max), (1000, 2000));
+    }
+}
@@ -0,0 +1,100 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// How long the API calls in flight are given to finish once the dataplane is
+/// asked to stop, the streams of the watchers never finishing on their own.
+const GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// The maps the eBPF programs pin by name under the --pin-path directory, see
+/// the declarations of the maps in the eBPF programs.
+const PINNED_MAPS: [&str; 9] = [
+    "BACKENDS",
+    "PORT_RANGES",
+    "VIP_ADDRESSES",
+    "GATEWAY_ALIASES",
+    "GATEWAY_INDEXES",
+    "MAGLEV_TABLES",
+    "BACKEND_CONNECTIONS",
+    "LB_CONNECTIONS",
+    "SNAT_CONNECTIONS",
+];
+
+/// Tells the tasks of the dataplane when it is asked to stop, with SIGTERM or
+/// SIGINT, which no longer kill it once it listens to them.
+#[derive(Clone)]
+pub struct Shutdown {
+    stopping: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    /// Starts listening to SIGTERM and SIGINT.
+    pub fn listen() -> Result<Shutdown, io::Error> {
+        let mut terminations = signal(SignalKind::terminate())?;
+        let mut interruptions = signal(SignalKind::interrupt())?;
+        let (sender, stopping) = watch::channel(false);
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = terminations.recv() => info!("stopping on SIGTERM"),
+                _ = interruptions.recv() => info!("stopping on SIGINT"),
+            }
+            let _ = sender.send(true);
+        });
+        Ok(Shutdown { stopping })
+    }
+
+    /// Resolves once the dataplane is asked to stop.
+    pub async fn stopping(mut self) {
+        let _ = self.stopping.wait_for(|stopping| *stopping).await;
+    }
+
+    /// Runs the API server until it stops on its own, or until the dataplane
+    /// is asked to stop and the calls in flight finished, for at most
+    /// GRACE_PERIOD. The server must stop accepting calls on `stopping`.
+    pub async fn serve(
+        self,
+        server: impl Future<Output = Result<(), anyhow::Error>>,
+    ) -> Result<(), anyhow::Error> {
+        tokio::pin!(server);
+        tokio::select! {
+            result = &mut server => return result,
+            _ = self.stopping() => {}
+        }
+        match tokio::time::timeout(GRACE_PERIOD, server).await {
+            Ok(result) => result,
+            Err(_) => {
+                warn!(
+                    grace_period = ?GRACE_PERIOD,
+                    "API calls still in flight after the grace period, stopping anyway"
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Unpins the maps pinned by name, so that the next programs start without the
+/// Gateways and the tracked connections. The directory is removed too if
+/// nothing else is pinned in it.
+pub fn flush_pinned_maps(pin_path: &Path) {
+    for name in PINNED_MAPS {
+        let path = pin_path.join(name);
+        match std::fs::remove_file(&path) {
+            Ok(()) => info!(map = name, "unpinned map"),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => warn!(map = name, error = %err, "failed to unpin map"),
+        }
+    }
+    let _ = std::fs::remove_dir(pin_path);
+}
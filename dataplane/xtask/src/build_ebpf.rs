@@ -42,6 +42,9 @@ pub struct Options {
     pub target: Architecture,
     #[clap(long)]
     pub release: bool,
+    /// Leave the logs out of the programs, which then ignore the log level
+    #[clap(long)]
+    pub no_logging: bool,
 }
 
 pub fn build_ebpf(opts: Options) -> Result<(), anyhow::Error> {
@@ -58,6 +61,9 @@ pub fn build_ebpf(opts: Options) -> Result<(), anyhow::Error> {
     if opts.release {
         args.push("--release")
     }
+    if opts.no_logging {
+        args.push("--no-default-features")
+    }
     let status = Command::new("cargo")
         .current_dir(&dir)
         .args(&args)
@@ -7,8 +7,8 @@ SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
 // Remember to run `cargo install bindgen-cli`
 
 mod build_ebpf;
-mod grpc;
 mod run;
+mod test_datapath;
 
 use std::process::exit;
 
@@ -24,18 +24,17 @@ pub struct Options {
 enum Command {
     BuildEbpf(build_ebpf::Options),
     Run(run::Options),
-    GrpcClient(grpc::Options),
+    TestDatapath(test_datapath::Options),
 }
 
-#[tokio::main]
-async fn main() {
+fn main() {
     let opts = Options::parse();
 
     use Command::*;
     let ret = match opts.command {
         BuildEbpf(opts) => build_ebpf::build_ebpf(opts),
         Run(opts) => run::run(opts),
-        GrpcClient(opts) => grpc::update(opts).await,
+        TestDatapath(opts) => test_datapath::test_datapath(opts),
     };
 
     if let Err(e) = ret {
@@ -19,6 +19,9 @@ pub struct Options {
     /// Build and run the release target
     #[clap(long)]
     pub release: bool,
+    /// Leave the logs out of the eBPF programs
+    #[clap(long)]
+    pub no_logging: bool,
     /// The command used to wrap your application
     #[clap(short, long, default_value = "sudo -E")]
     pub runner: String,
@@ -47,6 +50,7 @@ pub fn run(opts: Options) -> Result<(), anyhow::Error> {
     build_ebpf(BuildOptions {
         target: opts.bpf_target,
         release: opts.release,
+        no_logging: opts.no_logging,
     })
     .context("Error while building eBPF program")?;
     build(&opts).context("Error while building userspace application")?;
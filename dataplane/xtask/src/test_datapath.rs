@@ -0,0 +1,53 @@
+/*
+Copyright 2023 The Kubernetes Authors.
+
+SPDX-License-Identifier: (GPL-2.0-only OR BSD-2-Clause)
+*/
+
+use std::{os::unix::process::CommandExt, process::Command};
+
+use anyhow::Context as _;
+use clap::Parser;
+
+use crate::build_ebpf::{build_ebpf, Architecture, Options as BuildOptions};
+
+#[derive(Debug, Parser)]
+pub struct Options {
+    /// Set the endianness of the BPF target
+    #[clap(default_value = "bpfel-unknown-none", long)]
+    pub bpf_target: Architecture,
+    /// Build and test the release target
+    #[clap(long)]
+    pub release: bool,
+    /// The command used to wrap the tests, which load the eBPF programs
+    #[clap(short, long, default_value = "sudo -E")]
+    pub runner: String,
+    /// Arguments to pass to the test harness, e.g. a filter of the tests
+    #[clap(name = "args", last = true)]
+    pub test_args: Vec<String>,
+}
+
+/// Build the eBPF programs and run the datapath tests through them
+pub fn test_datapath(opts: Options) -> Result<(), anyhow::Error> {
+    build_ebpf(BuildOptions {
+        target: opts.bpf_target,
+        release: opts.release,
+        no_logging: false,
+    })
+    .context("Error while building eBPF program")?;
+
+    // the tests are ignored by default, as they need root
+    let runner = format!("target.'cfg(all())'.runner='{}'", opts.runner.trim());
+    let mut args = vec!["test", "-p", "datapath-tests", "--config", runner.as_str()];
+    if opts.release {
+        args.push("--release")
+    }
+    args.extend(["--", "--include-ignored"]);
+    args.extend(opts.test_args.iter().map(String::as_str));
+
+    // spawn the command
+    let err = Command::new("cargo").args(&args).exec();
+
+    // we shouldn't get here unless the command failed to spawn
+    Err(anyhow::Error::from(err).context(format!("Failed to run `cargo {}`", args.join(" "))))
+}